@@ -4,29 +4,200 @@ extern crate hyper;
 extern crate env_logger;
 
 use std::env;
-use std::io;
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::process;
+use std::time::Duration;
 
 use hyper::Client;
-use hyper::header::Connection;
+use hyper::client::{Pool, RedirectPolicy};
+use hyper::header::{Connection, Headers};
+use hyper::method::Method;
+
+const USAGE: &'static str = "\
+Usage: client [options] <url>
+
+Options:
+    -X <method>         Request method, GET by default
+    -H <name: value>    Add a request header, may be repeated
+    -d <data>           Send data as the body, or the contents of a file with -d @file
+    -o <file>           Write the body to a file instead of stdout
+    -L                  Follow redirects
+    --connect-timeout <secs>
+                        Fail if connecting takes longer, for http URLs only
+    --max-time <secs>   Fail if a read or write takes longer";
+
+// Exit codes, so scripts can tell failures apart.
+const EXIT_USAGE: i32 = 2;
+const EXIT_CONNECT: i32 = 7;
+const EXIT_HTTP_ERROR: i32 = 22;
+const EXIT_TIMEOUT: i32 = 28;
+const EXIT_OTHER: i32 = 1;
+
+struct Args {
+    url: String,
+    method: Method,
+    headers: Headers,
+    data: Option<Vec<u8>>,
+    output: Option<String>,
+    follow: bool,
+    connect_timeout: Option<Duration>,
+    max_time: Option<Duration>,
+}
+
+fn usage(msg: &str) -> ! {
+    let _ = writeln!(io::stderr(), "{}\n\n{}", msg, USAGE);
+    process::exit(EXIT_USAGE);
+}
+
+fn parse_args() -> Args {
+    let mut args = Args {
+        url: String::new(),
+        method: Method::Get,
+        headers: Headers::new(),
+        data: None,
+        output: None,
+        follow: false,
+        connect_timeout: None,
+        max_time: None,
+    };
+    let mut argv = env::args().skip(1);
+    while let Some(arg) = argv.next() {
+        let mut value = |name: &str| match argv.next() {
+            Some(value) => value,
+            None => usage(&format!("{} needs a value", name)),
+        };
+        match &arg[..] {
+            "-X" => {
+                args.method = match value("-X").parse() {
+                    Ok(method) => method,
+                    Err(_) => usage("invalid method"),
+                }
+            },
+            "-H" => {
+                let header = value("-H");
+                let colon = match header.find(':') {
+                    Some(colon) => colon,
+                    None => usage("headers look like 'Name: value'"),
+                };
+                let val = header[colon + 1..].trim().as_bytes().to_vec();
+                args.headers.set_raw(header[..colon].to_owned(), vec![val]);
+            },
+            "-d" => {
+                let data = value("-d");
+                args.data = Some(if data.starts_with('@') {
+                    let mut body = Vec::new();
+                    if let Err(e) = File::open(&data[1..]).and_then(|mut f| f.read_to_end(&mut body)) {
+                        usage(&format!("can't read {}: {}", &data[1..], e));
+                    }
+                    body
+                } else {
+                    data.into_bytes()
+                });
+                if args.method == Method::Get {
+                    args.method = Method::Post;
+                }
+            },
+            "-o" => args.output = Some(value("-o")),
+            "-L" => args.follow = true,
+            "--connect-timeout" => {
+                args.connect_timeout = match value("--connect-timeout").parse() {
+                    Ok(secs) => Some(Duration::from_secs(secs)),
+                    Err(_) => usage("--connect-timeout takes whole seconds"),
+                }
+            },
+            "--max-time" => {
+                args.max_time = match value("--max-time").parse() {
+                    Ok(secs) => Some(Duration::from_secs(secs)),
+                    Err(_) => usage("--max-time takes whole seconds"),
+                }
+            },
+            _ if arg.starts_with('-') => usage(&format!("unknown option {}", arg)),
+            _ => args.url = arg,
+        }
+    }
+    if args.url.is_empty() {
+        usage("no url given");
+    }
+    if args.connect_timeout.is_some() && !args.url.starts_with("http:") {
+        usage("--connect-timeout only works for http URLs");
+    }
+    args
+}
+
+// Connects to the first address of `host` that answers within `timeout`.
+fn connect(host: &str, port: u16, timeout: Duration) -> io::Result<TcpStream> {
+    (host, port).to_socket_addrs().and_then(|addrs| {
+        let mut last = io::Error::new(io::ErrorKind::InvalidInput, "host has no addresses");
+        for addr in addrs {
+            match TcpStream::connect_timeout(&addr, timeout) {
+                Ok(stream) => return Ok(stream),
+                Err(e) => last = e,
+            }
+        }
+        Err(last)
+    })
+}
 
 fn main() {
     env_logger::init().unwrap();
+    let args = parse_args();
 
-    let url = match env::args().nth(1) {
-        Some(url) => url,
-        None => {
-            println!("Usage: client <url>");
-            return;
-        }
+    let mut client = match args.connect_timeout {
+        Some(timeout) => {
+            let connector = move |host: &str, port: u16, scheme: &str| match scheme {
+                "http" => connect(host, port, timeout),
+                _ => Err(io::Error::new(io::ErrorKind::InvalidInput, "only http has a connect timeout")),
+            };
+            Client::with_connector(Pool::with_connector(Default::default(), connector))
+        },
+        None => Client::new(),
     };
+    client.set_redirect_policy(if args.follow {
+        RedirectPolicy::FollowAll
+    } else {
+        RedirectPolicy::FollowNone
+    });
+    client.set_read_timeout(args.max_time);
+    client.set_write_timeout(args.max_time);
 
-    let client = Client::new();
+    let mut req = client.request(args.method, &*args.url)
+        .headers(args.headers)
+        .header(Connection::close());
+    if let Some(ref data) = args.data {
+        req = req.body(&data[..]);
+    }
 
-    let mut res = client.get(&*url)
-        .header(Connection::close())
-        .send().unwrap();
+    let res = match args.output {
+        Some(ref path) => match File::create(path) {
+            Ok(file) => req.sink(file).map(|download| download.status),
+            Err(e) => {
+                let _ = writeln!(io::stderr(), "can't create {}: {}", path, e);
+                process::exit(EXIT_OTHER);
+            }
+        },
+        None => req.sink(io::stdout()).map(|download| download.status),
+    };
 
-    println!("Response: {}", res.status);
-    println!("Headers:\n{}", res.headers);
-    io::copy(&mut res, &mut io::stdout()).unwrap();
+    match res {
+        Ok(status) if status.is_client_error() || status.is_server_error() => {
+            let _ = writeln!(io::stderr(), "HTTP error: {}", status);
+            process::exit(EXIT_HTTP_ERROR);
+        },
+        Ok(_) => (),
+        Err(hyper::Error::Io(ref e)) if e.kind() == io::ErrorKind::ConnectionRefused => {
+            let _ = writeln!(io::stderr(), "couldn't connect: {}", e);
+            process::exit(EXIT_CONNECT);
+        },
+        Err(hyper::Error::Io(ref e)) if e.kind() == io::ErrorKind::TimedOut ||
+                                        e.kind() == io::ErrorKind::WouldBlock => {
+            let _ = writeln!(io::stderr(), "timed out: {}", e);
+            process::exit(EXIT_TIMEOUT);
+        },
+        Err(e) => {
+            let _ = writeln!(io::stderr(), "error: {}", e);
+            process::exit(EXIT_OTHER);
+        },
+    }
 }
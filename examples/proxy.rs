@@ -0,0 +1,63 @@
+#![deny(warnings)]
+extern crate hyper;
+extern crate env_logger;
+
+use std::env;
+use std::io;
+
+use hyper::Client;
+use hyper::client::RequestBuilder;
+use hyper::net::Fresh;
+use hyper::proxy::ProxyRewrite;
+use hyper::server::{Handler, Request, Response};
+use hyper::status::StatusCode;
+use hyper::Url;
+
+struct Proxy {
+    client: Client,
+    rewrite: ProxyRewrite,
+}
+
+impl Handler for Proxy {
+    fn handle<'a, 'k>(&'a self, mut req: Request<'a, 'k>, mut res: Response<'a, Fresh>) {
+        let forwarded = RequestBuilder::from_incoming(&self.client, &mut req, &self.rewrite);
+        let mut upstream = match forwarded.and_then(|builder| builder.send()) {
+            Ok(upstream) => upstream,
+            Err(e) => {
+                println!("upstream error: {}", e);
+                *res.status_mut() = StatusCode::BadGateway;
+                return;
+            }
+        };
+
+        res.copy_from(upstream.status, &upstream.headers);
+        let mut res = match res.start() {
+            Ok(res) => res,
+            Err(e) => return println!("error writing response: {}", e)
+        };
+        if let Err(e) = io::copy(&mut upstream, &mut res).and_then(|_| res.end()) {
+            println!("error writing response: {}", e);
+        }
+    }
+}
+
+fn main() {
+    env_logger::init().unwrap();
+
+    let backend = match env::args().nth(1) {
+        Some(url) => url,
+        None => {
+            println!("Usage: proxy <backend url>");
+            return;
+        }
+    };
+    let rewrite = ProxyRewrite::new(Url::parse(&backend).unwrap());
+
+    let proxy = Proxy {
+        client: Client::new(),
+        rewrite: rewrite,
+    };
+    let _listening = hyper::Server::http("127.0.0.1:3000").unwrap()
+        .handle(proxy);
+    println!("Proxying http://127.0.0.1:3000 to {}", backend);
+}
@@ -0,0 +1,59 @@
+#![deny(warnings)]
+extern crate hyper;
+extern crate env_logger;
+
+use std::collections::HashMap;
+
+use hyper::server::{Server, Request, Response};
+use hyper::server::shared::{Shared, SharedHandler};
+use hyper::status::StatusCode;
+use hyper::uri::RequestUri::AbsolutePath;
+
+#[derive(Default)]
+struct Stats {
+    requests: u64,
+    paths: HashMap<String, u64>,
+}
+
+fn stats(stats: &Shared<Stats>, req: Request, mut res: Response) {
+    let path = match req.uri {
+        AbsolutePath(ref path) => path.clone(),
+        _ => {
+            *res.status_mut() = StatusCode::BadRequest;
+            return;
+        }
+    };
+
+    if path == "/stats" {
+        // never wait on the lock, it's only a report
+        let report = stats.try_with(|stats| {
+            let mut report = format!("{} requests\n", stats.requests);
+            for (path, hits) in &stats.paths {
+                report.push_str(&format!("{}: {}\n", path, hits));
+            }
+            report
+        });
+        match report {
+            Ok(report) => res.send(report.as_bytes()).unwrap(),
+            Err(e) => {
+                *res.status_mut() = StatusCode::ServiceUnavailable;
+                res.send(e.to_string().as_bytes()).unwrap();
+            }
+        }
+        return;
+    }
+
+    // count, then let go of the lock before writing the response
+    stats.with(|stats| {
+        stats.requests += 1;
+        *stats.paths.entry(path).or_insert(0) += 1;
+    });
+    res.send(b"Counted, see /stats").unwrap();
+}
+
+fn main() {
+    env_logger::init().unwrap();
+    let handler = SharedHandler::new(Shared::new(Stats::default()), stats);
+    let _listening = Server::http("127.0.0.1:3000").unwrap().handle(handler);
+    println!("Listening on http://127.0.0.1:3000");
+}
@@ -6,6 +6,7 @@ pub struct BufReader<R> {
     buf: Vec<u8>,
     pos: usize,
     cap: usize,
+    total: u64,
 }
 
 const INIT_BUFFER_SIZE: usize = 4096;
@@ -24,6 +25,7 @@ impl<R: Read> BufReader<R> {
             buf: vec![0; cap],
             pos: 0,
             cap: 0,
+            total: 0,
         }
     }
 
@@ -47,6 +49,10 @@ impl<R: Read> BufReader<R> {
     #[inline]
     pub fn into_inner(self) -> R { self.inner }
 
+    /// How many bytes have been read from the inner reader.
+    #[inline]
+    pub fn total_read(&self) -> u64 { self.total }
+
     #[inline]
     pub fn read_into_buf(&mut self) -> io::Result<usize> {
         self.maybe_reserve();
@@ -55,6 +61,7 @@ impl<R: Read> BufReader<R> {
         if self.cap < v.capacity() {
             let nread = try!(self.inner.read(&mut v[self.cap..]));
             self.cap += nread;
+            self.total += nread as u64;
             Ok(nread)
         } else {
             trace!("read_into_buf at full capacity");
@@ -85,7 +92,9 @@ unsafe fn grow_zerofill(buf: &mut Vec<u8>, additional: usize) {
 impl<R: Read> Read for BufReader<R> {
     fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
         if self.cap == self.pos && buf.len() >= self.buf.len() {
-            return self.inner.read(buf);
+            let nread = try!(self.inner.read(buf));
+            self.total += nread as u64;
+            return Ok(nread);
         }
         let nread = {
            let mut rem = try!(self.fill_buf());
@@ -101,6 +110,7 @@ impl<R: Read> BufRead for BufReader<R> {
         if self.pos == self.cap {
             self.cap = try!(self.inner.read(&mut self.buf));
             self.pos = 0;
+            self.total += self.cap as u64;
         }
         Ok(&self.buf[self.pos..self.cap])
     }
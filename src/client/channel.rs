@@ -0,0 +1,160 @@
+//! Request bodies pushed from another thread.
+use std::fmt;
+use std::io::{self, Read};
+use std::sync::mpsc::{self, Receiver, SyncSender, TrySendError};
+
+/// How many chunks may be queued before `BodySender::send` blocks.
+const CHANNEL_CAPACITY: usize = 16;
+
+enum Message {
+    Chunk(Vec<u8>),
+    Finish,
+}
+
+/// The sending half of a body created with `Body::channel()`.
+///
+/// Chunks are written to the request as they arrive. The body ends when
+/// `finish` is called; if the sender is dropped without calling it, the
+/// request is aborted instead, and `RequestBuilder::send` returns an error.
+pub struct BodySender {
+    tx: SyncSender<Message>,
+}
+
+/// The receiving half of a body created with `Body::channel()`.
+pub struct BodyReceiver {
+    rx: Receiver<Message>,
+    chunk: Vec<u8>,
+    pos: usize,
+    finished: bool,
+}
+
+/// Creates a connected sender and receiver.
+pub fn channel() -> (BodySender, BodyReceiver) {
+    let (tx, rx) = mpsc::sync_channel(CHANNEL_CAPACITY);
+    (BodySender { tx: tx }, BodyReceiver {
+        rx: rx,
+        chunk: Vec::new(),
+        pos: 0,
+        finished: false,
+    })
+}
+
+impl BodySender {
+    /// Queues a chunk of the body, blocking while the queue is full.
+    ///
+    /// Returns an error if the request is no longer being sent.
+    pub fn send(&self, chunk: Vec<u8>) -> io::Result<()> {
+        self.tx.send(Message::Chunk(chunk)).map_err(|_| closed())
+    }
+
+    /// Queues a chunk of the body without blocking.
+    ///
+    /// If the queue is full, the chunk is handed back in `TrySendError::Full`.
+    pub fn try_send(&self, chunk: Vec<u8>) -> Result<(), TrySendError<Vec<u8>>> {
+        match self.tx.try_send(Message::Chunk(chunk)) {
+            Ok(()) => Ok(()),
+            Err(TrySendError::Full(Message::Chunk(chunk))) => Err(TrySendError::Full(chunk)),
+            Err(TrySendError::Disconnected(Message::Chunk(chunk))) => {
+                Err(TrySendError::Disconnected(chunk))
+            },
+            Err(_) => unreachable!("only chunks are sent by try_send"),
+        }
+    }
+
+    /// Ends the body.
+    pub fn finish(self) -> io::Result<()> {
+        self.tx.send(Message::Finish).map_err(|_| closed())
+    }
+}
+
+impl fmt::Debug for BodySender {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("BodySender")
+    }
+}
+
+impl fmt::Debug for BodyReceiver {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("BodyReceiver")
+         .field("finished", &self.finished)
+         .finish()
+    }
+}
+
+impl Read for BodyReceiver {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.pos == self.chunk.len() {
+            if self.finished {
+                return Ok(0);
+            }
+            match self.rx.recv() {
+                Ok(Message::Chunk(chunk)) => {
+                    self.chunk = chunk;
+                    self.pos = 0;
+                },
+                Ok(Message::Finish) => self.finished = true,
+                Err(_) => return Err(io::Error::new(io::ErrorKind::ConnectionAborted,
+                                                    "body sender dropped before finish")),
+            }
+        }
+
+        let n = try!((&self.chunk[self.pos..]).read(buf));
+        self.pos += n;
+        Ok(n)
+    }
+}
+
+fn closed() -> io::Error {
+    io::Error::new(io::ErrorKind::BrokenPipe, "request body is no longer being sent")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{ErrorKind, Read};
+    use std::sync::mpsc::TrySendError;
+    use std::thread;
+
+    use super::{channel, CHANNEL_CAPACITY};
+
+    #[test]
+    fn test_read_chunks() {
+        let (tx, mut rx) = channel();
+        tx.send(b"foo".to_vec()).unwrap();
+        tx.send(Vec::new()).unwrap();
+        tx.send(b"bar".to_vec()).unwrap();
+        tx.finish().unwrap();
+
+        let mut s = String::new();
+        rx.read_to_string(&mut s).unwrap();
+        assert_eq!(s, "foobar");
+        assert_eq!(rx.read(&mut [0; 8]).unwrap(), 0);
+    }
+
+    #[test]
+    fn test_dropped_sender_aborts() {
+        let (tx, mut rx) = channel();
+        thread::spawn(move || {
+            tx.send(b"foo".to_vec()).unwrap();
+        });
+
+        let mut buf = Vec::new();
+        let err = rx.read_to_end(&mut buf).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::ConnectionAborted);
+        assert_eq!(buf, b"foo");
+    }
+
+    #[test]
+    fn test_try_send_full() {
+        let (tx, rx) = channel();
+        for _ in 0..CHANNEL_CAPACITY {
+            tx.try_send(b"x".to_vec()).unwrap();
+        }
+        match tx.try_send(b"y".to_vec()) {
+            Err(TrySendError::Full(chunk)) => assert_eq!(chunk, b"y"),
+            other => panic!("expected Full, got {:?}", other),
+        }
+
+        drop(rx);
+        assert_eq!(tx.send(b"z".to_vec()).unwrap_err().kind(), ErrorKind::BrokenPipe);
+    }
+}
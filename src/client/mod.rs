@@ -32,6 +32,18 @@
 //! assert_eq!(res.status, hyper::Ok);
 //! ```
 //!
+//! ## Downloading
+//!
+//! ```no_run
+//! # use hyper::Client;
+//! use std::fs::File;
+//!
+//! let client = Client::new();
+//! let file = File::create("index.html").unwrap();
+//! let download = client.get("http://example.domain").sink(file).unwrap();
+//! println!("wrote {} bytes", download.len);
+//! ```
+//!
 //! # Sync
 //!
 //! The `Client` implements `Sync`, so you can share it among multiple threads
@@ -55,8 +67,10 @@
 //!     clone2.post("http://example.domain/post").body("foo=bar").send().unwrap();
 //! });
 //! ```
+use std::collections::hash_map::RandomState;
 use std::default::Default;
-use std::io::{self, copy, Read};
+use std::hash::{BuildHasher, Hasher};
+use std::io::{self, copy, Read, Seek, SeekFrom, Write};
 use std::iter::Extend;
 use std::fmt;
 
@@ -69,9 +83,13 @@ use header::{Headers, Header, HeaderFormat};
 use header::{ContentLength, Location};
 use method::Method;
 use net::{NetworkConnector, NetworkStream};
+use proxy::ProxyRewrite;
+use server;
+use status::StatusCode;
 use {Url};
 use Error;
 
+pub use self::channel::{BodySender, BodyReceiver};
 pub use self::pool::Pool;
 pub use self::request::Request;
 pub use self::response::Response;
@@ -80,7 +98,9 @@ pub mod pool;
 pub mod request;
 pub mod response;
 
-use http::Protocol;
+mod channel;
+
+use http::{Direction, Protocol, Tap};
 use http::h1::Http11Protocol;
 
 /// A Client to use additional features with Requests.
@@ -91,6 +111,8 @@ pub struct Client {
     redirect_policy: RedirectPolicy,
     read_timeout: Option<Duration>,
     write_timeout: Option<Duration>,
+    max_skip_bytes: usize,
+    max_retries: u32,
 }
 
 impl fmt::Debug for Client {
@@ -99,6 +121,8 @@ impl fmt::Debug for Client {
            .field("redirect_policy", &self.redirect_policy)
            .field("read_timeout", &self.read_timeout)
            .field("write_timeout", &self.write_timeout)
+           .field("max_skip_bytes", &self.max_skip_bytes)
+           .field("max_retries", &self.max_retries)
            .finish()
     }
 }
@@ -128,6 +152,8 @@ impl Client {
             redirect_policy: Default::default(),
             read_timeout: None,
             write_timeout: None,
+            max_skip_bytes: 0,
+            max_retries: 0,
         }
     }
 
@@ -146,12 +172,45 @@ impl Client {
         self.write_timeout = dur;
     }
 
+    /// Allows up to `max_skip_bytes` invalid bytes in front of a response,
+    /// such as a stray body left over from a previous response or a byte
+    /// order mark, before the response fails to parse.
+    ///
+    /// The invalid bytes are skipped one at a time until a response can be
+    /// parsed. If none can be within the limit, the error for the original
+    /// position is returned. `Response::skipped_bytes` tells how many bytes
+    /// were skipped.
+    ///
+    /// Default is 0, meaning the response must start right away.
+    pub fn set_lenient_response_parsing(&mut self, max_skip_bytes: usize) {
+        self.max_skip_bytes = max_skip_bytes;
+    }
+
+    /// Sets how many times a request is sent again when its connection
+    /// fails before a response arrives, such as a kept-alive connection
+    /// the server had already closed.
+    ///
+    /// Only requests with an idempotent method, or with an
+    /// `Idempotency-Key` header, are retried, since the server may have
+    /// acted on the failed attempt. Their body must also be replayable:
+    /// bytes, or a `Body::seekable` reader. Other bodies fail with
+    /// `Error::BodyNotReplayable` instead of being retried.
+    ///
+    /// Default is 0, meaning requests are never retried.
+    pub fn set_max_retries(&mut self, retries: u32) {
+        self.max_retries = retries;
+    }
+
     /// Build a Get request.
+    ///
+    /// A body set on the request is not sent.
     pub fn get<U: IntoUrl>(&self, url: U) -> RequestBuilder {
         self.request(Method::Get, url)
     }
 
     /// Build a Head request.
+    ///
+    /// A body set on the request is not sent.
     pub fn head<U: IntoUrl>(&self, url: U) -> RequestBuilder {
         self.request(Method::Head, url)
     }
@@ -185,6 +244,10 @@ impl Client {
             url: url.into_url(),
             body: None,
             headers: None,
+            upload_progress: None,
+            download_progress: None,
+            on_retry: None,
+            tap: None,
         }
     }
 }
@@ -212,11 +275,68 @@ pub struct RequestBuilder<'a> {
     headers: Option<Headers>,
     method: Method,
     body: Option<Body<'a>>,
+    upload_progress: Option<Progress>,
+    download_progress: Option<Progress>,
+    on_retry: Option<RetryHook>,
+    tap: Option<Tap>,
+}
+
+/// A progress callback, given the bytes transferred so far and the total
+/// if it is known.
+pub type Progress = fn(u64, Option<u64>);
+
+/// A callback run before a request is sent again, given the number of the
+/// retry, starting at 1, and the error that failed the previous attempt.
+pub type RetryHook = fn(u32, &Error);
+
+/// The key sent in the `Idempotency-Key` header, so a server can tell a
+/// retried request from a new one.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum IdempotencyKey {
+    /// A random key, generated once for the request.
+    Auto,
+    /// The given key.
+    Explicit(String),
 }
 
 impl<'a> RequestBuilder<'a> {
+    /// Creates a request forwarding an incoming server `Request`, such as
+    /// in a reverse proxy.
+    ///
+    /// The method is kept, the target is rewritten by `rewrite`, and the
+    /// headers are copied without the hop-by-hop ones. If the incoming
+    /// request has a body, it is streamed from `req` as this request is
+    /// sent, with the same `Content-Length`, or chunked if it had none.
+    ///
+    /// Returns `Error::Header` if a header can't be forwarded safely.
+    pub fn from_incoming<'b: 'a, 'c: 'a>(client: &'a Client,
+                                         req: &'a mut server::Request<'b, 'c>,
+                                         rewrite: &ProxyRewrite) -> ::Result<RequestBuilder<'a>> {
+        let method = req.method.clone();
+        let url = rewrite.target(&req.uri);
+        let headers = try!(rewrite.headers(&req.headers));
+        let body = match (req.content_length(), req.is_chunked()) {
+            (Some(len), _) => Some(Body::SizedBody(req, len)),
+            (None, true) => Some(Body::ChunkedBody(req)),
+            (None, false) => None
+        };
+        Ok(RequestBuilder {
+            client: client,
+            method: method,
+            url: url,
+            headers: Some(headers),
+            body: body,
+            upload_progress: None,
+            download_progress: None,
+            on_retry: None,
+            tap: None,
+        })
+    }
 
     /// Set a request body to be sent.
+    ///
+    /// Ignored for `GET` and `HEAD` requests, which are always sent without
+    /// a body.
     pub fn body<B: Into<Body<'a>>>(mut self, body: B) -> RequestBuilder<'a> {
         self.body = Some(body.into());
         self
@@ -244,9 +364,71 @@ impl<'a> RequestBuilder<'a> {
         self
     }
 
+    /// Send the request with an `Idempotency-Key` header.
+    ///
+    /// Every attempt of the request carries the same key, and the request
+    /// may be retried even if its method isn't idempotent. See
+    /// `Client::set_max_retries`.
+    pub fn idempotency_key(mut self, key: IdempotencyKey) -> RequestBuilder<'a> {
+        let key = match key {
+            IdempotencyKey::Auto => generate_key(),
+            IdempotencyKey::Explicit(key) => key,
+        };
+        if self.headers.is_none() {
+            self.headers = Some(Headers::new());
+        }
+        self.headers.as_mut().unwrap().set_raw("Idempotency-Key", vec![key.into_bytes()]);
+        self
+    }
+
+    /// Report each retry of the request, such as to log it.
+    pub fn on_retry(mut self, hook: RetryHook) -> RequestBuilder<'a> {
+        self.on_retry = Some(hook);
+        self
+    }
+
+    /// Show the heads this request sends and receives to `callback`, like
+    /// the output of `curl -v`.
+    ///
+    /// Only this request is seen, not others sharing its connection. The
+    /// values of sensitive headers are redacted; use `tap` to change that,
+    /// or to see the bodies as well.
+    pub fn verbose(self, callback: fn(Direction, &[u8])) -> RequestBuilder<'a> {
+        self.tap(Tap::new(callback))
+    }
+
+    /// Show the bytes this request sends and receives to `tap`.
+    ///
+    /// Each redirect and retry of the request is seen as well.
+    pub fn tap(mut self, tap: Tap) -> RequestBuilder<'a> {
+        self.tap = Some(tap);
+        self
+    }
+
+    /// Report the progress of sending the request body.
+    ///
+    /// The callback is called after each write of the body with the bytes
+    /// written so far, and once more when the body is done. The total is
+    /// `None` for a chunked body. It runs on the sending thread, so it should
+    /// be cheap.
+    pub fn on_upload_progress(mut self, progress: Progress) -> RequestBuilder<'a> {
+        self.upload_progress = Some(progress);
+        self
+    }
+
+    /// Report the progress of reading the response body.
+    ///
+    /// See `Response::on_progress`.
+    pub fn on_download_progress(mut self, progress: Progress) -> RequestBuilder<'a> {
+        self.download_progress = Some(progress);
+        self
+    }
+
     /// Execute this request and receive a Response back.
     pub fn send(self) -> ::Result<Response> {
-        let RequestBuilder { client, method, url, headers, body } = self;
+        let RequestBuilder {
+            client, method, url, headers, body, upload_progress, download_progress, on_retry, tap
+        } = self;
         let mut url = try!(url);
         trace!("send {:?} {:?}", method, url);
 
@@ -261,10 +443,16 @@ impl<'a> RequestBuilder<'a> {
             None
         };
 
+        let retryable = method.idempotent() ||
+            headers.as_ref().map_or(false, |headers| headers.get_raw("Idempotency-Key").is_some());
+        let mut retries = 0;
         loop {
             let message = {
                 let (host, port) = try!(get_host_and_port(&url));
-                try!(client.protocol.new_message(&host, port, &*url.scheme))
+                let mut message = try!(client.protocol.new_message(&host, port, &*url.scheme));
+                message.set_lenient_parsing(client.max_skip_bytes);
+                message.set_tap(tap);
+                message
             };
             let mut req = try!(Request::with_message(method.clone(), url.clone(), message));
             headers.as_ref().map(|headers| req.headers_mut().extend(headers.iter()));
@@ -280,10 +468,43 @@ impl<'a> RequestBuilder<'a> {
                 (true, None) => req.headers_mut().set(ContentLength(0)),
                 _ => () // neither
             }
-            let mut streaming = try!(req.start());
-            body.take().map(|mut rdr| copy(&mut rdr, &mut streaming));
-            let res = try!(streaming.send());
+            let attempt = req.start().and_then(|mut streaming| {
+                if let Some(ref mut body) = body {
+                    if let Err(e) = body.write_to(&mut streaming, upload_progress) {
+                        debug!("error writing request body: {:?}", e);
+                        streaming.abort();
+                        return Err(Error::Io(e));
+                    }
+                }
+                streaming.send()
+            });
+            let mut res = match attempt {
+                Ok(res) => res,
+                Err(e) => {
+                    if retries >= client.max_retries || !retryable || !is_retryable(&e) {
+                        return Err(e);
+                    }
+                    if let Some(ref mut body) = body {
+                        if !body.is_replayable() {
+                            debug!("not retrying, the body can't be replayed: {:?}", e);
+                            return Err(Error::BodyNotReplayable(Box::new(e)));
+                        }
+                        try!(body.rewind());
+                    }
+                    retries += 1;
+                    debug!("retry {} of {} {}: {:?}", retries, method, url, e);
+                    if let Some(hook) = on_retry {
+                        hook(retries, &e);
+                    }
+                    continue;
+                }
+            };
+            // redirects are followed without the body
+            body = None;
             if !res.status.is_redirection() {
+                if let Some(progress) = download_progress {
+                    res.on_progress(progress);
+                }
                 return Ok(res)
             }
             debug!("redirect code {:?} for {}", res.status, url);
@@ -318,8 +539,43 @@ impl<'a> RequestBuilder<'a> {
                 RedirectPolicy::FollowIf(cond) if cond(&url) => (), //continue
                 _ => return Ok(res),
             }
+            // a body left unread would poison the connection for the next request
+            res.drain(|reused| trace!("redirect response drained, reused = {}", reused));
         }
     }
+
+    /// Execute this request, writing the response body into `sink`.
+    ///
+    /// The body is copied through a buffer as it is read, so it is never
+    /// held in memory whole. If reading the body or writing to the sink
+    /// fails, the connection is closed and the error returned. Otherwise,
+    /// the sink is handed back once the body is done, in a `Download` with
+    /// the status and headers of the response.
+    pub fn sink<W: Write>(self, mut sink: W) -> ::Result<Download<W>> {
+        let mut res = try!(self.send());
+        // an unfinished response closes its connection when dropped
+        let len = try!(copy(&mut res, &mut sink));
+        try!(sink.flush());
+        Ok(Download {
+            status: res.status,
+            headers: res.headers.clone(),
+            len: len,
+            sink: sink,
+        })
+    }
+}
+
+/// A response whose body was written to a sink by `RequestBuilder::sink`.
+#[derive(Debug)]
+pub struct Download<W> {
+    /// The status from the server.
+    pub status: StatusCode,
+    /// The headers from the server.
+    pub headers: Headers,
+    /// How many bytes of body were written to the sink.
+    pub len: u64,
+    /// The sink the body was written to.
+    pub sink: W,
 }
 
 /// An enum of possible body types for a Request.
@@ -330,16 +586,94 @@ pub enum Body<'a> {
     SizedBody(&'a mut (Read + 'a), u64),
     /// A String has a size, and uses Content-Length.
     BufBody(&'a [u8] , usize),
+    /// Chunks pushed through a `BodySender`, sent chunked.
+    ChannelBody(BodyReceiver),
+    /// A seekable reader, like a `File`, sent with a Content-Length from
+    /// the start position for the length. See `Body::seekable`.
+    SeekableBody(&'a mut (ReadSeek + 'a), u64, u64),
 }
 
+/// A reader that can seek, so a `Body` made from it can be sent again.
+pub trait ReadSeek: Read + Seek {}
+
+impl<T: Read + Seek> ReadSeek for T {}
+
 impl<'a> Body<'a> {
+    /// Creates a body whose chunks are pushed through the returned
+    /// `BodySender`, such as from another thread.
+    ///
+    /// ```no_run
+    /// # use hyper::Client;
+    /// use std::thread;
+    /// use hyper::client::Body;
+    ///
+    /// let (tx, body) = Body::channel();
+    /// thread::spawn(move || {
+    ///     tx.send(b"log line\n".to_vec()).unwrap();
+    ///     tx.finish().unwrap();
+    /// });
+    /// Client::new().post("http://example.domain/logs").body(body).send().unwrap();
+    /// ```
+    pub fn channel() -> (BodySender, Body<'a>) {
+        let (tx, rx) = channel::channel();
+        (tx, Body::ChannelBody(rx))
+    }
+}
+
+impl<'a> Body<'a> {
+    /// Creates a body from a seekable reader, like a `File`, from its
+    /// current position to its end.
+    ///
+    /// Unlike other readers, it is rewound and sent again when a request
+    /// is retried.
+    pub fn seekable<R: ReadSeek>(r: &'a mut R) -> io::Result<Body<'a>> {
+        let start = try!(r.seek(SeekFrom::Current(0)));
+        let end = try!(r.seek(SeekFrom::End(0)));
+        try!(r.seek(SeekFrom::Start(start)));
+        Ok(Body::SeekableBody(r, start, end - start))
+    }
+
     fn size(&self) -> Option<u64> {
         match *self {
             Body::SizedBody(_, len) => Some(len),
             Body::BufBody(_, len) => Some(len as u64),
+            Body::SeekableBody(_, _, len) => Some(len),
             _ => None
         }
     }
+
+    // Whether the body can be sent again after an attempt failed.
+    fn is_replayable(&self) -> bool {
+        match *self {
+            Body::BufBody(..) | Body::SeekableBody(..) => true,
+            _ => false
+        }
+    }
+
+    // Goes back to the start of a replayable body, for a retry.
+    fn rewind(&mut self) -> io::Result<()> {
+        match *self {
+            Body::SeekableBody(ref mut r, start, _) => r.seek(SeekFrom::Start(start)).map(|_| ()),
+            _ => Ok(())
+        }
+    }
+
+    fn write_to<W: Write>(&mut self, wrt: &mut W, progress: Option<Progress>) -> io::Result<u64> {
+        let total = self.size();
+        let mut bytes;
+        let mut rdr: &mut Read = match *self {
+            // read from a copy of the slice, so the body keeps all of it
+            Body::BufBody(buf, _) => {
+                bytes = buf;
+                &mut bytes
+            },
+            ref mut body => body
+        };
+        match progress {
+            Some(progress) => copy_with_progress(&mut rdr, wrt, total, progress),
+            None => copy(&mut rdr, wrt)
+        }
+    }
 }
 
 impl<'a> Read for Body<'a> {
@@ -349,6 +683,8 @@ impl<'a> Read for Body<'a> {
             Body::ChunkedBody(ref mut r) => r.read(buf),
             Body::SizedBody(ref mut r, _) => r.read(buf),
             Body::BufBody(ref mut r, _) => Read::read(r, buf),
+            Body::ChannelBody(ref mut r) => r.read(buf),
+            Body::SeekableBody(ref mut r, _, _) => r.read(buf),
         }
     }
 }
@@ -439,6 +775,49 @@ impl Default for RedirectPolicy {
     }
 }
 
+// Like `io::copy`, reporting the bytes written after each write.
+fn copy_with_progress<R: Read, W: Write>(rdr: &mut R, wrt: &mut W, total: Option<u64>,
+                                         progress: Progress) -> io::Result<u64> {
+    let mut buf = [0; 8192];
+    let mut written = 0;
+    loop {
+        let n = match rdr.read(&mut buf) {
+            Ok(0) => break,
+            Ok(n) => n,
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        };
+        try!(wrt.write_all(&buf[..n]));
+        written += n as u64;
+        progress(written, total);
+    }
+    progress(written, total);
+    Ok(written)
+}
+
+// Whether an attempt failed in a way that sending it again may fix, such
+// as on a kept-alive connection the server had closed.
+fn is_retryable(err: &Error) -> bool {
+    match *err {
+        Error::Io(ref e) => match e.kind() {
+            io::ErrorKind::ConnectionReset |
+            io::ErrorKind::ConnectionAborted |
+            io::ErrorKind::BrokenPipe => true,
+            _ => false
+        },
+        _ => false
+    }
+}
+
+// A random key laid out like a UUID, drawn from the random keys std
+// seeds its hash maps with.
+fn generate_key() -> String {
+    let a = RandomState::new().build_hasher().finish();
+    let b = RandomState::new().build_hasher().finish();
+    format!("{:08x}-{:04x}-{:04x}-{:04x}-{:012x}",
+            a >> 32, (a >> 16) & 0xffff, a & 0xffff, b >> 48, b & 0xffff_ffff_ffff)
+}
+
 fn get_host_and_port(url: &Url) -> ::Result<(String, u16)> {
     let host = match url.serialize_host() {
         Some(host) => host,
@@ -455,12 +834,80 @@ fn get_host_and_port(url: &Url) -> ::Result<(String, u16)> {
 
 #[cfg(test)]
 mod tests {
-    use std::io::Read;
-    use header::Server;
-    use super::{Client, RedirectPolicy};
+    use std::cell::RefCell;
+    use std::io::{self, Cursor, Read, Write};
+    use std::sync::{Arc, Mutex};
+    use std::sync::atomic::{AtomicUsize, Ordering, ATOMIC_USIZE_INIT};
+    use std::thread;
+    use std::time::Duration;
+
+    use header::{Authorization, Server};
+    use http::{Direction, Tap};
+    use mock::{MockStream, CloneableMockStream};
+    use net::NetworkConnector;
+    use super::{Body, Client, IdempotencyKey, RedirectPolicy, RequestBuilder};
     use super::pool::Pool;
     use url::Url;
 
+    /// Hands out the same stream on every connect, so tests can inspect
+    /// what was written to it.
+    #[derive(Clone)]
+    struct SharedConnector(Arc<Mutex<MockStream>>);
+
+    impl SharedConnector {
+        fn new(response: &[u8]) -> SharedConnector {
+            SharedConnector(Arc::new(Mutex::new(MockStream::with_input(response))))
+        }
+
+        fn written(&self) -> String {
+            String::from_utf8(self.0.lock().unwrap().write.clone()).unwrap()
+        }
+    }
+
+    impl NetworkConnector for SharedConnector {
+        type Stream = CloneableMockStream;
+
+        fn connect(&self, _: &str, _: u16, _: &str) -> ::Result<CloneableMockStream> {
+            Ok(CloneableMockStream { inner: self.0.clone() })
+        }
+    }
+
+    /// Hands out a stream with the next response on each connect, keeping
+    /// them so tests can inspect what was written to each.
+    #[derive(Clone)]
+    struct SequenceConnector {
+        streams: Vec<Arc<Mutex<MockStream>>>,
+        next: Arc<AtomicUsize>,
+    }
+
+    impl SequenceConnector {
+        fn new(responses: &[&[u8]]) -> SequenceConnector {
+            SequenceConnector {
+                streams: responses.iter()
+                    .map(|res| Arc::new(Mutex::new(MockStream::with_input(res))))
+                    .collect(),
+                next: Arc::new(AtomicUsize::new(0)),
+            }
+        }
+
+        fn connects(&self) -> usize {
+            self.next.load(Ordering::SeqCst)
+        }
+
+        fn written(&self, i: usize) -> String {
+            String::from_utf8(self.streams[i].lock().unwrap().write.clone()).unwrap()
+        }
+    }
+
+    impl NetworkConnector for SequenceConnector {
+        type Stream = CloneableMockStream;
+
+        fn connect(&self, _: &str, _: u16, _: &str) -> ::Result<CloneableMockStream> {
+            let i = self.next.fetch_add(1, Ordering::SeqCst);
+            Ok(CloneableMockStream { inner: self.streams[i].clone() })
+        }
+    }
+
     mock_connector!(MockRedirectPolicy {
         "http://127.0.0.1" =>       "HTTP/1.1 301 Redirect\r\n\
                                      Location: http://127.0.0.2\r\n\
@@ -506,6 +953,24 @@ mod tests {
         assert_eq!(res.headers.get(), Some(&Server("mock2".to_owned())));
     }
 
+    mock_connector!(RedirectBodyConnector {
+        b"HTTP/1.1 302 Found\r\nLocation: /b\r\nContent-Length: 5\r\n\r\nmoved",
+        b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok"
+    });
+
+    #[test]
+    fn test_redirect_drains_body() {
+        // each connection starts over with the redirect, so the final
+        // response only arrives if the first connection was reused
+        let client = Client::with_connector(Pool::with_connector(Default::default(),
+                                                                 RedirectBodyConnector));
+        let mut res = client.get("http://127.0.0.1/a").send().unwrap();
+        assert_eq!(res.url.serialize(), "http://127.0.0.1/b");
+        let mut s = String::new();
+        res.read_to_string(&mut s).unwrap();
+        assert_eq!(s, "ok");
+    }
+
     mock_connector!(Issue640Connector {
         b"HTTP/1.1 200 OK\r\nContent-Length: 3\r\n\r\n",
         b"GET",
@@ -532,4 +997,480 @@ mod tests {
         client.post("http://127.0.0.1").send().unwrap().read_to_string(&mut s).unwrap();
         assert_eq!(s, "POST");
     }
+
+    #[test]
+    fn test_method_helpers() {
+        fn written<F>(build: F) -> String
+        where F: for<'c> Fn(&'c Client) -> RequestBuilder<'c> {
+            let connector = SharedConnector::new(b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n");
+            let client = Client::with_connector(connector.clone());
+            build(&client).send().unwrap();
+            connector.written()
+        }
+
+        let get = written(|c| c.get("http://127.0.0.1/a").body("ignored"));
+        assert!(get.starts_with("GET /a HTTP/1.1\r\n"));
+        assert!(!get.contains("Content-Length"));
+        assert!(get.ends_with("\r\n\r\n"));
+
+        let head = written(|c| c.head("http://127.0.0.1/a").body("ignored"));
+        assert!(head.starts_with("HEAD /a HTTP/1.1\r\n"));
+        assert!(!head.contains("Content-Length"));
+        assert!(head.ends_with("\r\n\r\n"));
+
+        let post = written(|c| c.post("http://127.0.0.1/a").body("abc"));
+        assert!(post.starts_with("POST /a HTTP/1.1\r\n"));
+        assert!(post.contains("\r\nContent-Length: 3\r\n"));
+        assert!(post.ends_with("\r\n\r\nabc"));
+
+        let put = written(|c| c.put("http://127.0.0.1/a").body("abc"));
+        assert!(put.starts_with("PUT /a HTTP/1.1\r\n"));
+        assert!(put.ends_with("\r\n\r\nabc"));
+
+        let patch = written(|c| c.patch("http://127.0.0.1/a").body("abc"));
+        assert!(patch.starts_with("PATCH /a HTTP/1.1\r\n"));
+        assert!(patch.ends_with("\r\n\r\nabc"));
+
+        let delete = written(|c| c.delete("http://127.0.0.1/a"));
+        assert!(delete.starts_with("DELETE /a HTTP/1.1\r\n"));
+        assert!(delete.contains("\r\nContent-Length: 0\r\n"));
+    }
+
+    thread_local!(static PROGRESS: RefCell<Vec<(u64, Option<u64>)>> = RefCell::new(Vec::new()));
+
+    fn record_progress(done: u64, total: Option<u64>) {
+        PROGRESS.with(|calls| calls.borrow_mut().push((done, total)));
+    }
+
+    fn take_progress() -> Vec<(u64, Option<u64>)> {
+        PROGRESS.with(|calls| calls.borrow_mut().drain(..).collect())
+    }
+
+    #[test]
+    fn test_lenient_response_parsing() {
+        let response = b"\xef\xbb\xbfjunk HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok";
+
+        let mut client = Client::with_connector(SharedConnector::new(response));
+        client.set_lenient_response_parsing(16);
+        let mut res = client.get("http://127.0.0.1").send().unwrap();
+        assert_eq!(res.skipped_bytes(), 8);
+        let mut body = String::new();
+        res.read_to_string(&mut body).unwrap();
+        assert_eq!(body, "ok");
+
+        let mut client = Client::with_connector(SharedConnector::new(response));
+        client.set_lenient_response_parsing(7);
+        match client.get("http://127.0.0.1").send() {
+            Err(::Error::Version) => (),
+            other => panic!("expected a version error, got {:?}", other),
+        }
+
+        let client = Client::with_connector(SharedConnector::new(response));
+        match client.get("http://127.0.0.1").send() {
+            Err(::Error::Version) => (),
+            other => panic!("expected a version error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_download_progress() {
+        let connector = SharedConnector::new(b"\
+            HTTP/1.1 200 OK\r\n\
+            Content-Length: 10\r\n\
+            \r\n\
+            0123456789\
+        ");
+        let client = Client::with_connector(connector);
+
+        let mut res = client.get("http://127.0.0.1")
+            .on_download_progress(record_progress)
+            .send().unwrap();
+        let mut buf = [0; 3];
+        while res.read(&mut buf).unwrap() > 0 {}
+        assert_eq!(res.read(&mut buf).unwrap(), 0);
+
+        assert_eq!(take_progress(), vec![
+            (3, Some(10)),
+            (6, Some(10)),
+            (9, Some(10)),
+            (10, Some(10)),
+            (10, Some(10)),
+        ]);
+    }
+
+    #[test]
+    fn test_upload_progress() {
+        let response = b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n";
+
+        let client = Client::with_connector(SharedConnector::new(response));
+        client.post("http://127.0.0.1")
+            .body("abc")
+            .on_upload_progress(record_progress)
+            .send().unwrap();
+        assert_eq!(take_progress(), vec![(3, Some(3)), (3, Some(3))]);
+
+        let client = Client::with_connector(SharedConnector::new(response));
+        let mut body: &[u8] = b"chunked";
+        client.post("http://127.0.0.1")
+            .body(Body::ChunkedBody(&mut body))
+            .on_upload_progress(record_progress)
+            .send().unwrap();
+        assert_eq!(take_progress(), vec![(7, None), (7, None)]);
+    }
+
+    fn forward(incoming: &[u8]) -> String {
+        use buffer::BufReader;
+        use net::NetworkStream;
+        use proxy::ProxyRewrite;
+        use server;
+
+        let connector = SharedConnector::new(b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n");
+        let client = Client::with_connector(connector.clone());
+
+        let mut mock = MockStream::with_input(incoming);
+        let mock: &mut NetworkStream = &mut mock;
+        let mut stream = BufReader::new(mock);
+        let mut req = server::Request::new(&mut stream, "127.0.0.1:80".parse().unwrap()).unwrap();
+
+        let rewrite = ProxyRewrite::new(Url::parse("http://127.0.0.1/api/").unwrap());
+        RequestBuilder::from_incoming(&client, &mut req, &rewrite).unwrap().send().unwrap();
+        connector.written()
+    }
+
+    #[test]
+    fn test_from_incoming_sized() {
+        let written = forward(b"\
+            PUT /users?id=1 HTTP/1.1\r\n\
+            Host: front.domain\r\n\
+            Connection: keep-alive, X-Hop\r\n\
+            X-Hop: 1\r\n\
+            X-End: 2\r\n\
+            Content-Length: 3\r\n\
+            \r\n\
+            abc\
+        ");
+        assert!(written.starts_with("PUT /api/users?id=1 HTTP/1.1\r\n"));
+        assert!(written.contains("\r\nHost: 127.0.0.1"));
+        assert!(written.contains("\r\nContent-Length: 3\r\n"));
+        assert!(written.contains("\r\nX-End: 2\r\n"));
+        assert!(!written.contains("X-Hop"));
+        assert!(!written.contains("keep-alive"));
+        assert!(!written.contains("front.domain"));
+        assert!(written.ends_with("\r\n\r\nabc"));
+    }
+
+    #[test]
+    fn test_from_incoming_chunked() {
+        let written = forward(b"\
+            POST /upload HTTP/1.1\r\n\
+            Host: front.domain\r\n\
+            Transfer-Encoding: chunked\r\n\
+            \r\n\
+            3\r\n\
+            abc\r\n\
+            0\r\n\
+            \r\n\
+        ");
+        assert!(written.starts_with("POST /api/upload HTTP/1.1\r\n"));
+        assert_eq!(written.matches("chunked").count(), 1);
+        assert!(!written.contains("Content-Length"));
+        assert!(written.ends_with("\r\n\r\n3\r\nabc\r\n0\r\n\r\n"));
+    }
+
+    #[test]
+    fn test_channel_body() {
+        let connector = SharedConnector::new(b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n");
+        let client = Client::with_connector(connector.clone());
+
+        let (tx, body) = Body::channel();
+        thread::spawn(move || {
+            tx.send(b"hello".to_vec()).unwrap();
+            thread::sleep(Duration::from_millis(20));
+            tx.send(b" ".to_vec()).unwrap();
+            thread::sleep(Duration::from_millis(20));
+            tx.send(b"world".to_vec()).unwrap();
+            tx.finish().unwrap();
+        });
+
+        let res = client.post("http://127.0.0.1").body(body).send().unwrap();
+        assert_eq!(res.status, ::status::StatusCode::Ok);
+
+        let written = connector.written();
+        assert!(written.contains("Transfer-Encoding: chunked\r\n"));
+        assert!(written.ends_with("\r\n\r\n5\r\nhello\r\n1\r\n \r\n5\r\nworld\r\n0\r\n\r\n"));
+    }
+
+    #[test]
+    fn test_channel_body_aborted() {
+        let connector = SharedConnector::new(b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n");
+        let client = Client::with_connector(connector.clone());
+
+        let (tx, body) = Body::channel();
+        thread::spawn(move || {
+            tx.send(b"partial".to_vec()).unwrap();
+        });
+
+        assert!(client.post("http://127.0.0.1").body(body).send().is_err());
+        assert!(connector.0.lock().unwrap().is_closed);
+        assert!(!connector.written().ends_with("0\r\n\r\n"));
+    }
+
+    // the first connection closes before a response, like a kept-alive
+    // connection the server gave up on
+    const CLOSED_THEN_OK: &'static [&'static [u8]] = &[
+        b"",
+        b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n",
+    ];
+
+    #[test]
+    fn test_retry_replays_body() {
+        static RETRIES: AtomicUsize = ATOMIC_USIZE_INIT;
+        fn on_retry(attempt: u32, _: &::Error) {
+            RETRIES.store(attempt as usize, Ordering::SeqCst);
+        }
+
+        let connector = SequenceConnector::new(CLOSED_THEN_OK);
+        let mut client = Client::with_connector(connector.clone());
+        client.set_max_retries(1);
+
+        let res = client.post("http://127.0.0.1")
+            .body("hello")
+            .idempotency_key(IdempotencyKey::Auto)
+            .on_retry(on_retry)
+            .send()
+            .unwrap();
+        assert_eq!(res.status, ::status::StatusCode::Ok);
+        assert_eq!(connector.connects(), 2);
+        assert_eq!(RETRIES.load(Ordering::SeqCst), 1);
+
+        let key = |written: String| {
+            assert!(written.ends_with("\r\n\r\nhello"));
+            written.lines().find(|line| line.starts_with("Idempotency-Key: ")).unwrap().to_owned()
+        };
+        assert_eq!(key(connector.written(0)), key(connector.written(1)));
+    }
+
+    #[test]
+    fn test_retry_rewinds_seekable_body() {
+        let connector = SequenceConnector::new(CLOSED_THEN_OK);
+        let mut client = Client::with_connector(connector.clone());
+        client.set_max_retries(1);
+
+        let mut file = Cursor::new(b"skip:hello".to_vec());
+        file.set_position(5);
+        client.put("http://127.0.0.1").body(Body::seekable(&mut file).unwrap()).send().unwrap();
+        assert_eq!(connector.connects(), 2);
+        assert!(connector.written(1).contains("Content-Length: 5\r\n"));
+        assert!(connector.written(1).ends_with("\r\n\r\nhello"));
+    }
+
+    #[test]
+    fn test_retry_refuses_channel_body() {
+        fn on_retry(_: u32, _: &::Error) {
+            panic!("a channel body can't be retried");
+        }
+
+        let connector = SequenceConnector::new(CLOSED_THEN_OK);
+        let mut client = Client::with_connector(connector.clone());
+        client.set_max_retries(1);
+
+        let (tx, body) = Body::channel();
+        thread::spawn(move || {
+            tx.send(b"hello".to_vec()).unwrap();
+            tx.finish().unwrap();
+        });
+
+        match client.put("http://127.0.0.1").body(body).on_retry(on_retry).send() {
+            Err(::Error::BodyNotReplayable(ref e)) => match **e {
+                ::Error::Io(ref e) => assert_eq!(e.kind(), io::ErrorKind::ConnectionAborted),
+                ref e => panic!("unexpected error {:?}", e),
+            },
+            other => panic!("expected BodyNotReplayable, got {:?}", other.map(|res| res.status)),
+        }
+        assert_eq!(connector.connects(), 1);
+    }
+
+    #[test]
+    fn test_no_retry_by_default() {
+        let connector = SequenceConnector::new(CLOSED_THEN_OK);
+        let client = Client::with_connector(connector.clone());
+        assert!(client.get("http://127.0.0.1").send().is_err());
+        assert_eq!(connector.connects(), 1);
+    }
+
+    thread_local!(static TAPPED: RefCell<Vec<(Direction, Vec<u8>)>> = RefCell::new(Vec::new()));
+
+    fn record_tap(direction: Direction, bytes: &[u8]) {
+        TAPPED.with(|calls| calls.borrow_mut().push((direction, bytes.to_vec())));
+    }
+
+    fn take_tapped() -> Vec<(Direction, String)> {
+        TAPPED.with(|calls| calls.borrow_mut().drain(..).map(|(direction, bytes)| {
+            (direction, String::from_utf8(bytes).unwrap())
+        }).collect())
+    }
+
+    #[test]
+    fn test_verbose_heads() {
+        let head = "HTTP/1.1 200 OK\r\nSet-Cookie: id=1\r\nContent-Length: 2\r\n\r\n";
+        let response = format!("{}ok", head);
+        let connector = SequenceConnector::new(&[response.as_bytes(), response.as_bytes()]);
+        let client = Client::with_connector(connector.clone());
+
+        let mut res = client.get("http://127.0.0.1/a")
+            .header(Authorization("secret".to_owned()))
+            .verbose(record_tap)
+            .send().unwrap();
+        // another request while the first is open, on its own connection
+        let mut other = client.get("http://127.0.0.1/b").send().unwrap();
+        other.read_to_end(&mut Vec::new()).unwrap();
+        res.read_to_end(&mut Vec::new()).unwrap();
+        assert_eq!(connector.connects(), 2);
+
+        let sent = connector.written(0).replace("Authorization: secret",
+                                                "Authorization: [redacted:6 bytes]");
+        let received = head.replace("id=1", "[redacted:4 bytes]");
+        assert_eq!(take_tapped(), vec![(Direction::Sent, sent), (Direction::Received, received)]);
+    }
+
+    #[test]
+    fn test_tap_bodies_unredacted() {
+        let response = b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n2\r\nok\r\n0\r\n\r\n";
+        let connector = SequenceConnector::new(&[response]);
+        let client = Client::with_connector(connector.clone());
+
+        let mut res = client.post("http://127.0.0.1")
+            .header(Authorization("secret".to_owned()))
+            .body("hello")
+            .tap(Tap::new(record_tap).bodies(true).redact(false))
+            .send().unwrap();
+        res.read_to_end(&mut Vec::new()).unwrap();
+
+        let written = connector.written(0);
+        let body_start = written.find("\r\n\r\n").unwrap() + 4;
+        assert_eq!(take_tapped(), vec![
+            (Direction::Sent, written[..body_start].to_owned()),
+            (Direction::Sent, "hello".to_owned()),
+            (Direction::Received, "HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n".to_owned()),
+            (Direction::Received, "ok".to_owned()),
+        ]);
+    }
+
+    #[test]
+    fn test_sink() {
+        let body = (0..3 * 1024 * 1024).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+        let mut response = format!("HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n",
+                                   body.len()).into_bytes();
+        response.extend(&body);
+
+        let client = Client::with_connector(SharedConnector::new(&response));
+        let download = client.get("http://127.0.0.1").sink(Vec::new()).unwrap();
+        assert_eq!(download.status, ::status::StatusCode::Ok);
+        assert_eq!(download.len, body.len() as u64);
+        assert!(download.sink == body);
+    }
+
+    /// Takes a few bytes per write, and fails once it has taken `fail_at`.
+    struct SlowSink {
+        written: Vec<u8>,
+        fail_at: Option<usize>,
+    }
+
+    impl Write for SlowSink {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            if self.fail_at.map_or(false, |at| self.written.len() >= at) {
+                return Err(io::Error::new(io::ErrorKind::Other, "disk full"));
+            }
+            let n = ::std::cmp::min(buf.len(), 7);
+            self.written.extend(&buf[..n]);
+            Ok(n)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_sink_short_writes() {
+        let body = (0..100_000).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+        let mut response = b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n".to_vec();
+        for chunk in body.chunks(4000) {
+            response.extend(format!("{:X}\r\n", chunk.len()).as_bytes());
+            response.extend(chunk);
+            response.extend(b"\r\n");
+        }
+        response.extend(b"0\r\n\r\n");
+
+        let client = Client::with_connector(SharedConnector::new(&response));
+        let sink = SlowSink { written: Vec::new(), fail_at: None };
+        let download = client.get("http://127.0.0.1").sink(sink).unwrap();
+        assert_eq!(download.len, body.len() as u64);
+        assert!(download.sink.written == body);
+    }
+
+    #[test]
+    fn test_sink_error_closes_connection() {
+        let mut response = b"HTTP/1.1 200 OK\r\nContent-Length: 100000\r\n\r\n".to_vec();
+        response.extend(&[b'x'; 100_000][..]);
+        let connector = SharedConnector::new(&response);
+        let client = Client::with_connector(connector.clone());
+
+        let sink = SlowSink { written: Vec::new(), fail_at: Some(1000) };
+        match client.get("http://127.0.0.1").sink(sink) {
+            Err(::Error::Io(ref e)) => assert_eq!(e.to_string(), "disk full"),
+            other => panic!("expected sink error, got {:?}", other.map(|d| d.len)),
+        }
+        assert!(connector.0.lock().unwrap().is_closed);
+    }
+
+    // Makes two requests to a server answering each with `response`,
+    // returning how many connections it accepted.
+    fn connections_for_two_requests(response: &'static [u8]) -> usize {
+        use std::io::{BufRead, BufReader};
+        use std::net::TcpListener;
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let accepts = Arc::new(AtomicUsize::new(0));
+        let counted = accepts.clone();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                counted.fetch_add(1, Ordering::SeqCst);
+                // keeps answering, so only the client closes a connection
+                thread::spawn(move || {
+                    let mut stream = BufReader::new(stream.unwrap());
+                    let mut line = String::new();
+                    while stream.read_line(&mut line).unwrap_or(0) > 0 {
+                        if line == "\r\n" {
+                            stream.get_mut().write_all(response).unwrap();
+                        }
+                        line.clear();
+                    }
+                });
+            }
+        });
+
+        let mut client = Client::new();
+        client.set_read_timeout(Some(Duration::from_secs(5)));
+        let url = format!("http://{}/", addr);
+        for _ in 0..2 {
+            let mut body = String::new();
+            client.get(&*url).send().unwrap().read_to_string(&mut body).unwrap();
+            assert_eq!(body, "ok");
+        }
+        accepts.load(Ordering::SeqCst)
+    }
+
+    #[test]
+    fn test_server_connection_close() {
+        assert_eq!(connections_for_two_requests(
+            b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok"), 1);
+        assert_eq!(connections_for_two_requests(
+            b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\nConnection: close\r\n\r\nok"), 2);
+        assert_eq!(connections_for_two_requests(
+            b"HTTP/1.0 200 OK\r\nContent-Length: 2\r\n\r\nok"), 2);
+        assert_eq!(connections_for_two_requests(
+            b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\nKeep-Alive: timeout=5, max=1\r\n\r\nok"), 2);
+    }
 }
@@ -35,10 +35,19 @@ impl Default for Config {
 struct PoolImpl<S> {
     conns: HashMap<Key, Vec<PooledStreamInner<S>>>,
     config: Config,
+    // Counts the clears, so connections made before one are known.
+    generation: u64,
+    // The generation of the last `clear`, and of the last `clear_host` of
+    // each host since then.
+    cleared: u64,
+    cleared_hosts: HashMap<String, u64>,
 }
 
 type Key = (String, u16, Scheme);
 
+// The most hosts whose clears are remembered apart from `cleared`.
+const MAX_CLEARED_HOSTS: usize = 64;
+
 fn key<T: Into<Scheme>>(host: &str, port: u16, scheme: T) -> Key {
     (host.to_owned(), port, scheme.into())
 }
@@ -77,6 +86,9 @@ impl<C: NetworkConnector> Pool<C> {
             inner: Arc::new(Mutex::new(PoolImpl {
                 conns: HashMap::new(),
                 config: config,
+                generation: 0,
+                cleared: 0,
+                cleared_hosts: HashMap::new(),
             }))
         }
     }
@@ -88,6 +100,60 @@ impl<C: NetworkConnector> Pool<C> {
     }
 }
 
+impl<C: NetworkConnector<Stream=S>, S: NetworkStream> Pool<C> {
+    /// Closes every connection of the Pool, so the next requests connect
+    /// anew, such as after credentials have been changed.
+    ///
+    /// Idle connections are closed now, and connections in use are closed
+    /// once their response is done with, rather than going back to the
+    /// Pool. A clone of the Pool given to a `Client` can clear it.
+    pub fn clear(&self) {
+        let mut pool = self.inner.lock().unwrap();
+        pool.generation += 1;
+        pool.cleared = pool.generation;
+        pool.cleared_hosts.clear();
+        let conns = pool.conns.drain().flat_map(|(_, conns)| conns);
+        close_all(conns);
+    }
+
+    /// Closes every connection of the Pool to `host`, like `clear`.
+    pub fn clear_host(&self, host: &str) {
+        let mut pool = self.inner.lock().unwrap();
+        pool.generation += 1;
+        let generation = pool.generation;
+        pool.cleared_hosts.insert(host.to_owned(), generation);
+        if pool.cleared_hosts.len() > MAX_CLEARED_HOSTS {
+            pool.forget_oldest_clear();
+        }
+        let keys = pool.conns.keys().filter(|key| key.0 == host).cloned().collect::<Vec<_>>();
+        let conns = keys.iter().flat_map(|key| pool.conns.remove(key).unwrap()).collect::<Vec<_>>();
+        close_all(conns);
+    }
+}
+
+impl<C: NetworkConnector + Clone> Clone for Pool<C> {
+    /// Clones the Pool, sharing its connections with the original.
+    fn clone(&self) -> Pool<C> {
+        Pool {
+            connector: self.connector.clone(),
+            inner: self.inner.clone(),
+        }
+    }
+}
+
+fn close_all<S, I>(conns: I) where S: NetworkStream, I: IntoIterator<Item=PooledStreamInner<S>> {
+    for mut conn in conns {
+        close(&mut conn);
+    }
+}
+
+fn close<S: NetworkStream>(conn: &mut PooledStreamInner<S>) {
+    trace!("closing {:?}", conn.key);
+    if let Err(e) = conn.stream.close(Shutdown::Both) {
+        debug!("error closing pooled connection: {:?}", e);
+    }
+}
+
 impl<S> PoolImpl<S> {
     fn reuse(&mut self, key: Key, conn: PooledStreamInner<S>) {
         trace!("reuse {:?}", key);
@@ -96,6 +162,23 @@ impl<S> PoolImpl<S> {
             conns.push(conn);
         }
     }
+
+    // Whether `conn` was made before a clear of its host.
+    fn is_cleared(&self, conn: &PooledStreamInner<S>) -> bool {
+        let host_cleared = self.cleared_hosts.get(&conn.key.0).cloned().unwrap_or(0);
+        conn.generation < ::std::cmp::max(self.cleared, host_cleared)
+    }
+
+    // Folds the oldest clear of a host into `cleared`, so `cleared_hosts`
+    // stays bounded however many hosts are cleared. Older connections to
+    // every host are then taken as cleared too, which only closes them
+    // sooner than needed.
+    fn forget_oldest_clear(&mut self) {
+        let oldest = self.cleared_hosts.values().cloned().min().unwrap_or(0);
+        self.cleared = ::std::cmp::max(self.cleared, oldest);
+        let cleared = self.cleared;
+        self.cleared_hosts.retain(|_, generation| *generation > cleared);
+    }
 }
 
 impl<C: NetworkConnector<Stream=S>, S: NetworkStream + Send> NetworkConnector for Pool<C> {
@@ -114,6 +197,7 @@ impl<C: NetworkConnector<Stream=S>, S: NetworkStream + Send> NetworkConnector fo
                 key: key.clone(),
                 stream: try!(self.connector.connect(host, port, scheme)),
                 previous_response_expected_no_content: false,
+                generation: locked.generation,
             }
         };
         if should_remove {
@@ -123,6 +207,7 @@ impl<C: NetworkConnector<Stream=S>, S: NetworkStream + Send> NetworkConnector fo
             inner: Some(inner),
             is_closed: false,
             pool: self.inner.clone(),
+            close: close::<S>,
         })
     }
 }
@@ -132,6 +217,8 @@ pub struct PooledStream<S> {
     inner: Option<PooledStreamInner<S>>,
     is_closed: bool,
     pool: Arc<Mutex<PoolImpl<S>>>,
+    // Closes the stream if the Pool was cleared while it was in use.
+    close: fn(&mut PooledStreamInner<S>),
 }
 
 #[derive(Debug)]
@@ -139,6 +226,7 @@ struct PooledStreamInner<S> {
     key: Key,
     stream: S,
     previous_response_expected_no_content: bool,
+    generation: u64,
 }
 
 impl<S: NetworkStream> Read for PooledStream<S> {
@@ -175,6 +263,11 @@ impl<S: NetworkStream> NetworkStream for PooledStream<S> {
         self.inner.as_mut().unwrap().stream.peer_addr()
     }
 
+    #[inline]
+    fn local_addr(&mut self) -> io::Result<SocketAddr> {
+        self.inner.as_mut().unwrap().stream.local_addr()
+    }
+
     #[inline]
     fn set_read_timeout(&self, dur: Option<Duration>) -> io::Result<()> {
         self.inner.as_ref().unwrap().stream.set_read_timeout(dur)
@@ -191,6 +284,12 @@ impl<S: NetworkStream> NetworkStream for PooledStream<S> {
         self.inner.as_mut().unwrap().stream.close(how)
     }
 
+    #[inline]
+    fn set_upgraded(&mut self) {
+        // it no longer speaks HTTP, so it must not go back to the pool
+        self.is_closed = true;
+    }
+
     #[inline]
     fn set_previous_response_expected_no_content(&mut self, expected: bool) {
         trace!("set_previous_response_expected_no_content {}", expected);
@@ -209,9 +308,14 @@ impl<S> Drop for PooledStream<S> {
     fn drop(&mut self) {
         trace!("PooledStream.drop, is_closed={}", self.is_closed);
         if !self.is_closed {
-            self.inner.take().map(|inner| {
+            let close = self.close;
+            self.inner.take().map(|mut inner| {
                 if let Ok(mut pool) = self.pool.lock() {
-                    pool.reuse(inner.key.clone(), inner);
+                    if pool.is_cleared(&inner) {
+                        close(&mut inner);
+                    } else {
+                        pool.reuse(inner.key.clone(), inner);
+                    }
                 }
                 // else poisoned, give up
             });
@@ -223,10 +327,11 @@ impl<S> Drop for PooledStream<S> {
 mod tests {
     use std::net::Shutdown;
     use std::io::Read;
-    use mock::{MockConnector};
+    use std::sync::{Arc, Mutex};
+    use mock::{CloneableMockStream, MockConnector, MockStream};
     use net::{NetworkConnector, NetworkStream};
 
-    use super::{Pool, key};
+    use super::{Pool, key, MAX_CLEARED_HOSTS};
 
     macro_rules! mocked {
         () => ({
@@ -262,6 +367,16 @@ mod tests {
         assert_eq!(locked.conns.len(), 0);
     }
 
+    #[test]
+    fn test_upgraded_not_reused() {
+        let pool = mocked!();
+        let mut stream = pool.connect("127.0.0.1", 3000, "http").unwrap();
+        stream.set_upgraded();
+        drop(stream);
+        let locked = pool.inner.lock().unwrap();
+        assert_eq!(locked.conns.len(), 0);
+    }
+
     #[test]
     fn test_eof_closes() {
         let pool = mocked!();
@@ -272,4 +387,97 @@ mod tests {
         let locked = pool.inner.lock().unwrap();
         assert_eq!(locked.conns.len(), 0);
     }
+
+    // Keeps a handle to each stream it connects, to see if it was closed.
+    #[derive(Clone, Default)]
+    struct Recording(Arc<Mutex<Vec<(String, CloneableMockStream)>>>);
+
+    impl Recording {
+        fn closed(&self) -> Vec<(String, bool)> {
+            self.0.lock().unwrap().iter()
+                .map(|&(ref host, ref stream)| (host.clone(), stream.inner.lock().unwrap().is_closed))
+                .collect()
+        }
+    }
+
+    impl NetworkConnector for Recording {
+        type Stream = CloneableMockStream;
+
+        fn connect(&self, host: &str, _: u16, _: &str) -> ::Result<CloneableMockStream> {
+            let stream = CloneableMockStream::with_stream(MockStream::new());
+            self.0.lock().unwrap().push((host.to_owned(), stream.clone()));
+            Ok(stream)
+        }
+    }
+
+    fn closed(host: &str, is_closed: bool) -> (String, bool) {
+        (host.to_owned(), is_closed)
+    }
+
+    #[test]
+    fn test_clear() {
+        let connector = Recording::default();
+        let pool = Pool::with_connector(Default::default(), connector.clone());
+        let idle = pool.connect("127.0.0.1", 3000, "http").unwrap();
+        let in_use = pool.connect("127.0.0.1", 3000, "http").unwrap();
+        drop(idle);
+
+        // a clone given away, such as to a Client, shares the connections
+        pool.clone().clear();
+        assert_eq!(connector.closed(), vec![closed("127.0.0.1", true), closed("127.0.0.1", false)]);
+        drop(in_use);
+        assert_eq!(connector.closed(), vec![closed("127.0.0.1", true), closed("127.0.0.1", true)]);
+        assert_eq!(pool.inner.lock().unwrap().conns.len(), 0);
+
+        // connections made after the clear are pooled again
+        drop(pool.connect("127.0.0.1", 3000, "http").unwrap());
+        drop(pool.connect("127.0.0.1", 3000, "http").unwrap());
+        assert_eq!(connector.closed().len(), 3);
+        assert_eq!(connector.closed()[2], closed("127.0.0.1", false));
+    }
+
+    #[test]
+    fn test_clear_host() {
+        let connector = Recording::default();
+        let pool = Pool::with_connector(Default::default(), connector.clone());
+        drop(pool.connect("a.example", 80, "http").unwrap());
+        drop(pool.connect("a.example", 443, "https").unwrap());
+        drop(pool.connect("b.example", 80, "http").unwrap());
+        let in_use = pool.connect("a.example", 80, "http").unwrap();
+
+        pool.clear_host("a.example");
+        assert_eq!(connector.closed(), vec![
+            closed("a.example", false), // in use
+            closed("a.example", true),
+            closed("b.example", false),
+        ]);
+        drop(in_use);
+        assert_eq!(connector.closed()[0], closed("a.example", true));
+
+        // b.example is still pooled, a.example connects anew
+        drop(pool.connect("b.example", 80, "http").unwrap());
+        drop(pool.connect("a.example", 80, "http").unwrap());
+        assert_eq!(connector.closed().len(), 4);
+        let locked = pool.inner.lock().unwrap();
+        assert_eq!(locked.conns.get(&key("b.example", 80, "http")).unwrap().len(), 1);
+        assert_eq!(locked.conns.get(&key("a.example", 80, "http")).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_clear_host_bounded() {
+        let connector = Recording::default();
+        let pool = Pool::with_connector(Default::default(), connector.clone());
+        let in_use = pool.connect("a.example", 80, "http").unwrap();
+        pool.clear_host("a.example");
+        for i in 0..MAX_CLEARED_HOSTS * 2 {
+            pool.clear_host(&format!("{}.example", i));
+        }
+        assert!(pool.inner.lock().unwrap().cleared_hosts.len() <= MAX_CLEARED_HOSTS);
+
+        // the clear of a.example still holds once it's forgotten
+        drop(in_use);
+        assert_eq!(connector.closed(), vec![closed("a.example", true)]);
+        drop(pool.connect("a.example", 80, "http").unwrap());
+        assert_eq!(connector.closed()[1], closed("a.example", false));
+    }
 }
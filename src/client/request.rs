@@ -132,6 +132,14 @@ impl Request<Streaming> {
     pub fn send(self) -> ::Result<Response> {
         Response::with_message(self.url, self.message)
     }
+
+    /// Closes the connection without completing the request.
+    ///
+    /// Use this when the body can't be written in full, so the server never
+    /// mistakes a truncated body for a complete one.
+    pub fn abort(mut self) {
+        let _ = self.message.close_connection();
+    }
 }
 
 impl Write for Request<Streaming> {
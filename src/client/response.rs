@@ -1,8 +1,10 @@
 //! Client Responses
 use std::io::{self, Read};
+use std::str;
 
 use url::Url;
 
+use client::Progress;
 use header;
 use net::NetworkStream;
 use http::{self, RawStatus, ResponseHead, HttpMessage};
@@ -10,6 +12,10 @@ use http::h1::Http11Message;
 use status;
 use version;
 
+/// The most body `Response::drain` reads before giving up on reusing the
+/// connection.
+const DRAIN_MAX_BYTES: u64 = 64 * 1024;
+
 /// A response for a client request to a remote server.
 #[derive(Debug)]
 pub struct Response {
@@ -22,7 +28,12 @@ pub struct Response {
     /// The final URL of this response.
     pub url: Url,
     status_raw: RawStatus,
-    message: Box<HttpMessage>,
+    // Taken when the connection is upgraded.
+    message: Option<Box<HttpMessage>>,
+    skipped_bytes: usize,
+    progress: Option<Progress>,
+    body_read: u64,
+    body_done: bool,
 }
 
 impl Response {
@@ -36,7 +47,7 @@ impl Response {
     /// Creates a new response received from the server on the given `HttpMessage`.
     pub fn with_message(url: Url, mut message: Box<HttpMessage>) -> ::Result<Response> {
         trace!("Response::with_message");
-        let ResponseHead { headers, raw_status, version } = match message.get_incoming() {
+        let ResponseHead { headers, raw_status, version, skipped_bytes } = match message.get_incoming() {
             Ok(head) => head,
             Err(e) => {
                 let _ = message.close_connection();
@@ -45,7 +56,7 @@ impl Response {
         };
         let status = status::StatusCode::from_u16(raw_status.0);
         debug!("version={:?}, status={:?}", version, status);
-        debug!("headers={:?}", headers);
+        debug!("headers={:?}", headers.redacted());
 
         Ok(Response {
             status: status,
@@ -53,26 +64,116 @@ impl Response {
             headers: headers,
             url: url,
             status_raw: raw_status,
-            message: message,
+            message: Some(message),
+            skipped_bytes: skipped_bytes,
+            progress: None,
+            body_read: 0,
+            body_done: false,
         })
     }
 
+    /// How many invalid bytes were skipped before this response, when
+    /// lenient parsing is enabled with `Client::set_lenient_response_parsing`.
+    #[inline]
+    pub fn skipped_bytes(&self) -> usize {
+        self.skipped_bytes
+    }
+
+    /// Report the progress of reading the body.
+    ///
+    /// The callback is called after each read that returns some of the
+    /// body with the bytes read so far, and once more when the end of the
+    /// body is reached. The total is the `Content-Length`, if there is one.
+    /// It runs on the reading thread, so it should be cheap.
+    pub fn on_progress(&mut self, progress: Progress) {
+        self.progress = Some(progress);
+    }
+
+    /// Takes over the connection of a `101 Switching Protocols` response,
+    /// such as to speak WebSocket after the handshake.
+    ///
+    /// Returns the connection, and any bytes of the new protocol that were
+    /// already read along with the response head. The connection won't be
+    /// reused for HTTP. Any other response is given back.
+    pub fn upgrade(mut self) -> Result<(Box<NetworkStream + Send>, Vec<u8>), Response> {
+        if self.status != status::StatusCode::SwitchingProtocols ||
+                !self.message().is::<Http11Message>() {
+            return Err(self);
+        }
+        let message = self.message.take().expect("Response already upgraded");
+        let message = message.downcast::<Http11Message>().ok().expect("checked above");
+        debug!("upgrading connection to {:?}", self.headers.get::<header::Upgrade>());
+        Ok(message.into_upgraded())
+    }
+
+    fn message(&mut self) -> &mut HttpMessage {
+        &mut **self.message.as_mut().expect("Response already upgraded")
+    }
+
     /// Get the raw status code and reason.
+    ///
+    /// The reason is kept exactly as the server sent it, even when it isn't
+    /// the canonical reason of the code.
     #[inline]
     pub fn status_raw(&self) -> &RawStatus {
         &self.status_raw
     }
+
+    /// Read and discard the rest of the body, so the connection can be
+    /// reused.
+    ///
+    /// If the body is longer than 64KB, or reading it fails, the connection
+    /// is closed instead. Once the connection has been released, `done` is
+    /// called with whether it can be reused.
+    pub fn drain<F: FnOnce(bool)>(mut self, done: F) {
+        let mut buf = [0; 4096];
+        let mut drained = 0;
+        let reusable = loop {
+            match self.read(&mut buf) {
+                Ok(0) => break true,
+                Ok(n) => {
+                    drained += n as u64;
+                    if drained > DRAIN_MAX_BYTES {
+                        debug!("response body over {} bytes, not draining", DRAIN_MAX_BYTES);
+                        break false;
+                    }
+                }
+                Err(e) => {
+                    debug!("error draining response body: {:?}", e);
+                    break false;
+                }
+            }
+        };
+        let reusable = reusable && server_keeps_alive(self.version, &self.headers);
+        // dropping closes the connection if it wasn't drained, and returns
+        // it to the pool otherwise
+        drop(self);
+        done(reusable);
+    }
 }
 
 impl Read for Response {
     #[inline]
     fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
-        match self.message.read(buf) {
+        match self.message().read(buf) {
             Err(e) => {
-                let _ = self.message.close_connection();
+                let _ = self.message().close_connection();
                 Err(e)
             }
-            r => r
+            Ok(n) => {
+                self.body_read += n as u64;
+                if let Some(progress) = self.progress {
+                    if n > 0 || (!buf.is_empty() && !self.body_done) {
+                        let total = self.headers.get::<header::ContentLength>()
+                                                .map(|&header::ContentLength(len)| len);
+                        progress(self.body_read, total);
+                    }
+                }
+                if n == 0 && !buf.is_empty() {
+                    self.body_done = true;
+                }
+                Ok(n)
+            }
         }
     }
 }
@@ -83,18 +184,66 @@ impl Drop for Response {
         // since those old bits would end up in new Responses
         //
         // otherwise, the response has been drained. we should check that the
-        // server has agreed to keep the connection open
-        let is_drained = !self.message.has_body();
+        // server has agreed to keep the connection open, or it may close it
+        // under the next request
+        //
+        // a connection switching protocols can't be reused either way
+        let message = match self.message {
+            Some(ref mut message) => message,
+            None => return
+        };
+        let is_drained = !message.has_body();
         trace!("Response.drop is_drained={}", is_drained);
-        if !(is_drained && http::should_keep_alive(self.version, &self.headers)) {
+        if !(is_drained && server_keeps_alive(self.version, &self.headers)) ||
+                self.status == status::StatusCode::SwitchingProtocols {
             trace!("Response.drop closing connection");
-            if let Err(e) = self.message.close_connection() {
+            if let Err(e) = message.close_connection() {
                 error!("Response.drop error closing connection: {}", e);
             }
         }
     }
 }
 
+/// Whether the server will take another request on the connection, going
+/// by the `Connection` field, and by a `Keep-Alive` field allowing at most
+/// one more request, which the server may close on before it arrives.
+fn server_keeps_alive(version: version::HttpVersion, headers: &header::Headers) -> bool {
+    if !http::should_keep_alive(version, headers) {
+        debug!("server closes the connection, version={:?}, connection={:?}",
+               version, headers.get::<header::Connection>());
+        return false;
+    }
+    match keep_alive_max(headers) {
+        Some(max) if max <= 1 => {
+            debug!("server closes the connection, Keep-Alive max={}", max);
+            false
+        }
+        _ => true
+    }
+}
+
+/// The `max` parameter of a `Keep-Alive` field, such as
+/// `Keep-Alive: timeout=5, max=100`.
+fn keep_alive_max(headers: &header::Headers) -> Option<u32> {
+    let raw = match headers.get_raw("Keep-Alive") {
+        Some(raw) => raw,
+        None => return None
+    };
+    raw.iter()
+        .filter_map(|line| str::from_utf8(line).ok())
+        .flat_map(|line| line.split(','))
+        .filter_map(|param| {
+            let mut param = param.splitn(2, '=');
+            match (param.next(), param.next()) {
+                (Some(name), Some(value)) if name.trim().eq_ignore_ascii_case("max") => {
+                    value.trim().parse().ok()
+                }
+                _ => None
+            }
+        })
+        .next()
+}
+
 #[cfg(test)]
 mod tests {
     use std::io::{self, Read};
@@ -221,6 +370,82 @@ mod tests {
         assert_eq!(read_to_string(res).unwrap(), "1".to_owned());
     }
 
+    #[test]
+    fn test_status_raw_reason_verbatim() {
+        use std::borrow::Cow;
+        use http::RawStatus;
+
+        let stream = MockStream::with_input(b"\
+            HTTP/1.1 200 Totally Fine\r\n\
+            Content-Length: 0\r\n\
+            \r\n"
+        );
+
+        let url = Url::parse("http://hyper.rs").unwrap();
+        let res = Response::new(url, Box::new(stream)).unwrap();
+        assert_eq!(res.status, status::StatusCode::Ok);
+        assert_eq!(*res.status_raw(), RawStatus(200, Cow::Borrowed("Totally Fine")));
+        assert_eq!(res.status_raw().1, "Totally Fine");
+    }
+
+    #[test]
+    fn test_upgrade() {
+        use std::io::Write;
+
+        let stream = MockStream::with_input(b"\
+            HTTP/1.1 101 Switching Protocols\r\n\
+            Connection: Upgrade\r\n\
+            Upgrade: websocket\r\n\
+            \r\n\
+            \x81\x05hello"
+        );
+
+        let url = Url::parse("http://hyper.rs").unwrap();
+        let res = Response::new(url, Box::new(stream)).unwrap();
+        assert_eq!(res.status, status::StatusCode::SwitchingProtocols);
+        let (mut transport, read_buf) = res.upgrade().ok().unwrap();
+        assert_eq!(read_buf, b"\x81\x05hello");
+        transport.write_all(b"\x88\x00").unwrap();
+        let stream = transport.downcast::<MockStream>().ok().unwrap();
+        assert_eq!(stream.write, b"\x88\x00");
+    }
+
+    #[test]
+    fn test_upgrade_not_switching() {
+        let stream = MockStream::with_input(b"\
+            HTTP/1.1 200 OK\r\n\
+            Content-Length: 2\r\n\
+            \r\n\
+            ok"
+        );
+
+        let url = Url::parse("http://hyper.rs").unwrap();
+        let res = Response::new(url, Box::new(stream)).unwrap();
+        let res = res.upgrade().err().unwrap();
+        assert_eq!(read_to_string(res).unwrap(), "ok");
+    }
+
+    #[test]
+    fn test_server_keeps_alive() {
+        use header::{Connection, Headers};
+        use super::server_keeps_alive;
+
+        let mut headers = Headers::new();
+        assert!(server_keeps_alive(version::HttpVersion::Http11, &headers));
+        assert!(!server_keeps_alive(version::HttpVersion::Http10, &headers));
+
+        headers.set(Connection::close());
+        assert!(!server_keeps_alive(version::HttpVersion::Http11, &headers));
+
+        headers.set(Connection::keep_alive());
+        headers.set_raw("Keep-Alive", vec![b"timeout=5, max=2".to_vec()]);
+        assert!(server_keeps_alive(version::HttpVersion::Http10, &headers));
+        headers.set_raw("Keep-Alive", vec![b"timeout=5, MAX=1".to_vec()]);
+        assert!(!server_keeps_alive(version::HttpVersion::Http11, &headers));
+        headers.set_raw("Keep-Alive", vec![b"max".to_vec()]);
+        assert!(server_keeps_alive(version::HttpVersion::Http11, &headers));
+    }
+
     #[test]
     fn test_parse_error_closes() {
         let url = Url::parse("http://hyper.rs").unwrap();
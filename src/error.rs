@@ -19,8 +19,10 @@ use self::Error::{
     Header,
     Status,
     Io,
+    UriTooLong,
     Ssl,
     TooLarge,
+    BodyNotReplayable,
     Http2,
     Utf8
 };
@@ -42,8 +44,13 @@ pub enum Error {
     Header,
     /// A message head is too large to be reasonable.
     TooLarge,
+    /// A request-target is longer than the server accepts.
+    UriTooLong,
     /// An invalid `Status`, such as `1337 ELITE`.
     Status,
+    /// A request failed and could have been retried, but its body can't be
+    /// sent again. The error of the failed attempt is inside.
+    BodyNotReplayable(Box<Error>),
     /// An `io::Error` that occurred while trying to read or write to a network stream.
     Io(IoError),
     /// An error from a SSL library.
@@ -79,7 +86,9 @@ impl StdError for Error {
             Version => "Invalid HTTP version specified",
             Header => "Invalid Header provided",
             TooLarge => "Message head is too large",
+            UriTooLong => "Request-target is too long",
             Status => "Invalid Status provided",
+            BodyNotReplayable(_) => "Request failed, and its body can't be replayed to retry it",
             Uri(ref e) => e.description(),
             Io(ref e) => e.description(),
             Ssl(ref e) => e.description(),
@@ -95,6 +104,7 @@ impl StdError for Error {
             Ssl(ref error) => Some(&**error),
             Uri(ref error) => Some(error),
             Http2(ref error) => Some(error),
+            BodyNotReplayable(ref error) => Some(&**error),
             _ => None,
         }
     }
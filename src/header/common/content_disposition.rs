@@ -8,10 +8,11 @@
 
 use language_tags::LanguageTag;
 use std::fmt;
+use std::str;
 use unicase::UniCase;
 use url::percent_encoding;
 
-use header::{Header, HeaderFormat, parsing};
+use header::{Header, HeaderFormat};
 use header::parsing::parse_extended_value;
 use header::shared::Charset;
 
@@ -88,62 +89,208 @@ pub struct ContentDisposition {
     pub parameters: Vec<DispositionParam>,
 }
 
+impl ContentDisposition {
+    /// An `attachment` with the given filename, to be saved rather than
+    /// displayed.
+    ///
+    /// A name that isn't printable ASCII is sent as a `filename*` in UTF-8,
+    /// after a plain `filename` with those characters replaced by `_` for
+    /// recipients that don't understand the extended form.
+    ///
+    /// ```
+    /// use hyper::header::ContentDisposition;
+    ///
+    /// let cd = ContentDisposition::attachment("résumé.pdf");
+    /// assert_eq!(cd.to_string(),
+    ///            "attachment; filename=\"r_sum_.pdf\"; filename*=UTF-8''r%C3%A9sum%C3%A9.pdf");
+    /// ```
+    pub fn attachment(filename: &str) -> ContentDisposition {
+        ContentDisposition {
+            disposition: DispositionType::Attachment,
+            parameters: filename_params(filename),
+        }
+    }
+
+    /// An `inline` disposition with the given filename, encoded as for
+    /// `attachment`.
+    pub fn inline(filename: &str) -> ContentDisposition {
+        ContentDisposition {
+            disposition: DispositionType::Inline,
+            parameters: filename_params(filename),
+        }
+    }
+
+    /// The filename, decoded from its charset.
+    ///
+    /// When there are several, the last one that can be decoded is used.
+    /// Parsing puts a `filename*` after any plain `filename`, wherever it
+    /// was sent, so the extended form is preferred as RFC 6266 asks, and
+    /// the plain one is the fallback for a charset that can't be decoded.
+    /// Only UTF-8, US-ASCII and ISO-8859-1 are decoded.
+    pub fn filename(&self) -> Option<String> {
+        self.parameters.iter().rev().filter_map(|param| match *param {
+            DispositionParam::Filename(ref charset, _, ref bytes) => decode(charset, bytes),
+            DispositionParam::Ext(..) => None,
+        }).next()
+    }
+}
+
+fn filename_params(filename: &str) -> Vec<DispositionParam> {
+    let utf8 = || Charset::Ext("UTF-8".to_owned());
+    if is_plain(filename.as_bytes()) {
+        return vec![DispositionParam::Filename(utf8(), None, filename.as_bytes().to_vec())];
+    }
+    let fallback = filename.chars().map(|c| {
+        if c >= ' ' && c <= '~' { c } else { '_' }
+    }).collect::<String>();
+    vec![
+        DispositionParam::Filename(utf8(), None, fallback.into_bytes()),
+        DispositionParam::Filename(utf8(), None, filename.as_bytes().to_vec()),
+    ]
+}
+
+// Whether the bytes can be sent as they are in a quoted-string.
+fn is_plain(bytes: &[u8]) -> bool {
+    bytes.iter().all(|&b| b >= 0x20 && b < 0x7f)
+}
+
+fn decode(charset: &Charset, bytes: &[u8]) -> Option<String> {
+    match *charset {
+        Charset::Iso_8859_1 => Some(bytes.iter().map(|&b| b as char).collect()),
+        Charset::Us_Ascii => String::from_utf8(bytes.to_vec()).ok(),
+        Charset::Ext(ref ext) if UniCase(&**ext) == UniCase("utf-8") => {
+            String::from_utf8(bytes.to_vec()).ok()
+        },
+        _ => None
+    }
+}
+
 impl Header for ContentDisposition {
     fn header_name() -> &'static str {
         "Content-Disposition"
     }
 
     fn parse_header(raw: &[Vec<u8>]) -> ::Result<ContentDisposition> {
-        parsing::from_one_raw_str(raw).and_then(|s: String| {
-            let mut sections = s.split(';');
-            let disposition = match sections.next() {
-                Some(s) => s.trim(),
-                None => return Err(::Error::Header),
-            };
-
-            let mut cd = ContentDisposition {
-                disposition: if UniCase(&*disposition) == UniCase("inline") {
-                    DispositionType::Inline
-                } else if UniCase(&*disposition) == UniCase("attachment") {
-                    DispositionType::Attachment
-                } else {
-                    DispositionType::Ext(disposition.to_owned())
-                },
-                parameters: Vec::new(),
-            };
+        if raw.len() != 1 {
+            return Err(::Error::Header);
+        }
+        let raw = &raw[0][..];
+        let end = raw.iter().position(|&b| b == b';').unwrap_or(raw.len());
+        let disposition = try!(str::from_utf8(trim(&raw[..end])).map_err(|_| ::Error::Header));
+        if disposition.is_empty() {
+            return Err(::Error::Header);
+        }
 
-            for section in sections {
-                let mut parts = section.splitn(2, '=');
+        let mut cd = ContentDisposition {
+            disposition: if UniCase(disposition) == UniCase("inline") {
+                DispositionType::Inline
+            } else if UniCase(disposition) == UniCase("attachment") {
+                DispositionType::Attachment
+            } else {
+                DispositionType::Ext(disposition.to_owned())
+            },
+            parameters: Vec::new(),
+        };
 
-                let key = if let Some(key) = parts.next() {
-                    key.trim()
+        let mut extended = Vec::new();
+        for (key, val) in try!(parse_params(&raw[end..])) {
+            if UniCase(&*key) == UniCase("filename*") {
+                let val = try!(str::from_utf8(&val).map_err(|_| ::Error::Header));
+                let extended_value = try!(parse_extended_value(val));
+                extended.push(DispositionParam::Filename(extended_value.charset,
+                                                         extended_value.language_tag,
+                                                         extended_value.value));
+                continue;
+            }
+            cd.parameters.push(
+                if UniCase(&*key) == UniCase("filename") {
+                    // Browsers send names as they are, which is UTF-8 from
+                    // most, but may be ISO-8859-1 from older ones.
+                    let charset = if str::from_utf8(&val).is_ok() {
+                        Charset::Ext("UTF-8".to_owned())
+                    } else {
+                        Charset::Iso_8859_1
+                    };
+                    DispositionParam::Filename(charset, None, val)
                 } else {
-                    return Err(::Error::Header);
-                };
+                    DispositionParam::Ext(key, String::from_utf8_lossy(&val).into_owned())
+                }
+            );
+        }
+        cd.parameters.extend(extended);
 
-                let val = if let Some(val) = parts.next() {
-                    val.trim()
-                } else {
-                    return Err(::Error::Header);
-                };
-
-                cd.parameters.push(
-                    if UniCase(&*key) == UniCase("filename") {
-                        DispositionParam::Filename(
-                            Charset::Ext("UTF-8".to_owned()), None,
-                            val.trim_matches('"').as_bytes().to_owned())
-                    } else if UniCase(&*key) == UniCase("filename*") {
-                        let extended_value = try!(parse_extended_value(val));
-                        DispositionParam::Filename(extended_value.charset, extended_value.language_tag, extended_value.value)
-                    } else {
-                        DispositionParam::Ext(key.to_owned(), val.trim_matches('"').to_owned())
-                    }
-                );
+        Ok(cd)
+    }
+}
+
+// Splits `; name=value` parameters, unquoting quoted-string values.
+//
+// Unquoted values run to the next `;`, so names with spaces that a
+// sender forgot to quote are kept whole.
+fn parse_params(mut s: &[u8]) -> ::Result<Vec<(String, Vec<u8>)>> {
+    let mut params = Vec::new();
+    loop {
+        s = trim(s);
+        if s.is_empty() {
+            return Ok(params);
+        }
+        if s[0] == b';' {
+            s = &s[1..];
+            continue;
+        }
+
+        let eq = try!(s.iter().position(|&b| b == b'=').ok_or(::Error::Header));
+        let key = trim(&s[..eq]);
+        if key.is_empty() || key.contains(&b';') {
+            return Err(::Error::Header);
+        }
+        let key = try!(String::from_utf8(key.to_vec()).map_err(|_| ::Error::Header));
+        s = trim(&s[eq + 1..]);
+
+        let val = if s.first() == Some(&b'"') {
+            let mut val = Vec::new();
+            let mut i = 1;
+            loop {
+                match s.get(i) {
+                    Some(&b'\\') if i + 1 < s.len() => {
+                        val.push(s[i + 1]);
+                        i += 2;
+                    },
+                    Some(&b'"') => break,
+                    Some(&b) => {
+                        val.push(b);
+                        i += 1;
+                    },
+                    None => return Err(::Error::Header),
+                }
             }
+            s = trim(&s[i + 1..]);
+            if !s.is_empty() && s[0] != b';' {
+                return Err(::Error::Header);
+            }
+            val
+        } else {
+            let end = s.iter().position(|&b| b == b';').unwrap_or(s.len());
+            let val = trim(&s[..end]).to_vec();
+            s = &s[end..];
+            val
+        };
+        params.push((key, val));
+    }
+}
 
-            Ok(cd)
-        })
+fn trim(mut s: &[u8]) -> &[u8] {
+    while s.first().map_or(false, is_space) {
+        s = &s[1..];
     }
+    while s.last().map_or(false, is_space) {
+        s = &s[..s.len() - 1];
+    }
+    s
+}
+
+fn is_space(b: &u8) -> bool {
+    *b == b' ' || *b == b'\t' || *b == b'\r' || *b == b'\n'
 }
 
 impl HeaderFormat for ContentDisposition {
@@ -164,7 +311,7 @@ impl fmt::Display for ContentDisposition {
             match *param {
                 DispositionParam::Filename(ref charset, ref opt_lang, ref bytes) => {
                     let mut use_simple_format: bool = false;
-                    if opt_lang.is_none() {
+                    if opt_lang.is_none() && is_plain(bytes) {
                         if let Charset::Ext(ref ext) = *charset {
                             if UniCase(&**ext) == UniCase("utf-8") {
                                 use_simple_format = true;
@@ -172,11 +319,9 @@ impl fmt::Display for ContentDisposition {
                         }
                     }
                     if use_simple_format {
-                        try!(write!(f, "; filename=\"{}\"",
-                                    match String::from_utf8(bytes.clone()) {
-                                        Ok(s) => s,
-                                        Err(_) => return Err(fmt::Error),
-                                    }));
+                        try!(f.write_str("; filename="));
+                        // is_plain checked these are ASCII
+                        try!(write_quoted(f, str::from_utf8(bytes).unwrap()));
                     } else {
                         try!(write!(f, "; filename*={}'", charset));
                         if let Some(ref lang) = *opt_lang {
@@ -188,13 +333,27 @@ impl fmt::Display for ContentDisposition {
                                 bytes, percent_encoding::HTTP_VALUE_ENCODE_SET)))
                     }
                 },
-                DispositionParam::Ext(ref k, ref v) => try!(write!(f, "; {}=\"{}\"", k, v)),
+                DispositionParam::Ext(ref k, ref v) => {
+                    try!(write!(f, "; {}=", k));
+                    try!(write_quoted(f, v));
+                },
             }
         }
         Ok(())
     }
 }
 
+fn write_quoted(f: &mut fmt::Formatter, s: &str) -> fmt::Result {
+    try!(f.write_str("\""));
+    for c in s.chars() {
+        if c == '"' || c == '\\' {
+            try!(f.write_str("\\"));
+        }
+        try!(write!(f, "{}", c));
+    }
+    f.write_str("\"")
+}
+
 #[cfg(test)]
 mod tests {
     use super::{ContentDisposition,DispositionType,DispositionParam};
@@ -263,4 +422,71 @@ mod tests {
         let display_rendered = format!("{}",a);
         assert_eq!("attachment; filename=\"colourful.csv\"".to_owned(), display_rendered);
     }
+
+    fn parse(raw: &[u8]) -> ContentDisposition {
+        ContentDisposition::parse_header([raw.to_vec()].as_ref()).unwrap()
+    }
+
+    #[test]
+    fn test_attachment_round_trip() {
+        let cases = [
+            ("report.csv", "attachment; filename=\"report.csv\""),
+            ("say \"hi\".txt", "attachment; filename=\"say \\\"hi\\\".txt\""),
+            ("\u{1f600}.png",
+             "attachment; filename=\"_.png\"; filename*=UTF-8''%F0%9F%98%80.png"),
+            ("caf\u{e9}.txt",
+             "attachment; filename=\"caf_.txt\"; filename*=UTF-8''caf%C3%A9.txt"),
+        ];
+        for &(name, expected) in cases.iter() {
+            let cd = ContentDisposition::attachment(name);
+            assert_eq!(cd.to_string(), expected);
+            assert_eq!(parse(expected.as_bytes()).filename().unwrap(), name);
+        }
+
+        let latin1 = ContentDisposition {
+            disposition: DispositionType::Inline,
+            parameters: vec![DispositionParam::Filename(Charset::Iso_8859_1, None, b"caf\xe9.txt".to_vec())],
+        };
+        assert_eq!(latin1.to_string(), "inline; filename*=ISO-8859-1''caf%E9.txt");
+        assert_eq!(parse(latin1.to_string().as_bytes()), latin1);
+        assert_eq!(latin1.filename().unwrap(), "caf\u{e9}.txt");
+    }
+
+    #[test]
+    fn test_parse_browser_values() {
+        let cases: &[(&[u8], &str)] = &[
+            // multipart parts, with the name as it was typed
+            (b"form-data; name=\"file\"; filename=\"r\xc3\xa9sum\xc3\xa9.pdf\"", "r\u{e9}sum\u{e9}.pdf"),
+            (b"form-data; name=\"file\"; filename=\"caf\xe9.txt\"", "caf\u{e9}.txt"),
+            (b"form-data; name=\"f\"; filename=\"C:\\\\fakepath\\\\a.txt\"", "C:\\fakepath\\a.txt"),
+            // downloads
+            (b"attachment; filename*=UTF-8''r%C3%A9sum%C3%A9.pdf", "r\u{e9}sum\u{e9}.pdf"),
+            (b"attachment; filename=\"resume.pdf\"; filename*=UTF-8''r%C3%A9sum%C3%A9.pdf", "r\u{e9}sum\u{e9}.pdf"),
+            (b"attachment; filename*=UTF-8''r%C3%A9sum%C3%A9.pdf; filename=\"resume.pdf\"", "r\u{e9}sum\u{e9}.pdf"),
+            // an extended form that can't be decoded falls back to the plain one
+            (b"attachment; filename*=Shift_JIS''%82%A0.txt; filename=\"a.txt\"", "a.txt"),
+            (b"attachment; filename*=iso-8859-1'en'foo-%E4.html", "foo-\u{e4}.html"),
+            (b"attachment; filename=\"a;b.txt\"", "a;b.txt"),
+            (b"attachment; filename=\"a \\\"quoted\\\" name.txt\"", "a \"quoted\" name.txt"),
+            (b"attachment; filename=\"back\\\\slash.txt\"", "back\\slash.txt"),
+            (b"attachment; filename=\"\\n\\a\\m\\e.txt\"", "name.txt"),
+            (b"attachment; filename=\"ends with \\\"\"", "ends with \""),
+            (b"attachment; filename=plain name.txt", "plain name.txt"),
+            (b"ATTACHMENT;FILENAME=foo.html;", "foo.html"),
+        ];
+        for &(raw, expected) in cases {
+            assert_eq!(parse(raw).filename().unwrap(), expected, "{:?}", String::from_utf8_lossy(raw));
+        }
+
+        let invalid: &[&[u8]] = &[
+            b"; filename=foo.html",
+            b"attachment; filename=\"unterminated",
+            b"attachment; filename=\"foo\" bar",
+            b"attachment; filename",
+        ];
+        for raw in invalid {
+            assert!(ContentDisposition::parse_header([raw.to_vec()].as_ref()).is_err(),
+                    "{:?}", String::from_utf8_lossy(raw));
+        }
+    }
 }
@@ -57,6 +57,18 @@ header! {
     }
 }
 
+impl IfNoneMatch {
+    /// Whether a representation with `etag` matches, using the weak
+    /// comparison, so that the condition fails and a `GET` or `HEAD` can be
+    /// answered with a `304 Not Modified`.
+    pub fn matches(&self, etag: &EntityTag) -> bool {
+        match *self {
+            IfNoneMatch::Any => true,
+            IfNoneMatch::Items(ref tags) => tags.iter().any(|tag| tag.weak_eq(etag)),
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::IfNoneMatch;
@@ -78,6 +90,14 @@ mod tests {
         entities.push(weak_etag);
         assert_eq!(if_none_match.ok(), Some(IfNoneMatch::Items(entities)));
     }
+
+    #[test]
+    fn test_matches() {
+        let tag = EntityTag::new(false, "foobar".to_owned());
+        assert!(IfNoneMatch::Any.matches(&tag));
+        assert!(IfNoneMatch::Items(vec![EntityTag::new(true, "foobar".to_owned())]).matches(&tag));
+        assert!(!IfNoneMatch::Items(vec![EntityTag::new(false, "other".to_owned())]).matches(&tag));
+    }
 }
 
 bench_header!(bench, IfNoneMatch, { vec![b"W/\"nonemptytag\"".to_vec()] });
@@ -20,7 +20,7 @@ pub use self::accept_encoding::AcceptEncoding;
 pub use self::accept_language::AcceptLanguage;
 pub use self::accept_ranges::{AcceptRanges, RangeUnit};
 pub use self::allow::Allow;
-pub use self::authorization::{Authorization, Scheme, Basic, Bearer};
+pub use self::authorization::{Authorization, Scheme, AuthScheme, Basic, Bearer};
 pub use self::cache_control::{CacheControl, CacheDirective};
 pub use self::connection::{Connection, ConnectionOption};
 pub use self::content_disposition::{ContentDisposition, DispositionType, DispositionParam};
@@ -46,9 +46,11 @@ pub use self::location::Location;
 pub use self::pragma::Pragma;
 pub use self::range::{Range, ByteRangeSpec};
 pub use self::referer::Referer;
+pub use self::retry_after::RetryAfter;
 pub use self::server::Server;
 pub use self::set_cookie::SetCookie;
 pub use self::strict_transport_security::StrictTransportSecurity;
+pub use self::te::Te;
 pub use self::transfer_encoding::TransferEncoding;
 pub use self::upgrade::{Upgrade, Protocol, ProtocolName};
 pub use self::user_agent::UserAgent;
@@ -394,9 +396,11 @@ mod location;
 mod pragma;
 mod range;
 mod referer;
+mod retry_after;
 mod server;
 mod set_cookie;
 mod strict_transport_security;
+mod te;
 mod transfer_encoding;
 mod upgrade;
 mod user_agent;
@@ -89,6 +89,25 @@ impl Range {
     }
 }
 
+impl ByteRangeSpec {
+    /// Given the full length of the entity, returns the inclusive byte range
+    /// this spec selects, or `None` if it is not satisfiable.
+    ///
+    /// A range running past the end is cut short at the last byte.
+    pub fn to_satisfiable_range(&self, full_length: u64) -> Option<(u64, u64)> {
+        match *self {
+            ByteRangeSpec::FromTo(from, to) if from < full_length && from <= to => {
+                Some((from, ::std::cmp::min(to, full_length - 1)))
+            },
+            ByteRangeSpec::AllFrom(from) if from < full_length => Some((from, full_length - 1)),
+            ByteRangeSpec::Last(last) if last > 0 && full_length > 0 => {
+                Some((full_length - ::std::cmp::min(last, full_length), full_length - 1))
+            },
+            _ => None,
+        }
+    }
+}
+
 
 impl fmt::Display for ByteRangeSpec {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
@@ -286,3 +305,23 @@ fn test_fmt() {
 
 bench_header!(bytes_multi, Range, { vec![b"bytes=1-1001,2001-3001,10001-".to_vec()]});
 bench_header!(custom_unit, Range, { vec![b"other=0-100000".to_vec()]});
+
+#[test]
+fn test_byte_range_spec_to_satisfiable_range() {
+    assert_eq!(Some((0, 0)), ByteRangeSpec::FromTo(0, 0).to_satisfiable_range(3));
+    assert_eq!(Some((1, 2)), ByteRangeSpec::FromTo(1, 2).to_satisfiable_range(3));
+    assert_eq!(Some((1, 2)), ByteRangeSpec::FromTo(1, 5).to_satisfiable_range(3));
+    assert_eq!(None, ByteRangeSpec::FromTo(3, 3).to_satisfiable_range(3));
+    assert_eq!(None, ByteRangeSpec::FromTo(2, 1).to_satisfiable_range(3));
+    assert_eq!(None, ByteRangeSpec::FromTo(0, 0).to_satisfiable_range(0));
+
+    assert_eq!(Some((0, 2)), ByteRangeSpec::AllFrom(0).to_satisfiable_range(3));
+    assert_eq!(Some((2, 2)), ByteRangeSpec::AllFrom(2).to_satisfiable_range(3));
+    assert_eq!(None, ByteRangeSpec::AllFrom(3).to_satisfiable_range(3));
+    assert_eq!(None, ByteRangeSpec::AllFrom(0).to_satisfiable_range(0));
+
+    assert_eq!(Some((1, 2)), ByteRangeSpec::Last(2).to_satisfiable_range(3));
+    assert_eq!(Some((0, 2)), ByteRangeSpec::Last(5).to_satisfiable_range(3));
+    assert_eq!(None, ByteRangeSpec::Last(0).to_satisfiable_range(3));
+    assert_eq!(None, ByteRangeSpec::Last(2).to_satisfiable_range(0));
+}
@@ -0,0 +1,81 @@
+use std::fmt::{self, Display};
+use std::str;
+use std::time::Duration;
+use header::{self, Header, HeaderFormat, HttpDate};
+
+/// `Retry-After` header, defined in [RFC7231](http://tools.ietf.org/html/rfc7231#section-7.1.3)
+///
+/// The `Retry-After` header field indicates how long the user agent ought
+/// to wait before making a follow-up request. It is sent with a `503
+/// (Service Unavailable)` response when the service is expected to be
+/// unavailable for a while, or with a `429 (Too Many Requests)` response,
+/// and its value is either an HTTP-date or a number of seconds to delay
+/// after the response is received.
+///
+/// # ABNF
+/// ```plain
+/// Retry-After = HTTP-date / delay-seconds
+/// ```
+///
+/// # Example values
+/// * `Fri, 31 Dec 1999 23:59:59 GMT`
+/// * `120`
+///
+/// # Examples
+/// ```
+/// use std::time::Duration;
+/// use hyper::header::{Headers, RetryAfter};
+///
+/// let mut headers = Headers::new();
+/// headers.set(RetryAfter::Delay(Duration::from_secs(120)));
+/// ```
+#[derive(Clone, Debug, PartialEq)]
+pub enum RetryAfter {
+    /// How long to wait, sent as whole seconds
+    Delay(Duration),
+    /// When to retry
+    DateTime(HttpDate),
+}
+
+impl Header for RetryAfter {
+    fn header_name() -> &'static str {
+        "Retry-After"
+    }
+    fn parse_header(raw: &[Vec<u8>]) -> ::Result<RetryAfter> {
+        if raw.len() != 1 {
+            return Err(::Error::Header);
+        }
+        let s = try!(str::from_utf8(&raw[0]));
+        if let Ok(secs) = s.trim().parse::<u64>() {
+            return Ok(RetryAfter::Delay(Duration::from_secs(secs)));
+        }
+        let date: ::Result<HttpDate> = header::parsing::from_one_raw_str(raw);
+        date.map(RetryAfter::DateTime)
+    }
+}
+
+impl HeaderFormat for RetryAfter {
+    fn fmt_header(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            RetryAfter::Delay(ref x) => Display::fmt(&x.as_secs(), f),
+            RetryAfter::DateTime(ref x) => Display::fmt(x, f),
+        }
+    }
+}
+
+impl Display for RetryAfter {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.fmt_header(f)
+    }
+}
+
+#[cfg(test)]
+mod test_retry_after {
+    use std::str;
+    use std::time::Duration;
+    use header::*;
+    use super::RetryAfter as HeaderField;
+    test_header!(test1, vec![b"Fri, 31 Dec 1999 23:59:59 GMT"]);
+    test_header!(test2, vec![b"120"], Some(RetryAfter::Delay(Duration::from_secs(120))));
+    test_header!(test3, vec![b"soon"], None::<RetryAfter>);
+}
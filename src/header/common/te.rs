@@ -0,0 +1,43 @@
+use header::{Encoding, QualityItem};
+
+header! {
+    /// `TE` header, defined in
+    /// [RFC7230](http://tools.ietf.org/html/rfc7230#section-4.3)
+    ///
+    /// As RFC7230 states, "The "TE" header field in a request indicates what
+    /// transfer codings, besides chunked, the client is willing to accept
+    /// in response, and whether or not the client is willing to accept
+    /// trailer fields in a chunked transfer coding."
+    ///
+    /// For instance the TE header is used to signal that the client
+    /// supports trailers, with the `trailers` token.
+    ///
+    /// # ABNF
+    /// ```plain
+    /// TE        = #t-codings
+    /// t-codings = "trailers" / ( transfer-coding [ t-ranking ] )
+    /// ```
+    ///
+    /// # Example values
+    /// * `trailers`
+    /// * `trailers, deflate;q=0.5`
+    /// * ``
+    ///
+    /// # Examples
+    /// ```
+    /// use hyper::header::{Headers, Te, Encoding, qitem};
+    ///
+    /// let mut headers = Headers::new();
+    /// headers.set(
+    ///     Te(vec![qitem(Encoding::EncodingExt("trailers".to_owned()))])
+    /// );
+    /// ```
+    (Te, "TE") => (QualityItem<Encoding>)*
+
+    test_te {
+        // From the RFC
+        test_header!(test1, vec![b"trailers"]);
+        test_header!(test2, vec![b"trailers, deflate;q=0.5"]);
+        test_header!(test3, vec![b""], Some(Te(vec![])));
+    }
+}
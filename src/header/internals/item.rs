@@ -1,6 +1,7 @@
 use std::any::Any;
 use std::any::TypeId;
 use std::fmt;
+use std::io::{self, Write};
 use std::str::from_utf8;
 
 use typeable::Typeable;
@@ -54,6 +55,34 @@ impl Item {
         &raw[..]
     }
 
+    /// The length of the serialized value, guessed for typed values since
+    /// knowing it would mean formatting them.
+    pub fn len_hint(&self) -> usize {
+        match *self.raw {
+            Some(ref raw) => raw.iter().fold(0, |len, part| len + part.len()),
+            None => TYPED_LEN_HINT
+        }
+    }
+
+    /// Appends the serialized value to `buf`, as the `Display` impl would
+    /// write it. Like it, a raw value that isn't UTF-8 is an error.
+    pub fn write_to(&self, buf: &mut Vec<u8>) -> io::Result<()> {
+        match *self.raw {
+            Some(ref raw) => {
+                for part in raw.iter() {
+                    if let Err(e) = from_utf8(&part[..]) {
+                        error!("raw header value is not utf8. header={:?}, error={:?}",
+                            part, e);
+                        return Err(io::Error::new(io::ErrorKind::InvalidData, e));
+                    }
+                    buf.extend_from_slice(&part[..]);
+                }
+                Ok(())
+            },
+            None => write!(buf, "{}", unsafe { self.typed.one() })
+        }
+    }
+
     pub fn typed<H: Header + HeaderFormat + Any>(&self) -> Option<&H> {
         let tid = TypeId::of::<H>();
         match self.typed.get(tid) {
@@ -84,6 +113,8 @@ impl Item {
     }
 }
 
+const TYPED_LEN_HINT: usize = 32;
+
 #[inline]
 fn parse<H: Header + HeaderFormat>(raw: &Vec<Vec<u8>>) ->
         ::Result<Box<HeaderFormat + Send + Sync>> {
@@ -82,6 +82,7 @@ use std::any::Any;
 use std::borrow::{Cow, ToOwned};
 use std::collections::HashMap;
 use std::collections::hash_map::{Iter, Entry};
+use std::io;
 use std::iter::{FromIterator, IntoIterator};
 use std::ops::{Deref, DerefMut};
 use std::{mem, fmt};
@@ -177,10 +178,58 @@ fn header_name<T: Header>() -> &'static str {
     <T as Header>::header_name()
 }
 
+/// How `Headers::merge` treats a field present in both maps.
+///
+/// `Set-Cookie` is an exception: each of its values is a separate cookie,
+/// so they are always appended.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MergePolicy {
+    /// The merged field replaces the existing one.
+    Replace,
+    /// The existing field is kept.
+    KeepExisting,
+}
+
 /// A map of header fields on requests and responses.
 #[derive(Clone)]
 pub struct Headers {
-    data: HashMap<HeaderName, Item>
+    data: HashMap<HeaderName, Item>,
+    framing: Option<Framing>,
+}
+
+// The fields deciding how a message is framed, looked up for every
+// request and response, parsed once by `Headers::from_raw` instead of
+// hashing their names on each lookup. Any change to the map drops it.
+//
+// For each field, `None` is absent, `Some(None)` failed to parse.
+#[derive(Clone)]
+struct Framing {
+    content_length: Option<Option<ContentLength>>,
+    transfer_encoding: Option<Option<TransferEncoding>>,
+    connection: Option<Option<Connection>>,
+    expect: Option<Option<Expect>>,
+}
+
+impl Framing {
+    fn parse(headers: &Headers) -> Framing {
+        fn field<H: Header>(headers: &Headers) -> Option<Option<H>> {
+            headers.get_raw(H::header_name()).map(|raw| H::parse_header(raw).ok())
+        }
+        Framing {
+            content_length: field(headers),
+            transfer_encoding: field(headers),
+            connection: field(headers),
+            expect: field(headers),
+        }
+    }
+
+    // The cached field of type `H`, or `None` if `H` isn't one of them.
+    #[inline]
+    fn field<H: Any>(&self) -> Option<&Option<Option<H>>> {
+        let fields: [&Any; 4] = [&self.content_length, &self.transfer_encoding,
+                                 &self.connection, &self.expect];
+        fields.iter().filter_map(|field| field.downcast_ref()).next()
+    }
 }
 
 impl Headers {
@@ -188,7 +237,8 @@ impl Headers {
     /// Creates a new, empty headers map.
     pub fn new() -> Headers {
         Headers {
-            data: HashMap::new()
+            data: HashMap::new(),
+            framing: None,
         }
     }
 
@@ -206,14 +256,72 @@ impl Headers {
             let value = &header.value[.. header.value.len() - trim];
             item.mut_raw().push(value.to_vec());
         }
+        headers.framing = Some(Framing::parse(&headers));
         Ok(headers)
     }
 
+    /// Creates headers from pairs of field names and raw values.
+    ///
+    /// Values of the same field are kept in order. Returns
+    /// `Error::Header` if a name isn't a token, or a value contains a
+    /// control character other than a tab, since a CR or LF could be used
+    /// to inject more fields.
+    ///
+    /// ```
+    /// # use hyper::header::Headers;
+    /// let headers = Headers::from_pairs(vec![
+    ///     ("X-Forwarded-For".to_owned(), b"10.0.0.1".to_vec()),
+    /// ]).unwrap();
+    /// assert_eq!(headers.get_raw("x-forwarded-for"), Some(&[b"10.0.0.1".to_vec()][..]));
+    /// assert!(Headers::from_pairs(vec![
+    ///     ("X-Evil".to_owned(), b"1\r\nSet-Cookie: a=b".to_vec()),
+    /// ]).is_err());
+    /// ```
+    pub fn from_pairs<I: IntoIterator<Item=(String, Vec<u8>)>>(pairs: I) -> ::Result<Headers> {
+        let mut headers = Headers::new();
+        for (name, value) in pairs {
+            if !is_valid_name(&name) || !is_valid_value(&value) {
+                debug!("invalid header field {:?}: {:?}", name, value);
+                return Err(::Error::Header);
+            }
+            let mut item = match headers.data.entry(UniCase(CowStr(Cow::Owned(name)))) {
+                Entry::Vacant(entry) => entry.insert(Item::new_raw(vec![])),
+                Entry::Occupied(entry) => entry.into_mut()
+            };
+            item.mut_raw().push(value);
+        }
+        Ok(headers)
+    }
+
+    /// Merges the fields of `other` into this map, resolving fields
+    /// present in both according to `policy`.
+    pub fn merge(&mut self, other: &Headers, policy: MergePolicy) {
+        self.framing = None;
+        let set_cookie = UniCase(CowStr(Cow::Borrowed("Set-Cookie")));
+        for (name, item) in other.data.iter() {
+            match self.data.entry(name.clone()) {
+                Entry::Vacant(entry) => {
+                    entry.insert(item.clone());
+                },
+                Entry::Occupied(mut entry) => {
+                    if *name == set_cookie {
+                        let mut raw = entry.get().raw().to_vec();
+                        raw.extend(item.raw().iter().cloned());
+                        entry.insert(Item::new_raw(raw));
+                    } else if policy == MergePolicy::Replace {
+                        entry.insert(item.clone());
+                    }
+                }
+            }
+        }
+    }
+
     /// Set a header field to the corresponding value.
     ///
     /// The field is determined by the type of the value being set.
     pub fn set<H: Header + HeaderFormat>(&mut self, value: H) {
         trace!("Headers.set( {:?}, {:?} )", header_name::<H>(), value);
+        self.framing = None;
         self.data.insert(UniCase(CowStr(Cow::Borrowed(header_name::<H>()))),
                          Item::new_typed(Box::new(value)));
     }
@@ -247,12 +355,14 @@ impl Headers {
     pub fn set_raw<K: Into<Cow<'static, str>> + fmt::Debug>(&mut self, name: K,
             value: Vec<Vec<u8>>) {
         trace!("Headers.set_raw( {:?}, {:?} )", name, value);
+        self.framing = None;
         self.data.insert(UniCase(CowStr(name.into())), Item::new_raw(value));
     }
 
     /// Remove a header set by set_raw
     pub fn remove_raw(&mut self, name: &str) {
         trace!("Headers.remove_raw( {:?} )", name);
+        self.framing = None;
         self.data.remove(
             &UniCase(CowStr(Cow::Borrowed(unsafe { mem::transmute::<&str, &str>(name) })))
         );
@@ -260,12 +370,16 @@ impl Headers {
 
     /// Get a reference to the header field's value, if it exists.
     pub fn get<H: Header + HeaderFormat>(&self) -> Option<&H> {
+        if let Some(field) = self.framing.as_ref().and_then(Framing::field::<H>) {
+            return field.as_ref().and_then(Option::as_ref);
+        }
         self.data.get(&UniCase(CowStr(Cow::Borrowed(header_name::<H>()))))
         .and_then(Item::typed::<H>)
     }
 
     /// Get a mutable reference to the header field's value, if it exists.
     pub fn get_mut<H: Header + HeaderFormat>(&mut self) -> Option<&mut H> {
+        self.framing = None;
         self.data.get_mut(&UniCase(CowStr(Cow::Borrowed(header_name::<H>()))))
         .and_then(Item::typed_mut::<H>)
     }
@@ -281,6 +395,9 @@ impl Headers {
     /// let has_type = headers.has::<ContentType>();
     /// ```
     pub fn has<H: Header + HeaderFormat>(&self) -> bool {
+        if let Some(field) = self.framing.as_ref().and_then(Framing::field::<H>) {
+            return field.is_some();
+        }
         self.data.contains_key(&UniCase(CowStr(Cow::Borrowed(header_name::<H>()))))
     }
 
@@ -288,6 +405,7 @@ impl Headers {
     /// Returns true if a header has been removed.
     pub fn remove<H: Header + HeaderFormat>(&mut self) -> bool {
         trace!("Headers.remove( {:?} )", header_name::<H>());
+        self.framing = None;
         self.data.remove(&UniCase(CowStr(Cow::Borrowed(header_name::<H>())))).is_some()
     }
 
@@ -298,6 +416,32 @@ impl Headers {
         }
     }
 
+    /// Returns about how many bytes `write_to` will write.
+    ///
+    /// This is exact for headers with raw values. The length of typed
+    /// values is guessed, since knowing it would mean formatting them.
+    pub fn serialized_size_hint(&self) -> usize {
+        self.data.iter().fold(0, |len, (name, item)| {
+            let name: &str = name.as_ref();
+            len + name.len() + 2 + item.len_hint() + 2
+        })
+    }
+
+    /// Appends the header fields to `buf`, each followed by a CRLF.
+    ///
+    /// This writes the same bytes as the `Display` impl, but copies raw
+    /// values directly instead of going through a `Formatter`.
+    pub fn write_to(&self, buf: &mut Vec<u8>) -> io::Result<()> {
+        for (name, item) in self.data.iter() {
+            let name: &str = name.as_ref();
+            buf.extend_from_slice(name.as_bytes());
+            buf.extend_from_slice(b": ");
+            try!(item.write_to(buf));
+            buf.extend_from_slice(b"\r\n");
+        }
+        Ok(())
+    }
+
     /// Returns the number of headers in the map.
     pub fn len(&self) -> usize {
         self.data.len()
@@ -305,8 +449,102 @@ impl Headers {
 
     /// Remove all headers from the map.
     pub fn clear(&mut self) {
+        self.framing = None;
         self.data.clear()
     }
+
+    /// Formats the headers for logging, with the values of the
+    /// `SENSITIVE_HEADERS` hidden.
+    pub fn redacted(&self) -> Redacted {
+        Redacted {
+            headers: self,
+            sensitive: SENSITIVE_HEADERS,
+            redact: true,
+        }
+    }
+}
+
+/// The headers whose values `Headers::redacted` hides by default, since
+/// they carry credentials.
+pub const SENSITIVE_HEADERS: &'static [&'static str] = &[
+    "Authorization",
+    "Proxy-Authorization",
+    "Cookie",
+    "Set-Cookie",
+];
+
+/// Formats `Headers` like their `Debug` output, but with the values of
+/// sensitive headers replaced by `[redacted:N bytes]`.
+///
+/// Created by `Headers::redacted`. Formatting doesn't allocate, and the
+/// headers themselves, as sent or received, are unchanged.
+#[derive(Clone, Copy)]
+pub struct Redacted<'a> {
+    headers: &'a Headers,
+    sensitive: &'a [&'a str],
+    redact: bool,
+}
+
+impl<'a> Redacted<'a> {
+    /// Hides the values of these headers, instead of the
+    /// `SENSITIVE_HEADERS`.
+    pub fn sensitive(self, names: &'a [&'a str]) -> Redacted<'a> {
+        Redacted { sensitive: names, ..self }
+    }
+
+    /// Shows every value, for debugging where the logs can be trusted.
+    pub fn unredacted(self) -> Redacted<'a> {
+        Redacted { redact: false, ..self }
+    }
+}
+
+impl<'a> fmt::Debug for Redacted<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        try!(f.write_str("Headers { "));
+        for header in self.headers.iter() {
+            let hide = self.redact && self.sensitive.iter().any(|name| {
+                UniCase(*name) == UniCase(header.name())
+            });
+            if hide {
+                let mut len = ByteCount(0);
+                try!(fmt::Write::write_fmt(&mut len, format_args!("{}", *header.1)));
+                try!(write!(f, "{}: [redacted:{} bytes], ", header.0, len.0));
+            } else {
+                try!(write!(f, "{:?}, ", header));
+            }
+        }
+        f.write_str("}")
+    }
+}
+
+// Counts the bytes formatted into it.
+struct ByteCount(usize);
+
+impl fmt::Write for ByteCount {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.0 += s.len();
+        Ok(())
+    }
+}
+
+/// Whether `b` may appear in a token, as defined in RFC 7230 section 3.2.6.
+#[doc(hidden)]
+pub fn is_token(b: u8) -> bool {
+    match b {
+        b'a'...b'z' | b'A'...b'Z' | b'0'...b'9' |
+        b'!' | b'#' | b'$' | b'%' | b'&' | b'\'' | b'*' | b'+' | b'-' | b'.' |
+        b'^' | b'_' | b'`' | b'|' | b'~' => true,
+        _ => false
+    }
+}
+
+// A field name must be a token.
+fn is_valid_name(name: &str) -> bool {
+    !name.is_empty() && name.bytes().all(is_token)
+}
+
+fn is_valid_value(value: &[u8]) -> bool {
+    value.iter().all(|&b| b == b'\t' || (b >= b' ' && b != 0x7f))
 }
 
 impl PartialEq for Headers {
@@ -435,6 +673,7 @@ impl<'a> fmt::Debug for HeaderView<'a> {
 
 impl<'a> Extend<HeaderView<'a>> for Headers {
     fn extend<I: IntoIterator<Item=HeaderView<'a>>>(&mut self, iter: I) {
+        self.framing = None;
         for header in iter {
             self.data.insert((*header.0).clone(), (*header.1).clone());
         }
@@ -520,7 +759,7 @@ mod tests {
     use mime::TopLevel::Text;
     use mime::SubLevel::Plain;
     use super::{Headers, Header, HeaderFormat, ContentLength, ContentType,
-                Accept, Host, qitem};
+                Accept, Authorization, Host, MergePolicy, SetCookie, qitem};
     use httparse;
 
     #[cfg(feature = "nightly")]
@@ -549,6 +788,30 @@ mod tests {
         })
     }
 
+    #[test]
+    fn test_redacted() {
+        let mut headers = Headers::new();
+        headers.set(Authorization("Bearer secret".to_owned()));
+        headers.set_raw("Cookie", vec![b"session=secret".to_vec()]);
+        headers.set(ContentLength(10));
+
+        let redacted = format!("{:?}", headers.redacted());
+        assert!(!redacted.contains("secret"));
+        assert!(redacted.contains("Authorization: [redacted:13 bytes], "));
+        assert!(redacted.contains("Cookie: [redacted:14 bytes], "));
+        assert!(redacted.contains("Content-Length: 10, "));
+
+        let names = ["content-length"];
+        let redacted = format!("{:?}", headers.redacted().sensitive(&names));
+        assert!(redacted.contains("Authorization: Bearer secret, "));
+        assert!(redacted.contains("Content-Length: [redacted:2 bytes], "));
+
+        assert_eq!(format!("{:?}", headers.redacted().unredacted()).len(),
+                   format!("{:?}", headers).len());
+        // what is written is untouched
+        assert!(headers.to_string().contains("Authorization: Bearer secret\r\n"));
+    }
+
     #[test]
     fn test_from_raw() {
         let headers = Headers::from_raw(&raw!(b"Content-Length: 10")).unwrap();
@@ -642,6 +905,26 @@ mod tests {
         assert_eq!(*headers.get::<ContentLength>().unwrap(), ContentLength(20));
     }
 
+    #[test]
+    fn test_framing_after_changes() {
+        use header::{Connection, Expect, TransferEncoding};
+
+        let mut headers = Headers::from_raw(
+            &raw!(b"content-length: 10", b"Transfer-Encoding: chunked", b"Expect: nope")).unwrap();
+        assert_eq!(headers.get::<ContentLength>(), Some(&ContentLength(10)));
+        assert!(headers.get::<TransferEncoding>().is_some());
+        assert!(!headers.has::<Connection>());
+        assert!(headers.has::<Expect>());
+        assert_eq!(headers.get::<Expect>(), None);
+
+        headers.set_raw("Content-Length", vec![b"20".to_vec()]);
+        assert_eq!(headers.get::<ContentLength>(), Some(&ContentLength(20)));
+        headers.remove::<TransferEncoding>();
+        assert!(!headers.has::<TransferEncoding>());
+        headers.set(Connection::close());
+        assert!(headers.has::<Connection>());
+    }
+
     #[test]
     fn test_headers_show() {
         let mut headers = Headers::new();
@@ -660,6 +943,95 @@ mod tests {
         assert_eq!(s, "Content-Length: 10\r\n");
     }
 
+    fn pairs(pairs: &[(&str, &str)]) -> ::Result<Headers> {
+        Headers::from_pairs(pairs.iter().map(|&(name, value)| {
+            (name.to_owned(), value.as_bytes().to_vec())
+        }))
+    }
+
+    #[test]
+    fn test_from_pairs() {
+        let headers = pairs(&[
+            ("Content-Length", "10"),
+            ("X-Multi", "a"),
+            ("x-multi", "b\tc"),
+        ]).unwrap();
+        assert_eq!(headers.len(), 2);
+        assert_eq!(headers.get(), Some(&ContentLength(10)));
+        assert_eq!(headers.get_raw("X-Multi").unwrap(), &[b"a".to_vec(), b"b\tc".to_vec()][..]);
+
+        assert!(pairs(&[("", "1")]).is_err());
+        assert!(pairs(&[("X Space", "1")]).is_err());
+        assert!(pairs(&[("X-Colon:", "1")]).is_err());
+        assert!(pairs(&[("X-Evil", "1\r\nSet-Cookie: a=b")]).is_err());
+        assert!(pairs(&[("X-Evil", "1\nX: 2")]).is_err());
+        assert!(pairs(&[("X-Nul", "a\0b")]).is_err());
+    }
+
+    #[test]
+    fn test_merge() {
+        let mut headers = pairs(&[("Content-Length", "10"), ("X-Kept", "1")]).unwrap();
+        let other = pairs(&[("content-length", "20"), ("X-New", "2")]).unwrap();
+
+        let mut keep = headers.clone();
+        keep.merge(&other, MergePolicy::KeepExisting);
+        assert_eq!(keep.len(), 3);
+        assert_eq!(keep.get(), Some(&ContentLength(10)));
+        assert_eq!(keep.get_raw("X-New").unwrap(), &[b"2".to_vec()][..]);
+
+        headers.merge(&other, MergePolicy::Replace);
+        assert_eq!(headers.len(), 3);
+        assert_eq!(headers.get(), Some(&ContentLength(20)));
+        assert_eq!(headers.get_raw("X-Kept").unwrap(), &[b"1".to_vec()][..]);
+    }
+
+    #[test]
+    fn test_merge_appends_set_cookie() {
+        let mut headers = pairs(&[("Set-Cookie", "a=1")]).unwrap();
+        let other = pairs(&[("Set-Cookie", "b=2"), ("set-cookie", "c=3")]).unwrap();
+
+        headers.merge(&other, MergePolicy::Replace);
+        assert_eq!(headers.get_raw("Set-Cookie").unwrap(),
+                   &[b"a=1".to_vec(), b"b=2".to_vec(), b"c=3".to_vec()][..]);
+
+        headers.merge(&other, MergePolicy::KeepExisting);
+        assert_eq!(headers.get::<SetCookie>().unwrap().len(), 5);
+    }
+
+    #[test]
+    fn test_headers_write_to() {
+        let mut typed = Headers::new();
+        typed.set(ContentLength(15));
+        typed.set(Host { hostname: "foo.bar".to_owned(), port: Some(8080) });
+        typed.set(ContentType(Mime(Text, Plain, vec![])));
+
+        let mut multi = Headers::new();
+        multi.set_raw("X-Multi", vec![b"a".to_vec(), b"b, c".to_vec()]);
+        multi.set_raw("Set-Cookie", vec![vec![b'x'; 4096]]);
+
+        let mut mixed = Headers::from_raw(&raw!(b"Content-Length: 10", b"X-Foo: bar")).unwrap();
+        mixed.set(Accept(vec![qitem(Mime(Text, Plain, vec![]))]));
+
+        let mut invalid = Headers::new();
+        invalid.set_raw("X-Latin1", vec![b"caf\xe9".to_vec()]);
+
+        let corpus = vec![Headers::new(), typed, multi, mixed, invalid];
+        for headers in &corpus {
+            let mut buf = Vec::with_capacity(headers.serialized_size_hint());
+            match headers.write_to(&mut buf) {
+                Ok(()) => assert_eq!(buf, headers.to_string().into_bytes()),
+                Err(_) => assert!(fmt::write(&mut String::new(),
+                                             format_args!("{}", headers)).is_err()),
+            }
+        }
+        assert!(corpus[4].write_to(&mut Vec::new()).is_err());
+
+        let raw = &corpus[2];
+        let mut buf = Vec::new();
+        raw.write_to(&mut buf).unwrap();
+        assert_eq!(raw.serialized_size_hint(), buf.len());
+    }
+
     #[test]
     fn test_set_raw() {
         let mut headers = Headers::new();
@@ -758,6 +1130,29 @@ mod tests {
         b.iter(|| Headers::from_raw(&raw).unwrap())
     }
 
+    #[cfg(feature = "nightly")]
+    #[bench]
+    fn bench_headers_request_framing(b: &mut Bencher) {
+        use header::{Connection, Expect, TransferEncoding};
+
+        let raw = raw!(b"Host: example.domain",
+                       b"User-Agent: hyper-bench",
+                       b"Accept: */*",
+                       b"Content-Length: 10",
+                       b"Connection: keep-alive");
+        // the lookups a server makes for every request it reads
+        b.iter(|| {
+            let headers = Headers::from_raw(&raw).unwrap();
+            assert!(headers.has::<ContentLength>());
+            assert_eq!(headers.get::<ContentLength>(), Some(&ContentLength(10)));
+            assert!(headers.get::<ContentLength>().is_some());
+            assert!(headers.get::<TransferEncoding>().is_none());
+            assert!(headers.get::<Connection>().is_some());
+            assert!(headers.get::<Expect>().is_none());
+            headers
+        })
+    }
+
     #[cfg(feature = "nightly")]
     #[bench]
     fn bench_headers_get(b: &mut Bencher) {
@@ -805,4 +1200,38 @@ mod tests {
         headers.set(ContentLength(11));
         b.iter(|| headers.to_string())
     }
+
+    #[cfg(feature = "nightly")]
+    fn twenty_headers() -> Headers {
+        let mut headers = Headers::new();
+        headers.set(ContentLength(11));
+        headers.set(ContentType(Mime(Text, Plain, vec![])));
+        for i in 0..18 {
+            headers.set_raw(format!("X-Header-{}", i), vec![b"some moderately long value".to_vec()]);
+        }
+        headers
+    }
+
+    #[cfg(feature = "nightly")]
+    #[bench]
+    fn bench_headers_fmt_20(b: &mut Bencher) {
+        use std::io::Write;
+        let headers = twenty_headers();
+        b.iter(|| {
+            let mut buf = Vec::with_capacity(100 * headers.len());
+            write!(&mut buf, "{}", headers).unwrap();
+            buf
+        })
+    }
+
+    #[cfg(feature = "nightly")]
+    #[bench]
+    fn bench_headers_write_to_20(b: &mut Bencher) {
+        let headers = twenty_headers();
+        b.iter(|| {
+            let mut buf = Vec::with_capacity(headers.serialized_size_hint());
+            headers.write_to(&mut buf).unwrap();
+            buf
+        })
+    }
 }
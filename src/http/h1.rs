@@ -4,6 +4,7 @@ use std::cmp::min;
 use std::fmt;
 use std::io::{self, Write, BufWriter, BufRead, Read};
 use std::net::Shutdown;
+use std::str;
 use std::time::Duration;
 
 use httparse;
@@ -28,6 +29,10 @@ use http::{
     HttpMessage,
     RequestHead,
     ResponseHead,
+    Direction,
+    RequestLeniency,
+    RequestLimits,
+    Tap,
 };
 use header;
 use version;
@@ -92,6 +97,8 @@ impl Stream {
 pub struct Http11Message {
     method: Option<Method>,
     stream: Wrapper<Stream>,
+    max_skip_bytes: usize,
+    tap: Option<Tap>,
 }
 
 impl Write for Http11Message {
@@ -100,7 +107,13 @@ impl Write for Http11Message {
         match self.stream.as_mut().writer_mut() {
             None => Err(io::Error::new(io::ErrorKind::Other,
                                           "Not in a writable state")),
-            Some(ref mut writer) => writer.write(buf),
+            Some(ref mut writer) => {
+                let n = try!(writer.write(buf));
+                if let Some(ref tap) = self.tap {
+                    tap.body(Direction::Sent, &buf[..n]);
+                }
+                Ok(n)
+            },
         }
     }
     #[inline]
@@ -119,7 +132,13 @@ impl Read for Http11Message {
         match self.stream.as_mut().reader_mut() {
             None => Err(io::Error::new(io::ErrorKind::Other,
                                           "Not in a readable state")),
-            Some(ref mut reader) => reader.read(buf),
+            Some(ref mut reader) => {
+                let n = try!(reader.read(buf));
+                if let Some(ref tap) = self.tap {
+                    tap.body(Direction::Received, &buf[..n]);
+                }
+                Ok(n)
+            },
         }
     }
 }
@@ -130,6 +149,7 @@ impl HttpMessage for Http11Message {
                             io::ErrorKind::Other,
                             "")));
         let mut method = None;
+        let tap = self.tap;
         self.stream.map_in_place(|stream: Stream| -> Stream {
             let stream = match stream {
                 Stream::Idle(stream) => stream,
@@ -150,8 +170,8 @@ impl HttpMessage for Http11Message {
 
             let version = version::HttpVersion::Http11;
             debug!("request line: {:?} {:?} {:?}", head.method, uri, version);
-            match write!(&mut stream, "{} {} {}{}",
-                         head.method, uri, version, LINE_ENDING) {
+            let line = format!("{} {} {}{}", head.method, uri, version, LINE_ENDING);
+            match stream.write_all(line.as_bytes()) {
                              Err(e) => {
                                  res = Err(From::from(e));
                                  // TODO What should we do if the BufWriter doesn't wanna
@@ -163,8 +183,19 @@ impl HttpMessage for Http11Message {
 
             let stream = {
                 let write_headers = |mut stream: BufWriter<Box<NetworkStream + Send>>, head: &RequestHead| {
-                    debug!("headers={:?}", head.headers);
-                    match write!(&mut stream, "{}{}", head.headers, LINE_ENDING) {
+                    debug!("headers={:?}", head.headers.redacted());
+                    let mut buf = Vec::with_capacity(head.headers.serialized_size_hint() +
+                                                     LINE_ENDING.len());
+                    let written = head.headers.write_to(&mut buf).and_then(|_| {
+                        buf.extend_from_slice(LINE_ENDING.as_bytes());
+                        if let Some(ref tap) = tap {
+                            let mut sent = line.as_bytes().to_vec();
+                            sent.extend_from_slice(&buf);
+                            tap.head(Direction::Sent, &sent);
+                        }
+                        stream.write_all(&buf)
+                    });
+                    match written {
                         Ok(_) => Ok(stream),
                         Err(e) => {
                             Err((e, stream.into_inner().unwrap()))
@@ -240,6 +271,8 @@ impl HttpMessage for Http11Message {
     fn get_incoming(&mut self) -> ::Result<ResponseHead> {
         try!(self.flush_outgoing());
         let method = self.method.take().unwrap_or(Method::Get);
+        let max_skip_bytes = self.max_skip_bytes;
+        let tap = self.tap;
         let mut res = Err(From::from(
                         io::Error::new(io::ErrorKind::Other,
                         "Read already in progress")));
@@ -262,9 +295,15 @@ impl HttpMessage for Http11Message {
             let mut stream = BufReader::new(stream);
 
             let mut invalid_bytes_read = 0;
+            let mut first_error = None;
             let head;
             loop {
-                head = match parse_response(&mut stream) {
+                let parsed = parse_response_tapped(&mut stream, |head| {
+                    if let Some(ref tap) = tap {
+                        tap.head(Direction::Received, head);
+                    }
+                });
+                head = match parsed {
                     Ok(head) => head,
                     Err(::Error::Version)
                         if expected_no_content && invalid_bytes_read < MAX_INVALID_RESPONSE_BYTES => {
@@ -274,7 +313,19 @@ impl HttpMessage for Http11Message {
                             continue;
                         }
                     Err(e) => {
-                        res = Err(e);
+                        let is_parse_error = match e {
+                            ::Error::Io(..) => false,
+                            _ => true
+                        };
+                        if is_parse_error && invalid_bytes_read < max_skip_bytes &&
+                                !stream.get_buf().is_empty() {
+                            trace!("invalid response, skipping a byte: {:?}", e);
+                            first_error = first_error.or(Some(e));
+                            invalid_bytes_read += 1;
+                            stream.consume(1);
+                            continue;
+                        }
+                        res = Err(first_error.unwrap_or(e));
                         return Stream::Idle(stream.into_inner());
                     }
                 };
@@ -323,6 +374,7 @@ impl HttpMessage for Http11Message {
                 headers: headers,
                 raw_status: raw_status,
                 version: head.version,
+                skipped_bytes: invalid_bytes_read,
             });
 
             Stream::Reading(reader)
@@ -340,6 +392,15 @@ impl HttpMessage for Http11Message {
         }
     }
 
+    #[inline]
+    fn set_lenient_parsing(&mut self, max_skip_bytes: usize) {
+        self.max_skip_bytes = max_skip_bytes;
+    }
+
+    fn set_tap(&mut self, tap: Option<Tap>) {
+        self.tap = tap;
+    }
+
     #[inline]
     fn set_read_timeout(&self, dur: Option<Duration>) -> io::Result<()> {
         self.get_ref().set_read_timeout(dur)
@@ -367,6 +428,23 @@ impl Http11Message {
         }
     }
 
+    /// Consumes the `Http11Message` for a connection switching protocols,
+    /// returning the underlying `NetworkStream` and any bytes already read
+    /// past the response head.
+    pub fn into_upgraded(self) -> (Box<NetworkStream + Send>, Vec<u8>) {
+        let (mut stream, read_buf) = match self.stream.into_inner() {
+            Stream::Idle(stream) => (stream, Vec::new()),
+            Stream::Writing(stream) => (stream.into_inner().into_inner().unwrap(), Vec::new()),
+            Stream::Reading(stream) => {
+                let stream = stream.into_inner();
+                let read_buf = stream.get_buf().to_vec();
+                (stream.into_inner(), read_buf)
+            }
+        };
+        stream.set_upgraded();
+        (stream, read_buf)
+    }
+
     /// Gets a mutable reference to the underlying `NetworkStream`, regardless of the state of the
     /// `Http11Message`.
     pub fn get_ref(&self) -> &(NetworkStream + Send) {
@@ -393,6 +471,8 @@ impl Http11Message {
         Http11Message {
             method: None,
             stream: Wrapper::new(Stream::new(stream)),
+            max_skip_bytes: 0,
+            tap: None,
         }
     }
 
@@ -524,6 +604,19 @@ impl<R: Read> HttpReader<R> {
         }
     }
 
+    /// Whether the end of the body has been read.
+    ///
+    /// An `EofReader` only knows once the connection is closed, so it is
+    /// never done.
+    pub fn is_eof(&self) -> bool {
+        match *self {
+            SizedReader(_, remaining) => remaining == 0,
+            ChunkedReader(_, remaining) => remaining == Some(0),
+            EofReader(_) => false,
+            EmptyReader(_) => true,
+        }
+    }
+
     /// Gets a mutable reference to the underlying Reader.
     pub fn get_mut(&mut self) -> &mut R {
         match *self {
@@ -625,8 +718,15 @@ fn eat<R: Read>(rdr: &mut R, bytes: &[u8]) -> io::Result<()> {
     Ok(())
 }
 
+/// The most hex digits accepted in a chunk size, enough for any `u64`.
+const MAX_CHUNK_SIZE_DIGITS: usize = 16;
+/// The most bytes of chunk extensions accepted on a single size line.
+const MAX_CHUNK_EXT_LEN: usize = 4096;
+
 /// Chunked chunks start with 1*HEXDIGIT, indicating the size of the chunk.
-fn read_chunk_size<R: Read>(rdr: &mut R) -> io::Result<u64> {
+///
+/// Reads a chunk size line, including any extension and the CRLF.
+pub fn read_chunk_size<R: Read>(rdr: &mut R) -> io::Result<u64> {
     macro_rules! byte (
         ($rdr:ident) => ({
             let mut buf = [0];
@@ -638,24 +738,30 @@ fn read_chunk_size<R: Read>(rdr: &mut R) -> io::Result<u64> {
             }
         })
     );
+    macro_rules! digit (
+        ($size:ident, $digits:ident, $value:expr) => ({
+            $digits += 1;
+            if $digits > MAX_CHUNK_SIZE_DIGITS {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                          "Chunk size line too long"));
+            }
+            $size = match $size.checked_mul(16).and_then(|s| s.checked_add($value as u64)) {
+                Some(size) => size,
+                None => return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                                  "Chunk size overflow"))
+            };
+        })
+    );
     let mut size = 0u64;
-    let radix = 16;
+    let mut digits = 0;
+    let mut ext_len = 0;
     let mut in_ext = false;
     let mut in_chunk_size = true;
     loop {
         match byte!(rdr) {
-            b@b'0'...b'9' if in_chunk_size => {
-                size *= radix;
-                size += (b - b'0') as u64;
-            },
-            b@b'a'...b'f' if in_chunk_size => {
-                size *= radix;
-                size += (b + 10 - b'a') as u64;
-            },
-            b@b'A'...b'F' if in_chunk_size => {
-                size *= radix;
-                size += (b + 10 - b'A') as u64;
-            },
+            b@b'0'...b'9' if in_chunk_size => digit!(size, digits, b - b'0'),
+            b@b'a'...b'f' if in_chunk_size => digit!(size, digits, b + 10 - b'a'),
+            b@b'A'...b'F' if in_chunk_size => digit!(size, digits, b + 10 - b'A'),
             CR => {
                 match byte!(rdr) {
                     LF => break,
@@ -679,7 +785,14 @@ fn read_chunk_size<R: Read>(rdr: &mut R) -> io::Result<u64> {
             // extensions would have a more strict syntax:
             //     (token ["=" (token | quoted-string)])
             // but we gain nothing by rejecting an otherwise valid chunk size.
+            // The extension is still bounded, so a peer can't keep us parsing
+            // a single size line forever.
             ext if in_ext => {
+                ext_len += 1;
+                if ext_len > MAX_CHUNK_EXT_LEN {
+                    return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                              "Chunk extension too long"));
+                }
                 todo!("chunk extension byte={}", ext);
             },
             // Finally, if we aren't in the extension and we're reading any
@@ -690,6 +803,10 @@ fn read_chunk_size<R: Read>(rdr: &mut R) -> io::Result<u64> {
             }
         }
     }
+    if digits == 0 {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                  "Invalid chunk size line"));
+    }
     trace!("chunk size={:?}", size);
     Ok(size)
 }
@@ -757,6 +874,15 @@ impl<W: Write> HttpWriter<W> {
         }
     }
 
+    /// Whether a sized body still has bytes to be written.
+    #[inline]
+    pub fn has_remaining(&self) -> bool {
+        match *self {
+            SizedWriter(_, remaining) => remaining > 0,
+            _ => false
+        }
+    }
+
     /// Ends the HttpWriter, and returns the underlying Writer.
     ///
     /// A final `write_all()` is called with an empty message, and then flushed.
@@ -846,22 +972,160 @@ const MAX_HEADERS: usize = 100;
 /// Parses a request into an Incoming message head.
 #[inline]
 pub fn parse_request<R: Read>(buf: &mut BufReader<R>) -> ::Result<Incoming<(Method, RequestUri)>> {
-    parse::<R, httparse::Request, (Method, RequestUri)>(buf)
+    parse_request_with_limits(buf, &RequestLimits::default())
+}
+
+/// Parses a request into an Incoming message head, rejecting it as soon
+/// as it exceeds one of the `limits`.
+///
+/// A request-target that is too long is always reported as
+/// `Error::UriTooLong`, even if the headers are also too large, since the
+/// request line is checked first. Too many or too large headers are
+/// reported as `Error::TooLarge`. A request-line that isn't tolerated by
+/// `limits.leniency` is reported as `Error::Header`.
+pub fn parse_request_with_limits<R: Read>(buf: &mut BufReader<R>, limits: &RequestLimits)
+        -> ::Result<Incoming<(Method, RequestUri)>> {
+    parse::<R, httparse::Request, (Method, RequestUri), _>(buf, limits.max_headers,
+                                                           Some(&limits.leniency), None,
+                                                           |head| check_request_head(head, limits))
+}
+
+/// Parses a request like `parse_request_with_limits`, also collecting its
+/// header fields into `raw` as name/value pairs, in the order they were
+/// sent and without merging repeated fields.
+pub fn parse_request_with_raw_headers<R: Read>(buf: &mut BufReader<R>, limits: &RequestLimits,
+                                               raw: &mut Vec<(Vec<u8>, Vec<u8>)>)
+        -> ::Result<Incoming<(Method, RequestUri)>> {
+    parse::<R, httparse::Request, (Method, RequestUri), _>(buf, limits.max_headers,
+                                                           Some(&limits.leniency), Some(raw),
+                                                           |head| check_request_head(head, limits))
 }
 
 /// Parses a response into an Incoming message head.
 #[inline]
 pub fn parse_response<R: Read>(buf: &mut BufReader<R>) -> ::Result<Incoming<RawStatus>> {
-    parse::<R, httparse::Response, RawStatus>(buf)
+    parse::<R, httparse::Response, RawStatus, _>(buf, MAX_HEADERS, None, None, |_| Ok(()))
+}
+
+/// Parses a response like `parse_response`, giving the bytes of its head
+/// to `tap` before they are consumed.
+pub fn parse_response_tapped<R: Read, F: FnOnce(&[u8])>(buf: &mut BufReader<R>, tap: F)
+        -> ::Result<Incoming<RawStatus>> {
+    let (head, len) = try!(parse_unconsumed::<R, httparse::Response, RawStatus, _>(
+        buf, MAX_HEADERS, None, None, |_| Ok(())));
+    tap(&buf.get_buf()[..len]);
+    buf.consume(len);
+    Ok(head)
+}
+
+fn check_request_head(buf: &[u8], limits: &RequestLimits) -> ::Result<()> {
+    let buf = &buf[leading_empty_lines(buf).1..];
+    let line_end = buf.iter().position(|&b| b == LF);
+    let line = &buf[..line_end.unwrap_or(buf.len())];
+    if let Some(sp) = line.iter().position(|&b| is_space(b)) {
+        let target = &line[sp + 1..];
+        let target = &target[target.iter().take_while(|&&b| is_space(b)).count()..];
+        let len = target.iter().position(|&b| is_space(b)).unwrap_or(target.len());
+        if len > limits.max_uri_length {
+            return Err(Error::UriTooLong);
+        }
+    }
+
+    if let Some(line_end) = line_end {
+        let fields = &buf[line_end + 1..];
+        let end = b"\r\n\r\n";
+        let len = if fields.starts_with(LINE_ENDING.as_bytes()) {
+            0
+        } else {
+            fields.windows(end.len())
+                  .position(|w| w == end)
+                  .map(|pos| pos + LINE_ENDING.len())
+                  .unwrap_or(fields.len())
+        };
+        if len > limits.max_header_list_size {
+            return Err(Error::TooLarge);
+        }
+    }
+    Ok(())
+}
+
+/// Ends a chunked body with the last chunk and a trailer of `trailers`.
+pub fn write_last_chunk<W: Write>(w: &mut W, trailers: &Headers) -> io::Result<()> {
+    write!(w, "0{}{}{}", LINE_ENDING, trailers, LINE_ENDING)
 }
 
-fn parse<R: Read, T: TryParse<Subject=I>, I>(rdr: &mut BufReader<R>) -> ::Result<Incoming<I>> {
+/// The most bytes accepted for the trailer of a chunked body.
+const MAX_TRAILER_SIZE: u64 = 64 * 1024;
+
+/// Reads the trailer that follows the last chunk of a chunked body.
+///
+/// The reader must be positioned right after the `0` size line. The
+/// trailer is consumed up to and including the empty line ending it.
+pub fn read_trailers<R: BufRead>(rdr: &mut R) -> ::Result<Headers> {
+    let mut rdr = rdr.take(MAX_TRAILER_SIZE);
+    let mut lines = Vec::new();
     loop {
-        match try!(try_parse::<R, T, I>(rdr)) {
-            httparse::Status::Complete((inc, len)) => {
-                rdr.consume(len);
-                return Ok(inc);
-            },
+        let mut line = Vec::new();
+        try!(rdr.read_until(LF, &mut line));
+        if !line.ends_with(LINE_ENDING.as_bytes()) {
+            return Err(if rdr.limit() == 0 {
+                Error::TooLarge
+            } else if line.last() != Some(&LF) {
+                Error::Io(io::Error::new(io::ErrorKind::UnexpectedEof,
+                                         "incomplete trailer section"))
+            } else {
+                Error::Header
+            });
+        }
+        let len = line.len() - LINE_ENDING.len();
+        line.truncate(len);
+        if line.is_empty() {
+            break;
+        }
+        if lines.len() == MAX_HEADERS {
+            return Err(Error::TooLarge);
+        }
+        lines.push(line);
+    }
+
+    let mut raw = Vec::with_capacity(lines.len());
+    for line in &lines {
+        let colon = match line.iter().position(|&b| b == b':') {
+            Some(colon) => colon,
+            None => return Err(Error::Header)
+        };
+        let name = try!(str::from_utf8(&line[..colon]));
+        if name.is_empty() || name.bytes().any(|b| b == b' ' || b == b'\t') {
+            return Err(Error::Header);
+        }
+        let value = &line[colon + 1..];
+        let trim = value.iter().take_while(|&&b| b == b' ' || b == b'\t').count();
+        raw.push(httparse::Header { name: name, value: &value[trim..] });
+    }
+    Headers::from_raw(&raw)
+}
+
+fn parse<R, T, I, F>(rdr: &mut BufReader<R>, max_headers: usize,
+                     leniency: Option<&RequestLeniency>,
+                     raw: Option<&mut Vec<(Vec<u8>, Vec<u8>)>>, check: F)
+        -> ::Result<Incoming<I>>
+where R: Read, T: TryParse<Subject=I>, F: Fn(&[u8]) -> ::Result<()> {
+    let (inc, len) = try!(parse_unconsumed::<R, T, I, F>(rdr, max_headers, leniency, raw, check));
+    rdr.consume(len);
+    Ok(inc)
+}
+
+// Parses a head, leaving its `len` bytes at the front of the buffer.
+fn parse_unconsumed<R, T, I, F>(rdr: &mut BufReader<R>, max_headers: usize,
+                                leniency: Option<&RequestLeniency>,
+                                mut raw: Option<&mut Vec<(Vec<u8>, Vec<u8>)>>, check: F)
+        -> ::Result<(Incoming<I>, usize)>
+where R: Read, T: TryParse<Subject=I>, F: Fn(&[u8]) -> ::Result<()> {
+    loop {
+        try!(check(rdr.get_buf()));
+        match try!(try_parse::<R, T, I>(rdr, max_headers, leniency,
+                                        raw.as_mut().map(|raw| &mut **raw))) {
+            httparse::Status::Complete((inc, len)) => return Ok((inc, len)),
             _partial => ()
         }
         match try!(rdr.read_into_buf()) {
@@ -877,20 +1141,166 @@ fn parse<R: Read, T: TryParse<Subject=I>, I>(rdr: &mut BufReader<R>) -> ::Result
     }
 }
 
-fn try_parse<R: Read, T: TryParse<Subject=I>, I>(rdr: &mut BufReader<R>) -> TryParseResult<I> {
-    let mut headers = [httparse::EMPTY_HEADER; MAX_HEADERS];
+fn try_parse<R: Read, T: TryParse<Subject=I>, I>(rdr: &mut BufReader<R>, max_headers: usize,
+                                                 leniency: Option<&RequestLeniency>,
+                                                 raw: Option<&mut Vec<(Vec<u8>, Vec<u8>)>>)
+        -> TryParseResult<I> {
     let buf = rdr.get_buf();
     if buf.len() == 0 {
         return Ok(httparse::Status::Partial);
     }
+    let normalized = match leniency {
+        Some(leniency) => try!(normalize_request_line(buf, leniency)),
+        None => None
+    };
+    let (buf, removed) = match normalized {
+        Some((ref head, removed)) => (&head[..], removed),
+        None => (buf, 0)
+    };
+    Ok(match try!(try_parse_head::<T, I>(buf, max_headers, raw)) {
+        httparse::Status::Complete((inc, len)) => httparse::Status::Complete((inc, len + removed)),
+        httparse::Status::Partial => httparse::Status::Partial
+    })
+}
+
+fn try_parse_head<T: TryParse<Subject=I>, I>(buf: &[u8], max_headers: usize,
+                                             mut raw: Option<&mut Vec<(Vec<u8>, Vec<u8>)>>)
+        -> TryParseResult<I> {
+    let mut headers = vec![httparse::EMPTY_HEADER; max_headers];
     trace!("try_parse({:?})", buf);
-    <T as TryParse>::try_parse(&mut headers, buf)
+    match <T as TryParse>::try_parse(&mut headers[..], buf, raw.as_mut().map(|raw| &mut **raw)) {
+        Err(Error::Version) => match later_minor_version(buf) {
+            // A later HTTP/1.x is understood as the latest one we know.
+            Some(pos) => {
+                trace!("treating HTTP/1.{} as HTTP/1.1", buf[pos] as char);
+                let mut normalized = buf.to_vec();
+                normalized[pos] = b'1';
+                let mut headers = vec![httparse::EMPTY_HEADER; max_headers];
+                <T as TryParse>::try_parse(&mut headers[..], &normalized, raw)
+            },
+            None => Err(Error::Version)
+        },
+        res => res
+    }
+}
+
+fn is_space(b: u8) -> bool {
+    b == b' ' || b == b'\t'
+}
+
+/// Counts the empty lines at the front of `buf`, and the bytes they take.
+///
+/// These are the lines skipped before a request-line, as far as
+/// `RequestLeniency::max_leading_empty_lines` allows.
+pub fn leading_empty_lines(buf: &[u8]) -> (usize, usize) {
+    let (mut lines, mut pos) = (0, 0);
+    loop {
+        if buf[pos..].starts_with(LINE_ENDING.as_bytes()) {
+            pos += LINE_ENDING.len();
+        } else if buf[pos..].starts_with(&[LF]) {
+            pos += 1;
+        } else {
+            return (lines, pos);
+        }
+        lines += 1;
+    }
+}
+
+const METHODS: [&'static str; 9] = [
+    "OPTIONS", "GET", "POST", "PUT", "DELETE", "HEAD", "TRACE", "CONNECT", "PATCH"
+];
+
+// Rewrites the request-line at the front of `buf` into one httparse
+// accepts, as far as `leniency` allows. Gives the rewritten head, which is
+// copied only up to its empty line, and how many bytes shorter it is, or
+// `None` if the line is fine as it is.
+fn normalize_request_line(buf: &[u8], leniency: &RequestLeniency)
+        -> ::Result<Option<(Vec<u8>, usize)>> {
+    let (lines, start) = leading_empty_lines(buf);
+    if lines > leniency.max_leading_empty_lines {
+        debug!("{} empty lines before request-line", lines);
+        return Err(Error::Header);
+    }
+    if !leniency.extra_whitespace && !leniency.fold_method_case {
+        return Ok(None);
+    }
+
+    let line_end = buf[start..].iter().position(|&b| b == LF).map(|pos| start + pos);
+    let line = &buf[start..line_end.unwrap_or(buf.len())];
+    let mut normalized = Vec::with_capacity(line.len());
+    let mut i = 0;
+    while i < line.len() {
+        let run = line[i..].iter().take_while(|&&b| is_space(b)).count();
+        if run == 0 {
+            normalized.push(line[i]);
+            i += 1;
+            continue;
+        }
+        // Only whitespace between two parts of the line is collapsed, or
+        // at the end of a line still being received.
+        let between = i > 0 && match line.get(i + run) {
+            Some(&b) => b != b'\r',
+            None => line_end.is_none()
+        };
+        if leniency.extra_whitespace && between {
+            normalized.push(b' ');
+        } else {
+            normalized.extend_from_slice(&line[i..i + run]);
+        }
+        i += run;
+    }
+
+    if leniency.fold_method_case {
+        if let Some(sp) = normalized.iter().position(|&b| b == b' ') {
+            let method = normalized[..sp].to_ascii_uppercase();
+            if method != &normalized[..sp] && METHODS.iter().any(|m| m.as_bytes() == &method[..]) {
+                debug!("folding method {:?} to {}", String::from_utf8_lossy(&normalized[..sp]),
+                       String::from_utf8_lossy(&method));
+                normalized[..sp].copy_from_slice(&method);
+            }
+        }
+    }
+
+    if normalized == line {
+        return Ok(None);
+    }
+    let removed = start + line.len() - normalized.len();
+    if let Some(line_end) = line_end {
+        let rest = &buf[line_end..];
+        let head_end = rest.windows(3).position(|w| w == b"\n\r\n").map(|pos| pos + 3);
+        let head_end = rest.windows(2).position(|w| w == b"\n\n").map(|pos| pos + 2)
+            .into_iter().chain(head_end).min().unwrap_or(rest.len());
+        normalized.extend_from_slice(&rest[..head_end]);
+    }
+    Ok(Some((normalized, removed)))
+}
+
+// Finds the minor version digit of an `HTTP/1.x` with `x` above 1, in the
+// start-line of either a request or a response.
+fn later_minor_version(buf: &[u8]) -> Option<usize> {
+    let line = &buf[..buf.iter().position(|&b| b == LF).unwrap_or(buf.len())];
+    let start = if line.starts_with(b"HTTP/") {
+        0
+    } else {
+        match line.iter().rposition(|&b| b == b' ') {
+            Some(sp) => sp + 1,
+            None => return None
+        }
+    };
+    let pos = start + b"HTTP/1.".len();
+    if line[start..].starts_with(b"HTTP/1.") && pos < line.len() &&
+            line[pos] >= b'2' && line[pos] <= b'9' {
+        Some(pos)
+    } else {
+        None
+    }
 }
 
 #[doc(hidden)]
 trait TryParse {
     type Subject;
-    fn try_parse<'a>(headers: &'a mut [httparse::Header<'a>], buf: &'a [u8]) ->
+    fn try_parse<'a>(headers: &'a mut [httparse::Header<'a>], buf: &'a [u8],
+                     raw: Option<&mut Vec<(Vec<u8>, Vec<u8>)>>) ->
         TryParseResult<Self::Subject>;
 }
 
@@ -899,18 +1309,26 @@ type TryParseResult<T> = Result<httparse::Status<(Incoming<T>, usize)>, Error>;
 impl<'a> TryParse for httparse::Request<'a, 'a> {
     type Subject = (Method, RequestUri);
 
-    fn try_parse<'b>(headers: &'b mut [httparse::Header<'b>], buf: &'b [u8]) ->
+    fn try_parse<'b>(headers: &'b mut [httparse::Header<'b>], buf: &'b [u8],
+                     raw: Option<&mut Vec<(Vec<u8>, Vec<u8>)>>) ->
             TryParseResult<(Method, RequestUri)> {
         trace!("Request.try_parse([Header; {}], [u8; {}])", headers.len(), buf.len());
         let mut req = httparse::Request::new(headers);
         Ok(match try!(req.parse(buf)) {
             httparse::Status::Complete(len) => {
                 trace!("Request.try_parse Complete({})", len);
+                if let Some(raw) = raw {
+                    *raw = req.headers.iter()
+                        .map(|h| (h.name.as_bytes().to_vec(), h.value.to_vec()))
+                        .collect();
+                }
+                // httparse fills in every field of a complete message, but
+                // a missing one is an error rather than a panic.
                 httparse::Status::Complete((Incoming {
-                    version: if req.version.unwrap() == 1 { Http11 } else { Http10 },
+                    version: try!(parse_version(req.version)),
                     subject: (
-                        try!(req.method.unwrap().parse()),
-                        try!(req.path.unwrap().parse())
+                        try!(req.method.unwrap_or("").parse()),
+                        try!(req.path.unwrap_or("").parse())
                     ),
                     headers: try!(Headers::from_raw(req.headers))
                 }, len))
@@ -923,20 +1341,30 @@ impl<'a> TryParse for httparse::Request<'a, 'a> {
 impl<'a> TryParse for httparse::Response<'a, 'a> {
     type Subject = RawStatus;
 
-    fn try_parse<'b>(headers: &'b mut [httparse::Header<'b>], buf: &'b [u8]) ->
+    fn try_parse<'b>(headers: &'b mut [httparse::Header<'b>], buf: &'b [u8],
+                     raw: Option<&mut Vec<(Vec<u8>, Vec<u8>)>>) ->
             TryParseResult<RawStatus> {
         trace!("Response.try_parse([Header; {}], [u8; {}])", headers.len(), buf.len());
         let mut res = httparse::Response::new(headers);
         Ok(match try!(res.parse(buf)) {
             httparse::Status::Complete(len) => {
                 trace!("Response.try_parse Complete({})", len);
-                let code = res.code.unwrap();
+                if let Some(raw) = raw {
+                    *raw = res.headers.iter()
+                        .map(|h| (h.name.as_bytes().to_vec(), h.value.to_vec()))
+                        .collect();
+                }
+                let code = match res.code {
+                    Some(code) => code,
+                    None => return Err(Error::Status)
+                };
+                let raw_reason = res.reason.unwrap_or("");
                 let reason = match StatusCode::from_u16(code).canonical_reason() {
-                    Some(reason) if reason == res.reason.unwrap() => Cow::Borrowed(reason),
-                    _ => Cow::Owned(res.reason.unwrap().to_owned())
+                    Some(reason) if reason == raw_reason => Cow::Borrowed(reason),
+                    _ => Cow::Owned(raw_reason.to_owned())
                 };
                 httparse::Status::Complete((Incoming {
-                    version: if res.version.unwrap() == 1 { Http11 } else { Http10 },
+                    version: try!(parse_version(res.version)),
                     subject: RawStatus(code, reason),
                     headers: try!(Headers::from_raw(res.headers))
                 }, len))
@@ -946,6 +1374,14 @@ impl<'a> TryParse for httparse::Response<'a, 'a> {
     }
 }
 
+fn parse_version(version: Option<u8>) -> ::Result<HttpVersion> {
+    match version {
+        Some(1) => Ok(Http11),
+        Some(0) => Ok(Http10),
+        _ => Err(Error::Version)
+    }
+}
+
 /// An Incoming Message head. Includes request/status line, and headers.
 #[derive(Debug)]
 pub struct Incoming<S> {
@@ -974,7 +1410,7 @@ mod tests {
     use mock::MockStream;
     use http::HttpMessage;
 
-    use super::{read_chunk_size, parse_request, parse_response, Http11Message};
+    use super::{read_chunk_size, read_trailers, parse_request, parse_response, Http11Message};
 
     #[test]
     fn test_write_chunked() {
@@ -1039,6 +1475,69 @@ mod tests {
         read_err("1 invalid extension\r\n");
         read_err("1 A\r\n");
         read_err("1;no CRLF");
+        // A size line needs at least one digit
+        read_err("\r\n");
+        read_err(";ext\r\n");
+    }
+
+    #[test]
+    fn test_read_chunk_size_bounds() {
+        fn read_err(s: &[u8]) {
+            assert_eq!(read_chunk_size(&mut &s[..]).unwrap_err().kind(),
+                io::ErrorKind::InvalidInput);
+        }
+
+        assert_eq!(read_chunk_size(&mut &b"FFFFFFFFFFFFFFFF\r\n"[..]).unwrap(), ::std::u64::MAX);
+        // u64 overflow
+        read_err(b"10000000000000000\r\n");
+        read_err(b"FFFFFFFFFFFFFFFFF\r\n");
+        // too many digits, even if they are just zeroes
+        read_err(b"00000000000000001\r\n");
+
+        let mut line = vec![b'1'; 1];
+        line.extend(::std::iter::repeat(b'0').take(1024 * 1024));
+        line.extend(b"\r\n");
+        read_err(&line);
+
+        let mut line = b"1;".to_vec();
+        line.extend(::std::iter::repeat(b'x').take(1024 * 1024));
+        line.extend(b"\r\n");
+        read_err(&line);
+    }
+
+    #[test]
+    fn test_read_many_small_chunks() {
+        let count = 100_000;
+        let mut body = Vec::with_capacity(count * 6 + 5);
+        for _ in 0..count {
+            body.extend(b"1\r\nx\r\n");
+        }
+        body.extend(b"0\r\n\r\n");
+
+        let mut r = super::HttpReader::ChunkedReader(&body[..], None);
+        let mut buf = [0u8; 64];
+        let mut total = 0;
+        loop {
+            match r.read(&mut buf).unwrap() {
+                0 => break,
+                n => total += n,
+            }
+        }
+        assert_eq!(total, count);
+    }
+
+    #[test]
+    fn test_read_empty_chunked() {
+        // the terminal chunk alone is the whole body, and nothing past its
+        // size line is read, so a pipelined request is left untouched
+        let input = b"0\r\n\r\nGET / HTTP/1.1\r\n";
+        let mut r = super::HttpReader::ChunkedReader(&input[..], None);
+        assert!(!r.is_eof());
+        let mut buf = [0u8; 10];
+        assert_eq!(r.read(&mut buf).unwrap(), 0);
+        assert!(r.is_eof());
+        assert_eq!(r.read(&mut buf).unwrap(), 0);
+        assert_eq!(r.into_inner(), &b"\r\nGET / HTTP/1.1\r\n"[..]);
     }
 
     #[test]
@@ -1065,6 +1564,32 @@ mod tests {
         assert_eq!(e.description(), "early eof");
     }
 
+    #[test]
+    fn test_read_trailers() {
+        let mut r = &b"X-Checksum:  abc\r\nGrpc-Status: 0\r\n\r\nnext"[..];
+        let trailers = read_trailers(&mut r).unwrap();
+        assert_eq!(trailers.len(), 2);
+        assert_eq!(trailers.get_raw("x-checksum").unwrap()[0], b"abc".to_vec());
+        assert_eq!(trailers.get_raw("grpc-status").unwrap()[0], b"0".to_vec());
+        assert_eq!(r, b"next");
+
+        let mut r = &b"\r\n"[..];
+        assert_eq!(read_trailers(&mut r).unwrap().len(), 0);
+
+        assert!(read_trailers(&mut &b"X-Checksum abc\r\n\r\n"[..]).is_err());
+        match read_trailers(&mut &b"X-Checksum: abc\r\n"[..]) {
+            Err(::Error::Io(ref e)) if e.kind() == io::ErrorKind::UnexpectedEof => (),
+            other => panic!("expected UnexpectedEof, got {:?}", other),
+        }
+        assert!(read_trailers(&mut &b"X-Checksum: abc\n\n"[..]).is_err());
+
+        let long = vec![b'a'; 128 * 1024];
+        match read_trailers(&mut &long[..]) {
+            Err(::Error::TooLarge) => (),
+            other => panic!("expected TooLarge, got {:?}", other),
+        }
+    }
+
     #[test]
     fn test_message_get_incoming_invalid_content_length() {
         let raw = MockStream::with_input(
@@ -1081,6 +1606,88 @@ mod tests {
         parse_request(&mut buf).unwrap();
     }
 
+    #[test]
+    fn test_parse_random_input_never_panics() {
+        // A fixed xorshift, so failures are reproducible.
+        let mut state = 0x2545f4914f6cdd1du64;
+        let mut next = move || {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state
+        };
+
+        let alphabet = b"GETPOSHT/1.0 \r\n:;,=-_%?#0123456789abcdefABCDEF\t\x00\x7f\xff";
+        let valid = b"GET /echo?q=1 HTTP/1.1\r\nHost: hyper.rs\r\nContent-Length: 3\r\n\r\nabc";
+        let status = b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n0\r\n\r\n";
+
+        for i in 0..5000 {
+            let input: Vec<u8> = match i % 3 {
+                0 => {
+                    let len = (next() % 256) as usize;
+                    (0..len).map(|_| alphabet[(next() % alphabet.len() as u64) as usize]).collect()
+                },
+                1 => {
+                    let mut input = valid.to_vec();
+                    for _ in 0..(next() % 4 + 1) {
+                        let pos = (next() % input.len() as u64) as usize;
+                        input[pos] = next() as u8;
+                    }
+                    input
+                },
+                _ => {
+                    let mut input = status.to_vec();
+                    let pos = (next() % input.len() as u64) as usize;
+                    input.truncate(pos);
+                    input.push(next() as u8);
+                    input
+                }
+            };
+
+            let mut raw = MockStream::with_input(&input);
+            let _ = parse_request(&mut BufReader::new(&mut raw));
+            let mut raw = MockStream::with_input(&input);
+            let _ = parse_response(&mut BufReader::new(&mut raw));
+            let _ = read_trailers(&mut &input[..]);
+            let _ = read_chunk_size(&mut &input[..]);
+        }
+    }
+
+    #[test]
+    fn test_parse_versions() {
+        use error::Error;
+        use version::HttpVersion::{self, Http10, Http11};
+
+        let cases: &[(&str, Option<HttpVersion>)] = &[
+            ("HTTP/1.1", Some(Http11)),
+            ("HTTP/1.0", Some(Http10)),
+            ("HTTP/1.2", Some(Http11)),
+            ("HTTP/1.9", Some(Http11)),
+            ("HTTP/1.x", None),
+            ("HTTP/0.9", None),
+            ("HTTP/2.0", None),
+            ("HTTP/9.9", None),
+        ];
+
+        for &(version, expected) in cases {
+            let req = format!("GET / {}\r\nHost: hyper.rs\r\n\r\n", version);
+            let mut raw = MockStream::with_input(req.as_bytes());
+            match (parse_request(&mut BufReader::new(&mut raw)), expected) {
+                (Ok(head), Some(expected)) => assert_eq!(head.version, expected, "{}", version),
+                (Err(Error::Version), None) => (),
+                (other, _) => panic!("request {}: unexpected result {:?}", version, other)
+            }
+
+            let res = format!("{} 200 OK\r\nContent-Length: 0\r\n\r\n", version);
+            let mut raw = MockStream::with_input(res.as_bytes());
+            match (parse_response(&mut BufReader::new(&mut raw)), expected) {
+                (Ok(head), Some(expected)) => assert_eq!(head.version, expected, "{}", version),
+                (Err(Error::Version), None) => (),
+                (other, _) => panic!("response {}: unexpected result {:?}", version, other)
+            }
+        }
+    }
+
     #[test]
     fn test_parse_raw_status() {
         let mut raw = MockStream::with_input(b"HTTP/1.1 200 OK\r\n\r\n");
@@ -340,6 +340,7 @@ fn parse_response(response: ::solicit::http::Response) -> ::Result<(ResponseHead
         headers: headers,
         raw_status: RawStatus(status, "".into()),
         version: version::HttpVersion::Http20,
+        skipped_bytes: 0,
     }, response.body))
 }
 
@@ -787,7 +788,7 @@ mod tests {
         let (head, body) = parse_response(response).unwrap();
 
         assert_eq!(body, vec![]);
-        let ResponseHead { headers, raw_status, version } = head;
+        let ResponseHead { headers, raw_status, version, .. } = head;
         assert_eq!(raw_status.0, 200);
         assert_eq!(raw_status.1, "");
         assert!(headers.has::<header::ContentLength>());
@@ -811,7 +812,7 @@ mod tests {
         let (head, body) = parse_response(response).unwrap();
 
         assert_eq!(body, expected_body);
-        let ResponseHead { headers, raw_status, version } = head;
+        let ResponseHead { headers, raw_status, version, .. } = head;
         assert_eq!(raw_status.0, 200);
         assert_eq!(raw_status.1, "");
         assert!(headers.has::<header::ContentLength>());
@@ -2,16 +2,18 @@
 //! request-response cycle on any HTTP connection.
 
 use std::any::{Any, TypeId};
-use std::fmt::Debug;
+use std::fmt::{self, Debug};
 use std::io::{Read, Write};
 use std::mem;
+use std::str;
 
 use std::io;
 use std::time::Duration;
 
 use typeable::Typeable;
+use unicase::UniCase;
 
-use header::Headers;
+use header::{Headers, SENSITIVE_HEADERS};
 use http::RawStatus;
 use url::Url;
 
@@ -46,6 +48,113 @@ pub struct ResponseHead {
     pub raw_status: RawStatus,
     /// The HTTP/2 version which generated the response
     pub version: version::HttpVersion,
+    /// How many invalid bytes were skipped before the response.
+    pub skipped_bytes: usize,
+}
+
+/// Which way the bytes seen by a `Tap` went.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Direction {
+    /// Written to the peer.
+    Sent,
+    /// Read from the peer.
+    Received,
+}
+
+/// Sees the bytes of a single message as they cross the wire, like the
+/// output of `curl -v`.
+///
+/// By default, only the heads are seen, with the values of the headers in
+/// `SENSITIVE_HEADERS` replaced by `[redacted:N bytes]`.
+#[derive(Clone, Copy)]
+pub struct Tap {
+    callback: fn(Direction, &[u8]),
+    bodies: bool,
+    redact: bool,
+}
+
+impl Tap {
+    /// Creates a tap passing what it sees to `callback`.
+    pub fn new(callback: fn(Direction, &[u8])) -> Tap {
+        Tap {
+            callback: callback,
+            bodies: false,
+            redact: true,
+        }
+    }
+
+    /// Controls whether the bytes of the bodies are seen too, without
+    /// their chunked framing.
+    ///
+    /// Default is disabled.
+    pub fn bodies(mut self, bodies: bool) -> Tap {
+        self.bodies = bodies;
+        self
+    }
+
+    /// Controls whether the values of sensitive headers are hidden.
+    ///
+    /// Default is enabled.
+    pub fn redact(mut self, redact: bool) -> Tap {
+        self.redact = redact;
+        self
+    }
+
+    /// Passes the bytes of a message head to the callback.
+    pub fn head(&self, direction: Direction, head: &[u8]) {
+        if self.redact {
+            (self.callback)(direction, &redact_head(head));
+        } else {
+            (self.callback)(direction, head);
+        }
+    }
+
+    /// Passes bytes of a message body to the callback, if bodies are seen.
+    pub fn body(&self, direction: Direction, body: &[u8]) {
+        if self.bodies && !body.is_empty() {
+            (self.callback)(direction, body);
+        }
+    }
+}
+
+impl fmt::Debug for Tap {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Tap")
+            .field("bodies", &self.bodies)
+            .field("redact", &self.redact)
+            .finish()
+    }
+}
+
+// Replaces the values of sensitive header fields in a serialized head.
+fn redact_head(head: &[u8]) -> Vec<u8> {
+    let mut redacted = Vec::with_capacity(head.len());
+    for line in head.split(|&b| b == b'\n') {
+        if !redacted.is_empty() {
+            redacted.push(b'\n');
+        }
+        let colon = line.iter().position(|&b| b == b':');
+        let name = colon.and_then(|colon| str::from_utf8(&line[..colon]).ok());
+        let sensitive = name.map_or(false, |name| {
+            SENSITIVE_HEADERS.iter().any(|s| UniCase(*s) == UniCase(name))
+        });
+        match (colon, sensitive) {
+            (Some(colon), true) => {
+                let value = &line[colon + 1..];
+                let cr = value.last() == Some(&b'\r');
+                let value = if cr { &value[..value.len() - 1] } else { value };
+                let trim = value.iter().take_while(|&&b| b == b' ' || b == b'\t').count();
+                redacted.extend_from_slice(&line[..colon]);
+                redacted.extend_from_slice(
+                    format!(": [redacted:{} bytes]", value.len() - trim).as_bytes());
+                if cr {
+                    redacted.push(b'\r');
+                }
+            },
+            _ => redacted.extend_from_slice(line),
+        }
+    }
+    redacted
 }
 
 /// The trait provides an API for sending an receiving HTTP messages.
@@ -70,6 +179,16 @@ pub trait HttpMessage: Write + Read + Send + Any + Typeable + Debug {
     fn close_connection(&mut self) -> ::Result<()>;
     /// Returns whether the incoming message has a body.
     fn has_body(&self) -> bool;
+    /// Allows skipping up to `max_skip_bytes` invalid bytes in front of the
+    /// incoming response, instead of failing on the first one.
+    ///
+    /// Messages that can't resynchronize ignore this.
+    fn set_lenient_parsing(&mut self, _max_skip_bytes: usize) {}
+    /// Shows the bytes of this message, and no other on the same
+    /// connection, to `tap`.
+    ///
+    /// Messages that can't tap their bytes ignore this.
+    fn set_tap(&mut self, _tap: Option<Tap>) {}
 }
 
 impl HttpMessage {
@@ -1,8 +1,9 @@
 //! Pieces pertaining to the HTTP message protocol.
 use std::borrow::Cow;
+use std::{u64, usize};
 
 use header::Connection;
-use header::ConnectionOption::{KeepAlive, Close};
+use header::ConnectionOption::{KeepAlive, Close, ConnectionHeader};
 use header::Headers;
 use version::HttpVersion;
 use version::HttpVersion::{Http10, Http11};
@@ -10,12 +11,114 @@ use version::HttpVersion::{Http10, Http11};
 #[cfg(feature = "serde-serialization")]
 use serde::{Deserialize, Deserializer, Serialize, Serializer};
 
-pub use self::message::{HttpMessage, RequestHead, ResponseHead, Protocol};
+pub use self::message::{HttpMessage, RequestHead, ResponseHead, Protocol, Direction, Tap};
 
 pub mod h1;
 pub mod h2;
 pub mod message;
 
+/// Limits on the size of request heads a `Server` accepts.
+///
+/// A request exceeding them is answered with `414 URI Too Long` if its
+/// request-target is too long, or `431 Request Header Fields Too Large` if
+/// it has too many or too large headers, and the connection is closed. The
+/// request-target is checked first, so a request exceeding several limits
+/// always gets a `414`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RequestLimits {
+    /// The most header fields accepted in a request.
+    ///
+    /// Default is 100.
+    pub max_headers: usize,
+    /// The most bytes accepted for all header fields of a request
+    /// together, including their line endings.
+    ///
+    /// Default is 64KB.
+    pub max_header_list_size: usize,
+    /// The most bytes accepted for the request-target.
+    ///
+    /// Default is 8KB.
+    pub max_uri_length: usize,
+    /// The largest chunk accepted in a chunked request body.
+    ///
+    /// A larger chunk size fails the read of the body with an
+    /// `InvalidInput` error.
+    ///
+    /// Default is 16MB.
+    pub max_chunk_size: u64,
+    /// The most bytes accepted in all chunks of a chunked request body
+    /// together.
+    ///
+    /// A chunk taking the body past it fails the read with an
+    /// `InvalidInput` error, however small the chunks are.
+    ///
+    /// Default is no limit.
+    pub max_chunked_body_size: u64,
+    /// The largest body `Request::body_typed` reads into memory.
+    ///
+    /// A larger body fails it with an `InvalidData` error, without reading
+    /// any of it if the size is known from a `Content-Length`.
+    ///
+    /// Default is 2MB.
+    pub max_body_size: u64,
+    /// How far a request-line may stray from the grammar and still be
+    /// accepted.
+    ///
+    /// Default is `RequestLeniency::default()`, which is strict.
+    pub leniency: RequestLeniency,
+}
+
+/// Tolerance of malformed request-lines, for clients that send them.
+///
+/// A request-line that isn't tolerated is answered with `400 Bad Request`,
+/// and the connection is closed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RequestLeniency {
+    /// The most empty lines skipped before a request-line.
+    ///
+    /// [RFC 7230](https://tools.ietf.org/html/rfc7230#section-3.5) asks
+    /// servers to ignore at least one, as some clients send a CRLF after
+    /// the body of a `POST`.
+    ///
+    /// Default is no limit, besides the limit on the size of the head.
+    pub max_leading_empty_lines: usize,
+    /// Whether several spaces or tabs between the method, request-target
+    /// and version are taken as one space.
+    ///
+    /// Default is false.
+    pub extra_whitespace: bool,
+    /// Whether a standard method sent in the wrong case, such as `get`, is
+    /// taken as that method rather than as an extension method. Each one
+    /// taken is logged at the debug level.
+    ///
+    /// Default is false.
+    pub fold_method_case: bool,
+}
+
+impl Default for RequestLeniency {
+    fn default() -> RequestLeniency {
+        RequestLeniency {
+            max_leading_empty_lines: usize::MAX,
+            extra_whitespace: false,
+            fold_method_case: false,
+        }
+    }
+}
+
+impl Default for RequestLimits {
+    fn default() -> RequestLimits {
+        RequestLimits {
+            max_headers: 100,
+            max_header_list_size: 64 * 1024,
+            max_uri_length: 8 * 1024,
+            max_chunk_size: 16 * 1024 * 1024,
+            max_chunked_body_size: u64::MAX,
+            max_body_size: 2 * 1024 * 1024,
+            leniency: RequestLeniency::default(),
+        }
+    }
+}
+
 /// The raw status code and reason-phrase.
 #[derive(Clone, PartialEq, Debug)]
 pub struct RawStatus(pub u16, pub Cow<'static, str>);
@@ -47,6 +150,37 @@ pub fn should_keep_alive(version: HttpVersion, headers: &Headers) -> bool {
     }
 }
 
+/// Headers that only apply to a single connection, and must not be
+/// forwarded.
+const HOP_BY_HOP: &'static [&'static str] = &[
+    "Connection",
+    "Keep-Alive",
+    "Proxy-Authenticate",
+    "Proxy-Authorization",
+    "TE",
+    "Trailer",
+    "Transfer-Encoding",
+    "Upgrade",
+];
+
+/// Removes the hop-by-hop headers, including any named by the
+/// `Connection` header.
+pub fn strip_hop_by_hop(headers: &mut Headers) {
+    let named: Vec<String> = match headers.get::<Connection>() {
+        Some(&Connection(ref options)) => options.iter().filter_map(|option| match *option {
+            ConnectionHeader(ref name) => Some(name.to_string()),
+            _ => None
+        }).collect(),
+        None => Vec::new()
+    };
+    for name in &named {
+        headers.remove_raw(name);
+    }
+    for name in HOP_BY_HOP {
+        headers.remove_raw(name);
+    }
+}
+
 #[test]
 fn test_should_keep_alive() {
     let mut headers = Headers::new();
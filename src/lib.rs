@@ -135,6 +135,10 @@ extern crate url;
 extern crate openssl;
 #[cfg(feature = "serde-serialization")]
 extern crate serde;
+#[cfg(feature = "serde-serialization")]
+extern crate serde_json;
+#[cfg(feature = "mmap")]
+extern crate memmap;
 extern crate cookie;
 extern crate unicase;
 extern crate httparse;
@@ -142,6 +146,7 @@ extern crate num_cpus;
 extern crate traitobject;
 extern crate typeable;
 extern crate solicit;
+extern crate libc;
 
 #[macro_use]
 extern crate language_tags;
@@ -189,6 +194,7 @@ pub mod method;
 pub mod header;
 pub mod http;
 pub mod net;
+pub mod proxy;
 pub mod server;
 pub mod status;
 pub mod uri;
@@ -12,18 +12,21 @@ use solicit::http::frame::{SettingsFrame, Frame};
 use solicit::http::connection::{HttpConnection, EndStream, DataChunk};
 
 use header::Headers;
-use net::{NetworkStream, NetworkConnector};
+use net::{NetworkStream, NetworkConnector, SslInfo};
 
 #[derive(Clone, Debug)]
 pub struct MockStream {
     pub read: Cursor<Vec<u8>>,
     next_reads: Vec<Vec<u8>>,
     pub write: Vec<u8>,
+    pub write_calls: usize,
     pub is_closed: bool,
+    pub is_reset: bool,
     pub error_on_write: bool,
     pub error_on_read: bool,
     pub read_timeout: Cell<Option<Duration>>,
     pub write_timeout: Cell<Option<Duration>>,
+    pub ssl_info: Option<SslInfo>,
 }
 
 impl PartialEq for MockStream {
@@ -46,11 +49,14 @@ impl MockStream {
             read: Cursor::new(responses.remove(0).to_vec()),
             next_reads: responses.into_iter().map(|arr| arr.to_vec()).collect(),
             write: vec![],
+            write_calls: 0,
             is_closed: false,
+            is_reset: false,
             error_on_write: false,
             error_on_read: false,
             read_timeout: Cell::new(None),
             write_timeout: Cell::new(None),
+            ssl_info: None,
         }
     }
 }
@@ -80,6 +86,7 @@ impl Write for MockStream {
         if self.error_on_write {
             Err(io::Error::new(io::ErrorKind::Other, "mock error"))
         } else {
+            self.write_calls += 1;
             Write::write(&mut self.write, msg)
         }
     }
@@ -94,6 +101,10 @@ impl NetworkStream for MockStream {
         Ok("127.0.0.1:1337".parse().unwrap())
     }
 
+    fn local_addr(&mut self) -> io::Result<SocketAddr> {
+        Ok("127.0.0.1:80".parse().unwrap())
+    }
+
     fn set_read_timeout(&self, dur: Option<Duration>) -> io::Result<()> {
         self.read_timeout.set(dur);
         Ok(())
@@ -108,6 +119,15 @@ impl NetworkStream for MockStream {
         self.is_closed = true;
         Ok(())
     }
+
+    fn reset(&mut self) -> io::Result<()> {
+        self.is_reset = true;
+        NetworkStream::close(self, Shutdown::Both)
+    }
+
+    fn ssl_info(&mut self) -> Option<SslInfo> {
+        self.ssl_info.clone()
+    }
 }
 
 /// A wrapper around a `MockStream` that allows one to clone it and keep an independent copy to the
@@ -148,6 +168,10 @@ impl NetworkStream for CloneableMockStream {
         self.inner.lock().unwrap().peer_addr()
     }
 
+    fn local_addr(&mut self) -> io::Result<SocketAddr> {
+        self.inner.lock().unwrap().local_addr()
+    }
+
     fn set_read_timeout(&self, dur: Option<Duration>) -> io::Result<()> {
         self.inner.lock().unwrap().set_read_timeout(dur)
     }
@@ -159,6 +183,14 @@ impl NetworkStream for CloneableMockStream {
     fn close(&mut self, how: Shutdown) -> io::Result<()> {
         NetworkStream::close(&mut *self.inner.lock().unwrap(), how)
     }
+
+    fn reset(&mut self) -> io::Result<()> {
+        NetworkStream::reset(&mut *self.inner.lock().unwrap())
+    }
+
+    fn ssl_info(&mut self) -> Option<SslInfo> {
+        self.inner.lock().unwrap().ssl_info()
+    }
 }
 
 impl CloneableMockStream {
@@ -30,6 +30,14 @@ pub trait NetworkListener: Clone {
     /// Get the address this Listener ended up listening on.
     fn local_addr(&mut self) -> io::Result<SocketAddr>;
 
+    /// Whether a connection is waiting to be accepted, without accepting it.
+    ///
+    /// The default returns false, for listeners that can't tell.
+    #[inline]
+    fn has_pending(&mut self) -> bool {
+        false
+    }
+
     /// Returns an iterator over incoming connections.
     fn incoming(&mut self) -> NetworkConnections<Self> {
         NetworkConnections(self)
@@ -51,6 +59,14 @@ pub trait NetworkStream: Read + Write + Any + Send + Typeable {
     /// Get the remote address of the underlying connection.
     fn peer_addr(&mut self) -> io::Result<SocketAddr>;
 
+    /// Get the local address of the underlying connection.
+    ///
+    /// The default returns an error, for streams without one.
+    #[inline]
+    fn local_addr(&mut self) -> io::Result<SocketAddr> {
+        Err(io::Error::new(ErrorKind::Other, "local address not available"))
+    }
+
     /// Set the maximum time to wait for a read to complete.
     fn set_read_timeout(&self, dur: Option<Duration>) -> io::Result<()>;
 
@@ -63,6 +79,32 @@ pub trait NetworkStream: Read + Write + Any + Send + Typeable {
         Ok(())
     }
 
+    /// Closes the connection abortively, so the peer sees a reset instead
+    /// of an orderly shutdown, and the socket skips `TIME_WAIT`.
+    ///
+    /// Anything not yet sent may be discarded. The reset goes out once every
+    /// handle to the connection has been dropped. By default, this is the
+    /// same as `close(Shutdown::Both)`.
+    #[inline]
+    fn reset(&mut self) -> io::Result<()> {
+        self.close(Shutdown::Both)
+    }
+
+    /// What was negotiated for the connection, if it is protected by SSL.
+    ///
+    /// The default returns `None`, as for plain `HttpStream`s.
+    #[inline]
+    fn ssl_info(&mut self) -> Option<SslInfo> {
+        None
+    }
+
+    /// Marks the connection as taken over by another protocol, after a
+    /// `101 Switching Protocols`, so it isn't reused for HTTP.
+    ///
+    /// The default does nothing, as for streams that aren't pooled.
+    #[inline]
+    fn set_upgraded(&mut self) {}
+
     // Unsure about name and implementation...
 
     #[doc(hidden)]
@@ -240,6 +282,22 @@ impl NetworkListener for HttpListener {
     fn local_addr(&mut self) -> io::Result<SocketAddr> {
         self.0.local_addr()
     }
+
+    #[cfg(unix)]
+    fn has_pending(&mut self) -> bool {
+        use std::os::unix::io::AsRawFd;
+
+        use libc;
+
+        let mut fd = libc::pollfd {
+            fd: self.0.as_raw_fd(),
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        // a zero timeout only checks, never waits
+        let ready = unsafe { libc::poll(&mut fd, 1, 0) };
+        ready > 0 && fd.revents & libc::POLLIN != 0
+    }
 }
 
 #[cfg(windows)]
@@ -338,6 +396,11 @@ impl NetworkStream for HttpStream {
             self.0.peer_addr()
     }
 
+    #[inline]
+    fn local_addr(&mut self) -> io::Result<SocketAddr> {
+        self.0.local_addr()
+    }
+
     #[inline]
     fn set_read_timeout(&self, dur: Option<Duration>) -> io::Result<()> {
         self.0.set_read_timeout(dur)
@@ -357,6 +420,33 @@ impl NetworkStream for HttpStream {
             err => err
         }
     }
+
+    #[cfg(unix)]
+    fn reset(&mut self) -> io::Result<()> {
+        // no shutdown, or a FIN would go out before the reset
+        set_linger_zero(&self.0)
+    }
+}
+
+// Sets SO_LINGER with a zero timeout, so closing the socket sends a RST.
+#[cfg(unix)]
+fn set_linger_zero(stream: &TcpStream) -> io::Result<()> {
+    use std::mem;
+    use std::os::unix::io::AsRawFd;
+
+    use libc;
+
+    let linger = libc::linger { l_onoff: 1, l_linger: 0 };
+    let ret = unsafe {
+        libc::setsockopt(stream.as_raw_fd(), libc::SOL_SOCKET, libc::SO_LINGER,
+                         &linger as *const libc::linger as *const libc::c_void,
+                         mem::size_of::<libc::linger>() as libc::socklen_t)
+    };
+    if ret == -1 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(())
+    }
 }
 
 /// A connector that will produce HttpStreams.
@@ -418,6 +508,82 @@ pub trait Ssl {
     fn wrap_client(&self, stream: HttpStream, host: &str) -> ::Result<Self::Stream>;
     /// Wrap a server stream with SSL.
     fn wrap_server(&self, stream: HttpStream) -> ::Result<Self::Stream>;
+
+    /// Configure how sessions are resumed and renegotiated.
+    ///
+    /// By default, the options are ignored.
+    fn set_session_options(&mut self, _options: &SslSessionOptions) -> ::Result<()> {
+        Ok(())
+    }
+
+    /// Refuse handshakes using a version of TLS older than `version`.
+    ///
+    /// By default, this returns an error, rather than leave older versions
+    /// allowed.
+    fn set_min_version(&mut self, _version: TlsVersion) -> ::Result<()> {
+        Err(::Error::Ssl(Box::new(io::Error::new(ErrorKind::Other,
+            "minimum TLS version not supported by this Ssl"))))
+    }
+}
+
+/// A version of the TLS protocol, for `Ssl::set_min_version`.
+///
+/// Versions are ordered oldest first. SSLv2 and SSLv3 are always refused
+/// once a minimum is set.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum TlsVersion {
+    /// TLS 1.0.
+    Tls10,
+    /// TLS 1.1.
+    Tls11,
+    /// TLS 1.2.
+    Tls12,
+}
+
+/// Options for resuming and renegotiating SSL sessions.
+///
+/// Resuming a session skips most of the handshake, which matters for
+/// clients that reconnect often.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SslSessionOptions {
+    /// Whether sessions can be resumed with session tickets, which keep
+    /// the session state on the client.
+    ///
+    /// Default is enabled.
+    pub enable_tickets: bool,
+    /// Whether peers that don't support secure renegotiation (RFC 5746)
+    /// may still renegotiate.
+    ///
+    /// Default is disabled.
+    pub allow_insecure_renegotiation: bool,
+}
+
+impl Default for SslSessionOptions {
+    fn default() -> SslSessionOptions {
+        SslSessionOptions {
+            enable_tickets: true,
+            allow_insecure_renegotiation: false,
+        }
+    }
+}
+
+/// What was negotiated for a connection protected by SSL.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct SslInfo {
+    /// The host name the client asked for with SNI.
+    pub server_name: Option<String>,
+    /// The protocol agreed with ALPN, such as `http/1.1`.
+    ///
+    /// This needs the `alpn` feature, and protocols to be offered on the
+    /// `SslContext`.
+    pub protocol: Option<String>,
+    /// The version of the SSL protocol, such as `TLSv1.2`.
+    pub version: Option<String>,
+    /// The name of the cipher suite.
+    pub cipher: Option<String>,
+    /// The certificate the peer presented, PEM encoded. A client only
+    /// sends one if the server asked for it.
+    pub peer_certificate: Option<Vec<u8>>,
 }
 
 /// A stream over the HTTP protocol, possibly protected by SSL.
@@ -466,6 +632,14 @@ impl<S: NetworkStream> NetworkStream for HttpsStream<S> {
         }
     }
 
+    #[inline]
+    fn local_addr(&mut self) -> io::Result<SocketAddr> {
+        match *self {
+            HttpsStream::Http(ref mut s) => s.local_addr(),
+            HttpsStream::Https(ref mut s) => s.local_addr()
+        }
+    }
+
     #[inline]
     fn set_read_timeout(&self, dur: Option<Duration>) -> io::Result<()> {
         match *self {
@@ -489,6 +663,22 @@ impl<S: NetworkStream> NetworkStream for HttpsStream<S> {
             HttpsStream::Https(ref mut s) => s.close(how)
         }
     }
+
+    #[inline]
+    fn reset(&mut self) -> io::Result<()> {
+        match *self {
+            HttpsStream::Http(ref mut s) => s.reset(),
+            HttpsStream::Https(ref mut s) => s.reset()
+        }
+    }
+
+    #[inline]
+    fn ssl_info(&mut self) -> Option<SslInfo> {
+        match *self {
+            HttpsStream::Http(_) => None,
+            HttpsStream::Https(ref mut s) => s.ssl_info()
+        }
+    }
 }
 
 /// A Http Listener over SSL.
@@ -514,20 +704,73 @@ impl<S: Ssl> HttpsListener<S> {
             ssl: ssl
         }
     }
+
+    /// Configure how sessions with clients are resumed and renegotiated.
+    #[inline]
+    pub fn set_session_options(&mut self, options: &SslSessionOptions) -> ::Result<()> {
+        self.ssl.set_session_options(options)
+    }
+
+    /// Refuse clients offering only versions of TLS older than `version`.
+    #[inline]
+    pub fn set_min_version(&mut self, version: TlsVersion) -> ::Result<()> {
+        self.ssl.set_min_version(version)
+    }
 }
 
 impl<S: Ssl + Clone> NetworkListener for HttpsListener<S> {
     type Stream = S::Stream;
 
-    #[inline]
     fn accept(&mut self) -> ::Result<S::Stream> {
-        self.listener.accept().and_then(|s| self.ssl.wrap_server(s))
+        let mut stream = try!(self.listener.accept());
+        if is_plaintext_http(&stream) {
+            reject_plaintext_http(&mut stream);
+            return Err(io::Error::new(ErrorKind::InvalidData,
+                                      "plain HTTP request sent to an HTTPS listener").into());
+        }
+        self.ssl.wrap_server(stream)
     }
 
     #[inline]
     fn local_addr(&mut self) -> io::Result<SocketAddr> {
         self.listener.local_addr()
     }
+
+    #[inline]
+    fn has_pending(&mut self) -> bool {
+        self.listener.has_pending()
+    }
+}
+
+/// Request line prefixes that mark a plain HTTP request where a TLS
+/// ClientHello was expected.
+const PLAINTEXT_METHODS: &'static [&'static [u8]] = &[
+    b"GET ", b"HEAD ", b"POST ", b"PUT ", b"DELETE ",
+    b"OPTIONS ", b"PATCH ", b"CONNECT ", b"TRACE ",
+];
+
+/// Whether the first bytes waiting on the stream are a plain HTTP request
+/// line. They are only peeked, so a handshake can still read them.
+fn is_plaintext_http(stream: &HttpStream) -> bool {
+    let mut buf = [0; 8];
+    match stream.0.peek(&mut buf) {
+        Ok(n) => PLAINTEXT_METHODS.iter().any(|method| buf[..n].starts_with(method)),
+        Err(_) => false
+    }
+}
+
+/// Tells a client that spoke plain HTTP to use HTTPS, and closes.
+fn reject_plaintext_http(stream: &mut HttpStream) {
+    const BODY: &'static str = "This port expects HTTPS; send the request over TLS.\n";
+    let res = format!("HTTP/1.0 400 Bad Request\r\n\
+                       Connection: close\r\n\
+                       Content-Type: text/plain\r\n\
+                       Content-Length: {}\r\n\
+                       \r\n\
+                       {}", BODY.len(), BODY);
+    debug!("plain HTTP request on an HTTPS listener");
+    let _ = stream.write_all(res.as_bytes()).and_then(|_| stream.flush());
+    let _ = stream.close(Shutdown::Both);
 }
 
 /// A connector that can protect HTTP streams using SSL.
@@ -541,6 +784,18 @@ impl<S: Ssl> HttpsConnector<S> {
     pub fn new(s: S) -> HttpsConnector<S> {
         HttpsConnector { ssl: s }
     }
+
+    /// Configure how sessions with servers are resumed and renegotiated.
+    #[inline]
+    pub fn set_session_options(&mut self, options: &SslSessionOptions) -> ::Result<()> {
+        self.ssl.set_session_options(options)
+    }
+
+    /// Refuse servers offering only versions of TLS older than `version`.
+    #[inline]
+    pub fn set_min_version(&mut self, version: TlsVersion) -> ::Result<()> {
+        self.ssl.set_min_version(version)
+    }
 }
 
 impl<S: Ssl> NetworkConnector for HttpsConnector<S> {
@@ -576,10 +831,12 @@ mod openssl {
     use std::time::Duration;
 
     use openssl::ssl::{Ssl, SslContext, SslStream, SslMethod, SSL_VERIFY_NONE};
+    use openssl::ssl::{SSL_OP_NO_TICKET, SSL_OP_ALLOW_UNSAFE_LEGACY_RENEGOTIATION};
+    use openssl::ssl::{SSL_OP_NO_SSLV2, SSL_OP_NO_SSLV3, SSL_OP_NO_TLSV1, SSL_OP_NO_TLSV1_1};
     use openssl::ssl::error::StreamError as SslIoError;
     use openssl::ssl::error::SslError;
     use openssl::x509::X509FileType;
-    use super::{NetworkStream, HttpStream};
+    use super::{NetworkStream, HttpStream, SslInfo, SslSessionOptions, TlsVersion};
 
     /// An implementation of `Ssl` for OpenSSL.
     ///
@@ -644,6 +901,44 @@ mod openssl {
                 Err(e) => Err(e.into())
             }
         }
+
+        /// Sets the options on the `SslContext`, which must not be shared
+        /// with another `Openssl` yet.
+        fn set_session_options(&mut self, options: &SslSessionOptions) -> ::Result<()> {
+            let ctx = try!(unshared(&mut self.context));
+            if options.enable_tickets {
+                ctx.clear_options(SSL_OP_NO_TICKET);
+            } else {
+                ctx.set_options(SSL_OP_NO_TICKET);
+            }
+            if options.allow_insecure_renegotiation {
+                ctx.set_options(SSL_OP_ALLOW_UNSAFE_LEGACY_RENEGOTIATION);
+            } else {
+                ctx.clear_options(SSL_OP_ALLOW_UNSAFE_LEGACY_RENEGOTIATION);
+            }
+            Ok(())
+        }
+
+        /// Sets the `SSL_OP_NO_*` options on the `SslContext`, which must
+        /// not be shared with another `Openssl` yet.
+        fn set_min_version(&mut self, version: TlsVersion) -> ::Result<()> {
+            let ctx = try!(unshared(&mut self.context));
+            ctx.set_options(SSL_OP_NO_SSLV2 | SSL_OP_NO_SSLV3);
+            for &(oldest, option) in &[(TlsVersion::Tls11, SSL_OP_NO_TLSV1),
+                                       (TlsVersion::Tls12, SSL_OP_NO_TLSV1_1)] {
+                if version >= oldest {
+                    ctx.set_options(option);
+                } else {
+                    ctx.clear_options(option);
+                }
+            }
+            Ok(())
+        }
+    }
+
+    fn unshared(context: &mut Arc<SslContext>) -> ::Result<&mut SslContext> {
+        Arc::get_mut(context).ok_or_else(|| ::Error::Ssl(Box::new(io::Error::new(
+            io::ErrorKind::Other, "SslContext is shared, its options can't be changed"))))
     }
 
     impl<S: NetworkStream> NetworkStream for SslStream<S> {
@@ -652,6 +947,11 @@ mod openssl {
             self.get_mut().peer_addr()
         }
 
+        #[inline]
+        fn local_addr(&mut self) -> io::Result<SocketAddr> {
+            self.get_mut().local_addr()
+        }
+
         #[inline]
         fn set_read_timeout(&self, dur: Option<Duration>) -> io::Result<()> {
             self.get_ref().set_read_timeout(dur)
@@ -665,13 +965,238 @@ mod openssl {
         fn close(&mut self, how: Shutdown) -> io::Result<()> {
             self.get_mut().close(how)
         }
+
+        fn reset(&mut self) -> io::Result<()> {
+            self.get_mut().reset()
+        }
+
+        fn ssl_info(&mut self) -> Option<SslInfo> {
+            let ssl = self.ssl();
+            let mut peer_certificate = None;
+            if let Some(cert) = ssl.peer_certificate() {
+                let mut pem = Vec::new();
+                match cert.write_pem(&mut pem) {
+                    Ok(()) => peer_certificate = Some(pem),
+                    Err(e) => debug!("error encoding peer certificate: {:?}", e)
+                }
+            }
+            Some(SslInfo {
+                server_name: ssl.get_servername(),
+                protocol: selected_protocol(ssl),
+                version: Some(ssl.version().to_owned()),
+                cipher: ssl.get_current_cipher().map(|cipher| cipher.name().to_owned()),
+                peer_certificate: peer_certificate,
+            })
+        }
+    }
+
+    #[cfg(feature = "alpn")]
+    fn selected_protocol(ssl: &Ssl) -> Option<String> {
+        ssl.selected_alpn_protocol().map(|proto| String::from_utf8_lossy(proto).into_owned())
+    }
+
+    #[cfg(not(feature = "alpn"))]
+    fn selected_protocol(_ssl: &Ssl) -> Option<String> {
+        None
     }
 }
 
 #[cfg(test)]
 mod tests {
     use mock::MockStream;
-    use super::{NetworkStream};
+    use super::{NetworkStream, HttpStream, HttpsConnector, Ssl, SslSessionOptions, TlsVersion};
+
+    #[derive(Clone, Default)]
+    struct SessionSsl(Option<SslSessionOptions>);
+
+    impl Ssl for SessionSsl {
+        type Stream = MockStream;
+
+        fn wrap_client(&self, _: HttpStream, _: &str) -> ::Result<MockStream> {
+            Ok(MockStream::new())
+        }
+
+        fn wrap_server(&self, _: HttpStream) -> ::Result<MockStream> {
+            Ok(MockStream::new())
+        }
+
+        fn set_session_options(&mut self, options: &SslSessionOptions) -> ::Result<()> {
+            self.0 = Some(*options);
+            Ok(())
+        }
+    }
+
+    // Reads the client_version of a ClientHello, and refuses it if older
+    // than the minimum.
+    #[derive(Clone, Default)]
+    struct VersionSsl(Option<TlsVersion>);
+
+    impl Ssl for VersionSsl {
+        type Stream = MockStream;
+
+        fn wrap_client(&self, _: HttpStream, _: &str) -> ::Result<MockStream> {
+            Ok(MockStream::new())
+        }
+
+        fn wrap_server(&self, mut stream: HttpStream) -> ::Result<MockStream> {
+            use std::io::{self, Read};
+
+            // record header, then handshake type and length
+            let mut hello = [0; 11];
+            try!(stream.read_exact(&mut hello));
+            let version = match (hello[9], hello[10]) {
+                (3, 1) => Some(TlsVersion::Tls10),
+                (3, 2) => Some(TlsVersion::Tls11),
+                (3, 3) => Some(TlsVersion::Tls12),
+                _ => None,
+            };
+            match self.0 {
+                Some(min) if version.map_or(true, |version| version < min) => {
+                    Err(::Error::Ssl(Box::new(io::Error::new(io::ErrorKind::Other,
+                                                             "protocol version"))))
+                }
+                _ => Ok(MockStream::new())
+            }
+        }
+
+        fn set_min_version(&mut self, version: TlsVersion) -> ::Result<()> {
+            self.0 = Some(version);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_https_listener_min_version() {
+        use std::io::{Read, Write};
+        use std::net::TcpStream;
+        use std::thread;
+        use super::{HttpsListener, NetworkListener};
+
+        fn client_hello(addr: ::std::net::SocketAddr, minor: u8) -> thread::JoinHandle<usize> {
+            thread::spawn(move || {
+                let mut tcp = TcpStream::connect(addr).unwrap();
+                tcp.write_all(&[0x16, 0x03, 0x01, 0x00, 0x2f, 0x01, 0x00, 0x00, 0x2b,
+                                0x03, minor]).unwrap();
+                let mut rest = Vec::new();
+                tcp.read_to_end(&mut rest).unwrap();
+                rest.len()
+            })
+        }
+
+        assert!(SessionSsl::default().set_min_version(TlsVersion::Tls12).is_err());
+
+        let mut listener = HttpsListener::new("127.0.0.1:0", VersionSsl::default()).unwrap();
+        listener.set_min_version(TlsVersion::Tls12).unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let tls10 = client_hello(addr, 1);
+        assert!(listener.accept().is_err());
+        assert_eq!(tls10.join().unwrap(), 0);
+
+        let tls12 = client_hello(addr, 3);
+        assert!(listener.accept().is_ok());
+        tls12.join().unwrap();
+    }
+
+    #[test]
+    fn test_connector_session_options() {
+        let options = SslSessionOptions { enable_tickets: false, ..Default::default() };
+        let mut connector = HttpsConnector::new(SessionSsl::default());
+        connector.set_session_options(&options).unwrap();
+        assert_eq!(connector.ssl.0, Some(options));
+    }
+
+    #[cfg(feature = "openssl")]
+    #[test]
+    fn test_openssl_session_options() {
+        use std::sync::Arc;
+        use openssl::ssl::{SSL_OP_NO_TICKET, SSL_OP_ALLOW_UNSAFE_LEGACY_RENEGOTIATION};
+        use super::Openssl;
+
+        let mut ssl = Openssl::default();
+        let options = SslSessionOptions { enable_tickets: false, ..Default::default() };
+        ssl.set_session_options(&options).unwrap();
+        {
+            let ctx = Arc::get_mut(&mut ssl.context).unwrap();
+            assert!(ctx.get_options().contains(SSL_OP_NO_TICKET));
+            assert!(!ctx.get_options().contains(SSL_OP_ALLOW_UNSAFE_LEGACY_RENEGOTIATION));
+        }
+
+        ssl.set_session_options(&Default::default()).unwrap();
+        assert!(!Arc::get_mut(&mut ssl.context).unwrap().get_options().contains(SSL_OP_NO_TICKET));
+
+        let shared = ssl.clone();
+        assert!(ssl.set_session_options(&options).is_err());
+        drop(shared);
+    }
+
+    #[cfg(feature = "openssl")]
+    #[test]
+    fn test_openssl_min_version() {
+        use std::sync::Arc;
+        use openssl::ssl::{SSL_OP_NO_SSLV3, SSL_OP_NO_TLSV1, SSL_OP_NO_TLSV1_1, SSL_OP_NO_TLSV1_2};
+        use super::Openssl;
+
+        let mut ssl = Openssl::default();
+        ssl.set_min_version(TlsVersion::Tls12).unwrap();
+        {
+            let options = Arc::get_mut(&mut ssl.context).unwrap().get_options();
+            assert!(options.contains(SSL_OP_NO_SSLV3 | SSL_OP_NO_TLSV1 | SSL_OP_NO_TLSV1_1));
+            assert!(!options.contains(SSL_OP_NO_TLSV1_2));
+        }
+
+        ssl.set_min_version(TlsVersion::Tls11).unwrap();
+        let options = Arc::get_mut(&mut ssl.context).unwrap().get_options();
+        assert!(options.contains(SSL_OP_NO_TLSV1));
+        assert!(!options.contains(SSL_OP_NO_TLSV1_1));
+    }
+
+    #[test]
+    fn test_https_listener_rejects_plain_http() {
+        use std::io::{Read, Write};
+        use std::net::TcpStream;
+        use std::thread;
+        use super::{HttpsListener, NetworkListener};
+
+        let mut listener = HttpsListener::new("127.0.0.1:0", SessionSsl::default()).unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let client = thread::spawn(move || {
+            let mut tcp = TcpStream::connect(addr).unwrap();
+            tcp.write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+            let mut res = String::new();
+            tcp.read_to_string(&mut res).unwrap();
+            res
+        });
+        assert!(listener.accept().is_err());
+        let res = client.join().unwrap();
+        assert!(res.starts_with("HTTP/1.0 400 Bad Request\r\n"));
+        assert!(res.contains("HTTPS"));
+
+        // a ClientHello is left for the handshake
+        let client = thread::spawn(move || {
+            let mut tcp = TcpStream::connect(addr).unwrap();
+            tcp.write_all(&[0x16, 0x03, 0x01, 0x00, 0x05]).unwrap();
+        });
+        assert!(listener.accept().is_ok());
+        client.join().unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_http_stream_reset() {
+        use std::io::{ErrorKind, Read};
+        use std::net::{TcpListener, TcpStream};
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let mut stream = HttpStream(listener.accept().unwrap().0);
+        stream.reset().unwrap();
+        drop(stream);
+
+        let err = client.read(&mut [0; 16]).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::ConnectionReset);
+    }
 
     #[test]
     fn test_downcast_box_stream() {
@@ -0,0 +1,151 @@
+//! Helpers for forwarding messages between a `Server` and a `Client`.
+//!
+//! A reverse proxy mostly consists of turning an incoming server `Request`
+//! into an outgoing client request, with `RequestBuilder::from_incoming`,
+//! and copying the status and headers of the client `Response` back, with
+//! `Response::copy_from`. Both drop the hop-by-hop headers, which only
+//! apply to a single connection, and leave the framing of the body to the
+//! new connection.
+use url::{Url, ParseError as UrlError};
+
+use header::Headers;
+use uri::RequestUri;
+
+pub use http::strip_hop_by_hop;
+
+/// How a forwarded request is rewritten.
+#[derive(Clone, Debug)]
+pub struct ProxyRewrite {
+    base: Url,
+    preserve_host: bool,
+}
+
+impl ProxyRewrite {
+    /// Forwards requests to `base`, with the incoming path and query
+    /// appended to its path.
+    pub fn new(base: Url) -> ProxyRewrite {
+        ProxyRewrite {
+            base: base,
+            preserve_host: false,
+        }
+    }
+
+    /// Keeps the incoming `Host` header, instead of using the host of the
+    /// base URL.
+    pub fn preserve_host(mut self, preserve: bool) -> ProxyRewrite {
+        self.preserve_host = preserve;
+        self
+    }
+
+    /// The URL an incoming request-target is forwarded to.
+    ///
+    /// Only origin-form and absolute-form targets can be forwarded.
+    pub fn target(&self, uri: &RequestUri) -> Result<Url, UrlError> {
+        let path = match *uri {
+            RequestUri::AbsolutePath(ref path) => path.clone(),
+            RequestUri::AbsoluteUri(ref url) => {
+                let mut path = url.serialize_path().unwrap_or_else(|| "/".to_owned());
+                if let Some(ref query) = url.query {
+                    path.push('?');
+                    path.push_str(query);
+                }
+                path
+            },
+            RequestUri::Authority(..) | RequestUri::Star => {
+                return Err(UrlError::ExpectedInitialSlash);
+            }
+        };
+
+        let mut target = self.base.serialize();
+        if let Some(i) = target.find(|c| c == '?' || c == '#') {
+            target.truncate(i);
+        }
+        if target.ends_with('/') {
+            target.pop();
+        }
+        target.push_str(&path);
+        Url::parse(&target)
+    }
+
+    /// Copies incoming request headers for forwarding.
+    ///
+    /// The fields are copied with `Headers::from_pairs`, so a value that
+    /// couldn't be sent safely, such as one set by a handler with a CR or
+    /// LF in it, is an `Error::Header` rather than forwarded.
+    pub fn headers(&self, incoming: &Headers) -> ::Result<Headers> {
+        let pairs = incoming.iter().flat_map(|field| {
+            let name = field.name();
+            incoming.get_raw(name).unwrap_or(&[]).iter()
+                .map(move |value| (name.to_owned(), value.clone()))
+        });
+        let mut headers = try!(Headers::from_pairs(pairs));
+        strip_hop_by_hop(&mut headers);
+        if !self.preserve_host {
+            headers.remove_raw("Host");
+        }
+        Ok(headers)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use header::{Headers, Connection, ConnectionOption, ContentLength, Host, TransferEncoding,
+                 Encoding};
+    use uri::RequestUri;
+    use url::Url;
+
+    use super::{strip_hop_by_hop, ProxyRewrite};
+
+    #[test]
+    fn test_strip_hop_by_hop() {
+        let mut headers = Headers::new();
+        headers.set(Connection(vec![
+            ConnectionOption::KeepAlive,
+            ConnectionOption::ConnectionHeader("X-Hop".parse().unwrap()),
+        ]));
+        headers.set(TransferEncoding(vec![Encoding::Chunked]));
+        headers.set_raw("Keep-Alive", vec![b"timeout=5".to_vec()]);
+        headers.set_raw("x-hop", vec![b"1".to_vec()]);
+        headers.set_raw("TE", vec![b"trailers".to_vec()]);
+        headers.set_raw("X-End", vec![b"2".to_vec()]);
+        headers.set(ContentLength(10));
+
+        strip_hop_by_hop(&mut headers);
+        assert_eq!(headers.len(), 2);
+        assert_eq!(headers.get(), Some(&ContentLength(10)));
+        assert!(headers.get_raw("X-End").is_some());
+    }
+
+    #[test]
+    fn test_target() {
+        let rewrite = ProxyRewrite::new(Url::parse("http://backend:8080/api/").unwrap());
+        let target = rewrite.target(&"/users?id=1".parse().unwrap()).unwrap();
+        assert_eq!(target.serialize(), "http://backend:8080/api/users?id=1");
+
+        let target = rewrite.target(&"http://front/users".parse().unwrap()).unwrap();
+        assert_eq!(target.serialize(), "http://backend:8080/api/users");
+
+        assert!(rewrite.target(&RequestUri::Star).is_err());
+        assert!(rewrite.target(&RequestUri::Authority("front:443".to_owned())).is_err());
+    }
+
+    #[test]
+    fn test_headers_host() {
+        let mut incoming = Headers::new();
+        incoming.set(Host { hostname: "front".to_owned(), port: None });
+        incoming.set(ContentLength(3));
+
+        let base = Url::parse("http://backend").unwrap();
+        let headers = ProxyRewrite::new(base.clone()).headers(&incoming).unwrap();
+        assert!(!headers.has::<Host>());
+        assert_eq!(headers.get(), Some(&ContentLength(3)));
+
+        let rewrite = ProxyRewrite::new(base.clone()).preserve_host(true);
+        let headers = rewrite.headers(&incoming).unwrap();
+        assert_eq!(headers.get::<Host>().unwrap().hostname, "front");
+
+        // a value that would inject fields isn't forwarded
+        incoming.set_raw("X-Evil", vec![b"1\r\nSet-Cookie: a=b".to_vec()]);
+        assert!(ProxyRewrite::new(base).headers(&incoming).is_err());
+    }
+}
@@ -0,0 +1,321 @@
+//! Caching presets for server responses.
+//!
+//! Setting caching headers by hand is easy to get subtly wrong: a `Vary`
+//! gets forgotten, or `no-store` ends up next to a `max-age`. The presets
+//! in this module are validated when they are built, and can be applied to
+//! a `Response` in one call with `Response::apply`.
+//!
+//! ```
+//! use std::time::Duration;
+//! use hyper::server::{cache, Request, Response};
+//!
+//! fn handler(_: Request, mut res: Response) {
+//!     let preset = cache::public_immutable(Duration::from_secs(31536000))
+//!         .unwrap()
+//!         .vary("Accept-Encoding");
+//!     res.apply(preset);
+//!     res.send(b"body").unwrap();
+//! }
+//! ```
+use std::time::Duration;
+use std::u32;
+
+use time::{self, Timespec, Tm};
+use unicase::UniCase;
+
+use header::{Headers, CacheControl, CacheDirective, Expires, HttpDate, Pragma, Vary};
+use version::HttpVersion;
+use Error;
+
+/// A validated set of caching headers.
+///
+/// A `Preset` is created with one of the functions in this module, and
+/// can be extended with further directives and `Vary` fields before being
+/// applied to a `Response`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Preset {
+    directives: Vec<CacheDirective>,
+    vary: Vec<UniCase<String>>,
+}
+
+/// Forbids any cache from storing the response.
+///
+/// HTTP/1.0 peers additionally receive `Pragma: no-cache` and an `Expires`
+/// date in the past.
+pub fn no_store() -> Preset {
+    Preset {
+        directives: vec![CacheDirective::NoStore],
+        vary: Vec::new(),
+    }
+}
+
+/// Allows only the client's private cache to store the response, for the
+/// given duration.
+pub fn private_max_age(age: Duration) -> ::Result<Preset> {
+    let secs = try!(seconds(age));
+    Ok(Preset {
+        directives: vec![CacheDirective::Private, CacheDirective::MaxAge(secs)],
+        vary: Vec::new(),
+    })
+}
+
+/// Allows any cache to store the response for the given duration, and
+/// marks it as never changing during that time.
+///
+/// A zero duration is rejected, since an immutable response that is
+/// immediately stale is contradictory.
+pub fn public_immutable(age: Duration) -> ::Result<Preset> {
+    let secs = try!(seconds(age));
+    if secs == 0 {
+        return Err(Error::Header);
+    }
+    Ok(Preset {
+        directives: vec![
+            CacheDirective::Public,
+            CacheDirective::MaxAge(secs),
+            CacheDirective::Extension("immutable".to_owned(), None),
+        ],
+        vary: Vec::new(),
+    })
+}
+
+impl Preset {
+    /// Adds a directive to this preset.
+    ///
+    /// Returns an error if the directive contradicts the ones already
+    /// present, such as `max-age` after `no-store`, or `public` after
+    /// `private`.
+    pub fn directive(mut self, directive: CacheDirective) -> ::Result<Preset> {
+        self.directives.push(directive);
+        try!(validate(&self.directives));
+        Ok(self)
+    }
+
+    /// Adds a request header name that the response varies on.
+    pub fn vary(mut self, field: &str) -> Preset {
+        let field = UniCase(field.to_owned());
+        if !self.vary.contains(&field) {
+            self.vary.push(field);
+        }
+        self
+    }
+
+    /// The `Cache-Control` directives of this preset.
+    pub fn directives(&self) -> &[CacheDirective] {
+        &self.directives
+    }
+
+    /// Sets the headers of this preset, as they should be sent to a peer
+    /// speaking the given version.
+    ///
+    /// `Vary` fields are added to any already present. For HTTP/1.0 peers,
+    /// which may not understand `Cache-Control`, an `Expires` header is
+    /// added as well, counted from the time of `clock`.
+    pub fn apply_to(&self, headers: &mut Headers, version: HttpVersion, clock: fn() -> Tm) {
+        headers.set(CacheControl(self.directives.clone()));
+
+        if !self.vary.is_empty() {
+            let mut vary = match headers.get::<Vary>() {
+                Some(&Vary::Any) => None,
+                Some(&Vary::Items(ref items)) => Some(items.clone()),
+                None => Some(Vec::new()),
+            };
+            if let Some(ref mut items) = vary {
+                for field in &self.vary {
+                    if !items.contains(field) {
+                        items.push(field.clone());
+                    }
+                }
+            }
+            if let Some(items) = vary {
+                headers.set(Vary::Items(items));
+            }
+        }
+
+        if version == HttpVersion::Http10 {
+            if self.directives.contains(&CacheDirective::NoStore) {
+                headers.set(Pragma::NoCache);
+                headers.set(Expires(HttpDate(time::at_utc(Timespec::new(0, 0)))));
+            } else if let Some(secs) = self.max_age() {
+                let expires = clock() + time::Duration::seconds(secs as i64);
+                headers.set(Expires(HttpDate(expires)));
+            }
+        }
+    }
+
+    fn max_age(&self) -> Option<u32> {
+        self.directives.iter().filter_map(|d| match *d {
+            CacheDirective::MaxAge(secs) => Some(secs),
+            _ => None
+        }).next()
+    }
+}
+
+fn seconds(age: Duration) -> ::Result<u32> {
+    if age.as_secs() > u32::MAX as u64 {
+        Err(Error::Header)
+    } else {
+        Ok(age.as_secs() as u32)
+    }
+}
+
+fn validate(directives: &[CacheDirective]) -> ::Result<()> {
+    let has = |want: &CacheDirective| directives.contains(want);
+    let mut max_ages = 0;
+    let mut s_max_ages = 0;
+    let mut immutable = false;
+    for directive in directives {
+        match *directive {
+            CacheDirective::MaxAge(..) => max_ages += 1,
+            CacheDirective::SMaxAge(..) => s_max_ages += 1,
+            CacheDirective::Extension(ref name, _) if name == "immutable" => immutable = true,
+            _ => ()
+        }
+    }
+
+    let expires = max_ages + s_max_ages > 0;
+    if has(&CacheDirective::NoStore) && (expires || immutable || has(&CacheDirective::Public)) {
+        return Err(Error::Header);
+    }
+    if has(&CacheDirective::NoCache) && (expires || immutable) {
+        return Err(Error::Header);
+    }
+    if has(&CacheDirective::Public) && has(&CacheDirective::Private) {
+        return Err(Error::Header);
+    }
+    if max_ages > 1 || s_max_ages > 1 {
+        return Err(Error::Header);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use time::{self, now_utc, Timespec, Tm};
+
+    use header::{Headers, CacheControl, CacheDirective, Expires, Pragma, Vary};
+    use mock::MockStream;
+    use server::Response;
+    use version::HttpVersion::{Http10, Http11};
+
+    use super::{no_store, private_max_age, public_immutable};
+
+    fn clock() -> Tm {
+        time::at_utc(Timespec::new(784111777, 0))
+    }
+
+    #[test]
+    fn test_no_store() {
+        let mut headers = Headers::new();
+        no_store().apply_to(&mut headers, Http11, now_utc);
+        assert_eq!(headers.len(), 1);
+        assert_eq!(headers.get(), Some(&CacheControl(vec![CacheDirective::NoStore])));
+    }
+
+    #[test]
+    fn test_no_store_http10() {
+        let mut headers = Headers::new();
+        no_store().apply_to(&mut headers, Http10, now_utc);
+        assert_eq!(headers.len(), 3);
+        assert_eq!(headers.get(), Some(&CacheControl(vec![CacheDirective::NoStore])));
+        assert_eq!(headers.get(), Some(&Pragma::NoCache));
+        assert_eq!(headers.get_raw("Expires").unwrap()[0],
+                   b"Thu, 01 Jan 1970 00:00:00 GMT".to_vec());
+    }
+
+    #[test]
+    fn test_private_max_age() {
+        let mut headers = Headers::new();
+        private_max_age(Duration::from_secs(60)).unwrap().apply_to(&mut headers, Http11, now_utc);
+        assert_eq!(headers.len(), 1);
+        assert_eq!(headers.get(), Some(&CacheControl(vec![
+            CacheDirective::Private,
+            CacheDirective::MaxAge(60),
+        ])));
+    }
+
+    #[test]
+    fn test_public_immutable() {
+        let mut headers = Headers::new();
+        public_immutable(Duration::from_secs(3600)).unwrap()
+            .vary("Accept-Encoding")
+            .apply_to(&mut headers, Http11, now_utc);
+        assert_eq!(headers.len(), 2);
+        assert_eq!(headers.get_raw("Cache-Control").unwrap()[0],
+                   b"public, max-age=3600, immutable".to_vec());
+        assert_eq!(headers.get(), Some(&Vary::Items(vec!["Accept-Encoding".parse().unwrap()])));
+    }
+
+    #[test]
+    fn test_max_age_http10_expires() {
+        let mut headers = Headers::new();
+        private_max_age(Duration::from_secs(60)).unwrap().apply_to(&mut headers, Http10, clock);
+        assert_eq!(headers.len(), 2);
+        assert_eq!(headers.get_raw("Expires").unwrap()[0],
+                   b"Sun, 06 Nov 1994 08:50:37 GMT".to_vec());
+    }
+
+    #[test]
+    fn test_vary_merges() {
+        let mut headers = Headers::new();
+        headers.set(Vary::Items(vec!["Cookie".parse().unwrap()]));
+        no_store().vary("cookie").vary("Accept").apply_to(&mut headers, Http11, now_utc);
+        assert_eq!(headers.get(), Some(&Vary::Items(vec![
+            "Cookie".parse().unwrap(),
+            "Accept".parse().unwrap(),
+        ])));
+
+        headers.set(Vary::Any);
+        no_store().vary("Accept").apply_to(&mut headers, Http11, now_utc);
+        assert_eq!(headers.get(), Some(&Vary::Any));
+    }
+
+    #[test]
+    fn test_rejects_contradictions() {
+        assert!(no_store().directive(CacheDirective::MaxAge(10)).is_err());
+        assert!(no_store().directive(CacheDirective::Public).is_err());
+        assert!(private_max_age(Duration::from_secs(10)).unwrap()
+            .directive(CacheDirective::Public).is_err());
+        assert!(private_max_age(Duration::from_secs(10)).unwrap()
+            .directive(CacheDirective::NoCache).is_err());
+        assert!(private_max_age(Duration::from_secs(10)).unwrap()
+            .directive(CacheDirective::MaxAge(20)).is_err());
+        assert!(public_immutable(Duration::from_secs(10)).unwrap()
+            .directive(CacheDirective::SMaxAge(10)).unwrap()
+            .directive(CacheDirective::SMaxAge(20)).is_err());
+        assert!(public_immutable(Duration::from_secs(0)).is_err());
+        assert!(private_max_age(Duration::from_secs(1 << 40)).is_err());
+
+        assert!(private_max_age(Duration::from_secs(10)).unwrap()
+            .directive(CacheDirective::MustRevalidate).is_ok());
+    }
+
+    #[test]
+    fn test_response_apply() {
+        let mut headers = Headers::new();
+        let mut stream = MockStream::new();
+        {
+            let mut res = Response::new(&mut stream, &mut headers);
+            res.version = Http10;
+            res.apply(no_store());
+        }
+        assert_eq!(headers.get(), Some(&Pragma::NoCache));
+        assert!(headers.has::<Expires>());
+
+        // Expires agrees with the Date of the response's clock
+        let mut headers = Headers::new();
+        let mut stream = MockStream::new();
+        {
+            let mut res = Response::new(&mut stream, &mut headers);
+            res.version = Http10;
+            res.set_date_clock(clock);
+            res.apply(private_max_age(Duration::from_secs(60)).unwrap());
+            res.start().unwrap().deconstruct();
+        }
+        let s = String::from_utf8(stream.write).unwrap();
+        assert!(s.contains("\r\nDate: Sun, 06 Nov 1994 08:49:37 GMT\r\n"));
+        assert!(s.contains("\r\nExpires: Sun, 06 Nov 1994 08:50:37 GMT\r\n"));
+    }
+}
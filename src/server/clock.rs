@@ -0,0 +1,51 @@
+//! The monotonic time that deadlines are measured in.
+//!
+//! Deadlines are only ever compared with `Instant`s, never with the wall
+//! clock, so stepping the system time can't make them fire early or hang.
+//! Tests swap in a `MockClock`, which only moves when advanced.
+#[cfg(test)]
+use std::sync::Mutex;
+#[cfg(test)]
+use std::time::Duration;
+use std::time::Instant;
+
+/// A source of monotonic time.
+pub trait Clock: Send + Sync {
+    /// The current time.
+    fn now(&self) -> Instant;
+}
+
+/// The time of `Instant::now`.
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+}
+
+/// A clock that stands still until it is advanced by hand.
+#[cfg(test)]
+pub struct MockClock {
+    now: Mutex<Instant>,
+}
+
+#[cfg(test)]
+impl MockClock {
+    pub fn new() -> MockClock {
+        MockClock { now: Mutex::new(Instant::now()) }
+    }
+
+    /// Moves the clock forward by `by`.
+    pub fn advance(&self, by: Duration) {
+        let mut now = self.now.lock().unwrap();
+        *now = *now + by;
+    }
+}
+
+#[cfg(test)]
+impl Clock for MockClock {
+    fn now(&self) -> Instant {
+        *self.now.lock().unwrap()
+    }
+}
@@ -0,0 +1,136 @@
+//! Answering requests whose handler is too slow to start a response.
+use std::io::{self, Write};
+use std::net::Shutdown;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use header::{Connection, Headers, RetryAfter};
+use net::NetworkStream;
+use status::StatusCode;
+use time::Tm;
+use version::HttpVersion;
+
+use super::Response;
+use super::wheel::{Timer, TimerWheel};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum State {
+    Waiting,
+    Responded,
+    TimedOut,
+}
+
+/// Watches a handler, answering its request with a
+/// `503 Service Unavailable` and closing the connection if the handler
+/// hasn't started a response by the deadline.
+pub struct HeadDeadline {
+    state: Arc<Mutex<State>>,
+    timer: Timer,
+}
+
+impl HeadDeadline {
+    /// Starts watching, answering on `stream` once `timeout` elapses.
+    ///
+    /// The thread of `timers` only marks the request as timed out, up to a
+    /// tick late, so one slow peer can't hold up the other timers. The
+    /// answer, with a `Retry-After` if `retry_after` is given, is written
+    /// from a thread of its own.
+    pub fn arm(timers: &TimerWheel, stream: Box<NetworkStream + Send>, timeout: Duration,
+               version: HttpVersion, clock: fn() -> Tm, retry_after: Option<Duration>)
+               -> HeadDeadline {
+        let state = Arc::new(Mutex::new(State::Waiting));
+        let watched = state.clone();
+        let mut stream = Some(stream);
+        let timer = timers.arm(timeout, Box::new(move || {
+            let stream = match stream.take() {
+                Some(stream) => stream,
+                None => return,
+            };
+            // once timed out, the handler can't start writing, so this
+            // response can be written without holding the lock
+            {
+                let mut state = watched.lock().unwrap();
+                if *state != State::Waiting {
+                    return;
+                }
+                *state = State::TimedOut;
+            }
+            debug!("handler did not start a response within {:?}", timeout);
+            thread::spawn(move || unavailable(stream, version, clock, retry_after));
+        }));
+        HeadDeadline { state: state, timer: timer }
+    }
+
+    /// Marks the response as started, returning false if it is too late.
+    pub fn respond(&self) -> bool {
+        let mut state = self.state.lock().unwrap();
+        if *state == State::Waiting {
+            *state = State::Responded;
+        }
+        *state == State::Responded
+    }
+
+    /// Whether the request was already answered with a 503.
+    pub fn timed_out(&self) -> bool {
+        *self.state.lock().unwrap() == State::TimedOut
+    }
+}
+
+// Answers a request whose handler was too slow with a 503, and closes the
+// connection.
+fn unavailable(mut stream: Box<NetworkStream + Send>, version: HttpVersion, clock: fn() -> Tm,
+               retry_after: Option<Duration>) {
+    let mut headers = Headers::new();
+    headers.set(Connection::close());
+    if let Some(delay) = retry_after {
+        headers.set(RetryAfter::Delay(delay));
+    }
+    {
+        let mut res = Response::new(&mut stream, &mut headers);
+        *res.status_mut() = StatusCode::ServiceUnavailable;
+        res.version = version;
+        res.set_date_clock(clock);
+        if let Err(e) = res.send(b"") {
+            debug!("error writing 503 response: {:?}", e);
+        }
+    }
+    if let Err(e) = stream.close(Shutdown::Both) {
+        debug!("error closing timed out connection: {:?}", e);
+    }
+}
+
+impl Drop for HeadDeadline {
+    fn drop(&mut self) {
+        self.respond();
+        // frees the stream the timer holds
+        self.timer.cancel();
+    }
+}
+
+/// Writes through to `inner`, unless the deadline passed before the first
+/// write.
+pub struct DeadlineWriter<'a, W> {
+    pub inner: W,
+    pub deadline: Option<&'a HeadDeadline>,
+    pub started: bool,
+}
+
+impl<'a, W: Write> Write for DeadlineWriter<'a, W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if !self.started {
+            if let Some(deadline) = self.deadline {
+                if !deadline.respond() {
+                    return Err(io::Error::new(io::ErrorKind::TimedOut,
+                                              "handler did not start a response in time"));
+                }
+            }
+            self.started = true;
+        }
+        self.inner.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
@@ -0,0 +1,210 @@
+//! Digests of response bodies, sent in a `Digest` trailer.
+use std::fmt;
+
+use serialize::base64::{ToBase64, STANDARD};
+
+/// An algorithm for `Response::with_digest_trailer`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DigestAlgorithm {
+    /// SHA-256, sent as `SHA-256=<base64>`.
+    Sha256,
+}
+
+/// A digest being computed over the bytes of a body as they are written.
+pub struct Digest {
+    algorithm: DigestAlgorithm,
+    sha256: Sha256,
+}
+
+impl Digest {
+    pub fn new(algorithm: DigestAlgorithm) -> Digest {
+        Digest { algorithm: algorithm, sha256: Sha256::new() }
+    }
+
+    pub fn update(&mut self, bytes: &[u8]) {
+        match self.algorithm {
+            DigestAlgorithm::Sha256 => self.sha256.update(bytes),
+        }
+    }
+
+    /// The digest of the bytes so far.
+    pub fn finish(self) -> Vec<u8> {
+        match self.algorithm {
+            DigestAlgorithm::Sha256 => self.sha256.finish().to_vec(),
+        }
+    }
+
+    /// The value of a `Digest` header for the bytes so far.
+    pub fn header_value(self) -> Vec<u8> {
+        let name = match self.algorithm {
+            DigestAlgorithm::Sha256 => "SHA-256",
+        };
+        format!("{}={}", name, self.finish().to_base64(STANDARD)).into_bytes()
+    }
+}
+
+impl fmt::Debug for Digest {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_tuple("Digest").field(&self.algorithm).finish()
+    }
+}
+
+const K: [u32; 64] = [
+    0x428a2f98, 0x71374491, 0xb5c0fbcf, 0xe9b5dba5, 0x3956c25b, 0x59f111f1, 0x923f82a4, 0xab1c5ed5,
+    0xd807aa98, 0x12835b01, 0x243185be, 0x550c7dc3, 0x72be5d74, 0x80deb1fe, 0x9bdc06a7, 0xc19bf174,
+    0xe49b69c1, 0xefbe4786, 0x0fc19dc6, 0x240ca1cc, 0x2de92c6f, 0x4a7484aa, 0x5cb0a9dc, 0x76f988da,
+    0x983e5152, 0xa831c66d, 0xb00327c8, 0xbf597fc7, 0xc6e00bf3, 0xd5a79147, 0x06ca6351, 0x14292967,
+    0x27b70a85, 0x2e1b2138, 0x4d2c6dfc, 0x53380d13, 0x650a7354, 0x766a0abb, 0x81c2c92e, 0x92722c85,
+    0xa2bfe8a1, 0xa81a664b, 0xc24b8b70, 0xc76c51a3, 0xd192e819, 0xd6990624, 0xf40e3585, 0x106aa070,
+    0x19a4c116, 0x1e376c08, 0x2748774c, 0x34b0bcb5, 0x391c0cb3, 0x4ed8aa4a, 0x5b9cca4f, 0x682e6ff3,
+    0x748f82ee, 0x78a5636f, 0x84c87814, 0x8cc70208, 0x90befffa, 0xa4506ceb, 0xbef9a3f7, 0xc67178f2,
+];
+
+/// SHA-256, as in FIPS 180-4.
+struct Sha256 {
+    state: [u32; 8],
+    block: [u8; 64],
+    block_len: usize,
+    total: u64,
+}
+
+impl Sha256 {
+    fn new() -> Sha256 {
+        Sha256 {
+            state: [0x6a09e667, 0xbb67ae85, 0x3c6ef372, 0xa54ff53a,
+                    0x510e527f, 0x9b05688c, 0x1f83d9ab, 0x5be0cd19],
+            block: [0; 64],
+            block_len: 0,
+            total: 0,
+        }
+    }
+
+    fn update(&mut self, mut bytes: &[u8]) {
+        self.total += bytes.len() as u64;
+        while !bytes.is_empty() {
+            let n = ::std::cmp::min(64 - self.block_len, bytes.len());
+            self.block[self.block_len..self.block_len + n].copy_from_slice(&bytes[..n]);
+            self.block_len += n;
+            bytes = &bytes[n..];
+            if self.block_len == 64 {
+                let block = self.block;
+                self.compress(&block);
+                self.block_len = 0;
+            }
+        }
+    }
+
+    fn finish(mut self) -> [u8; 32] {
+        let bits = self.total * 8;
+        self.update(&[0x80]);
+        while self.block_len != 56 {
+            self.update(&[0]);
+        }
+        let mut len = [0; 8];
+        for i in 0..8 {
+            len[i] = (bits >> (56 - i * 8)) as u8;
+        }
+        self.update(&len);
+
+        let mut out = [0; 32];
+        for (i, word) in self.state.iter().enumerate() {
+            for j in 0..4 {
+                out[i * 4 + j] = (word >> (24 - j * 8)) as u8;
+            }
+        }
+        out
+    }
+
+    fn compress(&mut self, block: &[u8; 64]) {
+        let mut w = [0u32; 64];
+        for i in 0..16 {
+            w[i] = (block[i * 4] as u32) << 24 | (block[i * 4 + 1] as u32) << 16 |
+                   (block[i * 4 + 2] as u32) << 8 | block[i * 4 + 3] as u32;
+        }
+        for i in 16..64 {
+            let s0 = w[i - 15].rotate_right(7) ^ w[i - 15].rotate_right(18) ^ (w[i - 15] >> 3);
+            let s1 = w[i - 2].rotate_right(17) ^ w[i - 2].rotate_right(19) ^ (w[i - 2] >> 10);
+            w[i] = w[i - 16].wrapping_add(s0).wrapping_add(w[i - 7]).wrapping_add(s1);
+        }
+
+        let mut s = self.state;
+        for i in 0..64 {
+            let s1 = s[4].rotate_right(6) ^ s[4].rotate_right(11) ^ s[4].rotate_right(25);
+            let ch = (s[4] & s[5]) ^ (!s[4] & s[6]);
+            let t1 = s[7].wrapping_add(s1).wrapping_add(ch).wrapping_add(K[i]).wrapping_add(w[i]);
+            let s0 = s[0].rotate_right(2) ^ s[0].rotate_right(13) ^ s[0].rotate_right(22);
+            let maj = (s[0] & s[1]) ^ (s[0] & s[2]) ^ (s[1] & s[2]);
+            let t2 = s0.wrapping_add(maj);
+            s = [t1.wrapping_add(t2), s[0], s[1], s[2], s[3].wrapping_add(t1), s[4], s[5], s[6]];
+        }
+        for (state, s) in self.state.iter_mut().zip(s.iter()) {
+            *state = state.wrapping_add(*s);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Digest, DigestAlgorithm, Sha256};
+
+    fn hex(bytes: &[u8]) -> String {
+        bytes.iter().map(|b| format!("{:02x}", b)).collect()
+    }
+
+    #[test]
+    fn test_sha256() {
+        let digest = |input: &[u8]| {
+            let mut sha = Sha256::new();
+            sha.update(input);
+            hex(&sha.finish())
+        };
+        assert_eq!(digest(b""),
+                   "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855");
+        assert_eq!(digest(b"abc"),
+                   "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad");
+        assert_eq!(digest(b"abcdbcdecdefdefgefghfghighijhijkijkljklmklmnlmnomnopnopq"),
+                   "248d6a61d20638b8e5c026930c3e6039a33ce45964ff2167f6ecedd419db06c1");
+        assert_eq!(digest(b"abcdefghbcdefghicdefghijdefghijkefghijklfghijklmghijklmn\
+                            hijklmnoijklmnopjklmnopqklmnopqrlmnopqrsmnopqrstnopqrstu"),
+                   "cf5b16a778af8380036ce59e7b0492370b249b11e8f07a51afac45037afee9d1");
+        assert_eq!(digest(&vec![b'a'; 1_000_000]),
+                   "cdc76e5c9914fb9281a1c7e284d73e67f1809a48a497200e046d39ccc7112cd0");
+    }
+
+    #[test]
+    fn test_sha256_padding_boundaries() {
+        // the length no longer fits in the last block past 55 bytes
+        let cases = [
+            (55, "9f4390f8d30c2dd92ec9f095b65e2b9ae9b0a925a5258e241c9f1e910f734318"),
+            (56, "b35439a4ac6f0948b6d6f9e3c6af0f5f590ce20f1bde7090ef7970686ec6738a"),
+            (63, "7d3e74a05d7db15bce4ad9ec0658ea98e3f06eeecf16b4c6fff2da457ddc2f34"),
+            (64, "ffe054fe7ae0cb6dc65c3af9b61d5209f439851db43d0ba5997337df154668eb"),
+            (65, "635361c48bb9eab14198e76ea8ab7f1a41685d6ad62aa9146d301d4f17eb0ae0"),
+        ];
+        for &(len, expected) in &cases {
+            let mut sha = Sha256::new();
+            sha.update(&vec![b'a'; len]);
+            assert_eq!(hex(&sha.finish()), expected, "{} bytes", len);
+        }
+    }
+
+    #[test]
+    fn test_sha256_split_updates() {
+        let input = (0..1000u32).map(|i| i as u8).collect::<Vec<_>>();
+        let mut whole = Sha256::new();
+        whole.update(&input);
+        let mut split = Sha256::new();
+        for part in input.chunks(7) {
+            split.update(part);
+        }
+        assert_eq!(hex(&whole.finish()), hex(&split.finish()));
+    }
+
+    #[test]
+    fn test_header_value() {
+        let mut digest = Digest::new(DigestAlgorithm::Sha256);
+        digest.update(b"abc");
+        assert_eq!(digest.header_value(),
+                   b"SHA-256=ungWv48Bz+pBQUDeXa4iI7ADYaOWF3qctBD/YfIAFa0=".to_vec());
+    }
+}
@@ -0,0 +1,84 @@
+//! A map of values keyed by their type.
+use std::any::{Any, TypeId};
+use std::collections::HashMap;
+use std::fmt;
+
+/// Values attached to a `Request`, at most one of each type.
+///
+/// Middleware wrapping a `Handler` can insert data, such as an
+/// authenticated user or a request id, for the handlers it calls to read.
+#[derive(Default)]
+pub struct Extensions {
+    map: HashMap<TypeId, Box<Any + Send>>,
+}
+
+impl Extensions {
+    /// Creates an empty `Extensions`.
+    pub fn new() -> Extensions {
+        Extensions { map: HashMap::new() }
+    }
+
+    /// Inserts a value, returning the one of the same type it replaced.
+    pub fn insert<T: Any + Send>(&mut self, val: T) -> Option<T> {
+        self.map.insert(TypeId::of::<T>(), Box::new(val)).and_then(|prev| {
+            prev.downcast().ok().map(|prev: Box<T>| *prev)
+        })
+    }
+
+    /// Gets a reference to the value of type `T`, if one was inserted.
+    pub fn get<T: Any + Send>(&self) -> Option<&T> {
+        self.map.get(&TypeId::of::<T>()).and_then(|val| val.downcast_ref())
+    }
+
+    /// Gets a mutable reference to the value of type `T`, if one was inserted.
+    pub fn get_mut<T: Any + Send>(&mut self) -> Option<&mut T> {
+        self.map.get_mut(&TypeId::of::<T>()).and_then(|val| val.downcast_mut())
+    }
+
+    /// Removes the value of type `T`, returning it.
+    pub fn remove<T: Any + Send>(&mut self) -> Option<T> {
+        self.map.remove(&TypeId::of::<T>()).and_then(|val| {
+            val.downcast().ok().map(|val: Box<T>| *val)
+        })
+    }
+
+    /// The number of values attached.
+    pub fn len(&self) -> usize {
+        self.map.len()
+    }
+
+    /// Whether no values are attached.
+    pub fn is_empty(&self) -> bool {
+        self.map.is_empty()
+    }
+}
+
+impl fmt::Debug for Extensions {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Extensions").field("len", &self.map.len()).finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Extensions;
+
+    #[derive(Debug, PartialEq)]
+    struct RequestId(u32);
+
+    #[test]
+    fn test_insert_get_remove() {
+        let mut ext = Extensions::new();
+        assert!(ext.get::<RequestId>().is_none());
+        assert_eq!(ext.insert(RequestId(1)), None);
+        assert_eq!(ext.insert(RequestId(2)), Some(RequestId(1)));
+        ext.insert("user");
+
+        assert_eq!(ext.len(), 2);
+        assert_eq!(ext.get::<RequestId>(), Some(&RequestId(2)));
+        ext.get_mut::<RequestId>().unwrap().0 = 3;
+        assert_eq!(ext.remove::<RequestId>(), Some(RequestId(3)));
+        assert!(ext.get::<RequestId>().is_none());
+        assert_eq!(ext.get::<&'static str>(), Some(&"user"));
+    }
+}
@@ -0,0 +1,466 @@
+//! Proxy chains from the `Forwarded` and `X-Forwarded-For` headers.
+//!
+//! Any client can send these headers, so they only say something about the
+//! client when the proxies that added them are trusted. A `Server` is told
+//! which addresses its proxies use with `Server::trusted_proxies`, and
+//! `Request::client_ip` then skips over those proxies to the address of the
+//! client that reached the first of them.
+use std::ascii::AsciiExt;
+use std::fmt;
+use std::net::{IpAddr, Ipv6Addr};
+use std::str::FromStr;
+
+use header::Headers;
+use Error;
+
+/// A range of IP addresses, written as an address and an optional prefix
+/// length, such as `10.0.0.0/8` or `fd00::/8`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct IpRange {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl IpRange {
+    /// Creates a range of the addresses sharing the first `prefix` bits
+    /// of `addr`.
+    ///
+    /// Returns `None` if `prefix` is longer than the address.
+    pub fn new(addr: IpAddr, prefix: u8) -> Option<IpRange> {
+        let max = match addr {
+            IpAddr::V4(_) => 32,
+            IpAddr::V6(_) => 128,
+        };
+        if prefix > max {
+            return None;
+        }
+        Some(IpRange { addr: addr, prefix: prefix })
+    }
+
+    /// Whether `addr` is in this range.
+    pub fn contains(&self, addr: &IpAddr) -> bool {
+        match (self.addr, *addr) {
+            (IpAddr::V4(ref net), IpAddr::V4(ref addr)) => {
+                prefix_matches(&net.octets(), &addr.octets(), self.prefix)
+            },
+            (IpAddr::V6(ref net), IpAddr::V6(ref addr)) => {
+                prefix_matches(&octets(net), &octets(addr), self.prefix)
+            },
+            _ => false
+        }
+    }
+}
+
+impl FromStr for IpRange {
+    type Err = Error;
+
+    fn from_str(s: &str) -> ::Result<IpRange> {
+        let mut parts = s.splitn(2, '/');
+        let addr = try!(parts.next().unwrap_or("").parse::<IpAddr>().map_err(|_| Error::Header));
+        let prefix = match parts.next() {
+            Some(prefix) => try!(prefix.parse::<u8>().map_err(|_| Error::Header)),
+            None => match addr {
+                IpAddr::V4(_) => 32,
+                IpAddr::V6(_) => 128,
+            }
+        };
+        IpRange::new(addr, prefix).ok_or(Error::Header)
+    }
+}
+
+impl fmt::Display for IpRange {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix)
+    }
+}
+
+fn octets(addr: &Ipv6Addr) -> [u8; 16] {
+    let mut octets = [0; 16];
+    for (i, segment) in addr.segments().iter().enumerate() {
+        octets[i * 2] = (segment >> 8) as u8;
+        octets[i * 2 + 1] = *segment as u8;
+    }
+    octets
+}
+
+fn prefix_matches(net: &[u8], addr: &[u8], prefix: u8) -> bool {
+    let whole = (prefix / 8) as usize;
+    if net[..whole] != addr[..whole] {
+        return false;
+    }
+    let bits = prefix % 8;
+    if bits == 0 {
+        return true;
+    }
+    let mask = !0u8 << (8 - bits);
+    net[whole] & mask == addr[whole] & mask
+}
+
+/// One element of a `Forwarded` header, describing a single proxy hop.
+///
+/// Values are unquoted, but otherwise as sent; `for_` and `by` may be
+/// obfuscated identifiers or `unknown` rather than addresses.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ForwardedElement {
+    /// The interface the request came in on at the proxy.
+    pub by: Option<String>,
+    /// The node that made the request to the proxy.
+    pub for_: Option<String>,
+    /// The `Host` the proxy received.
+    pub host: Option<String>,
+    /// The scheme the proxy was reached with.
+    pub proto: Option<String>,
+}
+
+/// Parses all `Forwarded` headers, nearest to the client first.
+///
+/// Unknown parameters are ignored, and parsing stops at the first element
+/// that is malformed.
+pub fn parse_forwarded(headers: &Headers) -> Vec<ForwardedElement> {
+    let mut elements = Vec::new();
+    if let Some(raw) = headers.get_raw("Forwarded") {
+        for line in raw {
+            let line = String::from_utf8_lossy(line);
+            let mut rest = &line[..];
+            loop {
+                match parse_element(rest) {
+                    Some((element, next)) => {
+                        elements.push(element);
+                        match next {
+                            Some(next) => rest = next,
+                            None => break,
+                        }
+                    },
+                    None => return elements,
+                }
+            }
+        }
+    }
+    elements
+}
+
+// Parses one element, returning it and the input after its `,`, if any.
+fn parse_element(mut s: &str) -> Option<(ForwardedElement, Option<&str>)> {
+    let mut element = ForwardedElement::default();
+    loop {
+        s = s.trim_left();
+        let eq = match s.find('=') {
+            Some(eq) => eq,
+            None => return None,
+        };
+        let name = s[..eq].trim();
+        let (value, rest) = match parse_value(&s[eq + 1..]) {
+            Some(parsed) => parsed,
+            None => return None,
+        };
+        if name.eq_ignore_ascii_case("by") {
+            element.by = Some(value);
+        } else if name.eq_ignore_ascii_case("for") {
+            element.for_ = Some(value);
+        } else if name.eq_ignore_ascii_case("host") {
+            element.host = Some(value);
+        } else if name.eq_ignore_ascii_case("proto") {
+            element.proto = Some(value);
+        }
+
+        let rest = rest.trim_left();
+        if rest.starts_with(';') {
+            s = &rest[1..];
+        } else if rest.starts_with(',') {
+            return Some((element, Some(&rest[1..])));
+        } else if rest.is_empty() {
+            return Some((element, None));
+        } else {
+            return None;
+        }
+    }
+}
+
+// Parses a token or quoted-string, returning it and the input after it.
+fn parse_value(s: &str) -> Option<(String, &str)> {
+    let s = s.trim_left();
+    if !s.starts_with('"') {
+        let end = s.find(|c| c == ';' || c == ',').unwrap_or(s.len());
+        return Some((s[..end].trim_right().to_owned(), &s[end..]));
+    }
+    let mut value = String::new();
+    let mut escaped = false;
+    for (i, c) in s.char_indices().skip(1) {
+        if escaped {
+            value.push(c);
+            escaped = false;
+        } else if c == '\\' {
+            escaped = true;
+        } else if c == '"' {
+            return Some((value, &s[i + 1..]));
+        } else {
+            value.push(c);
+        }
+    }
+    None
+}
+
+/// Parses a node from a `for` parameter or `X-Forwarded-For` entry, such
+/// as `192.0.2.43`, `192.0.2.43:4711`, `[2001:db8::17]:4711` or a bare
+/// IPv6 address.
+pub fn parse_node(node: &str) -> Option<IpAddr> {
+    let node = node.trim().trim_matches('"');
+    if node.starts_with('[') {
+        return node[1..].find(']').and_then(|end| {
+            node[1..end + 1].parse::<Ipv6Addr>().ok().map(IpAddr::V6)
+        });
+    }
+    if let Ok(addr) = node.parse::<IpAddr>() {
+        return Some(addr);
+    }
+    let mut parts = node.rsplitn(2, ':');
+    match (parts.next(), parts.next()) {
+        (Some(port), Some(host)) if port.parse::<u16>().is_ok() && !host.contains(':') => {
+            host.parse::<IpAddr>().ok()
+        },
+        _ => None
+    }
+}
+
+/// The nodes the request passed through, nearest to the client first.
+///
+/// The `Forwarded` header is preferred over `X-Forwarded-For`. A node
+/// that isn't an address is `None`.
+pub fn chain(headers: &Headers) -> Vec<Option<IpAddr>> {
+    if headers.get_raw("Forwarded").is_some() {
+        return parse_forwarded(headers).iter().map(|element| {
+            element.for_.as_ref().and_then(|node| parse_node(node))
+        }).collect();
+    }
+    list(headers, "X-Forwarded-For").iter().map(|node| parse_node(node)).collect()
+}
+
+// The entries of a comma separated header, over all its lines.
+fn list(headers: &Headers, name: &str) -> Vec<String> {
+    let mut entries = Vec::new();
+    if let Some(raw) = headers.get_raw(name) {
+        for line in raw {
+            for entry in String::from_utf8_lossy(line).split(',') {
+                entries.push(entry.trim().to_owned());
+            }
+        }
+    }
+    entries
+}
+
+/// Finds the client behind a chain of trusted proxies.
+///
+/// The headers are only believed if `peer` is trusted. The chain is then
+/// walked from the right, and the first node that isn't a trusted proxy
+/// is the client. If a node isn't an address, the walk stops at the last
+/// trusted one before it.
+pub fn client_ip(peer: IpAddr, headers: &Headers, trusted: &[IpRange]) -> IpAddr {
+    let nodes = chain(headers);
+    match client_index(peer, &nodes, trusted) {
+        Some(i) => nodes[i].unwrap_or(peer),
+        None => peer,
+    }
+}
+
+/// Finds the scheme the client behind a chain of trusted proxies used.
+///
+/// This is the `proto` of the `Forwarded` element `client_ip` takes the
+/// client from. Without a `Forwarded` header, it is the entry of
+/// `X-Forwarded-Proto` in the same place, counting from the right, as the
+/// client in `X-Forwarded-For`, or the last entry when a single proxy sent
+/// no `X-Forwarded-For` at all.
+pub fn client_proto(peer: IpAddr, headers: &Headers, trusted: &[IpRange]) -> Option<String> {
+    let nodes = chain(headers);
+    if nodes.is_empty() {
+        return if is_trusted(&peer, trusted) {
+            list(headers, "X-Forwarded-Proto").pop()
+        } else {
+            None
+        };
+    }
+    let index = match client_index(peer, &nodes, trusted) {
+        Some(index) => index,
+        None => return None,
+    };
+    if headers.get_raw("Forwarded").is_some() {
+        return parse_forwarded(headers).into_iter().nth(index).and_then(|element| element.proto);
+    }
+    let protos = list(headers, "X-Forwarded-Proto");
+    let from_right = nodes.len() - index;
+    protos.len().checked_sub(from_right).map(|i| protos[i].clone())
+}
+
+// Walks the chain from the right over trusted proxies, returning the
+// index of the node taken as the client, or `None` if the headers aren't
+// believed or name no client.
+fn client_index(peer: IpAddr, nodes: &[Option<IpAddr>], trusted: &[IpRange]) -> Option<usize> {
+    if !is_trusted(&peer, trusted) {
+        return None;
+    }
+    let mut client = None;
+    for (i, node) in nodes.iter().enumerate().rev() {
+        match *node {
+            Some(ref addr) => {
+                client = Some(i);
+                if !is_trusted(addr, trusted) {
+                    break;
+                }
+            },
+            None => break,
+        }
+    }
+    client
+}
+
+fn is_trusted(addr: &IpAddr, trusted: &[IpRange]) -> bool {
+    trusted.iter().any(|range| range.contains(addr))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::IpAddr;
+
+    use header::Headers;
+    use super::{client_ip, client_proto, parse_forwarded, parse_node, ForwardedElement, IpRange};
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    fn headers(raw: &[(&str, &str)]) -> Headers {
+        let mut headers = Headers::new();
+        for &(name, _) in raw {
+            let values = raw.iter().filter(|&&(other, _)| other == name)
+                .map(|&(_, value)| value.as_bytes().to_vec()).collect();
+            headers.set_raw(name.to_owned(), values);
+        }
+        headers
+    }
+
+    #[test]
+    fn test_ip_range() {
+        let range: IpRange = "10.1.0.0/16".parse().unwrap();
+        assert!(range.contains(&ip("10.1.200.3")));
+        assert!(!range.contains(&ip("10.2.0.1")));
+        assert!(!range.contains(&ip("::ffff:10.1.0.1")));
+
+        let range: IpRange = "fd00::/7".parse().unwrap();
+        assert!(range.contains(&ip("fdff::1")));
+        assert!(range.contains(&ip("fc00::1")));
+        assert!(!range.contains(&ip("fe80::1")));
+
+        let single: IpRange = "192.0.2.1".parse().unwrap();
+        assert_eq!(single.to_string(), "192.0.2.1/32");
+        assert!(single.contains(&ip("192.0.2.1")));
+        assert!(!single.contains(&ip("192.0.2.2")));
+
+        assert!("10.0.0.0/33".parse::<IpRange>().is_err());
+        assert!("proxy/8".parse::<IpRange>().is_err());
+    }
+
+    #[test]
+    fn test_parse_node() {
+        assert_eq!(parse_node("192.0.2.43"), Some(ip("192.0.2.43")));
+        assert_eq!(parse_node(" 192.0.2.43:4711"), Some(ip("192.0.2.43")));
+        assert_eq!(parse_node("\"[2001:db8:cafe::17]:4711\""), Some(ip("2001:db8:cafe::17")));
+        assert_eq!(parse_node("[2001:db8::1]"), Some(ip("2001:db8::1")));
+        assert_eq!(parse_node("2001:db8::1"), Some(ip("2001:db8::1")));
+        assert_eq!(parse_node("unknown"), None);
+        assert_eq!(parse_node("_hidden"), None);
+    }
+
+    #[test]
+    fn test_parse_forwarded() {
+        let h = headers(&[
+            ("Forwarded", "for=192.0.2.60;proto=http;by=203.0.113.43, For=\"[2001:db8::17]:4711\""),
+            ("Forwarded", "for=198.51.100.17;host=\"ex\\\"ample.com\";ext=1"),
+        ]);
+        assert_eq!(parse_forwarded(&h), vec![
+            ForwardedElement {
+                by: Some("203.0.113.43".to_owned()),
+                for_: Some("192.0.2.60".to_owned()),
+                host: None,
+                proto: Some("http".to_owned()),
+            },
+            ForwardedElement {
+                for_: Some("[2001:db8::17]:4711".to_owned()),
+                ..Default::default()
+            },
+            ForwardedElement {
+                for_: Some("198.51.100.17".to_owned()),
+                host: Some("ex\"ample.com".to_owned()),
+                ..Default::default()
+            },
+        ]);
+
+        let h = headers(&[("Forwarded", "for=192.0.2.60, for=\"unterminated")]);
+        assert_eq!(parse_forwarded(&h).len(), 1);
+    }
+
+    #[test]
+    fn test_client_ip() {
+        let trusted: Vec<IpRange> = vec!["10.0.0.0/8".parse().unwrap(), "fd00::/8".parse().unwrap()];
+        let cases: &[(&str, &[(&str, &str)], &str)] = &[
+            // no headers
+            ("10.0.0.1", &[], "10.0.0.1"),
+            // one trusted proxy
+            ("10.0.0.1", &[("X-Forwarded-For", "203.0.113.9")], "203.0.113.9"),
+            // a spoofed entry left of the real client is ignored
+            ("10.0.0.1", &[("X-Forwarded-For", "1.2.3.4, 203.0.113.9, 10.0.0.7")], "203.0.113.9"),
+            // a peer that isn't trusted is the client, whatever it claims
+            ("203.0.113.50", &[("X-Forwarded-For", "1.2.3.4")], "203.0.113.50"),
+            ("203.0.113.50", &[("Forwarded", "for=1.2.3.4")], "203.0.113.50"),
+            // Forwarded is preferred, and ports and IPv6 are understood
+            ("fd00::2", &[
+                ("Forwarded", "for=\"[2001:db8::17]:4711\", for=10.0.0.9:80"),
+                ("X-Forwarded-For", "1.2.3.4"),
+            ], "2001:db8::17"),
+            // entries over several header lines form one chain
+            ("10.0.0.1", &[
+                ("X-Forwarded-For", "198.51.100.1"),
+                ("X-Forwarded-For", "10.0.0.2"),
+            ], "198.51.100.1"),
+            // an obfuscated node stops the walk at the last trusted proxy
+            ("10.0.0.1", &[("Forwarded", "for=_hidden, for=10.0.0.3")], "10.0.0.3"),
+            // every hop is trusted
+            ("10.0.0.1", &[("X-Forwarded-For", "10.0.0.5, 10.0.0.6")], "10.0.0.5"),
+        ];
+        for &(peer, raw, expected) in cases {
+            assert_eq!(client_ip(ip(peer), &headers(raw), &trusted), ip(expected),
+                       "peer={} headers={:?}", peer, raw);
+        }
+    }
+
+    #[test]
+    fn test_client_proto() {
+        let trusted: Vec<IpRange> = vec!["10.0.0.0/8".parse().unwrap()];
+        let cases: &[(&str, &[(&str, &str)], Option<&str>)] = &[
+            ("10.0.0.1", &[("Forwarded", "for=203.0.113.9;proto=https")], Some("https")),
+            // a spoofed leftmost element is ignored
+            ("10.0.0.1", &[
+                ("Forwarded", "for=1.2.3.4;proto=https, for=203.0.113.9;proto=http"),
+            ], Some("http")),
+            ("10.0.0.1", &[
+                ("X-Forwarded-For", "203.0.113.9"),
+                ("X-Forwarded-Proto", "https, http"),
+            ], Some("http")),
+            // the client's hop is counted from the right in both lists
+            ("10.0.0.1", &[
+                ("X-Forwarded-For", "203.0.113.9, 10.0.0.2"),
+                ("X-Forwarded-Proto", "https, http"),
+            ], Some("https")),
+            ("10.0.0.1", &[
+                ("X-Forwarded-For", "203.0.113.9, 10.0.0.2"),
+                ("X-Forwarded-Proto", "http"),
+            ], None),
+            ("10.0.0.1", &[("X-Forwarded-Proto", "https, http")], Some("http")),
+            // a peer that isn't trusted is believed about nothing
+            ("203.0.113.50", &[("Forwarded", "for=1.2.3.4;proto=https")], None),
+            ("203.0.113.50", &[("X-Forwarded-Proto", "https")], None),
+        ];
+        for &(peer, raw, expected) in cases {
+            assert_eq!(client_proto(ip(peer), &headers(raw), &trusted).as_ref().map(|s| &s[..]),
+                       expected, "peer={} headers={:?}", peer, raw);
+        }
+    }
+}
@@ -0,0 +1,273 @@
+//! Tracking of idle keep-alive connections, so they can be shed, of
+//! whether the server is draining, and of what each connection is doing.
+use std::cmp;
+use std::collections::HashMap;
+use std::net::{Shutdown, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use net::NetworkStream;
+use super::clock::{Clock, SystemClock};
+
+/// The connections of a server that are waiting for their next request.
+pub struct IdleConnections {
+    state: Mutex<State>,
+    clock: Arc<Clock>,
+}
+
+struct State {
+    // Least recently active first, by the id of their connection.
+    idle: Vec<(u64, Box<NetworkStream + Send>)>,
+    next_conn: u64,
+    conns: HashMap<u64, Conn>,
+    draining: bool,
+}
+
+/// What a connection being handled is doing, as last reported.
+pub struct Conn {
+    remote_addr: SocketAddr,
+    started: Instant,
+    pub state: ConnState,
+    pub keep_alive: bool,
+    pub bytes_in: u64,
+    pub bytes_out: u64,
+    // Closed while idle, so about to end.
+    shed: bool,
+}
+
+/// What a connection is doing, in a `ConnDebug`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ConnState {
+    /// Reading the head of a request. A connection stuck here has a slow
+    /// or stalled client.
+    ReadingHead,
+    /// In a handler, which may be reading the request body or writing the
+    /// response.
+    Handling,
+    /// Kept alive, waiting for the next request.
+    Idle,
+    /// Reading and dropping what the client still sends, before closing.
+    Lingering,
+}
+
+/// A snapshot of a connection, from `Listening::dump_connections`.
+#[derive(Clone, Debug)]
+pub struct ConnDebug {
+    /// The address of the client.
+    pub remote_addr: SocketAddr,
+    /// What the connection is doing.
+    pub state: ConnState,
+    /// Whether the connection is kept alive after the current request.
+    pub keep_alive: bool,
+    /// How long ago the connection was accepted.
+    pub age: Duration,
+    /// The bytes read from the connection, as of its last change of state.
+    pub bytes_in: u64,
+    /// The bytes written to the connection, as of its last change of state.
+    pub bytes_out: u64,
+}
+
+impl IdleConnections {
+    pub fn new() -> IdleConnections {
+        IdleConnections::with_clock(Arc::new(SystemClock))
+    }
+
+    /// Creates an empty set, whose connection ages are by `clock`.
+    pub fn with_clock(clock: Arc<Clock>) -> IdleConnections {
+        IdleConnections {
+            state: Mutex::new(State {
+                idle: Vec::new(),
+                next_conn: 0,
+                conns: HashMap::new(),
+                draining: false,
+            }),
+            clock: clock,
+        }
+    }
+
+    /// Counts a connection as being handled, returning its id.
+    pub fn start(&self, remote_addr: SocketAddr) -> u64 {
+        let mut state = self.state.lock().unwrap();
+        let id = state.next_conn;
+        state.next_conn += 1;
+        state.conns.insert(id, Conn {
+            remote_addr: remote_addr,
+            started: self.clock.now(),
+            state: ConnState::ReadingHead,
+            keep_alive: false,
+            bytes_in: 0,
+            bytes_out: 0,
+            shed: false,
+        });
+        id
+    }
+
+    /// Counts a connection as no longer being handled.
+    pub fn end(&self, id: u64) {
+        self.state.lock().unwrap().conns.remove(&id);
+    }
+
+    /// Records what a connection being handled is doing.
+    pub fn update<F: FnOnce(&mut Conn)>(&self, id: u64, f: F) {
+        if let Some(conn) = self.state.lock().unwrap().conns.get_mut(&id) {
+            f(conn);
+        }
+    }
+
+    /// Snapshots the connections being handled, oldest first.
+    pub fn dump(&self) -> Vec<ConnDebug> {
+        let now = self.clock.now();
+        let mut conns = self.state.lock().unwrap().conns.values().map(|conn| ConnDebug {
+            remote_addr: conn.remote_addr,
+            state: conn.state,
+            keep_alive: conn.keep_alive,
+            age: now - conn.started,
+            bytes_in: conn.bytes_in,
+            bytes_out: conn.bytes_out,
+        }).collect::<Vec<_>>();
+        conns.sort_by(|a, b| b.age.cmp(&a.age));
+        conns
+    }
+
+    /// Marks the connection `id` as idle, until `leave` is called.
+    pub fn enter(&self, id: u64, stream: Box<NetworkStream + Send>) {
+        self.state.lock().unwrap().idle.push((id, stream));
+    }
+
+    /// Marks a connection as active again.
+    pub fn leave(&self, id: u64) {
+        self.state.lock().unwrap().idle.retain(|&(idle_id, _)| idle_id != id);
+    }
+
+    /// Closes up to `n` of the least recently active idle connections,
+    /// returning how many were closed.
+    pub fn shed(&self, n: usize) -> usize {
+        shed(&mut self.state.lock().unwrap(), n)
+    }
+
+    /// Closes the least recently active idle connection if `capacity`
+    /// connections are being handled, for a connection waiting to be
+    /// accepted. Returns whether one was closed.
+    pub fn relieve(&self, capacity: usize) -> bool {
+        let mut state = self.state.lock().unwrap();
+        // those already shed are on their way out, and free a thread soon
+        let active = state.conns.values().filter(|conn| !conn.shed).count();
+        if active < capacity {
+            return false;
+        }
+        debug!("{} connections at capacity, shedding an idle one", active);
+        shed(&mut state, 1) == 1
+    }
+
+    /// Starts draining, after which no connection should be kept alive.
+    pub fn drain(&self) {
+        self.state.lock().unwrap().draining = true;
+    }
+
+    pub fn is_draining(&self) -> bool {
+        self.state.lock().unwrap().draining
+    }
+
+    /// How many connections are being handled.
+    pub fn active(&self) -> usize {
+        self.state.lock().unwrap().conns.len()
+    }
+}
+
+fn shed(state: &mut State, n: usize) -> usize {
+    let n = cmp::min(n, state.idle.len());
+    let shed = state.idle.drain(..n).collect::<Vec<_>>();
+    for (id, mut stream) in shed {
+        trace!("shedding idle connection {}", id);
+        if let Some(conn) = state.conns.get_mut(&id) {
+            conn.shed = true;
+        }
+        if let Err(e) = stream.close(Shutdown::Both) {
+            debug!("error shedding idle connection: {:?}", e);
+        }
+    }
+    n
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use mock::MockStream;
+    use server::clock::MockClock;
+    use super::{ConnState, IdleConnections};
+
+    #[test]
+    fn test_shed_least_recently_active() {
+        let idle = IdleConnections::new();
+        let (first, second, third) = (idle.start(addr()), idle.start(addr()), idle.start(addr()));
+        for &id in &[first, second, third] {
+            idle.enter(id, Box::new(MockStream::new()));
+        }
+        idle.leave(second);
+
+        assert_eq!(idle.shed(1), 1);
+        {
+            let state = idle.state.lock().unwrap();
+            assert_eq!(state.idle.iter().map(|&(id, _)| id).collect::<Vec<_>>(), vec![third]);
+        }
+        idle.leave(first);
+        assert_eq!(idle.shed(5), 1);
+        assert_eq!(idle.shed(5), 0);
+    }
+
+    fn addr() -> SocketAddr {
+        "127.0.0.1:1337".parse().unwrap()
+    }
+
+    #[test]
+    fn test_relieve_at_capacity() {
+        let idle = IdleConnections::new();
+        let first = idle.start(addr());
+        idle.enter(first, Box::new(MockStream::new()));
+        assert!(!idle.relieve(2));
+        assert_eq!(idle.state.lock().unwrap().idle.len(), 1);
+
+        let second = idle.start(addr());
+        idle.enter(second, Box::new(MockStream::new()));
+        assert!(idle.relieve(2));
+        assert_eq!(idle.state.lock().unwrap().idle.len(), 1);
+        // the shed one is on its way out, so there is room again
+        assert!(!idle.relieve(2));
+
+        idle.end(first);
+        idle.start(addr());
+        assert!(idle.relieve(2));
+        assert_eq!(idle.state.lock().unwrap().idle.len(), 0);
+    }
+
+    #[test]
+    fn test_dump() {
+        let clock = Arc::new(MockClock::new());
+        let idle = IdleConnections::with_clock(clock.clone());
+        let first = idle.start(addr());
+        clock.advance(Duration::from_secs(3));
+        let second = idle.start("127.0.0.1:1338".parse().unwrap());
+        idle.update(second, |conn| {
+            conn.state = ConnState::Handling;
+            conn.keep_alive = true;
+            conn.bytes_in = 40;
+        });
+
+        let dump = idle.dump();
+        assert_eq!(dump.len(), 2);
+        assert_eq!(dump[0].remote_addr, addr());
+        assert_eq!(dump[0].state, ConnState::ReadingHead);
+        assert_eq!(dump[0].age, Duration::from_secs(3));
+        assert_eq!(dump[1].age, Duration::from_secs(0));
+        assert_eq!(dump[1].state, ConnState::Handling);
+        assert!(dump[1].keep_alive);
+        assert_eq!(dump[1].bytes_in, 40);
+
+        idle.end(first);
+        idle.end(second);
+        assert!(idle.dump().is_empty());
+    }
+}
@@ -1,16 +1,22 @@
 use std::sync::{Arc, mpsc};
 use std::thread;
+use std::time::Duration;
+
+use libc;
 
 use net::NetworkListener;
+use Error;
 
 pub struct ListenerPool<A: NetworkListener> {
-    acceptor: A
+    acceptor: A,
+    backoff: Duration,
 }
 
 impl<A: NetworkListener + Send + 'static> ListenerPool<A> {
-    /// Create a thread pool to manage the acceptor.
-    pub fn new(acceptor: A) -> ListenerPool<A> {
-        ListenerPool { acceptor: acceptor }
+    /// Create a thread pool to manage the acceptor, pausing for `backoff`
+    /// after an accept error that isn't about a single connection.
+    pub fn new(acceptor: A, backoff: Duration) -> ListenerPool<A> {
+        ListenerPool { acceptor: acceptor, backoff: backoff }
     }
 
     /// Runs the acceptor pool. Blocks until the acceptors are closed.
@@ -28,18 +34,19 @@ impl<A: NetworkListener + Send + 'static> ListenerPool<A> {
 
         // Begin work.
         for _ in 0..threads {
-            spawn_with(super_tx.clone(), work.clone(), self.acceptor.clone())
+            spawn_with(super_tx.clone(), work.clone(), self.acceptor.clone(), self.backoff)
         }
 
         // Monitor for panics.
         // FIXME(reem): This won't ever exit since we still have a super_tx handle.
         for _ in supervisor_rx.iter() {
-            spawn_with(super_tx.clone(), work.clone(), self.acceptor.clone());
+            spawn_with(super_tx.clone(), work.clone(), self.acceptor.clone(), self.backoff);
         }
     }
 }
 
-fn spawn_with<A, F>(supervisor: mpsc::Sender<()>, work: Arc<F>, mut acceptor: A)
+fn spawn_with<A, F>(supervisor: mpsc::Sender<()>, work: Arc<F>, mut acceptor: A,
+                    backoff: Duration)
 where A: NetworkListener + Send + 'static,
       F: Fn(<A as NetworkListener>::Stream) + Send + Sync + 'static {
     thread::spawn(move || {
@@ -48,14 +55,46 @@ where A: NetworkListener + Send + 'static,
         loop {
             match acceptor.accept() {
                 Ok(stream) => work(stream),
-                Err(e) => {
+                Err(ref e) if is_connection_error(e) => {
                     error!("Connection failed: {}", e);
                 }
+                Err(e) => {
+                    // Errors such as running out of file descriptors last
+                    // until something else closes, so retrying at once would
+                    // only spin. Pausing also logs them once a pause.
+                    error!("Accept failed, pausing for {:?}: {}", backoff, e);
+                    thread::sleep(backoff);
+                }
             }
         }
     });
 }
 
+// Whether an accept error was about the connection being accepted, rather
+// than the listener, so the next accept may well succeed. Only running out
+// of descriptors or memory is the listener's; anything else, such as a
+// handshake that timed out or was cut short, is the connection's.
+fn is_connection_error(err: &Error) -> bool {
+    match *err {
+        Error::Io(ref e) => !e.raw_os_error().map_or(false, is_resource_error),
+        _ => true
+    }
+}
+
+#[cfg(unix)]
+fn is_resource_error(code: i32) -> bool {
+    match code {
+        libc::EMFILE | libc::ENFILE | libc::ENOBUFS | libc::ENOMEM => true,
+        _ => false
+    }
+}
+
+#[cfg(windows)]
+fn is_resource_error(code: i32) -> bool {
+    // WSAEMFILE and WSAENOBUFS
+    code == 10024 || code == 10055
+}
+
 struct Sentinel<T: Send + 'static> {
     value: Option<T>,
     supervisor: mpsc::Sender<T>,
@@ -77,3 +116,83 @@ impl<T: Send + 'static> Drop for Sentinel<T> {
     }
 }
 
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+    use std::net::SocketAddr;
+    use std::sync::{Arc, Mutex, mpsc};
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    use libc;
+
+    use mock::MockStream;
+    use net::NetworkListener;
+
+    use super::spawn_with;
+
+    // Fails to accept with `error` a few times, then waits forever.
+    #[derive(Clone)]
+    struct Failing {
+        error: fn() -> io::Error,
+        calls: Arc<Mutex<Vec<Instant>>>,
+    }
+
+    impl NetworkListener for Failing {
+        type Stream = MockStream;
+
+        fn accept(&mut self) -> ::Result<MockStream> {
+            if self.calls.lock().unwrap().len() == 3 {
+                loop {
+                    thread::park();
+                }
+            }
+            self.calls.lock().unwrap().push(Instant::now());
+            Err(::Error::Io((self.error)()))
+        }
+
+        fn local_addr(&mut self) -> io::Result<SocketAddr> {
+            Ok("127.0.0.1:1337".parse().unwrap())
+        }
+    }
+
+    fn accept_calls(error: fn() -> io::Error, backoff: Duration) -> Vec<Instant> {
+        let calls = Arc::new(Mutex::new(Vec::new()));
+        let listener = Failing { error: error, calls: calls.clone() };
+        let (tx, _rx) = mpsc::channel();
+        spawn_with(tx, Arc::new(|_| ()), listener, backoff);
+
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while calls.lock().unwrap().len() < 3 && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(5));
+        }
+        let calls = calls.lock().unwrap().clone();
+        calls
+    }
+
+    #[test]
+    fn test_accept_error_backs_off() {
+        let backoff = Duration::from_millis(50);
+        let calls = accept_calls(|| io::Error::from_raw_os_error(libc::EMFILE), backoff);
+        assert_eq!(calls.len(), 3);
+        for pair in calls.windows(2) {
+            assert!(pair[1] - pair[0] >= backoff, "retried after {:?}", pair[1] - pair[0]);
+        }
+    }
+
+    #[test]
+    fn test_connection_error_retried() {
+        let errors: &[fn() -> io::Error] = &[
+            || io::Error::new(io::ErrorKind::ConnectionAborted, "accept failed"),
+            || io::Error::new(io::ErrorKind::InvalidData, "bad handshake"),
+            || io::Error::new(io::ErrorKind::UnexpectedEof, "handshake cut short"),
+            || io::Error::new(io::ErrorKind::TimedOut, "handshake timed out"),
+        ];
+        for &error in errors {
+            let calls = accept_calls(error, Duration::from_secs(60));
+            // a pause would have kept this from seeing more than one
+            assert_eq!(calls.len(), 3, "{}", error());
+        }
+    }
+}
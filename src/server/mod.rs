@@ -107,35 +107,64 @@
 //! out by calling `start` on the `Response<Fresh>`. This will return a new
 //! `Response<Streaming>` object, that no longer has `headers_mut()`, but does
 //! implement `Write`.
+use std::ascii::AsciiExt;
+use std::borrow::Cow;
+use std::cell::{Cell, RefCell};
 use std::fmt;
-use std::io::{self, ErrorKind, BufWriter, Write};
-use std::net::{SocketAddr, ToSocketAddrs};
+use std::io::{self, ErrorKind, BufRead, BufWriter, Read, Write};
+use std::net::{Shutdown, SocketAddr, ToSocketAddrs};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::thread::{self, JoinHandle};
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 use num_cpus;
+use time::{now_utc, Tm};
 
-pub use self::request::Request;
+pub use self::digest::DigestAlgorithm;
+pub use self::extensions::Extensions;
+pub use self::idle::{ConnDebug, ConnState};
+pub use self::request::{BodyValue, Request, SpooledBody};
 pub use self::response::Response;
 
+pub use http::{RequestLeniency, RequestLimits};
 pub use net::{Fresh, Streaming};
 
 use Error;
 use buffer::BufReader;
-use header::{Headers, Expect, Connection};
+use header::{Headers, ContentLength, Expect, Connection, Host, RetryAfter, TransferEncoding};
+use header::is_token;
 use http;
 use method::Method;
-use net::{NetworkListener, NetworkStream, HttpListener, HttpsListener, Ssl};
+use net::{NetworkListener, NetworkStream, HttpListener, HttpsListener, Ssl, SslInfo};
+use net::{SslSessionOptions, TlsVersion};
 use status::StatusCode;
 use uri::RequestUri;
 use version::HttpVersion::Http11;
 
+use self::deadline::{DeadlineWriter, HeadDeadline};
+use self::forwarded::IpRange;
+use self::request::Scheme;
+use self::idle::IdleConnections;
 use self::listener::ListenerPool;
+use self::throttle::TokenBucket;
+use self::wheel::TimerWheel;
 
+pub mod cache;
+pub mod forwarded;
 pub mod request;
 pub mod response;
+pub mod shared;
+pub mod staticfile;
 
+mod clock;
+mod deadline;
+mod digest;
+mod extensions;
+mod idle;
 mod listener;
+mod throttle;
+mod wheel;
 
 /// A server can listen on a TCP socket.
 ///
@@ -145,13 +174,27 @@ mod listener;
 pub struct Server<L = HttpListener> {
     listener: L,
     timeouts: Timeouts,
+    options: Options,
 }
 
+/// How many seconds a closing connection waits for the client to stop
+/// sending.
+const LINGER_TIMEOUT_SECS: u64 = 2;
+/// The most bytes read and dropped from a closing connection.
+const LINGER_MAX_BYTES: usize = 1024 * 1024;
+/// The most bytes of a response held back to go out with the response to
+/// a pipelined request.
+const COALESCE_MAX_BYTES: usize = 16 * 1024;
+/// How often, in milliseconds, a server shedding idle connections checks
+/// for a connection waiting to be accepted.
+const SHED_POLL_MS: u64 = 10;
+
 #[derive(Clone, Copy, Debug)]
 struct Timeouts {
     read: Option<Duration>,
     write: Option<Duration>,
     keep_alive: Option<Duration>,
+    handler: Option<Duration>,
 }
 
 impl Default for Timeouts {
@@ -159,7 +202,57 @@ impl Default for Timeouts {
         Timeouts {
             read: None,
             write: None,
-            keep_alive: Some(Duration::from_secs(5))
+            keep_alive: Some(Duration::from_secs(5)),
+            handler: None,
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+struct Options {
+    clock: fn() -> Tm,
+    limits: RequestLimits,
+    cork: bool,
+    shed_idle: bool,
+    lenient_host: bool,
+    prefer_sni: bool,
+    preserve_raw_headers: bool,
+    reset_on_abuse: bool,
+    scheme: Scheme,
+    trust_proxy_headers: bool,
+    trusted_proxies: Arc<Vec<IpRange>>,
+    absolute_redirects: bool,
+    max_response_header_size: Option<usize>,
+    defer_content_length: bool,
+    retry_after: Option<fn(StatusCode) -> Option<Duration>>,
+    accept_backoff: Duration,
+    rate_limit: Option<u64>,
+    read_rate_limit: Option<u64>,
+    threads: Option<usize>,
+}
+
+impl Default for Options {
+    fn default() -> Options {
+        Options {
+            clock: now_utc,
+            limits: RequestLimits::default(),
+            cork: false,
+            shed_idle: false,
+            lenient_host: false,
+            prefer_sni: false,
+            preserve_raw_headers: false,
+            reset_on_abuse: false,
+            scheme: Scheme::Http,
+            trust_proxy_headers: false,
+            trusted_proxies: Arc::new(Vec::new()),
+            absolute_redirects: false,
+            max_response_header_size: None,
+            defer_content_length: false,
+            retry_after: None,
+            accept_backoff: Duration::from_millis(10),
+            rate_limit: None,
+            read_rate_limit: None,
+            threads: None,
         }
     }
 }
@@ -179,7 +272,8 @@ impl<L: NetworkListener> Server<L> {
     pub fn new(listener: L) -> Server<L> {
         Server {
             listener: listener,
-            timeouts: Timeouts::default()
+            timeouts: Timeouts::default(),
+            options: Options::default(),
         }
     }
 
@@ -205,6 +299,236 @@ impl<L: NetworkListener> Server<L> {
     pub fn set_write_timeout(&mut self, dur: Option<Duration>) {
         self.timeouts.write = dur;
     }
+
+    /// Sets how long a handler may take to start its response.
+    ///
+    /// The deadline starts when the request head has been read. If the
+    /// handler hasn't written anything by then, the request is answered with
+    /// a `503 Service Unavailable`, the connection is closed, and further
+    /// writes by the handler fail. The handler itself keeps running until it
+    /// returns, since it can't be interrupted.
+    ///
+    /// Default is no limit.
+    pub fn handler_timeout(&mut self, dur: Option<Duration>) {
+        self.timeouts.handler = dur;
+    }
+
+    /// Sets how many threads `handle` and `handle_router` accept and handle
+    /// connections on.
+    ///
+    /// Default is 5/4 of the number of CPUs.
+    pub fn threads(&mut self, threads: usize) {
+        self.options.threads = Some(threads);
+    }
+
+    /// Sets how long an accepting thread pauses after the listener fails.
+    ///
+    /// Some accept errors last until other connections close, such as
+    /// running out of file descriptors, so retrying at once would spin.
+    /// Errors about a single connection, such as one reset before it was
+    /// accepted, are retried without pausing.
+    ///
+    /// Default is 10ms.
+    pub fn accept_backoff(&mut self, pause: Duration) {
+        self.options.accept_backoff = pause;
+    }
+
+    /// Sets where the `Retry-After` of responses the server makes itself
+    /// comes from.
+    ///
+    /// The server answers some requests without the handler, such as with
+    /// a `503 Service Unavailable` once `handler_timeout` passes, or a
+    /// `400 Bad Request` for a malformed head. `provider` is asked with the
+    /// status of each, and a delay it returns is sent as a `Retry-After`,
+    /// in whole seconds.
+    ///
+    /// Default is no `Retry-After`.
+    pub fn retry_after(&mut self, provider: fn(StatusCode) -> Option<Duration>) {
+        self.options.retry_after = Some(provider);
+    }
+
+    /// Sets the clock used for the `Date` header of Responses.
+    ///
+    /// Pinning the clock makes responses reproducible, for instance in
+    /// snapshot tests.
+    ///
+    /// Default is `time::now_utc`.
+    pub fn set_date_clock(&mut self, clock: fn() -> Tm) {
+        self.options.clock = clock;
+    }
+
+    /// Controls whether flushes of a Response are deferred until its
+    /// handler returns.
+    ///
+    /// When enabled, the head and any writes are held in the connection's
+    /// write buffer, even if the handler calls `flush`, and are sent together
+    /// once the handler is done, or earlier if they outgrow the buffer. This
+    /// saves packets for responses built from many small writes, at the cost
+    /// of latency for handlers that flush on purpose to stream.
+    ///
+    /// Default is disabled.
+    pub fn cork_responses(&mut self, cork: bool) {
+        self.options.cork = cork;
+    }
+
+    /// Controls whether an idle keep-alive connection is closed when all
+    /// threads are busy.
+    ///
+    /// Each connection occupies a thread for as long as it is kept alive,
+    /// so idle clients can keep new connections from being accepted at all.
+    /// When enabled, a connection waiting to be accepted while every thread
+    /// is handling one causes the least recently active idle connection to
+    /// be closed, freeing its thread. Only listeners that can tell a
+    /// connection is waiting, such as `HttpListener` on unix, shed this way.
+    /// Idle connections can also be shed on demand with
+    /// `Listening::shed_idle`.
+    ///
+    /// Default is disabled.
+    pub fn shed_idle_at_capacity(&mut self, shed: bool) {
+        self.options.shed_idle = shed;
+    }
+
+    /// Controls whether requests with an ambiguous `Host` are accepted.
+    ///
+    /// As required by RFC 7230, a request is answered with a `400 Bad
+    /// Request` by default if it has more than one `Host` header, has none
+    /// but is HTTP/1.1, or has one that differs from the authority of an
+    /// absolute-form request-target, since proxies along the way may each
+    /// pick a different host. The check runs before the handler is called.
+    ///
+    /// When lenient, such requests are logged and accepted instead. The
+    /// authority of an absolute-form request-target replaces the `Host`,
+    /// and otherwise only the first `Host` is kept.
+    ///
+    /// Default is disabled.
+    pub fn lenient_host(&mut self, lenient: bool) {
+        self.options.lenient_host = lenient;
+    }
+
+    /// Controls whether the SNI server name of an SSL connection replaces
+    /// a `Host` header naming a different host.
+    ///
+    /// Handlers routing by `Host` then serve the host the certificate was
+    /// chosen for. The port of the `Host` is kept. Requests without a
+    /// `Host`, or over connections without SNI, are left alone.
+    ///
+    /// Default is disabled, so the `Host` header wins.
+    pub fn prefer_sni(&mut self, prefer: bool) {
+        self.options.prefer_sni = prefer;
+    }
+
+    /// Controls whether requests keep their header fields as they were
+    /// sent, for `Request::raw_headers`.
+    ///
+    /// This costs a copy of every header field, so is only worth it for
+    /// handlers that need the original order or repeated fields, such as
+    /// signature checks.
+    ///
+    /// Default is disabled.
+    pub fn preserve_raw_headers(&mut self, preserve: bool) {
+        self.options.preserve_raw_headers = preserve;
+    }
+
+    /// Controls whether requests believe the `Forwarded` and
+    /// `X-Forwarded-Proto` headers about the scheme they were made with.
+    ///
+    /// Like `Request::client_ip`, they are only believed from connections
+    /// from `trusted_proxies`, and only the part of them those proxies
+    /// added. See `Request::scheme_hint`.
+    ///
+    /// Default is disabled.
+    pub fn trust_proxy_headers(&mut self, trust: bool) {
+        self.options.trust_proxy_headers = trust;
+    }
+
+    /// Sets the address ranges of the proxies in front of this server.
+    ///
+    /// `Request::client_ip` only believes the `Forwarded` and
+    /// `X-Forwarded-For` headers of connections from these ranges, and
+    /// skips over them in the chain to find the client.
+    ///
+    /// Default is none.
+    pub fn trusted_proxies(&mut self, proxies: &[IpRange]) {
+        self.options.trusted_proxies = Arc::new(proxies.to_vec());
+    }
+
+    /// Controls whether abusive requests get their connection reset.
+    ///
+    /// When enabled, a request with an oversized head or malformed headers,
+    /// such as duplicate `Host` headers, is not answered: its connection is
+    /// closed with a TCP reset instead, so the socket is freed at once
+    /// rather than waiting out `TIME_WAIT`. See `NetworkStream::reset`.
+    ///
+    /// Default is disabled.
+    pub fn reset_on_abuse(&mut self, reset: bool) {
+        self.options.reset_on_abuse = reset;
+    }
+
+    /// Controls whether `Response::redirect` sends an absolute `Location`.
+    ///
+    /// When enabled, redirect locations are resolved against the
+    /// `effective_url` of the request, for clients that don't accept
+    /// relative ones.
+    ///
+    /// Default is disabled.
+    pub fn absolute_redirects(&mut self, absolute: bool) {
+        self.options.absolute_redirects = absolute;
+    }
+
+    /// Sets the most bytes the header fields of a response may take up.
+    ///
+    /// Starting a response whose header fields are larger fails, so a
+    /// handler that sets far too many or too large headers by mistake gets
+    /// an error instead of sending them. See
+    /// `Response::set_max_header_size`.
+    ///
+    /// Default is no limit.
+    pub fn max_response_header_size(&mut self, max: Option<usize>) {
+        self.options.max_response_header_size = max;
+    }
+
+    /// Sends a `Content-Length` with small bodies written in full after
+    /// `Response::start`, instead of chunking them.
+    ///
+    /// Each started response holds its head back while up to 64KB of body
+    /// is buffered, as `Response::set_defer_length` describes. A handler
+    /// that streams should flush to send what it has written so far.
+    ///
+    /// Default is disabled.
+    pub fn defer_content_length(&mut self, enabled: bool) {
+        self.options.defer_content_length = enabled;
+    }
+
+    /// Limits how many bytes of response bodies a second are sent on each
+    /// connection.
+    ///
+    /// The limit covers every response on a kept-alive connection
+    /// together. Writes are paced by sleeping, so a throttled connection
+    /// takes up its thread, but not the CPU. A handler can give its
+    /// response a limit of its own with `Response::set_rate_limit`.
+    ///
+    /// Default is no limit.
+    pub fn per_connection_rate_limit(&mut self, bytes_per_sec: Option<u64>) {
+        self.options.rate_limit = bytes_per_sec;
+    }
+
+    /// Limits how many bytes of request bodies a second are read on each
+    /// connection, like `per_connection_rate_limit` does for responses.
+    ///
+    /// A handler can give its request a limit of its own with
+    /// `Request::set_read_rate_limit`.
+    ///
+    /// Default is no limit.
+    pub fn per_connection_read_rate_limit(&mut self, bytes_per_sec: Option<u64>) {
+        self.options.read_rate_limit = bytes_per_sec;
+    }
+
+    /// Sets the limits on the size of request heads.
+    ///
+    /// Default is `RequestLimits::default()`.
+    pub fn limits(&mut self, limits: RequestLimits) {
+        self.options.limits = limits;
+    }
 }
 
 impl Server<HttpListener> {
@@ -219,31 +543,116 @@ impl<S: Ssl + Clone + Send> Server<HttpsListener<S>> {
     ///
     /// You can use any SSL implementation, as long as implements `hyper::net::Ssl`.
     pub fn https<A: ToSocketAddrs>(addr: A, ssl: S) -> ::Result<Server<HttpsListener<S>>> {
-        HttpsListener::new(addr, ssl).map(Server::new)
+        HttpsListener::new(addr, ssl).map(|listener| {
+            let mut server = Server::new(listener);
+            server.options.scheme = Scheme::Https;
+            server
+        })
+    }
+
+    /// Configure how SSL sessions with clients are resumed and
+    /// renegotiated.
+    pub fn set_ssl_session_options(&mut self, options: &SslSessionOptions) -> ::Result<()> {
+        self.listener.set_session_options(options)
+    }
+
+    /// Refuse handshakes with clients offering only versions of TLS older
+    /// than `version`, such as to require TLS 1.2.
+    ///
+    /// Returns an error if the `Ssl` can't enforce a minimum. Default is
+    /// whatever versions the `Ssl` allows.
+    pub fn min_tls_version(&mut self, version: TlsVersion) -> ::Result<()> {
+        self.listener.set_min_version(version)
     }
 }
 
 impl<L: NetworkListener + Send + 'static> Server<L> {
     /// Binds to a socket and starts handling connections.
     pub fn handle<H: Handler + 'static>(self, handler: H) -> ::Result<Listening> {
-        self.handle_threads(handler, num_cpus::get() * 5 / 4)
+        let threads = self.thread_count();
+        self.handle_threads(handler, threads)
     }
 
     /// Binds to a socket and starts handling connections with the provided
-    /// number of threads.
+    /// number of threads, in place of the number set with `threads`.
     pub fn handle_threads<H: Handler + 'static>(self, handler: H,
             threads: usize) -> ::Result<Listening> {
-        handle(self, handler, threads)
+        handle(self, handler, None, threads)
+    }
+
+    /// Binds to a socket and starts handling connections, picking the
+    /// handler of each request with a `Router`.
+    ///
+    /// The router is called with the method and path of each request once
+    /// its head is read. Requests it returns `None` for are answered with a
+    /// `404 Not Found`.
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// use hyper::method::Method;
+    /// use hyper::server::{Handler, Request, Response, Server};
+    ///
+    /// fn index(_: Request, res: Response) {
+    ///     res.send(b"index").unwrap();
+    /// }
+    ///
+    /// Server::http("0.0.0.0:0").unwrap().handle_router(|method: &Method, path: &str| {
+    ///     match (method, path) {
+    ///         (&Method::Get, "/") => Some(Box::new(index) as Box<Handler>),
+    ///         _ => None
+    ///     }
+    /// }).unwrap();
+    /// ```
+    pub fn handle_router<R>(self, router: R) -> ::Result<Listening>
+    where R: Fn(&Method, &str) -> Option<Box<Handler>> + Send + Sync + 'static {
+        let threads = self.thread_count();
+        handle(self, not_found, Some(Box::new(router)), threads)
+    }
+
+    fn thread_count(&self) -> usize {
+        self.options.threads.unwrap_or(num_cpus::get() * 5 / 4)
     }
 }
 
-fn handle<H, L>(mut server: Server<L>, handler: H, threads: usize) -> ::Result<Listening>
+/// Picks the handler for a request, by its method and path.
+///
+/// See `Server::handle_router`.
+pub type Router = Fn(&Method, &str) -> Option<Box<Handler>> + Send + Sync;
+
+fn not_found(_: Request, mut res: Response<Fresh>) {
+    *res.status_mut() = StatusCode::NotFound;
+    if let Err(e) = res.send(b"") {
+        debug!("error sending 404: {:?}", e);
+    }
+}
+
+fn handle<H, L>(mut server: Server<L>, handler: H, router: Option<Box<Router>>,
+                threads: usize) -> ::Result<Listening>
 where H: Handler + 'static, L: NetworkListener + Send + 'static {
     let socket = try!(server.listener.local_addr());
 
     debug!("threads = {:?}", threads);
-    let pool = ListenerPool::new(server.listener);
-    let worker = Worker::new(handler, server.timeouts);
+    let shed_idle = server.options.shed_idle;
+    let mut pending = server.listener.clone();
+    let pool = ListenerPool::new(server.listener, server.options.accept_backoff);
+    let mut worker = Worker::new(handler, server.timeouts, server.options);
+    worker.router = router;
+    let idle = worker.idle.clone();
+    let stop_shedding = Arc::new(AtomicBool::new(false));
+    if shed_idle {
+        let idle = idle.clone();
+        let stop = stop_shedding.clone();
+        thread::spawn(move || {
+            while !stop.load(Ordering::SeqCst) {
+                thread::sleep(Duration::from_millis(SHED_POLL_MS));
+                if pending.has_pending() {
+                    idle.relieve(threads);
+                }
+            }
+            debug!("stopped shedding idle connections");
+        });
+    }
     let work = move |mut stream| worker.handle_connection(&mut stream);
 
     let guard = thread::spawn(move || pool.accept(work, threads));
@@ -251,25 +660,43 @@ where H: Handler + 'static, L: NetworkListener + Send + 'static {
     Ok(Listening {
         _guard: Some(guard),
         socket: socket,
+        idle: idle,
+        stop_shedding: stop_shedding,
     })
 }
 
 struct Worker<H: Handler + 'static> {
     handler: H,
     timeouts: Timeouts,
+    options: Options,
+    idle: Arc<IdleConnections>,
+    router: Option<Box<Router>>,
+    timers: TimerWheel,
 }
 
 impl<H: Handler + 'static> Worker<H> {
-    fn new(handler: H, timeouts: Timeouts) -> Worker<H> {
+    fn new(handler: H, timeouts: Timeouts, options: Options) -> Worker<H> {
         Worker {
             handler: handler,
             timeouts: timeouts,
+            options: options,
+            idle: Arc::new(IdleConnections::new()),
+            router: None,
+            timers: TimerWheel::new(),
         }
     }
 
     fn handle_connection<S>(&self, mut stream: &mut S) where S: NetworkStream + Clone {
         debug!("Incoming stream");
 
+        if self.idle.is_draining() {
+            debug!("draining, closing new connection");
+            if let Err(e) = stream.close(Shutdown::Both) {
+                debug!("error closing connection: {:?}", e);
+            }
+            return;
+        }
+
         self.handler.on_connection_start();
 
         if let Err(e) = self.set_timeouts(&*stream) {
@@ -285,23 +712,67 @@ impl<H: Handler + 'static> Worker<H> {
             }
         };
 
+        let local = match stream.local_addr() {
+            Ok(local) => Some(local),
+            Err(e) => {
+                debug!("local_addr error: {:?}", e);
+                None
+            }
+        };
+
+        let ssl_info = stream.ssl_info().map(Arc::new);
+
         // FIXME: Use Type ascription
         let stream_clone: &mut NetworkStream = &mut stream.clone();
         let mut rdr = BufReader::new(stream_clone);
-        let mut wrt = BufWriter::new(stream);
+        let control = stream.clone();
+        let mut wrt = BufWriter::new(Counted { inner: stream, written: 0 });
 
-        while self.keep_alive_loop(&mut rdr, &mut wrt, addr) {
+        let rates = Rates {
+            read: self.options.read_rate_limit.map(|rate| RefCell::new(TokenBucket::new(rate))),
+            write: self.options.rate_limit.map(|rate| RefCell::new(TokenBucket::new(rate))),
+        };
+        let conn = self.idle.start(addr);
+        while self.keep_alive_loop(&mut rdr, &mut wrt, addr, local, ssl_info.as_ref(), conn,
+                                   &rates, &control) {
+            let (read, written) = (rdr.total_read(), wrt.get_ref().written);
+            self.idle.update(conn, |conn| {
+                conn.state = ConnState::Idle;
+                conn.bytes_in = read;
+                conn.bytes_out = written;
+            });
             if let Err(e) = self.set_read_timeout(*rdr.get_ref(), self.timeouts.keep_alive) {
                 error!("set_read_timeout keep_alive {:?}", e);
                 break;
             }
+            if rdr.get_buf().is_empty() && !self.wait_idle(&mut rdr, conn, &control) {
+                break;
+            }
+            self.idle.update(conn, |conn| conn.state = ConnState::ReadingHead);
         }
+        self.idle.end(conn);
 
         self.handler.on_connection_end();
 
         debug!("keep_alive loop ending for {}", addr);
     }
 
+    // Waits for the next request on a kept-alive connection, which may be
+    // shed in the meantime. Returns false if the connection is done.
+    fn wait_idle<S>(&self, rdr: &mut BufReader<&mut NetworkStream>, conn: u64, stream: &S)
+            -> bool where S: NetworkStream + Clone {
+        self.idle.enter(conn, Box::new(stream.clone()));
+        let res = rdr.read_into_buf().map(|n| n > 0);
+        self.idle.leave(conn);
+        match res {
+            Ok(more) => more,
+            Err(e) => {
+                debug!("error waiting on idle connection: {:?}", e);
+                false
+            }
+        }
+    }
+
     fn set_timeouts(&self, s: &NetworkStream) -> io::Result<()> {
         try!(self.set_read_timeout(s, self.timeouts.read));
         self.set_write_timeout(s, self.timeouts.write)
@@ -315,9 +786,24 @@ impl<H: Handler + 'static> Worker<H> {
         s.set_read_timeout(timeout)
     }
 
-    fn keep_alive_loop<W: Write>(&self, mut rdr: &mut BufReader<&mut NetworkStream>,
-            wrt: &mut W, addr: SocketAddr) -> bool {
-        let req = match Request::new(rdr, addr) {
+    fn keep_alive_loop<W, S>(&self, mut rdr: &mut BufReader<&mut NetworkStream>,
+            wrt: &mut W, addr: SocketAddr, local: Option<SocketAddr>,
+            ssl_info: Option<&Arc<SslInfo>>, conn: u64, rates: &Rates, stream: &S) -> bool
+    where W: Write, S: NetworkStream + Clone {
+        if starts_tls_handshake(rdr) {
+            debug!("TLS handshake from {} on a plain HTTP server, closing", addr);
+            return false;
+        }
+
+        // raised by the request and response if they don't reach their end
+        let unread = Cell::new(false);
+        let unfinished = Cell::new(false);
+        let parse = if self.options.preserve_raw_headers {
+            Request::with_raw_headers
+        } else {
+            Request::with_limits
+        };
+        let mut req = match parse(rdr, addr, &self.options.limits) {
             Ok(req) => req,
             Err(Error::Io(ref e)) if e.kind() == ErrorKind::ConnectionAborted => {
                 trace!("tcp closed, cancelling keep-alive loop");
@@ -328,13 +814,50 @@ impl<H: Handler + 'static> Worker<H> {
                 return false;
             }
             Err(e) => {
-                //TODO: send a 400 response
                 error!("request error = {:?}", e);
+                self.reject(wrt, stream, &e);
                 return false;
             }
         };
 
-        if !self.handle_expect(&req, wrt) {
+        req.set_scheme(self.options.scheme);
+        req.set_local_addr(local);
+        req.set_ssl_info(ssl_info.cloned());
+        req.set_trust_proxy_headers(self.options.trust_proxy_headers);
+        req.set_trusted_proxies(self.options.trusted_proxies.clone());
+        if let Some(ref bucket) = rates.read {
+            req.set_connection_read_rate_limit(bucket);
+        }
+        let draining = self.idle.is_draining();
+        req.set_server_draining(draining);
+        req.set_unread_flag(&unread);
+
+        // a body without a final chunked coding runs to the end of the
+        // connection, which can't be told apart from the next request
+        if !req.headers.has::<ContentLength>() && req.headers.has::<TransferEncoding>() &&
+                !req.is_chunked() {
+            debug!("unsupported Transfer-Encoding {:?}", req.headers.get::<TransferEncoding>());
+            self.send_status(wrt, StatusCode::NotImplemented);
+            return false;
+        }
+        if let Err(e) = self.check_host(&mut req) {
+            self.reject(wrt, stream, &e);
+            return false;
+        }
+        if self.options.prefer_sni {
+            prefer_sni(&mut req);
+        }
+
+        let routed = self.router.as_ref().map(|router| {
+            router(&req.method, &*route_path(&req.uri))
+        });
+        let handler: &Handler = match routed {
+            Some(Some(ref handler)) => &**handler,
+            Some(None) => &not_found,
+            None => &self.handler,
+        };
+
+        if !self.handle_expect(handler, &req, wrt) {
             return false;
         }
 
@@ -343,32 +866,235 @@ impl<H: Handler + 'static> Worker<H> {
             return false;
         }
 
-        let mut keep_alive = self.timeouts.keep_alive.is_some() &&
+        let has_body = req.content_length().map_or(false, |len| len > 0) || req.is_chunked();
+        // the whole head of the next request is already here, so its
+        // response can share a write with this one
+        let pipelined = !has_body &&
+            req.buffered_input().windows(4).any(|w| w == b"\r\n\r\n");
+        let keep_alive = self.timeouts.keep_alive.is_some() && !draining &&
             http::should_keep_alive(req.version, &req.headers);
         let version = req.version;
+        self.idle.update(conn, |conn| {
+            conn.state = ConnState::Handling;
+            conn.keep_alive = keep_alive;
+        });
         let mut res_headers = Headers::new();
         if !keep_alive {
             res_headers.set(Connection::close());
         }
+        let redirect_base = if self.options.absolute_redirects {
+            req.effective_url()
+        } else {
+            None
+        };
+        let accepts_trailers = req.accepts_trailers();
+        // the deadline answers on the stream itself, so a response still
+        // held for coalescing must go out before it could be overtaken
+        if self.timeouts.handler.is_some() {
+            if let Err(e) = wrt.flush() {
+                debug!("error flushing held response: {:?}", e);
+                return false;
+            }
+        }
+        let deadline = self.timeouts.handler.map(|timeout| {
+            HeadDeadline::arm(&self.timers, Box::new(stream.clone()), timeout, version,
+                              self.options.clock,
+                              self.retry_after(StatusCode::ServiceUnavailable))
+        });
         {
-            let mut res = Response::new(wrt, &mut res_headers);
-            res.version = version;
-            self.handler.handle(req, res);
+            let mut wrt = Cork {
+                inner: &mut *wrt,
+                corked: self.options.cork || pipelined,
+                written: 0,
+            };
+            {
+                let mut wrt = DeadlineWriter {
+                    inner: &mut wrt,
+                    deadline: deadline.as_ref(),
+                    started: false,
+                };
+                let mut res = Response::new(&mut wrt, &mut res_headers);
+                res.version = version;
+                res.set_request_version(version);
+                res.set_request_accepts_trailers(accepts_trailers);
+                res.set_date_clock(self.options.clock);
+                res.set_redirect_base(redirect_base);
+                res.set_max_header_size(self.options.max_response_header_size);
+                if let Some(ref bucket) = rates.write {
+                    res.set_connection_rate_limit(bucket);
+                }
+                res.set_defer_length(self.options.defer_content_length);
+                res.set_unfinished_flag(&unfinished);
+                handler.handle(req, res);
+            }
+            if deadline.as_ref().map_or(false, |deadline| deadline.timed_out()) {
+                return false;
+            }
+            if pipelined && keep_alive && wrt.written <= COALESCE_MAX_BYTES {
+                trace!("holding {} bytes for the next response", wrt.written);
+            } else if let Err(e) = wrt.uncork() {
+                debug!("error flushing corked response: {:?}", e);
+                return false;
+            }
         }
 
         // if the request was keep-alive, we need to check that the server agrees
         // if it wasn't, then the server cannot force it to be true anyways
-        if keep_alive {
-            keep_alive = http::should_keep_alive(version, &res_headers);
+        let wanted = keep_alive && http::should_keep_alive(version, &res_headers);
+        let keep_alive = reuse_connection(wanted, !unread.get(), !unfinished.get());
+
+        // Bytes sent after a request without a body are the next request,
+        // unless the client sent a body anyway. Reading such a body as
+        // requests would serve whatever it smuggles in.
+        if keep_alive && !has_body && !starts_request_line(rdr.get_buf(), &self.options.limits.leniency) {
+            debug!("unframed bytes after a request from {}, closing", addr);
+            self.send_status(wrt, StatusCode::BadRequest);
+            return false;
         }
 
         debug!("keep_alive = {:?} for {}", keep_alive, addr);
+        if !keep_alive && unread.get() {
+            if let Err(e) = wrt.flush() {
+                debug!("error flushing response before closing: {:?}", e);
+                return false;
+            }
+            self.idle.update(conn, |conn| {
+                conn.state = ConnState::Lingering;
+                conn.keep_alive = false;
+            });
+            self.linger(rdr, stream);
+        }
         keep_alive
     }
 
-    fn handle_expect<W: Write>(&self, req: &Request, wrt: &mut W) -> bool {
+    // Closing a socket with unread data resets the connection, which can
+    // make the client lose the response while it is still sending a body
+    // the handler didn't read. So the writing side is shut first, and what
+    // the client still sends is read and dropped, within limits.
+    fn linger<S>(&self, rdr: &mut BufReader<&mut NetworkStream>, stream: &S)
+    where S: NetworkStream + Clone {
+        if let Err(e) = stream.clone().close(Shutdown::Write) {
+            debug!("error shutting down writes: {:?}", e);
+            return;
+        }
+        let timeout = Duration::from_secs(LINGER_TIMEOUT_SECS);
+        if let Err(e) = self.set_read_timeout(*rdr.get_ref(), Some(timeout)) {
+            debug!("set_read_timeout linger {:?}", e);
+            return;
+        }
+
+        let deadline = Instant::now() + timeout;
+        let mut buf = [0; 4096];
+        let mut drained = 0;
+        while drained < LINGER_MAX_BYTES && Instant::now() < deadline {
+            match rdr.get_mut().read(&mut buf) {
+                Ok(0) => break,
+                Ok(n) => drained += n,
+                Err(e) => {
+                    debug!("error lingering: {:?}", e);
+                    break;
+                }
+            }
+        }
+        trace!("lingered over {} bytes", drained);
+    }
+
+    fn check_host(&self, req: &mut Request) -> ::Result<()> {
+        let authority = match req.uri {
+            RequestUri::AbsoluteUri(ref url) => url.serialize_host().map(|host| {
+                match url.port() {
+                    Some(port) => format!("{}:{}", host, port),
+                    None => host
+                }
+            }),
+            _ => None
+        };
+        // the url drops a port that is the scheme's default, which the Host
+        // header may still spell out
+        let defaulted = match req.uri {
+            RequestUri::AbsoluteUri(ref url) if url.port().is_none() => {
+                match (authority.as_ref(), url.port_or_default()) {
+                    (Some(authority), Some(port)) => Some(format!("{}:{}", authority, port)),
+                    _ => None
+                }
+            },
+            _ => None
+        };
+        let problem = match (req.headers.get_raw("Host"), authority.as_ref()) {
+            (Some(raw), _) if raw.len() > 1 => "several Host headers",
+            // the url lowercases the host, so compare without case
+            (Some(raw), Some(authority)) if !raw[0].eq_ignore_ascii_case(authority.as_bytes()) &&
+                !defaulted.map_or(false, |d| raw[0].eq_ignore_ascii_case(d.as_bytes())) => {
+                "a Host header differing from the request-target"
+            },
+            (None, _) if req.version >= Http11 => "no Host header",
+            _ => return Ok(())
+        };
+        if !self.options.lenient_host {
+            debug!("rejecting request with {}", problem);
+            return Err(Error::Header);
+        }
+        debug!("accepting request with {}", problem);
+        let host = match authority {
+            Some(authority) => authority.into_bytes(),
+            None => match req.headers.get_raw("Host") {
+                Some(raw) => raw[0].clone(),
+                None => return Ok(())
+            }
+        };
+        req.headers.set_raw("Host", vec![host]);
+        Ok(())
+    }
+
+    fn reject<W, S>(&self, wrt: &mut W, stream: &S, err: &Error)
+    where W: Write, S: NetworkStream + Clone {
+        let abusive = match *err {
+            Error::UriTooLong | Error::TooLarge | Error::Header => true,
+            _ => false,
+        };
+        if !(abusive && self.options.reset_on_abuse) {
+            return self.send_error(wrt, err);
+        }
+        debug!("resetting connection after {:?}", err);
+        if let Err(e) = stream.clone().reset() {
+            debug!("error resetting connection: {:?}", e);
+        }
+    }
+
+    // How long the client should wait before retrying a request the server
+    // answered with `status` itself.
+    fn retry_after(&self, status: StatusCode) -> Option<Duration> {
+        self.options.retry_after.and_then(|provider| provider(status))
+    }
+
+    fn send_error<W: Write>(&self, wrt: &mut W, err: &Error) {
+        let status = match *err {
+            Error::UriTooLong => StatusCode::UriTooLong,
+            Error::TooLarge => StatusCode::RequestHeaderFieldsTooLarge,
+            Error::Version => StatusCode::HttpVersionNotSupported,
+            _ => StatusCode::BadRequest,
+        };
+        self.send_status(wrt, status);
+    }
+
+    // Sends an empty response with `status` and closes the connection.
+    fn send_status<W: Write>(&self, wrt: &mut W, status: StatusCode) {
+        let mut headers = Headers::new();
+        headers.set(Connection::close());
+        if let Some(delay) = self.retry_after(status) {
+            headers.set(RetryAfter::Delay(delay));
+        }
+        let mut res = Response::new(wrt, &mut headers);
+        *res.status_mut() = status;
+        res.set_date_clock(self.options.clock);
+        if let Err(e) = res.send(b"") {
+            debug!("error writing {} response: {:?}", status, e);
+        }
+    }
+
+    fn handle_expect<W: Write>(&self, handler: &Handler, req: &Request, wrt: &mut W) -> bool {
          if req.version == Http11 && req.headers.get() == Some(&Expect::Continue) {
-            let status = self.handler.check_continue((&req.method, &req.uri, &req.headers));
+            let status = handler.check_continue((&req.method, &req.uri, &req.headers));
             match write!(wrt, "{} {}\r\n\r\n", Http11, status).and_then(|_| wrt.flush()) {
                 Ok(..) => (),
                 Err(e) => {
@@ -387,11 +1113,157 @@ impl<H: Handler + 'static> Worker<H> {
     }
 }
 
+// Whether the next bytes are a TLS handshake record, from a client that
+// expected HTTPS. Parsing them as a request line would only produce noise.
+fn starts_tls_handshake<R: BufRead>(rdr: &mut R) -> bool {
+    rdr.fill_buf().map(|buf| buf.starts_with(&[0x16, 0x03])).unwrap_or(false)
+}
+
+// Whether a connection can take another request once an exchange on it is
+// over. Both sides must have wanted it kept alive, and the request must
+// have been read and the response written to their ends, else the next
+// bytes on it aren't the start of the next message.
+fn reuse_connection(wanted: bool, read_done: bool, written_done: bool) -> bool {
+    if wanted && !(read_done && written_done) {
+        debug!("closing a kept-alive connection, read_done={}, written_done={}",
+               read_done, written_done);
+    }
+    wanted && read_done && written_done
+}
+
+// Whether `buf` could start a request line. Only the method can be checked
+// until the whole line has arrived.
+fn starts_request_line(buf: &[u8], leniency: &RequestLeniency) -> bool {
+    // empty lines before a request line are skipped, as when parsing it
+    let (lines, start) = http::h1::leading_empty_lines(buf);
+    if lines > leniency.max_leading_empty_lines {
+        return false;
+    }
+    let buf = &buf[start..];
+    if buf == b"\r" {
+        // the rest of an empty line is still to come
+        return true;
+    }
+    let line = match buf.iter().position(|&b| b == b'\n') {
+        Some(end) => &buf[..end],
+        None => return match buf.iter().position(|&b| !is_token(b)) {
+            Some(end) => end > 0 && (buf[end] == b' ' ||
+                                     leniency.extra_whitespace && buf[end] == b'\t'),
+            None => true
+        }
+    };
+    let line = if line.ends_with(b"\r") { &line[..line.len() - 1] } else { line };
+    let mut parts = line.split(|&b| b == b' ' || leniency.extra_whitespace && b == b'\t')
+                        .filter(|part| !leniency.extra_whitespace || !part.is_empty());
+    match (parts.next(), parts.next(), parts.next(), parts.next()) {
+        (Some(method), Some(target), Some(version), None) => {
+            !method.is_empty() && method.iter().all(|&b| is_token(b)) &&
+                !target.is_empty() && version.starts_with(b"HTTP/")
+        },
+        _ => false
+    }
+}
+
+// Replaces a Host naming another host than the SNI server name, keeping
+// its port.
+fn prefer_sni(req: &mut Request) {
+    let name = match req.ssl_info().and_then(|info| info.server_name.clone()) {
+        Some(name) => name,
+        None => return
+    };
+    let port = match req.headers.get::<Host>() {
+        Some(host) if !host.hostname.eq_ignore_ascii_case(&name) => host.port,
+        _ => return
+    };
+    debug!("Host differs from SNI server name {:?}, using the server name", name);
+    req.headers.set(Host { hostname: name, port: port });
+}
+
+// The path of a request-target, as given to a `Router`.
+fn route_path(uri: &RequestUri) -> Cow<str> {
+    match *uri {
+        RequestUri::AbsolutePath(ref path) => Cow::Borrowed(path.splitn(2, '?').next().unwrap()),
+        RequestUri::AbsoluteUri(ref url) => {
+            Cow::Owned(url.serialize_path().unwrap_or_else(|| "/".to_owned()))
+        }
+        RequestUri::Authority(ref authority) => Cow::Borrowed(authority),
+        RequestUri::Star => Cow::Borrowed("*"),
+    }
+}
+
+// The buckets pacing the bodies of a connection, which last across its
+// requests, so keep-alive can't be used to get a fresh allowance.
+struct Rates {
+    read: Option<RefCell<TokenBucket>>,
+    write: Option<RefCell<TokenBucket>>,
+}
+
+// Counts the bytes written to a connection.
+struct Counted<W> {
+    inner: W,
+    written: u64,
+}
+
+impl<W: Write> Write for Counted<W> {
+    #[inline]
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = try!(self.inner.write(buf));
+        self.written += n as u64;
+        Ok(n)
+    }
+
+    #[inline]
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+// Defers flushes until the Response is done, so the head and small
+// writes go out together.
+struct Cork<W> {
+    inner: W,
+    corked: bool,
+    written: usize,
+}
+
+impl<W: Write> Cork<W> {
+    fn uncork(&mut self) -> io::Result<()> {
+        if self.corked {
+            self.corked = false;
+            self.inner.flush()
+        } else {
+            Ok(())
+        }
+    }
+}
+
+impl<W: Write> Write for Cork<W> {
+    #[inline]
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = try!(self.inner.write(buf));
+        self.written += n;
+        Ok(n)
+    }
+
+    #[inline]
+    fn flush(&mut self) -> io::Result<()> {
+        if self.corked {
+            Ok(())
+        } else {
+            self.inner.flush()
+        }
+    }
+}
+
 /// A listening server, which can later be closed.
 pub struct Listening {
     _guard: Option<JoinHandle<()>>,
     /// The socket addresses that the server is bound to.
     pub socket: SocketAddr,
+    idle: Arc<IdleConnections>,
+    // Ends the thread shedding idle connections, which holds a clone of
+    // the listener.
+    stop_shedding: Arc<AtomicBool>,
 }
 
 impl fmt::Debug for Listening {
@@ -413,9 +1285,65 @@ impl Listening {
     /// Stop the server from listening to its socket address.
     pub fn close(&mut self) -> ::Result<()> {
         let _ = self._guard.take();
+        self.stop_shedding.store(true, Ordering::SeqCst);
         debug!("closing server");
         Ok(())
     }
+
+    /// Closes up to `n` idle keep-alive connections, least recently active
+    /// first, returning how many were closed.
+    ///
+    /// This frees their threads for new connections, for instance when the
+    /// process is running low on memory or file descriptors.
+    pub fn shed_idle(&self, n: usize) -> usize {
+        self.idle.shed(n)
+    }
+
+    /// Starts draining the server, so it can be stopped without cutting
+    /// off requests.
+    ///
+    /// New connections are closed as soon as they are accepted. Requests
+    /// on connections already open are still handled, but every response
+    /// closes its connection, so clients move to another server. Idle
+    /// connections close once their keep-alive timeout passes, or sooner
+    /// with `shed_idle`.
+    pub fn drain(&self) {
+        debug!("draining server");
+        self.idle.drain();
+        // connections aren't kept anymore, so none need to be shed
+        self.stop_shedding.store(true, Ordering::SeqCst);
+    }
+
+    /// Snapshots the connections being handled, oldest first, such as to
+    /// find the ones stuck on a slow client.
+    ///
+    /// Connections report what they are doing as they move from one request
+    /// to the next, so taking a snapshot doesn't wait on any of them.
+    pub fn dump_connections(&self) -> Vec<ConnDebug> {
+        self.idle.dump()
+    }
+
+    /// Whether the server is running, draining, or done draining.
+    pub fn state(&self) -> ServerState {
+        if !self.idle.is_draining() {
+            ServerState::Running
+        } else if self.idle.active() > 0 {
+            ServerState::Draining
+        } else {
+            ServerState::Stopped
+        }
+    }
+}
+
+/// The lifecycle of a `Server`, as reported by `Listening::state`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ServerState {
+    /// Handling connections.
+    Running,
+    /// `Listening::drain` was called, and some connections are still open.
+    Draining,
+    /// Drained, with no connections left open.
+    Stopped,
 }
 
 /// A handler that can handle incoming requests for a server.
@@ -452,13 +1380,18 @@ impl<F> Handler for F where F: Fn(Request, Response<Fresh>), F: Sync + Send {
 
 #[cfg(test)]
 mod tests {
-    use header::Headers;
+    use std::sync::{Arc, Mutex};
+
+    use header::{Headers, HeaderFormatter, Host};
     use method::Method;
     use mock::MockStream;
+    use net::SslInfo;
     use status::StatusCode;
     use uri::RequestUri;
 
-    use super::{Request, Response, Fresh, Handler, Worker};
+    use super::{Request, Response, Fresh, Handler, Worker, Options, RequestLimits, RequestLeniency,
+                Timeouts};
+    use super::clock::MockClock;
 
     #[test]
     fn test_check_continue_default() {
@@ -475,7 +1408,7 @@ mod tests {
             res.start().unwrap().end().unwrap();
         }
 
-        Worker::new(handle, Default::default()).handle_connection(&mut mock);
+        Worker::new(handle, Default::default(), Default::default()).handle_connection(&mut mock);
         let cont = b"HTTP/1.1 100 Continue\r\n\r\n";
         assert_eq!(&mock.write[..cont.len()], cont);
         let res = b"HTTP/1.1 200 OK\r\n";
@@ -504,7 +1437,1659 @@ mod tests {
             1234567890\
         ");
 
-        Worker::new(Reject, Default::default()).handle_connection(&mut mock);
+        Worker::new(Reject, Default::default(), Default::default()).handle_connection(&mut mock);
         assert_eq!(mock.write, &b"HTTP/1.1 417 Expectation Failed\r\n\r\n"[..]);
     }
+
+    #[test]
+    fn test_http10_close_delimited() {
+        use std::io::Write;
+
+        let mut mock = MockStream::with_input(b"\
+            GET / HTTP/1.0\r\n\
+            Connection: keep-alive\r\n\
+            \r\n\
+            GET / HTTP/1.0\r\n\
+            Connection: keep-alive\r\n\
+            \r\n\
+        ");
+
+        fn handle(_: Request, res: Response<Fresh>) {
+            let mut res = res.start().unwrap();
+            res.write_all(b"hello ").unwrap();
+            res.write_all(b"world").unwrap();
+            res.end().unwrap();
+        }
+
+        Worker::new(handle, Default::default(), Default::default()).handle_connection(&mut mock);
+        let written = String::from_utf8(mock.write).unwrap();
+        assert!(written.starts_with("HTTP/1.0 200 OK\r\n"));
+        assert!(written.contains("\r\nConnection: close\r\n"));
+        assert!(!written.contains("Transfer-Encoding"));
+        // the body is everything up to the close, and only one response is sent
+        assert!(written.ends_with("\r\n\r\nhello world"));
+        assert_eq!(written.matches("HTTP/1.0").count(), 1);
+    }
+
+    #[test]
+    fn test_empty_chunked_keep_alive() {
+        use std::io::Read;
+
+        let mut mock = MockStream::with_input(b"\
+            POST / HTTP/1.1\r\n\
+            Host: example.domain\r\n\
+            Transfer-Encoding: chunked\r\n\
+            \r\n\
+            0\r\n\
+            \r\n\
+            GET /second HTTP/1.1\r\n\
+            Host: example.domain\r\n\
+            Connection: close\r\n\
+            \r\n\
+        ");
+
+        fn handle(mut req: Request, res: Response<Fresh>) {
+            let mut body = String::new();
+            req.read_to_string(&mut body).unwrap();
+            assert_eq!(body, "");
+            let path = format!("{}", req.uri);
+            res.send(path.as_bytes()).unwrap();
+        }
+
+        Worker::new(handle, Default::default(), Default::default()).handle_connection(&mut mock);
+        let written = String::from_utf8(mock.write).unwrap();
+        // the empty body ends the first request and the connection is reused
+        assert_eq!(written.matches("HTTP/1.1 200 OK\r\n").count(), 2);
+        assert!(written.contains("\r\n\r\n/HTTP/1.1 200 OK\r\n"));
+        assert!(written.ends_with("\r\n\r\n/second"));
+    }
+
+    #[test]
+    fn test_request_extensions() {
+        struct RequestId(u32);
+
+        struct Tag<H>(H);
+
+        impl<H: Handler> Handler for Tag<H> {
+            fn handle<'a, 'k>(&'a self, mut req: Request<'a, 'k>, res: Response<'a, Fresh>) {
+                req.extensions_mut().insert(RequestId(7));
+                self.0.handle(req, res)
+            }
+        }
+
+        fn handle(req: Request, res: Response<Fresh>) {
+            let id = req.extensions().get::<RequestId>().unwrap().0;
+            res.send(format!("request {}", id).as_bytes()).unwrap();
+        }
+
+        let mut mock = MockStream::with_input(b"\
+            GET / HTTP/1.1\r\n\
+            Host: example.domain\r\n\
+            Connection: close\r\n\
+            \r\n\
+        ");
+        Worker::new(Tag(handle), Default::default(), Default::default()).handle_connection(&mut mock);
+        let written = String::from_utf8(mock.write).unwrap();
+        assert!(written.ends_with("\r\n\r\nrequest 7"));
+    }
+
+    #[test]
+    fn test_tls_handshake_closes() {
+        fn handle(_: Request, _: Response<Fresh>) {
+            panic!("a ClientHello isn't a request");
+        }
+
+        let mut mock = MockStream::with_input(&[0x16, 0x03, 0x01, 0x00, 0xa5, 0x01, 0x00]);
+        Worker::new(handle, Default::default(), Default::default()).handle_connection(&mut mock);
+        assert!(mock.write.is_empty());
+    }
+
+    #[test]
+    fn test_trusted_proxies() {
+        use std::sync::Arc;
+
+        fn handle(req: Request, res: Response<Fresh>) {
+            res.send(req.client_ip().to_string().as_bytes()).unwrap();
+        }
+
+        fn client_ip(options: Options) -> String {
+            let mut mock = MockStream::with_input(b"\
+                GET / HTTP/1.1\r\n\
+                Host: example.domain\r\n\
+                X-Forwarded-For: 203.0.113.9\r\n\
+                Connection: close\r\n\
+                \r\n\
+            ");
+            Worker::new(handle, Default::default(), options).handle_connection(&mut mock);
+            let written = String::from_utf8(mock.write).unwrap();
+            written.split("\r\n\r\n").nth(1).unwrap().to_owned()
+        }
+
+        assert_eq!(client_ip(Default::default()), "127.0.0.1");
+        let proxies = Arc::new(vec!["127.0.0.0/8".parse().unwrap()]);
+        let options = Options { trusted_proxies: proxies, ..Default::default() };
+        assert_eq!(client_ip(options), "203.0.113.9");
+    }
+
+    #[test]
+    fn test_http10_request_with_http11_response() {
+        use std::io::Write;
+        use version::HttpVersion;
+
+        let mut mock = MockStream::with_input(b"\
+            GET / HTTP/1.0\r\n\
+            \r\n\
+        ");
+
+        fn handle(_: Request, mut res: Response<Fresh>) {
+            res.version = HttpVersion::Http11;
+            let mut res = res.start().unwrap();
+            res.write_all(b"hello").unwrap();
+            res.end().unwrap();
+        }
+
+        Worker::new(handle, Default::default(), Default::default()).handle_connection(&mut mock);
+        let written = String::from_utf8(mock.write).unwrap();
+        assert!(written.contains("\r\nConnection: close\r\n"));
+        assert!(!written.contains("Transfer-Encoding"));
+        assert!(written.ends_with("\r\n\r\nhello"));
+    }
+
+    #[test]
+    fn test_digest_trailer() {
+        use std::io::{Read, Write};
+
+        use http::h1::HttpReader::ChunkedReader;
+        use http::h1::read_trailers;
+        use super::DigestAlgorithm;
+        use super::digest::Digest;
+
+        fn handle(_: Request, mut res: Response<Fresh>) {
+            res.with_digest_trailer(DigestAlgorithm::Sha256);
+            let mut res = res.start().unwrap();
+            res.write_all(b"generated ").unwrap();
+            res.write_all(b"as it goes").unwrap();
+            res.end().unwrap();
+        }
+
+        let mut mock = MockStream::with_input(b"\
+            GET / HTTP/1.1\r\n\
+            Host: example.domain\r\n\
+            TE: trailers\r\n\
+            Connection: close\r\n\
+            \r\n\
+        ");
+        Worker::new(handle, Default::default(), Default::default()).handle_connection(&mut mock);
+
+        let written = mock.write;
+        let end = written.windows(4).position(|w| w == b"\r\n\r\n").unwrap() + 4;
+        let head = String::from_utf8(written[..end].to_vec()).unwrap();
+        assert!(head.contains("\r\nTrailer: Digest\r\n"));
+        assert!(head.contains("\r\nTransfer-Encoding: chunked\r\n"));
+
+        let mut rdr = &written[end..];
+        let mut body = Vec::new();
+        ChunkedReader(&mut rdr, None).read_to_end(&mut body).unwrap();
+        assert_eq!(body, b"generated as it goes");
+        let trailers = read_trailers(&mut rdr).unwrap();
+        assert!(rdr.is_empty());
+
+        let mut digest = Digest::new(DigestAlgorithm::Sha256);
+        digest.update(&body);
+        assert_eq!(trailers.get_raw("Digest").unwrap(), &[digest.header_value()][..]);
+
+        // a client that didn't ask for trailers gets none
+        let mut mock = MockStream::with_input(b"\
+            GET / HTTP/1.1\r\n\
+            Host: example.domain\r\n\
+            Connection: close\r\n\
+            \r\n\
+        ");
+        Worker::new(handle, Default::default(), Default::default()).handle_connection(&mut mock);
+        let written = String::from_utf8(mock.write).unwrap();
+        assert!(!written.contains("Trailer"));
+        assert!(!written.contains("Digest"));
+        assert!(written.ends_with("\r\n0\r\n\r\n"));
+    }
+
+    #[test]
+    fn test_send_with_etag() {
+        fn handle(req: Request, res: Response<Fresh>) {
+            res.send_with_etag(b"cacheable", true, req.headers.get()).unwrap();
+        }
+
+        fn get(if_none_match: &str) -> String {
+            let mut mock = MockStream::with_input(format!("\
+                GET / HTTP/1.1\r\n\
+                Host: example.domain\r\n\
+                {}\
+                Connection: close\r\n\
+                \r\n\
+            ", if_none_match).as_bytes());
+            Worker::new(handle, Default::default(), Default::default()).handle_connection(&mut mock);
+            String::from_utf8(mock.write).unwrap()
+        }
+
+        let first = get("");
+        assert!(first.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(first.ends_with("\r\n\r\ncacheable"));
+        let etag = first.lines().find(|line| line.starts_with("ETag: ")).unwrap();
+        assert!(etag.starts_with("ETag: W/\""), "{}", etag);
+
+        // the weak tag matches itself or its strong form
+        for tag in &[&etag[6..], &etag[8..]] {
+            let second = get(&format!("If-None-Match: \"other\", {}\r\n", tag));
+            assert!(second.starts_with("HTTP/1.1 304 Not Modified\r\n"), "{}", second);
+            assert!(second.contains(&format!("\r\n{}\r\n", etag)));
+            assert!(second.ends_with("\r\n\r\n"));
+        }
+
+        let other = get("If-None-Match: \"other\"\r\n");
+        assert!(other.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(other.ends_with("\r\n\r\ncacheable"));
+    }
+
+    #[test]
+    fn test_reuse_connection() {
+        use header::Connection;
+
+        // an HTTP/1.0 connection is only kept alive if the response agrees
+        fn handle(req: Request, mut res: Response<Fresh>) {
+            if let Some(conn) = req.headers.get::<Connection>() {
+                res.headers_mut().set(conn.clone());
+            }
+            res.send(b"ok").unwrap();
+        }
+
+        let second = "GET /second HTTP/1.1\r\nHost: example.domain\r\n\r\n";
+        let cases: &[(&str, usize)] = &[
+            ("GET / HTTP/1.1\r\nHost: example.domain\r\n\r\n", 2),
+            ("POST / HTTP/1.1\r\nHost: example.domain\r\nContent-Length: 3\r\n\r\nabc", 1),
+            ("GET / HTTP/1.1\r\nHost: example.domain\r\nConnection: close\r\n\r\n", 1),
+            ("GET / HTTP/1.0\r\n\r\n", 1),
+            ("GET / HTTP/1.0\r\nConnection: keep-alive\r\n\r\n", 2),
+        ];
+        for &(first, responses) in cases {
+            let mut mock = MockStream::with_input(format!("{}{}", first, second).as_bytes());
+            Worker::new(handle, Default::default(), Default::default())
+                .handle_connection(&mut mock);
+
+            let written = String::from_utf8(mock.write).unwrap();
+            assert_eq!(written.matches(" 200 OK\r\n").count(), responses,
+                       "{:?}: {}", first, written);
+        }
+    }
+
+    #[test]
+    fn test_unread_body_closes() {
+        fn handle(_: Request, res: Response<Fresh>) {
+            res.send(b"ok").unwrap();
+        }
+
+        let mut mock = MockStream::with_input(b"\
+            POST / HTTP/1.1\r\n\
+            Host: example.domain\r\n\
+            Content-Length: 11\r\n\
+            \r\n\
+            not read...\
+            GET /second HTTP/1.1\r\n\
+            Host: example.domain\r\n\
+            \r\n\
+        ");
+        Worker::new(handle, Default::default(), Default::default()).handle_connection(&mut mock);
+
+        let written = String::from_utf8(mock.write).unwrap();
+        assert_eq!(written.matches("HTTP/1.1 ").count(), 1, "{}", written);
+        assert!(written.starts_with("HTTP/1.1 200 OK\r\n"));
+    }
+
+    #[test]
+    fn test_short_response_closes() {
+        use std::io::Write;
+
+        fn handle(_: Request, mut res: Response<Fresh>) {
+            res.headers_mut().set(::header::ContentLength(10));
+            let mut res = res.start().unwrap();
+            res.write_all(b"short").unwrap();
+        }
+
+        let mut mock = MockStream::with_input(b"\
+            GET / HTTP/1.1\r\n\
+            Host: example.domain\r\n\
+            \r\n\
+            GET /second HTTP/1.1\r\n\
+            Host: example.domain\r\n\
+            \r\n\
+        ");
+        Worker::new(handle, Default::default(), Default::default()).handle_connection(&mut mock);
+
+        let written = String::from_utf8(mock.write).unwrap();
+        assert_eq!(written.matches("HTTP/1.1 ").count(), 1, "{}", written);
+        assert!(written.ends_with("\r\n\r\nshort"));
+    }
+
+    #[test]
+    fn test_get_body_not_read_as_request() {
+        fn handle(_: Request, res: Response<Fresh>) {
+            res.send(b"ok").unwrap();
+        }
+
+        let mut mock = MockStream::with_input(b"\
+            GET / HTTP/1.1\r\n\
+            Host: example.domain\r\n\
+            \r\n\
+            I shouldn't be read.\r\n\
+            GET /smuggled HTTP/1.1\r\n\
+            Host: example.domain\r\n\
+            \r\n\
+        ");
+        Worker::new(handle, Default::default(), Default::default()).handle_connection(&mut mock);
+        let written = String::from_utf8(mock.write).unwrap();
+        assert!(written.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert_eq!(written.matches("HTTP/1.1 200 OK\r\n").count(), 1);
+        assert!(written.contains("\r\n\r\nokHTTP/1.1 400 Bad Request\r\n"));
+        assert!(written.ends_with("\r\n\r\n"));
+        assert!(!written.contains("smuggled"));
+    }
+
+    #[test]
+    fn test_starts_request_line() {
+        use super::starts_request_line;
+
+        let strict = RequestLeniency::default();
+        assert!(starts_request_line(b"", &strict));
+        assert!(starts_request_line(b"GE", &strict));
+        assert!(starts_request_line(b"GET /b HT", &strict));
+        assert!(starts_request_line(b"\r\nGET /b HTTP/1.1\r\nHost: a\r\n", &strict));
+        assert!(!starts_request_line(b"I shouldn't be read.\r\n", &strict));
+        assert!(!starts_request_line(b"{\"json\": true}", &strict));
+        assert!(!starts_request_line(b"GET\t/ HTTP/1.1", &strict));
+
+        let lenient = RequestLeniency { extra_whitespace: true, ..Default::default() };
+        assert!(starts_request_line(b"GET\t/ HTTP/1.1", &lenient));
+        assert!(starts_request_line(b"GET  / \tHTTP/1.1\r\n", &lenient));
+        assert!(starts_request_line(b"GET\t", &lenient));
+        assert!(!starts_request_line(b"I shouldn't be read.\r\n", &lenient));
+    }
+
+    #[test]
+    fn test_coalesce_pipelined_responses() {
+        fn handle(req: Request, res: Response<Fresh>) {
+            let path = format!("{}", req.uri);
+            res.send(path.as_bytes()).unwrap();
+        }
+
+        let mut mock = MockStream::with_input(b"\
+            GET /a HTTP/1.1\r\n\
+            Host: example.domain\r\n\
+            \r\n\
+            GET /b HTTP/1.1\r\n\
+            Host: example.domain\r\n\
+            \r\n\
+            GET /c HTTP/1.1\r\n\
+            Host: example.domain\r\n\
+            Connection: close\r\n\
+            \r\n\
+        ");
+        Worker::new(handle, Default::default(), Default::default()).handle_connection(&mut mock);
+        assert_eq!(mock.write_calls, 1);
+        let written = String::from_utf8(mock.write).unwrap();
+        assert_eq!(written.matches("HTTP/1.1 200 OK\r\n").count(), 3);
+        assert!(written.contains("\r\n\r\n/aHTTP/1.1 200 OK\r\n"));
+        assert!(written.contains("\r\n\r\n/bHTTP/1.1 200 OK\r\n"));
+        assert!(written.ends_with("\r\n\r\n/c"));
+
+        // a partial head isn't waited for before flushing
+        let mut mock = MockStream::with_responses(vec![
+            b"GET /a HTTP/1.1\r\nHost: a\r\n\r\nGET /b HTTP/1.1\r\n",
+            b"Host: a\r\nConnection: close\r\n\r\n",
+        ]);
+        Worker::new(handle, Default::default(), Default::default()).handle_connection(&mut mock);
+        assert_eq!(mock.write_calls, 2);
+    }
+
+    #[test]
+    fn test_pipelined_responses_in_order() {
+        use std::thread;
+        use std::time::Duration;
+
+        // a connection's requests are handled one after another, so a slow
+        // handler holds back the responses pipelined behind it
+        fn handle(req: Request, res: Response<Fresh>) {
+            let path = format!("{}", req.uri);
+            if path == "/slow" {
+                thread::sleep(Duration::from_millis(50));
+            }
+            res.send(path.as_bytes()).unwrap();
+        }
+
+        let mut mock = MockStream::with_input(b"\
+            GET /slow HTTP/1.1\r\n\
+            Host: example.domain\r\n\
+            \r\n\
+            GET /fast HTTP/1.1\r\n\
+            Host: example.domain\r\n\
+            Connection: close\r\n\
+            \r\n\
+        ");
+        Worker::new(handle, Default::default(), Default::default()).handle_connection(&mut mock);
+        let written = String::from_utf8(mock.write).unwrap();
+        let slow = written.find("\r\n\r\n/slow").unwrap();
+        let fast = written.find("\r\n\r\n/fast").unwrap();
+        assert!(slow < fast);
+    }
+
+    #[test]
+    fn test_router() {
+        fn index(_: Request, res: Response<Fresh>) {
+            res.send(b"index").unwrap();
+        }
+
+        fn create(_: Request, res: Response<Fresh>) {
+            res.send(b"create").unwrap();
+        }
+
+        fn route(method: &Method, path: &str) -> Option<Box<Handler>> {
+            match (method, path) {
+                (&Method::Get, "/") => Some(Box::new(index)),
+                (&Method::Post, "/items") => Some(Box::new(create)),
+                _ => None
+            }
+        }
+
+        fn routed(input: &[u8]) -> String {
+            let mut mock = MockStream::with_input(input);
+            let mut worker = Worker::new(super::not_found, Default::default(), Default::default());
+            worker.router = Some(Box::new(route));
+            worker.handle_connection(&mut mock);
+            String::from_utf8(mock.write).unwrap()
+        }
+
+        let res = routed(b"GET /?q=1 HTTP/1.1\r\nHost: a\r\nConnection: close\r\n\r\n");
+        assert!(res.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(res.ends_with("\r\n\r\nindex"));
+
+        let res = routed(b"\
+            POST /items HTTP/1.1\r\n\
+            Host: example.domain\r\n\
+            Content-Length: 0\r\n\
+            Connection: close\r\n\
+            \r\n\
+        ");
+        assert!(res.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(res.ends_with("\r\n\r\ncreate"));
+
+        let res = routed(b"GET /items HTTP/1.1\r\nHost: a\r\nConnection: close\r\n\r\n");
+        assert!(res.starts_with("HTTP/1.1 404 Not Found\r\n"));
+    }
+
+    #[test]
+    fn test_max_response_header_size() {
+        use std::cell::Cell;
+        use std::io::ErrorKind;
+
+        thread_local!(static RESULT: Cell<Option<ErrorKind>> = Cell::new(None));
+
+        fn handle(_: Request, mut res: Response<Fresh>) {
+            res.headers_mut().set_raw("X-Echo", vec![vec![b'a'; 100]]);
+            let kind = res.start().err().map(|e| e.kind());
+            RESULT.with(|result| result.set(kind));
+        }
+
+        let input = b"GET / HTTP/1.1\r\nHost: a\r\nConnection: close\r\n\r\n";
+        let options = Options { max_response_header_size: Some(64), ..Default::default() };
+        let mut mock = MockStream::with_input(input);
+        Worker::new(handle, Default::default(), options).handle_connection(&mut mock);
+        assert_eq!(RESULT.with(|result| result.get()), Some(ErrorKind::InvalidData));
+        let written = String::from_utf8(mock.write).unwrap();
+        assert!(written.starts_with("HTTP/1.1 500 Internal Server Error\r\n"));
+        assert!(!written.contains("X-Echo"));
+
+        let options = Options { max_response_header_size: Some(1024), ..Default::default() };
+        let mut mock = MockStream::with_input(input);
+        Worker::new(handle, Default::default(), options).handle_connection(&mut mock);
+        assert_eq!(RESULT.with(|result| result.get()), None);
+        assert!(String::from_utf8(mock.write).unwrap().starts_with("HTTP/1.1 200 OK\r\n"));
+    }
+
+    #[test]
+    fn test_max_response_header_size_keep_alive() {
+        fn handle(_: Request, mut res: Response<Fresh>) {
+            res.headers_mut().set_raw("X-Echo", vec![vec![b'a'; 100]]);
+        }
+
+        let mut mock = MockStream::with_input(b"\
+            GET /a HTTP/1.1\r\n\
+            Host: example.domain\r\n\
+            \r\n\
+            GET /b HTTP/1.1\r\n\
+            Host: example.domain\r\n\
+            \r\n\
+        ");
+        let options = Options { max_response_header_size: Some(64), ..Default::default() };
+        Worker::new(handle, Default::default(), options).handle_connection(&mut mock);
+        let written = String::from_utf8(mock.write).unwrap();
+        assert!(written.starts_with("HTTP/1.1 500 Internal Server Error\r\n"), "{}", written);
+        assert!(written.contains("Connection: close\r\n"));
+        assert!(!written.contains("X-Echo"));
+        assert_eq!(written.matches("HTTP/1.1").count(), 1);
+    }
+
+    fn redirected(options: Options, location: &str, forwarded_proto: &str) -> String {
+        fn handle(req: Request, mut res: Response<Fresh>) {
+            let location = String::from_utf8(req.headers.get_raw("X-Target").unwrap()[0].clone());
+            res.redirect(StatusCode::Found, &location.unwrap());
+        }
+
+        let input = format!("GET /a/b HTTP/1.1\r\n\
+                             Host: example.domain\r\n\
+                             X-Target: {}\r\n\
+                             X-Forwarded-Proto: {}\r\n\
+                             Connection: close\r\n\
+                             \r\n", location, forwarded_proto);
+        let mut mock = MockStream::with_input(input.as_bytes());
+        Worker::new(handle, Default::default(), options).handle_connection(&mut mock);
+        let written = String::from_utf8(mock.write).unwrap();
+        assert!(written.starts_with("HTTP/1.1 302 Found\r\n"));
+        let start = written.find("\r\nLocation: ").unwrap() + 12;
+        let end = start + written[start..].find("\r\n").unwrap();
+        written[start..end].to_owned()
+    }
+
+    #[test]
+    fn test_redirect() {
+        use std::sync::Arc;
+
+        let relative = Options::default();
+        assert_eq!(redirected(relative.clone(), "/new path", "https"), "/new%20path");
+        assert_eq!(redirected(relative.clone(), "c", "https"), "c");
+        assert_eq!(redirected(relative.clone(), "//other.domain/c", "https"), "//other.domain/c");
+
+        let absolute = Options { absolute_redirects: true, ..Default::default() };
+        assert_eq!(redirected(absolute.clone(), "/new path", "https"),
+                   "http://example.domain/new%20path");
+        assert_eq!(redirected(absolute.clone(), "c", "https"), "http://example.domain/a/c");
+        assert_eq!(redirected(absolute.clone(), "//other.domain/c", "https"),
+                   "http://other.domain/c");
+        assert_eq!(redirected(absolute.clone(), "https://other.domain/c", "http"),
+                   "https://other.domain/c");
+
+        let untrusted = Options { trust_proxy_headers: true, ..absolute.clone() };
+        assert_eq!(redirected(untrusted, "/new", "https"), "http://example.domain/new");
+
+        let proxies = Arc::new(vec!["127.0.0.1".parse().unwrap()]);
+        let trusted = Options { trust_proxy_headers: true, trusted_proxies: proxies, ..absolute };
+        assert_eq!(redirected(trusted.clone(), "/new", "https"), "https://example.domain/new");
+        assert_eq!(redirected(trusted.clone(), "c", "https"), "https://example.domain/a/c");
+        assert_eq!(redirected(trusted.clone(), "//other.domain/c", "https"),
+                   "https://other.domain/c");
+        assert_eq!(redirected(trusted, "http://other.domain/c", "https"),
+                   "http://other.domain/c");
+    }
+
+    fn limited(limits: RequestLimits, input: &[u8]) -> String {
+        fn handle(_: Request, res: Response<Fresh>) {
+            res.send(b"ok").unwrap();
+        }
+
+        let mut mock = MockStream::with_input(input);
+        let options = Options { limits: limits, ..Default::default() };
+        Worker::new(handle, Default::default(), options).handle_connection(&mut mock);
+        String::from_utf8(mock.write).unwrap()
+    }
+
+    #[test]
+    fn test_limits_uri_length() {
+        let limits = RequestLimits { max_uri_length: 8, ..Default::default() };
+        let res = limited(limits, b"GET /1234567 HTTP/1.1\r\nHost: a\r\nConnection: close\r\n\r\n");
+        assert!(res.starts_with("HTTP/1.1 200 OK\r\n"));
+
+        let res = limited(limits, b"GET /12345678 HTTP/1.1\r\n\r\n");
+        assert!(res.starts_with("HTTP/1.1 414 URI Too Long\r\n"));
+        assert!(res.contains("\r\nConnection: close\r\n"));
+        // rejected before the request line is even complete
+        let res = limited(limits, b"GET /12345678");
+        assert!(res.starts_with("HTTP/1.1 414 URI Too Long\r\n"));
+    }
+
+    fn parsed(leniency: RequestLeniency, input: &[u8]) -> String {
+        fn handle(req: Request, res: Response<Fresh>) {
+            res.send(format!("{} {}", req.method, req.uri).as_bytes()).unwrap();
+        }
+
+        let limits = RequestLimits { leniency: leniency, ..Default::default() };
+        let mut mock = MockStream::with_input(input);
+        let options = Options { limits: limits, ..Default::default() };
+        Worker::new(handle, Default::default(), options).handle_connection(&mut mock);
+        String::from_utf8(mock.write).unwrap()
+    }
+
+    #[test]
+    fn test_leniency_sloppy_request_lines() {
+        let lenient = RequestLeniency {
+            max_leading_empty_lines: 2,
+            extra_whitespace: true,
+            fold_method_case: false,
+        };
+        let sloppy: &[(&[u8], &str)] = &[
+            (b"GET  /a HTTP/1.1", "GET /a"),
+            (b"GET /a\tHTTP/1.1", "GET /a"),
+            (b"GET \t /a \t\t HTTP/1.1", "GET /a"),
+            (b"\r\nPOST\t\t/a  HTTP/1.1", "POST /a"),
+        ];
+        for &(line, expected) in sloppy {
+            let mut input = line.to_vec();
+            input.extend_from_slice(b"\r\nHost: a\r\nConnection: close\r\n\r\n");
+
+            let res = parsed(lenient, &input);
+            assert!(res.starts_with("HTTP/1.1 200 OK\r\n"), "{:?}: {}", line, res);
+            assert!(res.ends_with(expected), "{:?}: {}", line, res);
+
+            let res = parsed(RequestLeniency::default(), &input);
+            assert!(res.starts_with("HTTP/1.1 400 Bad Request\r\n"), "{:?}: {}", line, res);
+        }
+    }
+
+    #[test]
+    fn test_leniency_malformed_request_lines() {
+        let lenient = RequestLeniency {
+            max_leading_empty_lines: 2,
+            extra_whitespace: true,
+            fold_method_case: true,
+        };
+        let malformed: &[&[u8]] = &[
+            b" GET /a HTTP/1.1",
+            b"\tGET /a HTTP/1.1",
+            b"GET /a HTTP/1.1 ",
+            b"GET /a HTTP/1.1\t",
+            b"GET/a HTTP/1.1",
+            b"G@T /a HTTP/1.1",
+            b"GET /a b HTTP/1.1",
+            b"\r\n \r\nGET /a HTTP/1.1",
+        ];
+        for &line in malformed {
+            let mut input = line.to_vec();
+            input.extend_from_slice(b"\r\nHost: a\r\nConnection: close\r\n\r\n");
+            for &leniency in &[RequestLeniency::default(), lenient] {
+                let res = parsed(leniency, &input);
+                assert!(!res.starts_with("HTTP/1.1 200 OK\r\n"), "{:?}: {}", line, res);
+                assert!(res.contains("\r\nConnection: close\r\n"), "{:?}: {}", line, res);
+            }
+        }
+    }
+
+    #[test]
+    fn test_leniency_leading_empty_lines() {
+        let limited = RequestLeniency { max_leading_empty_lines: 2, ..Default::default() };
+        let cases: &[(&[u8], bool)] = &[
+            (b"\r\nGET /a HTTP/1.1", true),
+            (b"\n\nGET /a HTTP/1.1", true),
+            (b"\r\n\r\nGET /a HTTP/1.1", true),
+            (b"\r\n\r\n\r\nGET /a HTTP/1.1", false),
+            (b"\r\n\n\r\n\n\r\nGET /a HTTP/1.1", false),
+        ];
+        for &(line, within_limit) in cases {
+            let mut input = line.to_vec();
+            input.extend_from_slice(b"\r\nHost: a\r\nConnection: close\r\n\r\n");
+
+            // like httparse, any number of them is skipped by default
+            let res = parsed(RequestLeniency::default(), &input);
+            assert!(res.starts_with("HTTP/1.1 200 OK\r\n"), "{:?}: {}", line, res);
+            assert!(res.ends_with("GET /a"), "{:?}: {}", line, res);
+
+            let res = parsed(limited, &input);
+            assert_eq!(res.starts_with("HTTP/1.1 200 OK\r\n"), within_limit,
+                       "{:?}: {}", line, res);
+        }
+
+        assert!(super::starts_request_line(b"\r\n\r\n\r\nGET /", &RequestLeniency::default()));
+        assert!(!super::starts_request_line(b"\r\n\r\n\r\nGET /", &limited));
+        assert!(super::starts_request_line(b"\r\n\r", &limited));
+    }
+
+    #[test]
+    fn test_leniency_fold_method_case() {
+        let folding = RequestLeniency { fold_method_case: true, ..Default::default() };
+        let cases: &[(&[u8], &str, &str)] = &[
+            (b"get", "GET", "get"),
+            (b"Post", "POST", "Post"),
+            (b"pAtCh", "PATCH", "pAtCh"),
+            (b"fetch", "fetch", "fetch"),
+        ];
+        for &(method, folded, strict) in cases {
+            let mut input = method.to_vec();
+            input.extend_from_slice(b" /a HTTP/1.1\r\nHost: a\r\nConnection: close\r\n\r\n");
+            let res = parsed(folding, &input);
+            assert!(res.ends_with(&format!("\r\n\r\n{} /a", folded)), "{}", res);
+            let res = parsed(RequestLeniency::default(), &input);
+            assert!(res.ends_with(&format!("\r\n\r\n{} /a", strict)), "{}", res);
+        }
+    }
+
+    #[test]
+    fn test_leniency_pipelined() {
+        let lenient = RequestLeniency {
+            extra_whitespace: true,
+            fold_method_case: true,
+            ..Default::default()
+        };
+        let res = parsed(lenient, b"get  /a   HTTP/1.1\r\nHost: a\r\n\r\n\
+                                    \r\nGET\t/b HTTP/1.1\r\nHost: a\r\n\
+                                    Connection: close\r\n\r\n");
+        let mut responses = res.split("HTTP/1.1 200 OK\r\n").skip(1);
+        assert!(responses.next().unwrap().ends_with("\r\n\r\nGET /a"), "{}", res);
+        assert!(responses.next().unwrap().ends_with("\r\n\r\nGET /b"), "{}", res);
+        assert!(responses.next().is_none(), "{}", res);
+    }
+
+    fn hosted(lenient: bool, input: &[u8]) -> String {
+        fn handle(req: Request, res: Response<Fresh>) {
+            let host = req.headers.get::<Host>().map(|host| HeaderFormatter(host).to_string());
+            res.send(host.unwrap_or("none".to_owned()).as_bytes()).unwrap();
+        }
+
+        let mut mock = MockStream::with_input(input);
+        let options = Options { lenient_host: lenient, ..Default::default() };
+        Worker::new(handle, Default::default(), options).handle_connection(&mut mock);
+        String::from_utf8(mock.write).unwrap()
+    }
+
+    #[test]
+    fn test_duplicate_host() {
+        let input = b"\
+            GET / HTTP/1.1\r\n\
+            Host: example.domain\r\n\
+            Host: evil.domain\r\n\
+            Connection: close\r\n\
+            \r\n\
+        ";
+
+        let written = hosted(false, input);
+        assert!(written.starts_with("HTTP/1.1 400 Bad Request\r\n"));
+        assert!(written.contains("\r\nConnection: close\r\n"));
+
+        let written = hosted(true, input);
+        assert!(written.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(written.ends_with("\r\n\r\nexample.domain"));
+    }
+
+    #[test]
+    fn test_missing_host() {
+        let written = hosted(false, b"GET / HTTP/1.1\r\nConnection: close\r\n\r\n");
+        assert!(written.starts_with("HTTP/1.1 400 Bad Request\r\n"));
+        let written = hosted(true, b"GET / HTTP/1.1\r\nConnection: close\r\n\r\n");
+        assert!(written.ends_with("\r\n\r\nnone"));
+
+        // HTTP/1.0 predates Host
+        let written = hosted(false, b"GET / HTTP/1.0\r\n\r\n");
+        assert!(written.starts_with("HTTP/1.0 200 OK\r\n"));
+        assert!(written.ends_with("\r\n\r\nnone"));
+    }
+
+    #[test]
+    fn test_host_differs_from_absolute_uri() {
+        let input = b"\
+            GET http://example.domain:8080/ HTTP/1.1\r\n\
+            Host: evil.domain\r\n\
+            Connection: close\r\n\
+            \r\n\
+        ";
+        let written = hosted(false, input);
+        assert!(written.starts_with("HTTP/1.1 400 Bad Request\r\n"));
+        let written = hosted(true, input);
+        assert!(written.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(written.ends_with("\r\n\r\nexample.domain:8080"));
+
+        let written = hosted(false, b"\
+            GET http://example.domain/ HTTP/1.1\r\n\
+            Host: Example.Domain\r\n\
+            Connection: close\r\n\
+            \r\n\
+        ");
+        assert!(written.ends_with("\r\n\r\nExample.Domain"));
+
+        // a default port is the same authority, spelled out or not
+        let written = hosted(false, b"\
+            GET http://example.domain:80/ HTTP/1.1\r\n\
+            Host: example.domain\r\n\
+            Connection: close\r\n\
+            \r\n\
+        ");
+        assert!(written.starts_with("HTTP/1.1 200 OK\r\n"));
+        let written = hosted(false, b"\
+            GET http://example.domain/ HTTP/1.1\r\n\
+            Host: example.domain:80\r\n\
+            Connection: close\r\n\
+            \r\n\
+        ");
+        assert!(written.starts_with("HTTP/1.1 200 OK\r\n"));
+        let written = hosted(false, b"\
+            GET http://example.domain/ HTTP/1.1\r\n\
+            Host: example.domain:443\r\n\
+            Connection: close\r\n\
+            \r\n\
+        ");
+        assert!(written.starts_with("HTTP/1.1 400 Bad Request\r\n"));
+
+        // the authority also settles duplicates
+        let written = hosted(true, b"\
+            GET http://example.domain/ HTTP/1.1\r\n\
+            Host: evil.domain\r\n\
+            Host: example.domain\r\n\
+            Connection: close\r\n\
+            \r\n\
+        ");
+        assert!(written.ends_with("\r\n\r\nexample.domain"));
+    }
+
+    fn over_ssl(info: Option<SslInfo>, prefer_sni: bool) -> String {
+        fn handle(req: Request, res: Response<Fresh>) {
+            let negotiated = req.ssl_info().map_or("plain".to_owned(), |info| {
+                format!("{} {}", info.server_name.as_ref().map_or("-", |name| &name[..]),
+                        info.protocol.as_ref().map_or("-", |proto| &proto[..]))
+            });
+            let host = HeaderFormatter(req.headers.get::<Host>().unwrap()).to_string();
+            res.send(format!("{} {}", negotiated, host).as_bytes()).unwrap();
+        }
+
+        let mut mock = MockStream::with_input(b"\
+            GET / HTTP/1.1\r\n\
+            Host: other.domain:8443\r\n\
+            Connection: close\r\n\
+            \r\n\
+        ");
+        mock.ssl_info = info;
+        let options = Options { prefer_sni: prefer_sni, ..Default::default() };
+        Worker::new(handle, Default::default(), options).handle_connection(&mut mock);
+        String::from_utf8(mock.write).unwrap()
+    }
+
+    fn negotiated() -> SslInfo {
+        SslInfo {
+            server_name: Some("example.domain".to_owned()),
+            protocol: Some("http/1.1".to_owned()),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_ssl_info() {
+        let written = over_ssl(None, false);
+        assert!(written.ends_with("\r\n\r\nplain other.domain:8443"));
+        let written = over_ssl(Some(negotiated()), false);
+        assert!(written.ends_with("\r\n\r\nexample.domain http/1.1 other.domain:8443"));
+    }
+
+    #[test]
+    fn test_prefer_sni() {
+        let written = over_ssl(Some(negotiated()), true);
+        assert!(written.ends_with("\r\n\r\nexample.domain http/1.1 example.domain:8443"));
+        let written = over_ssl(Some(SslInfo { server_name: None, ..negotiated() }), true);
+        assert!(written.ends_with("\r\n\r\n- http/1.1 other.domain:8443"));
+        let written = over_ssl(None, true);
+        assert!(written.ends_with("\r\n\r\nplain other.domain:8443"));
+    }
+
+    #[test]
+    fn test_unsupported_transfer_encoding() {
+        let res = limited(Default::default(), b"\
+            POST / HTTP/1.1\r\n\
+            Host: example.domain\r\n\
+            Transfer-Encoding: gzip\r\n\
+            \r\n\
+            \x1f\x8b\x08\
+        ");
+        assert!(res.starts_with("HTTP/1.1 501 Not Implemented\r\n"));
+        assert!(res.contains("\r\nConnection: close\r\n"));
+    }
+
+    #[test]
+    fn test_unsupported_version() {
+        let res = limited(Default::default(), b"GET / HTTP/2.0\r\nHost: example.domain\r\n\r\n");
+        assert!(res.starts_with("HTTP/1.1 505 HTTP Version Not Supported\r\n"));
+        assert!(res.contains("\r\nConnection: close\r\n"));
+
+        let res = limited(Default::default(), b"GET / HTTP/1.2\r\nHost: a\r\nConnection: close\r\n\r\n");
+        assert!(res.starts_with("HTTP/1.1 200 OK\r\n"));
+    }
+
+    #[test]
+    fn test_limits_max_headers() {
+        let limits = RequestLimits { max_headers: 3, ..Default::default() };
+        let res = limited(limits, b"GET / HTTP/1.1\r\nHost: a\r\nA: 1\r\nConnection: close\r\n\r\n");
+        assert!(res.starts_with("HTTP/1.1 200 OK\r\n"));
+
+        let res = limited(limits, b"GET / HTTP/1.1\r\nHost: a\r\nA: 1\r\nB: 2\r\nC: 3\r\n\r\n");
+        assert!(res.starts_with("HTTP/1.1 431 Request Header Fields Too Large\r\n"));
+    }
+
+    #[test]
+    fn test_limits_header_list_size() {
+        let limits = RequestLimits { max_header_list_size: 35, ..Default::default() };
+        // "Host: a\r\n" + "A: 1\r\n" + "Connection: close\r\n" is 34 bytes
+        let res = limited(limits, b"GET / HTTP/1.1\r\nHost: a\r\nA: 1\r\nConnection: close\r\n\r\n");
+        assert!(res.starts_with("HTTP/1.1 200 OK\r\n"));
+
+        let res = limited(limits, b"GET / HTTP/1.1\r\nHost: a\r\nA: 1234\r\nConnection: close\r\n\r\n");
+        assert!(res.starts_with("HTTP/1.1 431 Request Header Fields Too Large\r\n"));
+    }
+
+    #[test]
+    fn test_reset_on_abuse() {
+        use mock::CloneableMockStream;
+
+        fn handle(_: Request, res: Response<Fresh>) {
+            res.send(b"ok").unwrap();
+        }
+
+        fn serve(options: Options, input: &[u8]) -> MockStream {
+            let mut mock = CloneableMockStream::with_stream(MockStream::with_input(input));
+            Worker::new(handle, Default::default(), options).handle_connection(&mut mock);
+            let mock = mock.inner.lock().unwrap();
+            mock.clone()
+        }
+
+        let reset = Options { reset_on_abuse: true, ..Default::default() };
+        let limits = RequestLimits { max_header_list_size: 26, ..Default::default() };
+        let large = b"GET / HTTP/1.1\r\nA: 1234\r\nConnection: close\r\n\r\n";
+        let duplicate = b"GET / HTTP/1.1\r\nHost: a\r\nHost: b\r\n\r\n";
+
+        let mock = serve(Options { limits: limits, ..reset.clone() }, large);
+        assert!(mock.is_reset);
+        assert!(mock.write.is_empty());
+        let mock = serve(reset.clone(), duplicate);
+        assert!(mock.is_reset);
+        assert!(mock.write.is_empty());
+
+        // an unsupported version is an honest mistake
+        let mock = serve(reset.clone(), b"GET / HTTP/2.0\r\n\r\n");
+        assert!(!mock.is_reset);
+        assert!(mock.write.starts_with(b"HTTP/1.1 505 "));
+
+        let mock = serve(Options { limits: limits, ..Default::default() }, large);
+        assert!(!mock.is_reset);
+        assert!(mock.write.starts_with(b"HTTP/1.1 431 "));
+    }
+
+    #[test]
+    fn test_limits_precedence() {
+        let limits = RequestLimits {
+            max_headers: 1,
+            max_header_list_size: 8,
+            max_uri_length: 4,
+            ..Default::default()
+        };
+        let res = limited(limits, b"GET /12345 HTTP/1.1\r\nA: 1234\r\nB: 5678\r\n\r\n");
+        assert!(res.starts_with("HTTP/1.1 414 URI Too Long\r\n"));
+
+        let res = limited(limits, b"GET / HTTP/1.1\r\nA: 1234\r\nB: 5678\r\n\r\n");
+        assert!(res.starts_with("HTTP/1.1 431 Request Header Fields Too Large\r\n"));
+    }
+
+    #[test]
+    fn test_cork_responses() {
+        use std::io::Write;
+
+        fn handle(_: Request, res: Response<Fresh>) {
+            let mut res = res.start().unwrap();
+            res.flush().unwrap();
+            res.write_all(b"foo").unwrap();
+            res.flush().unwrap();
+            res.write_all(b"bar").unwrap();
+            res.end().unwrap();
+        }
+
+        let input = b"\
+            GET / HTTP/1.1\r\n\
+            Host: example.domain\r\n\
+            Connection: close\r\n\
+            \r\n\
+        ";
+
+        let mut mock = MockStream::with_input(input);
+        Worker::new(handle, Default::default(), Default::default()).handle_connection(&mut mock);
+        assert!(mock.write_calls >= 3);
+        let uncorked = mock.write;
+
+        let mut mock = MockStream::with_input(input);
+        let options = Options { cork: true, ..Default::default() };
+        Worker::new(handle, Default::default(), options).handle_connection(&mut mock);
+        assert_eq!(mock.write_calls, 1);
+        assert_eq!(mock.write.len(), uncorked.len());
+        assert!(String::from_utf8(mock.write).unwrap().ends_with("3\r\nfoo\r\n3\r\nbar\r\n0\r\n\r\n"));
+    }
+
+    #[test]
+    fn test_date_clock() {
+        use time::{self, Timespec, Tm};
+
+        fn clock() -> Tm {
+            time::at_utc(Timespec::new(784111777, 0))
+        }
+
+        let mut mock = MockStream::with_input(b"\
+            GET / HTTP/1.1\r\n\
+            Host: example.domain\r\n\
+            Connection: close\r\n\
+            \r\n\
+        ");
+
+        fn handle(_: Request, res: Response<Fresh>) {
+            res.send(b"").unwrap();
+        }
+
+        let options = Options { clock: clock, ..Default::default() };
+        Worker::new(handle, Default::default(), options).handle_connection(&mut mock);
+        let written = String::from_utf8(mock.write).unwrap();
+        assert!(written.contains("\r\nDate: Sun, 06 Nov 1994 08:49:37 GMT\r\n"));
+    }
+
+    // A handler that doesn't start its response in time. It moves the
+    // clock past the deadline and waits for the 503, giving up after a few
+    // seconds so a deadline that never fires fails the test instead of
+    // hanging it.
+    struct Stuck {
+        stream: Arc<Mutex<MockStream>>,
+        clock: Arc<MockClock>,
+    }
+
+    impl Stuck {
+        fn wait_for_deadline(&self) {
+            use std::thread;
+            use std::time::{Duration, Instant};
+
+            self.clock.advance(Duration::from_secs(10));
+            let give_up = Instant::now() + Duration::from_secs(5);
+            while !String::from_utf8_lossy(&self.stream.lock().unwrap().write).contains(" 503 ") {
+                assert!(Instant::now() < give_up, "the handler deadline never fired");
+                thread::sleep(Duration::from_millis(1));
+            }
+        }
+    }
+
+    impl Handler for Stuck {
+        fn handle<'a, 'k>(&'a self, _: Request<'a, 'k>, res: Response<'a, Fresh>) {
+            self.wait_for_deadline();
+            assert!(res.send(b"late").is_err());
+        }
+    }
+
+    #[test]
+    fn test_handler_timeout() {
+        use std::time::Duration;
+
+        use mock::CloneableMockStream;
+        use super::wheel::TimerWheel;
+
+        let mut mock = CloneableMockStream::with_stream(MockStream::with_input(b"\
+            GET / HTTP/1.1\r\n\
+            Host: example.domain\r\n\
+            \r\n\
+            GET / HTTP/1.1\r\n\
+            Host: example.domain\r\n\
+            \r\n\
+        "));
+
+        let clock = Arc::new(MockClock::new());
+        let stuck = Stuck { stream: mock.inner.clone(), clock: clock.clone() };
+        let timeouts = Timeouts { handler: Some(Duration::from_secs(5)), ..Default::default() };
+        let mut worker = Worker::new(stuck, timeouts, Default::default());
+        worker.timers = TimerWheel::with_clock(clock);
+        worker.handle_connection(&mut mock);
+
+        let stream = mock.inner.lock().unwrap();
+        let written = String::from_utf8(stream.write.clone()).unwrap();
+        assert!(written.starts_with("HTTP/1.1 503 Service Unavailable\r\n"));
+        assert!(written.contains("Connection: close\r\n"));
+        assert_eq!(written.matches("HTTP/1.1").count(), 1);
+        assert!(!written.contains("late"));
+        assert!(stream.is_closed);
+    }
+
+    #[test]
+    fn test_handler_timeout_retry_after() {
+        use std::time::Duration;
+
+        use mock::CloneableMockStream;
+        use super::wheel::TimerWheel;
+
+        let mut mock = CloneableMockStream::with_stream(MockStream::with_input(b"\
+            GET / HTTP/1.1\r\n\
+            Host: example.domain\r\n\
+            \r\n\
+        "));
+
+        fn retry_after(status: StatusCode) -> Option<Duration> {
+            match status {
+                StatusCode::ServiceUnavailable => Some(Duration::from_secs(30)),
+                _ => None
+            }
+        }
+
+        let clock = Arc::new(MockClock::new());
+        let stuck = Stuck { stream: mock.inner.clone(), clock: clock.clone() };
+        let timeouts = Timeouts { handler: Some(Duration::from_secs(5)), ..Default::default() };
+        let options = Options { retry_after: Some(retry_after), ..Default::default() };
+        let mut worker = Worker::new(stuck, timeouts, options);
+        worker.timers = TimerWheel::with_clock(clock);
+        worker.handle_connection(&mut mock);
+
+        let stream = mock.inner.lock().unwrap();
+        let written = String::from_utf8(stream.write.clone()).unwrap();
+        assert!(written.starts_with("HTTP/1.1 503 Service Unavailable\r\n"));
+        assert!(written.contains("\r\nRetry-After: 30\r\n"));
+    }
+
+    #[test]
+    fn test_handler_timeout_stalled_peer() {
+        use std::thread;
+        use std::time::{Duration, Instant};
+
+        use time;
+
+        use mock::CloneableMockStream;
+        use version::HttpVersion;
+        use super::deadline::HeadDeadline;
+        use super::wheel::TimerWheel;
+
+        let clock = Arc::new(MockClock::new());
+        let timers = TimerWheel::with_clock(clock.clone());
+        let stalled = CloneableMockStream::with_stream(MockStream::new());
+        let other = CloneableMockStream::with_stream(MockStream::new());
+        // a peer that never drains its socket blocks every write to it
+        let guard = stalled.inner.lock().unwrap();
+
+        let first = HeadDeadline::arm(&timers, Box::new(stalled.clone()), Duration::from_secs(5),
+                                      HttpVersion::Http11, time::now_utc, None);
+        let second = HeadDeadline::arm(&timers, Box::new(other.clone()), Duration::from_secs(6),
+                                       HttpVersion::Http11, time::now_utc, None);
+        clock.advance(Duration::from_secs(10));
+
+        let give_up = Instant::now() + Duration::from_secs(5);
+        while !String::from_utf8_lossy(&other.inner.lock().unwrap().write).contains(" 503 ") {
+            assert!(Instant::now() < give_up, "a stalled peer held up the other deadline");
+            thread::sleep(Duration::from_millis(1));
+        }
+        assert!(first.timed_out());
+        assert!(second.timed_out());
+        drop(guard);
+    }
+
+    #[test]
+    fn test_handler_timeout_after_pipelined() {
+        use std::time::Duration;
+
+        use mock::CloneableMockStream;
+        use super::wheel::TimerWheel;
+
+        let mut mock = CloneableMockStream::with_stream(MockStream::with_input(b"\
+            GET /fast HTTP/1.1\r\n\
+            Host: example.domain\r\n\
+            \r\n\
+            GET /stuck HTTP/1.1\r\n\
+            Host: example.domain\r\n\
+            \r\n\
+        "));
+
+        struct FastThenStuck(Stuck);
+
+        impl Handler for FastThenStuck {
+            fn handle<'a, 'k>(&'a self, req: Request<'a, 'k>, res: Response<'a, Fresh>) {
+                if req.uri.to_string() == "/fast" {
+                    res.send(b"fast").unwrap();
+                } else {
+                    self.0.handle(req, res);
+                }
+            }
+        }
+
+        let clock = Arc::new(MockClock::new());
+        let stuck = Stuck { stream: mock.inner.clone(), clock: clock.clone() };
+        let timeouts = Timeouts { handler: Some(Duration::from_secs(5)), ..Default::default() };
+        let mut worker = Worker::new(FastThenStuck(stuck), timeouts, Default::default());
+        worker.timers = TimerWheel::with_clock(clock);
+        worker.handle_connection(&mut mock);
+
+        let stream = mock.inner.lock().unwrap();
+        let written = String::from_utf8(stream.write.clone()).unwrap();
+        assert!(written.starts_with("HTTP/1.1 200 OK\r\n"), "{}", written);
+        let fast = written.find("\r\n\r\nfast").unwrap();
+        let timeout = written.find("HTTP/1.1 503 Service Unavailable\r\n").unwrap();
+        assert!(fast < timeout);
+    }
+
+    #[test]
+    fn test_handler_timeout_not_reached() {
+        use std::time::Duration;
+
+        let mut mock = MockStream::with_input(b"\
+            GET / HTTP/1.1\r\n\
+            Host: example.domain\r\n\
+            Connection: close\r\n\
+            \r\n\
+        ");
+
+        fn handle(_: Request, res: Response<Fresh>) {
+            res.send(b"quick").unwrap();
+        }
+
+        let timeouts = Timeouts { handler: Some(Duration::from_secs(5)), ..Default::default() };
+        Worker::new(handle, timeouts, Default::default()).handle_connection(&mut mock);
+
+        let written = String::from_utf8(mock.write).unwrap();
+        assert!(written.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(written.ends_with("quick"));
+    }
+
+    #[test]
+    fn test_shed_poller_stops_on_close() {
+        use std::io;
+        use std::net::SocketAddr;
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        use std::thread;
+        use std::time::Duration;
+
+        use net::NetworkListener;
+        use super::Server;
+
+        // Never accepts, and counts the checks for waiting connections.
+        #[derive(Clone)]
+        struct Polled(Arc<AtomicUsize>);
+
+        impl NetworkListener for Polled {
+            type Stream = MockStream;
+
+            fn accept(&mut self) -> ::Result<MockStream> {
+                loop {
+                    thread::park();
+                }
+            }
+
+            fn local_addr(&mut self) -> io::Result<SocketAddr> {
+                Ok("127.0.0.1:1337".parse().unwrap())
+            }
+
+            fn has_pending(&mut self) -> bool {
+                self.0.fetch_add(1, Ordering::SeqCst);
+                false
+            }
+        }
+
+        fn handle(_: Request, _: Response<Fresh>) {}
+
+        let polls = Arc::new(AtomicUsize::new(0));
+        let mut server = Server::new(Polled(polls.clone()));
+        server.shed_idle_at_capacity(true);
+        let mut listening = server.handle_threads(handle, 1).unwrap();
+        thread::sleep(Duration::from_millis(100));
+        assert!(polls.load(Ordering::SeqCst) > 0);
+        let clones = Arc::strong_count(&polls);
+
+        listening.close().unwrap();
+        thread::sleep(Duration::from_millis(100));
+        let polled = polls.load(Ordering::SeqCst);
+        thread::sleep(Duration::from_millis(100));
+        assert_eq!(polls.load(Ordering::SeqCst), polled);
+        // the poller let go of its clone of the listener
+        assert_eq!(Arc::strong_count(&polls), clones - 1);
+    }
+
+    #[test]
+    fn test_shed_idle_at_capacity() {
+        use std::io::{Read, Write};
+        use std::net::TcpStream;
+        use std::thread;
+        use std::time::Duration;
+
+        use super::Server;
+
+        fn handle(_: Request, res: Response<Fresh>) {
+            res.send(b"ok").unwrap();
+        }
+
+        fn connect(addr: ::std::net::SocketAddr, req: &[u8]) -> TcpStream {
+            let mut stream = TcpStream::connect(addr).unwrap();
+            stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+            stream.write_all(req).unwrap();
+            stream
+        }
+
+        let mut server = Server::http("127.0.0.1:0").unwrap();
+        server.shed_idle_at_capacity(true);
+        let mut listening = server.handle_threads(handle, 2).unwrap();
+        let addr = listening.socket;
+
+        let mut idle = Vec::new();
+        for _ in 0..2 {
+            let mut stream = connect(addr, b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n");
+            let mut res = Vec::new();
+            let mut buf = [0; 256];
+            while !res.ends_with(b"ok") {
+                let n = stream.read(&mut buf).unwrap();
+                assert!(n > 0);
+                res.extend_from_slice(&buf[..n]);
+            }
+            // let the connection go idle before the next one arrives
+            thread::sleep(Duration::from_millis(100));
+            idle.push(stream);
+        }
+
+        // at capacity, but nothing is shed until another connection waits
+        thread::sleep(Duration::from_millis(100));
+        idle[0].write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+        let mut res = Vec::new();
+        let mut buf = [0; 256];
+        while !res.ends_with(b"ok") {
+            let n = idle[0].read(&mut buf).unwrap();
+            assert!(n > 0);
+            res.extend_from_slice(&buf[..n]);
+        }
+        thread::sleep(Duration::from_millis(100));
+
+        // both threads are taken, so this is only served if one was shed
+        let mut stream = connect(addr, b"\
+            GET / HTTP/1.1\r\n\
+            Host: localhost\r\n\
+            Connection: close\r\n\
+            \r\n\
+        ");
+        let mut res = String::new();
+        stream.read_to_string(&mut res).unwrap();
+        assert!(res.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(res.ends_with("ok"));
+
+        // the least recently active connection was closed cleanly
+        assert_eq!(idle[1].read(&mut [0; 8]).unwrap(), 0);
+
+        assert_eq!(listening.shed_idle(5), 1);
+        assert_eq!(idle[0].read(&mut [0; 8]).unwrap(), 0);
+        listening.close().unwrap();
+    }
+
+    #[test]
+    fn test_local_addr() {
+        use std::io::{Read, Write};
+        use std::net::TcpStream;
+
+        use super::Server;
+
+        fn handle(req: Request, res: Response<Fresh>) {
+            res.send(req.local_addr().unwrap().to_string().as_bytes()).unwrap();
+        }
+
+        let mut listening = Server::http("127.0.0.1:0").unwrap().handle_threads(handle, 1).unwrap();
+        let mut stream = TcpStream::connect(listening.socket).unwrap();
+        stream.write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")
+            .unwrap();
+        let mut res = String::new();
+        stream.read_to_string(&mut res).unwrap();
+        assert!(res.ends_with(&format!("\r\n\r\n{}", listening.socket)));
+        listening.close().unwrap();
+    }
+
+    #[test]
+    fn test_drain() {
+        use std::io::{Read, Write};
+        use std::net::TcpStream;
+        use std::thread;
+        use std::time::{Duration, Instant};
+
+        use super::{Server, ServerState};
+
+        fn handle(req: Request, res: Response<Fresh>) {
+            let body = if req.server_draining() { "draining" } else { "ok" };
+            res.send(body.as_bytes()).unwrap();
+        }
+
+        fn get(stream: &mut TcpStream) -> String {
+            stream.write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+            let mut res = Vec::new();
+            let mut buf = [0; 256];
+            while !res.ends_with(b"ok") && !res.ends_with(b"draining") {
+                let n = stream.read(&mut buf).unwrap();
+                assert!(n > 0);
+                res.extend_from_slice(&buf[..n]);
+            }
+            String::from_utf8(res).unwrap()
+        }
+
+        let mut listening = Server::http("127.0.0.1:0").unwrap().handle_threads(handle, 2).unwrap();
+        let mut stream = TcpStream::connect(listening.socket).unwrap();
+        stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let res = get(&mut stream);
+        assert!(!res.contains("Connection: close"));
+        assert_eq!(listening.state(), ServerState::Running);
+
+        listening.drain();
+        assert_eq!(listening.state(), ServerState::Draining);
+        let res = get(&mut stream);
+        assert!(res.contains("\r\nConnection: close\r\n"));
+        assert!(res.ends_with("\r\n\r\ndraining"));
+        assert_eq!(stream.read(&mut [0; 8]).unwrap(), 0);
+
+        let mut refused = TcpStream::connect(listening.socket).unwrap();
+        refused.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        assert_eq!(refused.read(&mut [0; 8]).unwrap(), 0);
+
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while listening.state() != ServerState::Stopped && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(5));
+        }
+        assert_eq!(listening.state(), ServerState::Stopped);
+        listening.close().unwrap();
+    }
+
+    #[test]
+    fn test_dump_connections() {
+        use std::io::{Read, Write};
+        use std::net::TcpStream;
+        use std::thread;
+        use std::time::{Duration, Instant};
+
+        use super::{ConnDebug, ConnState, Listening, Server};
+
+        fn handle(_: Request, res: Response<Fresh>) {
+            res.send(b"ok").unwrap();
+        }
+
+        fn wait_for(listening: &Listening, state: ConnState) -> ConnDebug {
+            let deadline = Instant::now() + Duration::from_secs(5);
+            loop {
+                let dump = listening.dump_connections();
+                if dump.len() == 1 && dump[0].state == state {
+                    return dump[0].clone();
+                }
+                assert!(Instant::now() < deadline, "never {:?}: {:?}", state, dump);
+                thread::sleep(Duration::from_millis(5));
+            }
+        }
+
+        let mut listening = Server::http("127.0.0.1:0").unwrap().handle_threads(handle, 1).unwrap();
+        assert!(listening.dump_connections().is_empty());
+        let mut stream = TcpStream::connect(listening.socket).unwrap();
+        stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+
+        stream.write_all(b"GET / HTTP/1.1\r\nHost: loc").unwrap();
+        let conn = wait_for(&listening, ConnState::ReadingHead);
+        assert_eq!(conn.remote_addr, stream.local_addr().unwrap());
+
+        stream.write_all(b"alhost\r\n\r\n").unwrap();
+        let mut res = Vec::new();
+        let mut buf = [0; 256];
+        while !res.ends_with(b"ok") {
+            let n = stream.read(&mut buf).unwrap();
+            assert!(n > 0);
+            res.extend_from_slice(&buf[..n]);
+        }
+        let conn = wait_for(&listening, ConnState::Idle);
+        listening.close().unwrap();
+        assert!(conn.keep_alive);
+        assert_eq!(conn.bytes_in, 35);
+        assert_eq!(conn.bytes_out, res.len() as u64);
+    }
+
+    #[test]
+    fn test_close_lingers_over_unread_body() {
+        use std::io::{Read, Write};
+        use std::net::TcpStream;
+        use std::thread;
+        use std::time::Duration;
+
+        use super::Server;
+
+        fn handle(_: Request, res: Response<Fresh>) {
+            res.send(b"ok").unwrap();
+        }
+
+        let listening = Server::http("127.0.0.1:0").unwrap();
+        let mut listening = listening.handle_threads(handle, 1).unwrap();
+
+        let body = vec![b'x'; 512 * 1024];
+        let mut stream = TcpStream::connect(listening.socket).unwrap();
+        stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let mut writer = stream.try_clone().unwrap();
+        let sending = thread::spawn(move || {
+            write!(writer, "POST / HTTP/1.1\r\n\
+                            Host: localhost\r\n\
+                            Connection: close\r\n\
+                            Content-Length: {}\r\n\
+                            \r\n", body.len()).unwrap();
+            writer.write_all(&body).unwrap();
+        });
+
+        // without lingering, the unread body would reset the connection
+        let mut res = String::new();
+        stream.read_to_string(&mut res).unwrap();
+        assert!(res.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(res.ends_with("ok"));
+        sending.join().unwrap();
+        listening.close().unwrap();
+    }
+
+    #[test]
+    fn test_ignored_chunked_body_from_idle_client() {
+        use std::io::{Read, Write};
+        use std::net::TcpStream;
+        use std::time::Duration;
+
+        use super::Server;
+
+        fn handle(_: Request, res: Response<Fresh>) {
+            res.send(b"ok").unwrap();
+        }
+
+        let listening = Server::http("127.0.0.1:0").unwrap();
+        let mut listening = listening.handle_threads(handle, 1).unwrap();
+
+        // no chunk ever arrives, and there is no read timeout
+        let mut stream = TcpStream::connect(listening.socket).unwrap();
+        stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        stream.write_all(b"POST / HTTP/1.1\r\n\
+                           Host: localhost\r\n\
+                           Transfer-Encoding: chunked\r\n\
+                           \r\n").unwrap();
+
+        let mut res = String::new();
+        stream.read_to_string(&mut res).unwrap();
+        assert!(res.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(res.ends_with("ok"));
+        listening.close().unwrap();
+    }
+
+    #[test]
+    fn test_close_lingers_only_over_unread_body() {
+        use std::io::Read;
+
+        use mock::CloneableMockStream;
+
+        fn handle(mut req: Request, res: Response<Fresh>) {
+            if req.uri == RequestUri::AbsolutePath("/read".to_owned()) {
+                req.read_to_end(&mut Vec::new()).unwrap();
+            }
+            res.send(b"ok").unwrap();
+        }
+
+        for &(path, lingers) in &[("/read", false), ("/unread", true)] {
+            let input = format!("POST {} HTTP/1.1\r\n\
+                                 Host: example.domain\r\n\
+                                 Connection: close\r\n\
+                                 Content-Length: 5\r\n\
+                                 \r\n\
+                                 hello", path);
+            let mut mock = CloneableMockStream::with_stream(MockStream::with_input(input.as_bytes()));
+            Worker::new(handle, Default::default(), Default::default())
+                .handle_connection(&mut mock);
+
+            let stream = mock.inner.lock().unwrap();
+            assert!(String::from_utf8_lossy(&stream.write).ends_with("ok"));
+            // lingering shuts the writing side first
+            assert_eq!(stream.is_closed, lingers, "{}", path);
+        }
+    }
+
+    #[test]
+    fn test_rate_limit_spans_connection() {
+        use std::time::{Duration, Instant};
+
+        fn handle(_: Request, res: Response<Fresh>) {
+            res.send(&[b'a'; 10 * 1024]).unwrap();
+        }
+
+        let request = "GET / HTTP/1.1\r\nHost: example.domain\r\n\r\n";
+        let mut mock = MockStream::with_input(request.repeat(3).as_bytes());
+        let mut options = Options::default();
+        options.rate_limit = Some(100 * 1024);
+        let start = Instant::now();
+        Worker::new(handle, Default::default(), options).handle_connection(&mut mock);
+
+        // the first 10KB used up the burst of the connection, not just of
+        // the first response, so the other 20KB take 200ms
+        assert!(start.elapsed() >= Duration::from_millis(150));
+        let written = String::from_utf8(mock.write).unwrap();
+        assert_eq!(written.matches("HTTP/1.1 200 OK").count(), 3);
+        assert_eq!(written.matches(&*"a".repeat(10 * 1024)).count(), 3);
+    }
+
+    #[test]
+    fn test_handle_router_threads() {
+        use std::io::{ErrorKind, Read, Write};
+        use std::net::TcpStream;
+        use std::time::Duration;
+
+        use method::Method;
+        use super::{Handler, Server};
+
+        fn index(_: Request, res: Response<Fresh>) {
+            res.send(b"index").unwrap();
+        }
+
+        fn get(addr: ::std::net::SocketAddr) -> TcpStream {
+            let mut stream = TcpStream::connect(addr).unwrap();
+            stream.write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+            stream
+        }
+
+        let mut server = Server::http("127.0.0.1:0").unwrap();
+        server.threads(1);
+        let mut listening = server.handle_router(|_: &Method, _: &str| {
+            Some(Box::new(index) as Box<Handler>)
+        }).unwrap();
+
+        // the one thread is kept by the first connection while it is alive
+        let mut first = get(listening.socket);
+        let mut buf = [0; 256];
+        assert!(first.read(&mut buf).unwrap() > 0);
+        let mut second = get(listening.socket);
+        second.set_read_timeout(Some(Duration::from_millis(200))).unwrap();
+        match second.read(&mut buf) {
+            Err(ref e) if e.kind() == ErrorKind::WouldBlock || e.kind() == ErrorKind::TimedOut => (),
+            other => panic!("second connection served alongside the first: {:?}", other),
+        }
+
+        drop(first);
+        second.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let n = second.read(&mut buf).unwrap();
+        assert!(String::from_utf8_lossy(&buf[..n]).starts_with("HTTP/1.1 200 OK\r\n"));
+        listening.close().unwrap();
+    }
 }
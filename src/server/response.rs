@@ -3,19 +3,41 @@
 //! These are responses sent by a `hyper::Server` to clients, after
 //! receiving a request.
 use std::any::{Any, TypeId};
+use std::cell::{Cell, RefCell};
+#[cfg(feature = "serde-serialization")]
+use std::error::Error as StdError;
+use std::ffi::OsString;
+use std::fs::{self, File};
 use std::marker::PhantomData;
 use std::mem;
 use std::io::{self, Write};
-use std::ptr;
+#[cfg(feature = "mmap")]
+use std::ops::Range;
+use std::path::{Path, PathBuf};
 use std::thread;
 
-use time::now_utc;
+#[cfg(feature = "mmap")]
+use memmap::Mmap;
+#[cfg(feature = "serde-serialization")]
+use mime::{Mime, TopLevel, SubLevel};
+#[cfg(feature = "serde-serialization")]
+use serde::Serialize;
+#[cfg(feature = "serde-serialization")]
+use serde_json;
+use serialize::base64::{ToBase64, STANDARD};
+use time::{now_utc, Tm};
+use unicase::UniCase;
+use url::{Url, UrlParser};
 
 use header;
-use http::h1::{CR, LF, LINE_ENDING, HttpWriter};
+use http;
+use http::h1::{CR, LF, LINE_ENDING, HttpWriter, write_last_chunk};
 use http::h1::HttpWriter::{ThroughWriter, ChunkedWriter, SizedWriter, EmptyWriter};
 use status;
 use net::{Fresh, Streaming};
+use server::cache;
+use server::digest::{Digest, DigestAlgorithm};
+use server::throttle::{Throttle, TokenBucket};
 use version;
 
 
@@ -30,24 +52,56 @@ use version;
 pub struct Response<'a, W: Any = Fresh> {
     /// The HTTP version of this response.
     pub version: version::HttpVersion,
+    // Taken out when the Response is deconstructed, leaving nothing for
+    // Drop to finish.
+    inner: Option<Inner<'a>>,
+
+    _writing: PhantomData<W>
+}
+
+#[derive(Debug)]
+struct Inner<'a> {
     // Stream the Response is writing to, not accessible through UnwrittenResponse
     body: HttpWriter<&'a mut (Write + 'a)>,
     // The status code for the request.
     status: status::StatusCode,
     // The outgoing headers on this response.
     headers: &'a mut header::Headers,
-
-    _writing: PhantomData<W>
+    // The clock used for the Date header.
+    clock: fn() -> Tm,
+    // How the body is delimited.
+    framing: Framing,
+    // Whether the first write of a raw body should be checked to be chunked.
+    check_chunked: bool,
+    // What `redirect` resolves relative locations against.
+    redirect_base: Option<Url>,
+    // The most bytes of header fields the head may have.
+    max_header_size: Option<usize>,
+    // Paces writes of the body.
+    rate_limit: Option<Throttle<'a>>,
+    // The version of the request being answered.
+    request_version: version::HttpVersion,
+    // Whether the request being answered said it takes trailers.
+    trailers_accepted: bool,
+    // Whether `start` holds back a chunked head until the body's length
+    // might be known.
+    defer_length: bool,
+    // The body written so far, while the head is held back.
+    deferred: Option<Vec<u8>>,
+    // The digest of the body written so far, to send in a trailer.
+    digest: Option<Digest>,
+    // Raised if the body ends short of its length.
+    unfinished: Option<&'a Cell<bool>>,
 }
 
 impl<'a, W: Any> Response<'a, W> {
     /// The status of this response.
     #[inline]
-    pub fn status(&self) -> status::StatusCode { self.status }
+    pub fn status(&self) -> status::StatusCode { self.inner().status }
 
     /// The headers of this response.
     #[inline]
-    pub fn headers(&self) -> &header::Headers { &*self.headers }
+    pub fn headers(&self) -> &header::Headers { &*self.inner().headers }
 
     /// Construct a Response from its constituent parts.
     #[inline]
@@ -55,49 +109,163 @@ impl<'a, W: Any> Response<'a, W> {
                      body: HttpWriter<&'a mut (Write + 'a)>,
                      status: status::StatusCode,
                      headers: &'a mut header::Headers) -> Response<'a, Fresh> {
-        Response {
-            status: status,
-            version: version,
+        Response::with_inner(version, Inner {
             body: body,
+            status: status,
             headers: headers,
-            _writing: PhantomData,
-        }
+            clock: now_utc,
+            framing: Framing::Auto,
+            check_chunked: false,
+            redirect_base: None,
+            max_header_size: None,
+            rate_limit: None,
+            request_version: version::HttpVersion::Http11,
+            trailers_accepted: true,
+            defer_length: false,
+            deferred: None,
+            digest: None,
+            unfinished: None,
+        })
     }
 
     /// Deconstruct this Response into its constituent parts.
     #[inline]
-    pub fn deconstruct(self) -> (version::HttpVersion, HttpWriter<&'a mut (Write + 'a)>,
-                                 status::StatusCode, &'a mut header::Headers) {
-        unsafe {
-            let parts = (
-                self.version,
-                ptr::read(&self.body),
-                self.status,
-                ptr::read(&self.headers)
-            );
-            mem::forget(self);
-            parts
+    pub fn deconstruct(mut self) -> (version::HttpVersion, HttpWriter<&'a mut (Write + 'a)>,
+                                     status::StatusCode, &'a mut header::Headers) {
+        let inner = self.inner.take().expect("Response already deconstructed");
+        (self.version, inner.body, inner.status, inner.headers)
+    }
+
+    fn with_inner(version: version::HttpVersion, inner: Inner<'a>) -> Response<'a, W> {
+        Response {
+            version: version,
+            inner: Some(inner),
+            _writing: PhantomData,
         }
     }
 
+    /// Limits how many bytes of the body a second are written, pacing
+    /// writes by sleeping.
+    ///
+    /// This replaces the limit a `Server` sets with
+    /// `per_connection_rate_limit`, for this response only.
+    pub fn set_rate_limit(&mut self, bytes_per_sec: Option<u64>) {
+        self.inner_mut().rate_limit = bytes_per_sec.map(|rate| Throttle::Own(TokenBucket::new(rate)));
+    }
+
+    /// Paces the body by the token bucket of its connection, which every
+    /// response on the connection draws from.
+    ///
+    /// A `Server` sets this if it has a `per_connection_rate_limit`.
+    #[doc(hidden)]
+    pub fn set_connection_rate_limit(&mut self, bucket: &'a RefCell<TokenBucket>) {
+        self.inner_mut().rate_limit = Some(Throttle::Connection(bucket));
+    }
+
+    /// Sets a flag to raise if the body ends short of the length sent in
+    /// the head, or is aborted, so the connection isn't reused.
+    ///
+    /// A `Server` sets this for every response.
+    pub fn set_unfinished_flag(&mut self, unfinished: &'a Cell<bool>) {
+        self.inner_mut().unfinished = Some(unfinished);
+    }
+
+    // Only a Response being deconstructed or dropped has no inner.
+    #[inline]
+    fn inner(&self) -> &Inner<'a> {
+        self.inner.as_ref().expect("Response already deconstructed")
+    }
+
+    #[inline]
+    fn inner_mut(&mut self) -> &mut Inner<'a> {
+        self.inner.as_mut().expect("Response already deconstructed")
+    }
+
     fn write_head(&mut self) -> io::Result<Body> {
-        debug!("writing head: {:?} {:?}", self.version, self.status);
-        try!(write!(&mut self.body, "{} {}{}{}", self.version, self.status,
-            CR as char, LF as char));
+        let version = self.version;
+        self.inner_mut().write_head(version)
+    }
 
-        if !self.headers.has::<header::Date>() {
-            self.headers.set(header::Date(header::HttpDate(now_utc())));
-        }
+    // Sends a head held back by `set_defer_length`, and what was buffered.
+    fn write_deferred(&mut self, len: Option<u64>) -> io::Result<()> {
+        let version = self.version;
+        let inner = mem::replace(&mut self.inner, None).expect("Response already deconstructed");
+        let (inner, result) = inner.write_deferred(version, len);
+        self.inner = Some(inner);
+        result
+    }
+}
 
-        let body_type = match self.status {
+impl<'a> Inner<'a> {
+    // How the body will be delimited, from the status and headers so far.
+    fn body_type(&self, version: version::HttpVersion) -> Body {
+        match self.status {
+            _ if self.framing == Framing::Raw => Body::Raw,
             status::StatusCode::NoContent | status::StatusCode::NotModified => Body::Empty,
             c if c.class() == status::StatusClass::Informational => Body::Empty,
             _ => if let Some(cl) = self.headers.get::<header::ContentLength>() {
                 Body::Sized(**cl)
+            } else if self.framing == Framing::Close ||
+                    version < version::HttpVersion::Http11 ||
+                    self.request_version < version::HttpVersion::Http11 {
+                // HTTP/1.0 peers can't read chunked bodies, whatever version
+                // the response claims.
+                Body::Close
             } else {
                 Body::Chunked
             }
+        }
+    }
+
+    // The trailer to end a chunked body with, if its digest was asked for.
+    fn take_trailers(&mut self) -> Option<header::Headers> {
+        let digest = match self.body {
+            ChunkedWriter(_) => self.digest.take(),
+            _ => None
+        };
+        digest.map(|digest| {
+            let mut trailers = header::Headers::new();
+            trailers.set_raw("Digest", vec![digest.header_value()]);
+            trailers
+        })
+    }
+
+    // Writes the held back head, framing it by `len` if the whole body is
+    // known, or else chunked, followed by the body so far. It takes the
+    // Inner by value to move the stream into its new framing, and gives
+    // it back whether or not writing failed.
+    fn write_deferred(mut self, version: version::HttpVersion, len: Option<u64>)
+            -> (Inner<'a>, io::Result<()>) {
+        let buf = match self.deferred.take() {
+            Some(buf) => buf,
+            None => return (self, Ok(()))
+        };
+        if let Some(len) = len {
+            self.headers.set(header::ContentLength(len));
+        }
+        let body_type = match self.write_head(version) {
+            Ok(body_type) => body_type,
+            Err(e) => return (self, Err(e))
         };
+        self.body = framed(self.body.into_inner(), body_type);
+        let result = self.write_all(&buf);
+        (self, result)
+    }
+
+    fn write_head(&mut self, version: version::HttpVersion) -> io::Result<Body> {
+        debug!("writing head: {:?} {:?}", version, self.status);
+        if !self.headers.has::<header::Date>() {
+            self.headers.set(header::Date(header::HttpDate((self.clock)())));
+        }
+
+        let body_type = self.body_type(version);
+        if body_type == Body::Close {
+            self.headers.set(header::Connection::close());
+        }
+        if body_type != Body::Chunked && self.digest.take().is_some() {
+            debug!("body isn't chunked, so there is no trailer for its digest");
+            self.headers.remove_raw("Trailer");
+        }
 
         // can't do in match above, thanks borrowck
         if body_type == Body::Chunked {
@@ -117,9 +285,24 @@ impl<'a, W: Any> Response<'a, W> {
         }
 
 
-        debug!("headers [\n{:?}]", self.headers);
-        try!(write!(&mut self.body, "{}", self.headers));
-        try!(write!(&mut self.body, "{}", LINE_ENDING));
+        debug!("headers [\n{:?}]", self.headers.redacted());
+        let mut head = Vec::with_capacity(STATUS_LINE_SIZE_HINT +
+                                          self.headers.serialized_size_hint() +
+                                          LINE_ENDING.len());
+        try!(write!(&mut head, "{} {}{}{}", version, self.status,
+            CR as char, LF as char));
+        let status_line_len = head.len();
+        try!(self.headers.write_to(&mut head));
+        if let Some(max) = self.max_header_size {
+            let size = head.len() - status_line_len;
+            if size > max {
+                debug!("response header fields are {} bytes, over the limit of {}", size, max);
+                return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                          "response header fields too large"));
+            }
+        }
+        head.extend_from_slice(LINE_ENDING.as_bytes());
+        try!(self.body.write_all(&head));
 
         Ok(body_type)
     }
@@ -130,13 +313,23 @@ impl<'a> Response<'a, Fresh> {
     #[inline]
     pub fn new(stream: &'a mut (Write + 'a), headers: &'a mut header::Headers) ->
             Response<'a, Fresh> {
-        Response {
+        Response::with_inner(version::HttpVersion::Http11, Inner {
+            body: ThroughWriter(stream),
             status: status::StatusCode::Ok,
-            version: version::HttpVersion::Http11,
             headers: headers,
-            body: ThroughWriter(stream),
-            _writing: PhantomData,
-        }
+            clock: now_utc,
+            framing: Framing::Auto,
+            check_chunked: false,
+            redirect_base: None,
+            max_header_size: None,
+            rate_limit: None,
+            request_version: version::HttpVersion::Http11,
+            trailers_accepted: true,
+            defer_length: false,
+            deferred: None,
+            digest: None,
+            unfinished: None,
+        })
     }
 
     /// Writes the body and ends the response.
@@ -168,58 +361,468 @@ impl<'a> Response<'a, Fresh> {
     /// ```
     #[inline]
     pub fn send(mut self, body: &[u8]) -> io::Result<()> {
-        self.headers.set(header::ContentLength(body.len() as u64));
+        // a digest trailer needs the body chunked
+        if self.inner().digest.is_none() {
+            self.headers_mut().set(header::ContentLength(body.len() as u64));
+        }
         let mut stream = try!(self.start());
         try!(stream.write_all(body));
         stream.end()
     }
 
+    /// Writes the body and ends the response, with an `ETag` made from a
+    /// SHA-256 of the body.
+    ///
+    /// If `if_none_match`, the request's `If-None-Match`, has a matching
+    /// tag, the response is a `304 Not Modified` instead, without the body.
+    /// It should only be given for a `GET` or `HEAD` request.
+    ///
+    /// ```
+    /// # use hyper::server::{Request, Response};
+    /// fn handler(req: Request, res: Response) {
+    ///     res.send_with_etag(b"Hello World!", false, req.headers.get()).unwrap();
+    /// }
+    /// ```
+    pub fn send_with_etag(mut self, body: &[u8], weak: bool,
+                          if_none_match: Option<&header::IfNoneMatch>) -> io::Result<()> {
+        let mut digest = Digest::new(DigestAlgorithm::Sha256);
+        digest.update(body);
+        let etag = header::EntityTag::new(weak, digest.finish().to_base64(STANDARD));
+
+        let not_modified = if_none_match.map_or(false, |cond| cond.matches(&etag));
+        self.headers_mut().set(header::ETag(etag));
+        if not_modified {
+            *self.status_mut() = status::StatusCode::NotModified;
+            return self.start().and_then(|res| res.end());
+        }
+        self.send(body)
+    }
+
+    /// Streams `items` as newline-delimited JSON, one item a line, and ends
+    /// the response.
+    ///
+    /// The `Content-Type` is set to `application/x-ndjson`, and each line is
+    /// written as its own chunk as soon as it is serialized. If an item is
+    /// an error or fails to serialize, the response is aborted, so the
+    /// client sees the body cut short, and the error is returned.
+    #[cfg(feature = "serde-serialization")]
+    pub fn send_ndjson<I, T, E>(mut self, items: I) -> io::Result<()>
+    where I: IntoIterator<Item=Result<T, E>>, T: Serialize,
+          E: Into<Box<StdError + Send + Sync>> {
+        self.headers_mut().set(header::ContentType(Mime(TopLevel::Application,
+                                                        SubLevel::Ext("x-ndjson".to_owned()),
+                                                        vec![])));
+        let mut stream = try!(self.start());
+        let mut line = Vec::new();
+        for item in items {
+            line.clear();
+            let res = item.map_err(|e| io::Error::new(io::ErrorKind::Other, e))
+                .and_then(|item| serde_json::to_writer(&mut line, &item));
+            if let Err(e) = res {
+                debug!("aborting ndjson response: {:?}", e);
+                try!(stream.abort());
+                return Err(e);
+            }
+            line.push(b'\n');
+            try!(stream.write_all(&line));
+        }
+        stream.end()
+    }
+
+    /// Writes the file at `path` as the body and ends the response,
+    /// preferring a precompressed variant when the client accepts it.
+    ///
+    /// If a `.br` or `.gz` file exists next to `path` (checked in that
+    /// order), and its encoding is acceptable according to `accept_encoding`,
+    /// that file is sent instead with the matching `Content-Encoding`. When
+    /// any variant exists, `Vary: Accept-Encoding` is added so caches keep
+    /// the representations apart. Otherwise the file at `path` is sent as is.
+    ///
+    /// The `Content-Type` is left to the caller.
+    pub fn send_file_with_variants<P: AsRef<Path>>(mut self, path: P,
+            accept_encoding: Option<&header::AcceptEncoding>) -> io::Result<()> {
+        let path = path.as_ref();
+        let variants = [
+            (header::Encoding::EncodingExt("br".to_owned()), ".br"),
+            (header::Encoding::Gzip, ".gz"),
+        ];
+
+        let mut chosen = None;
+        let mut has_variant = false;
+        for &(ref encoding, ext) in variants.iter() {
+            let variant = variant_path(path, ext);
+            if !is_file(&variant) {
+                continue;
+            }
+            has_variant = true;
+            if chosen.is_none() && accepts(accept_encoding, encoding) {
+                chosen = Some((encoding.clone(), variant));
+            }
+        }
+
+        if has_variant {
+            add_vary_accept_encoding(self.headers_mut());
+        }
+        let file_path = match chosen {
+            Some((encoding, variant)) => {
+                self.headers_mut().set(header::ContentEncoding(vec![encoding]));
+                variant
+            },
+            None => path.to_owned()
+        };
+
+        let mut file = try!(File::open(&file_path));
+        let len = try!(file.metadata()).len();
+        self.headers_mut().set(header::ContentLength(len));
+        let mut stream = try!(self.start());
+        try!(io::copy(&mut file, &mut stream));
+        stream.end()
+    }
+
     /// Consume this Response<Fresh>, writing the Headers and Status and
     /// creating a Response<Streaming>
     pub fn start(mut self) -> io::Result<Response<'a, Streaming>> {
+        let version = self.version;
+        if self.inner().defer_length && self.inner().digest.is_none() &&
+                self.inner().body_type(version) == Body::Chunked &&
+                !self.headers().has::<header::TransferEncoding>() {
+            trace!("holding back the head until the body's length is known");
+            let mut inner = self.inner.take().expect("Response already deconstructed");
+            inner.deferred = Some(Vec::new());
+            return Ok(Response::with_inner(version, inner));
+        }
         let body_type = try!(self.write_head());
+        let clock = self.inner().clock;
+        let framing = self.inner().framing;
+        let rate_limit = self.inner_mut().rate_limit.take();
+        let request_version = self.inner().request_version;
+        let trailers_accepted = self.inner().trailers_accepted;
+        let digest = self.inner_mut().digest.take();
+        let unfinished = self.inner().unfinished;
+        let check_chunked = body_type == Body::Raw && cfg!(debug_assertions) &&
+            match self.headers().get::<header::TransferEncoding>() {
+                Some(&header::TransferEncoding(ref encodings)) => {
+                    encodings.last() == Some(&header::Encoding::Chunked)
+                },
+                None => false
+            };
         let (version, body, status, headers) = self.deconstruct();
-        let stream = match body_type {
-            Body::Chunked => ChunkedWriter(body.into_inner()),
-            Body::Sized(len) => SizedWriter(body.into_inner(), len),
-            Body::Empty => EmptyWriter(body.into_inner()),
-        };
+        let stream = framed(body.into_inner(), body_type);
 
         // "copy" to change the phantom type
-        Ok(Response {
-            version: version,
+        Ok(Response::with_inner(version, Inner {
             body: stream,
             status: status,
             headers: headers,
-            _writing: PhantomData,
-        })
+            clock: clock,
+            framing: framing,
+            check_chunked: check_chunked,
+            redirect_base: None,
+            max_header_size: None,
+            rate_limit: rate_limit,
+            request_version: request_version,
+            trailers_accepted: trailers_accepted,
+            defer_length: false,
+            deferred: None,
+            digest: digest,
+            unfinished: unfinished,
+        }))
+    }
+
+    /// Like `start`, but also flushes the head to the client before
+    /// returning, instead of letting it go out with the first body writes.
+    ///
+    /// Use this when the head should arrive on its own, such as for clients
+    /// that act on the head before the body is ready.
+    ///
+    /// A server that corks responses, or holds one back to go out with the
+    /// response to a pipelined request, defers this flush like any other,
+    /// so the head is then sent once the handler returns.
+    pub fn start_flushed(self) -> io::Result<Response<'a, Streaming>> {
+        let mut res = try!(self.start());
+        // sends a held back head too
+        try!(res.flush());
+        Ok(res)
+    }
+
+    /// Starts a body whose length is decided once it is known, instead of
+    /// by the headers.
+    ///
+    /// This is `start` with `set_defer_length` enabled, so a body ended
+    /// within 64KB, and without a flush, is sent with a `Content-Length`.
+    #[inline]
+    pub fn start_deferred_length(mut self) -> io::Result<Response<'a, Streaming>> {
+        self.set_defer_length(true);
+        self.start()
     }
+
     /// Get a mutable reference to the status.
     #[inline]
-    pub fn status_mut(&mut self) -> &mut status::StatusCode { &mut self.status }
+    pub fn status_mut(&mut self) -> &mut status::StatusCode { &mut self.inner_mut().status }
 
     /// Get a mutable reference to the Headers.
     #[inline]
-    pub fn headers_mut(&mut self) -> &mut header::Headers { self.headers }
+    pub fn headers_mut(&mut self) -> &mut header::Headers { self.inner_mut().headers }
+
+    /// Sets the clock used for the `Date` header, if the handler doesn't
+    /// set one itself.
+    #[inline]
+    pub fn set_date_clock(&mut self, clock: fn() -> Tm) { self.inner_mut().clock = clock; }
+
+    /// Delimits the body by closing the connection after it, instead of
+    /// using chunked encoding.
+    ///
+    /// This is chosen automatically for HTTP/1.0 responses without a
+    /// `Content-Length`. Either way, `Connection: close` is sent and the
+    /// connection is not kept alive. A `Content-Length` still takes
+    /// precedence.
+    #[inline]
+    pub fn delimit_by_close(&mut self) { self.inner_mut().framing = Framing::Close; }
+
+    /// Writes the body exactly as the handler writes it, without any
+    /// framing, for instance to replay a previously captured chunked body.
+    ///
+    /// The head is sent with the headers as the handler set them; no
+    /// `Transfer-Encoding` or `Connection` header is added. The handler is
+    /// responsible for framing the body consistently with those headers.
+    /// In debug builds, if the last `Transfer-Encoding` is `chunked`, the
+    /// first bytes written are checked to start a chunk.
+    #[inline]
+    pub fn raw_body_mode(&mut self) { self.inner_mut().framing = Framing::Raw; }
+
+    /// Sends a `Digest` trailer after the body, with its digest by
+    /// `algorithm`.
+    ///
+    /// The body is chunked, so any `Content-Length` is removed, and a
+    /// `Trailer: Digest` header declares the trailer. The digest is
+    /// computed over the body as it is written, and sent when the body is
+    /// ended, either by `end` or when the response is dropped. A body that
+    /// is aborted, or can't be chunked, such as for an HTTP/1.0 request,
+    /// gets no trailer.
+    ///
+    /// Nothing is changed if the request didn't accept trailers, as
+    /// `Request::accepts_trailers` tells, since the client could drop the
+    /// trailer or be confused by it. See `set_request_accepts_trailers`.
+    pub fn with_digest_trailer(&mut self, algorithm: DigestAlgorithm) {
+        let inner = self.inner_mut();
+        if !inner.trailers_accepted {
+            debug!("request didn't accept trailers, so no digest trailer is sent");
+            return;
+        }
+        inner.headers.remove::<header::ContentLength>();
+        inner.headers.set_raw("Trailer", vec![b"Digest".to_vec()]);
+        inner.digest = Some(Digest::new(algorithm));
+    }
+
+    /// Sets the most bytes the header fields of this response may take up,
+    /// including their line endings.
+    ///
+    /// Starting a response with larger header fields fails with an
+    /// `InvalidData` error, and none of its head is written. The client
+    /// gets a bare `500 Internal Server Error` instead, and the connection
+    /// is closed.
+    #[inline]
+    pub fn set_max_header_size(&mut self, max: Option<usize>) {
+        self.inner_mut().max_header_size = max;
+    }
+
+    /// Controls whether `start` holds the head back, so that a body
+    /// written in full soon after can be sent with a `Content-Length`.
+    ///
+    /// This only applies to a response that would otherwise be chunked.
+    /// Writes are buffered, up to 64KB, and a body ended within that gets
+    /// a `Content-Length`. A larger body,
+    /// or a flush, sends the head and what was buffered, and the body
+    /// continues chunked. A `Server` sets this for every response if
+    /// `defer_content_length` is enabled.
+    #[inline]
+    pub fn set_defer_length(&mut self, defer: bool) {
+        self.inner_mut().defer_length = defer;
+    }
+
+    /// Sets the version of the request this response answers.
+    ///
+    /// A body without a `Content-Length` is delimited by closing the
+    /// connection for an HTTP/1.0 request, even if `version` was raised to
+    /// HTTP/1.1. A `Server` sets this for every response.
+    #[inline]
+    pub fn set_request_version(&mut self, version: version::HttpVersion) {
+        self.inner_mut().request_version = version;
+    }
+
+    /// Sets whether the request this response answers accepts trailers.
+    ///
+    /// `with_digest_trailer` does nothing when it doesn't. A `Server` sets
+    /// this from `Request::accepts_trailers` for every response; otherwise
+    /// trailers are taken to be accepted.
+    #[inline]
+    pub fn set_request_accepts_trailers(&mut self, accepts: bool) {
+        self.inner_mut().trailers_accepted = accepts;
+    }
+
+    /// Sets the URL that `redirect` resolves locations against.
+    ///
+    /// A `Server` with `absolute_redirects` enabled sets this to the
+    /// `effective_url` of the request.
+    #[inline]
+    pub fn set_redirect_base(&mut self, base: Option<Url>) { self.inner_mut().redirect_base = base; }
+
+    /// Redirects the client to `location`, setting the status and the
+    /// `Location` header.
+    ///
+    /// If there is a redirect base, relative and protocol-relative
+    /// locations are resolved against it, so the `Location` is absolute.
+    /// Otherwise the location is sent as given. Characters that aren't
+    /// allowed in a URI are percent-encoded either way.
+    pub fn redirect(&mut self, status: status::StatusCode, location: &str) {
+        let location = self.inner().redirect_base.as_ref().and_then(|base| {
+            match UrlParser::new().base_url(base).parse(location) {
+                Ok(url) => Some(url.serialize()),
+                Err(e) => {
+                    debug!("redirect location {:?} not resolved: {:?}", location, e);
+                    None
+                }
+            }
+        }).unwrap_or_else(|| encode_location(location));
+        *self.status_mut() = status;
+        self.headers_mut().set(header::Location(location));
+    }
+
+    /// Copies a status and headers, such as those of a client `Response` in
+    /// a reverse proxy, leaving out the hop-by-hop headers.
+    ///
+    /// A `Content-Length` is kept; otherwise the body is framed anew when
+    /// this response starts. Headers already set on this response, such as
+    /// `Connection: close`, are kept unless the copied ones replace them;
+    /// `Set-Cookie` values from both are kept.
+    pub fn copy_from(&mut self, status: status::StatusCode, headers: &header::Headers) {
+        *self.status_mut() = status;
+        let mut headers = headers.clone();
+        http::strip_hop_by_hop(&mut headers);
+        self.headers_mut().merge(&headers, header::MergePolicy::Replace);
+    }
+
+    /// Sets the caching headers of a `cache::Preset` on this response.
+    ///
+    /// The version of this response decides whether HTTP/1.0 fallback
+    /// headers are included, and an `Expires` is counted from the clock
+    /// of its `Date`.
+    #[inline]
+    pub fn apply(&mut self, preset: cache::Preset) {
+        let version = self.version;
+        let clock = self.inner().clock;
+        preset.apply_to(self.headers_mut(), version, clock);
+    }
 }
 
 
 impl<'a> Response<'a, Streaming> {
     /// Flushes all writing of a response to the client.
     #[inline]
-    pub fn end(self) -> io::Result<()> {
+    pub fn end(mut self) -> io::Result<()> {
         trace!("ending");
+        let len = self.inner().deferred.as_ref().map(|buf| buf.len() as u64);
+        try!(self.write_deferred(len));
+        let trailers = self.inner_mut().take_trailers();
+        let unfinished = self.inner().unfinished;
         let (_, body, _, _) = self.deconstruct();
-        try!(body.end());
+        if body.has_remaining() {
+            debug!("ending a response short of its Content-Length");
+            raise(unfinished);
+        }
+        match trailers {
+            Some(trailers) => {
+                let mut w = body.into_inner();
+                try!(write_last_chunk(&mut w, &trailers));
+                try!(w.flush());
+            },
+            None => { try!(body.end()); }
+        }
         Ok(())
     }
+
+    /// Stops the response without ending its body, and closes the
+    /// connection once it has been flushed.
+    ///
+    /// This is for a body that fails partway, so the client sees it cut
+    /// short instead of mistaking what was written for the whole body.
+    pub fn abort(mut self) -> io::Result<()> {
+        trace!("aborting");
+        try!(self.write_deferred(None));
+        raise(self.inner().unfinished);
+        let (_, mut body, _, headers) = self.deconstruct();
+        // the head is already written, this tells the server to close
+        headers.set(header::Connection::close());
+        body.get_mut().flush()
+    }
+
+    /// Writes the bytes of `range` in a memory-mapped file as part of the
+    /// body.
+    ///
+    /// The mapped bytes are given straight to the body's encoder, so a large
+    /// region is written to the stream without being copied into a buffer
+    /// first. The file must not be truncated while it is mapped.
+    #[cfg(feature = "mmap")]
+    pub fn write_mmap(&mut self, region: &Mmap, range: Range<usize>) -> io::Result<()> {
+        if range.start > range.end || range.end > region.len() {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                      "range is outside the mapped region"));
+        }
+        let bytes = unsafe { &region.as_slice()[range] };
+        self.write_all(bytes)
+    }
 }
 
 impl<'a> Write for Response<'a, Streaming> {
     #[inline]
     fn write(&mut self, msg: &[u8]) -> io::Result<usize> {
         debug!("write {:?} bytes", msg.len());
-        self.body.write(msg)
+        let overflow = {
+            let inner = self.inner_mut();
+            if inner.check_chunked && !msg.is_empty() {
+                inner.check_chunked = false;
+                debug_assert!((msg[0] as char).is_digit(16),
+                              "raw body declared as chunked doesn't start with a chunk size");
+            }
+            match inner.deferred {
+                Some(ref mut buf) if buf.len() + msg.len() <= DEFERRED_LENGTH_CAP => {
+                    buf.extend_from_slice(msg);
+                    return Ok(msg.len());
+                },
+                Some(_) => true,
+                None => false
+            }
+        };
+        if overflow {
+            debug!("deferred body over {} bytes, streaming it", DEFERRED_LENGTH_CAP);
+            try!(self.write_deferred(None));
+        }
+        self.inner_mut().write(msg)
+    }
+
+    #[inline]
+    fn flush(&mut self) -> io::Result<()> {
+        try!(self.write_deferred(None));
+        self.inner_mut().body.flush()
+    }
+}
+
+impl<'a> Write for Inner<'a> {
+    #[inline]
+    fn write(&mut self, msg: &[u8]) -> io::Result<usize> {
+        let msg = match self.rate_limit {
+            Some(ref mut bucket) => &msg[..bucket.take(msg.len())],
+            None => msg
+        };
+        let n = try!(self.body.write(msg));
+        // only what was written is charged
+        if let Some(ref mut bucket) = self.rate_limit {
+            bucket.give_back(msg.len() - n);
+        }
+        if let Some(ref mut digest) = self.digest {
+            digest.update(&msg[..n]);
+        }
+        Ok(n)
     }
 
     #[inline]
@@ -228,32 +831,177 @@ impl<'a> Write for Response<'a, Streaming> {
     }
 }
 
+/// The most bytes a response buffers while its head is held back by
+/// `set_defer_length`.
+const DEFERRED_LENGTH_CAP: usize = 64 * 1024;
+
+fn variant_path(path: &Path, ext: &str) -> PathBuf {
+    let mut name = OsString::from(path.as_os_str());
+    name.push(ext);
+    PathBuf::from(name)
+}
+
+fn encode_location(location: &str) -> String {
+    let mut encoded = String::with_capacity(location.len());
+    for &b in location.as_bytes() {
+        match b {
+            b'!'...b'~' if !b"\"<>\\^`{|}".contains(&b) => encoded.push(b as char),
+            _ => encoded.push_str(&format!("%{:02X}", b)),
+        }
+    }
+    encoded
+}
+
+fn is_file(path: &Path) -> bool {
+    fs::metadata(path).map(|meta| meta.is_file()).unwrap_or(false)
+}
+
+fn accepts(accept: Option<&header::AcceptEncoding>, encoding: &header::Encoding) -> bool {
+    let accept = match accept {
+        Some(accept) => accept,
+        None => return false
+    };
+    let star = header::Encoding::EncodingExt("*".to_owned());
+    let mut wildcard = false;
+    for item in accept.iter() {
+        if item.item == *encoding {
+            return item.quality.0 > 0;
+        }
+        if item.item == star {
+            wildcard = item.quality.0 > 0;
+        }
+    }
+    wildcard
+}
+
+fn add_vary_accept_encoding(headers: &mut header::Headers) {
+    let field = UniCase("Accept-Encoding".to_owned());
+    let items = match headers.get::<header::Vary>() {
+        Some(&header::Vary::Any) => return,
+        Some(&header::Vary::Items(ref items)) if items.contains(&field) => return,
+        Some(&header::Vary::Items(ref items)) => items.clone(),
+        None => Vec::new(),
+    };
+    let mut items = items;
+    items.push(field);
+    headers.set(header::Vary::Items(items));
+}
+
+// Enough for "HTTP/1.1 200 OK\r\n" and most other status lines.
+const STATUS_LINE_SIZE_HINT: usize = 32;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Framing {
+    Auto,
+    Close,
+    Raw,
+}
+
 #[derive(PartialEq)]
 enum Body {
     Chunked,
     Sized(u64),
+    Close,
+    Raw,
     Empty,
 }
 
+fn raise(flag: Option<&Cell<bool>>) {
+    if let Some(flag) = flag {
+        flag.set(true);
+    }
+}
+
+fn framed<W: Write>(stream: W, body_type: Body) -> HttpWriter<W> {
+    match body_type {
+        Body::Chunked => ChunkedWriter(stream),
+        Body::Sized(len) => SizedWriter(stream, len),
+        Body::Close | Body::Raw => ThroughWriter(stream),
+        Body::Empty => EmptyWriter(stream),
+    }
+}
+
 impl<'a, T: Any> Drop for Response<'a, T> {
     fn drop(&mut self) {
+        let version = self.version;
+        if self.inner.is_none() {
+            // deconstructed, so the parts are finished elsewhere
+            return;
+        }
         if TypeId::of::<T>() == TypeId::of::<Fresh>() {
+            let inner = self.inner_mut();
             if thread::panicking() {
-                self.status = status::StatusCode::InternalServerError;
+                inner.status = status::StatusCode::InternalServerError;
             }
 
-            let mut body = match self.write_head() {
-                Ok(Body::Chunked) => ChunkedWriter(self.body.get_mut()),
-                Ok(Body::Sized(len)) => SizedWriter(self.body.get_mut(), len),
-                Ok(Body::Empty) => EmptyWriter(self.body.get_mut()),
+            let mut body = match inner.write_head(version) {
+                Ok(Body::Chunked) => ChunkedWriter(inner.body.get_mut()),
+                Ok(Body::Sized(len)) => SizedWriter(inner.body.get_mut(), len),
+                Ok(Body::Close) | Ok(Body::Raw) => ThroughWriter(inner.body.get_mut()),
+                Ok(Body::Empty) => EmptyWriter(inner.body.get_mut()),
                 Err(e) => {
                     debug!("error dropping request: {:?}", e);
+                    // the client got no answer, so the connection can't go on
+                    raise(inner.unfinished);
+                    if e.kind() == io::ErrorKind::InvalidData {
+                        // the head was refused before any of it was written
+                        inner.status = status::StatusCode::InternalServerError;
+                        inner.headers.clear();
+                        inner.headers.set(header::ContentLength(0));
+                        inner.headers.set(header::Connection::close());
+                        inner.max_header_size = None;
+                        if let Err(e) = inner.write_head(version)
+                                .and_then(|_| inner.body.get_mut().flush()) {
+                            debug!("error dropping request: {:?}", e);
+                        }
+                    }
                     return;
                 }
             };
             end(&mut body);
+            if body.has_remaining() {
+                raise(inner.unfinished);
+            }
+        } else if thread::panicking() {
+            // like abort, leave the body unended so the client sees it cut
+            // short, rather than taking what was written for all of it
+            debug!("panicked while writing the body, closing the connection");
+            self.inner_mut().headers.set(header::Connection::close());
+            raise(self.inner().unfinished);
+            let len = if self.inner().deferred.is_some() {
+                // nothing was sent yet, so answer as a fresh response would
+                let inner = self.inner_mut();
+                inner.status = status::StatusCode::InternalServerError;
+                inner.deferred = Some(Vec::new());
+                Some(0)
+            } else {
+                None
+            };
+            if let Err(e) = self.write_deferred(len)
+                    .and_then(|_| self.inner_mut().body.get_mut().flush()) {
+                debug!("error dropping request: {:?}", e);
+            }
         } else {
-            end(&mut self.body);
+            let len = self.inner().deferred.as_ref().map(|buf| buf.len() as u64);
+            if let Err(e) = self.write_deferred(len) {
+                debug!("error dropping request: {:?}", e);
+                return;
+            }
+            let inner = self.inner_mut();
+            match inner.take_trailers() {
+                Some(trailers) => {
+                    let w = inner.body.get_mut();
+                    match write_last_chunk(w, &trailers).and_then(|_| w.flush()) {
+                        Ok(_) => debug!("drop successful"),
+                        Err(e) => debug!("error dropping request: {:?}", e)
+                    }
+                },
+                None => end(&mut inner.body)
+            }
+            if inner.body.has_remaining() {
+                debug!("dropped a response short of its Content-Length");
+                raise(inner.unfinished);
+            }
         };
 
 
@@ -309,6 +1057,27 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_date_clock() {
+        use time::{self, Timespec, Tm};
+
+        fn clock() -> Tm {
+            time::at_utc(Timespec::new(784111777, 0))
+        }
+
+        let mut headers = Headers::new();
+        let mut stream = MockStream::new();
+        {
+            let mut res = Response::new(&mut stream, &mut headers);
+            res.set_date_clock(clock);
+            res.start().unwrap().deconstruct();
+        }
+
+        let s = String::from_utf8(stream.write).unwrap();
+        assert!(s.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(s.contains("\r\nDate: Sun, 06 Nov 1994 08:49:37 GMT\r\n"));
+    }
+
     #[test]
     fn test_streaming_end() {
         let mut headers = Headers::new();
@@ -329,34 +1098,514 @@ mod tests {
     }
 
     #[test]
-    fn test_fresh_drop() {
-        use status::StatusCode;
+    fn test_http10_close_delimited() {
+        use std::io::Write;
+        use version::HttpVersion;
+
         let mut headers = Headers::new();
         let mut stream = MockStream::new();
         {
             let mut res = Response::new(&mut stream, &mut headers);
-            *res.status_mut() = StatusCode::NotFound;
+            res.version = HttpVersion::Http10;
+            let mut res = res.start().unwrap();
+            res.write_all(b"foo bar").unwrap();
+            res.end().unwrap();
         }
 
-        lines! { stream =
-            "HTTP/1.1 404 Not Found",
-            _date,
-            _transfer_encoding,
-            "",
-            "0",
-            "" // empty zero body
-        }
+        let s = String::from_utf8(stream.write).unwrap();
+        assert!(s.starts_with("HTTP/1.0 200 OK\r\n"));
+        assert!(s.contains("\r\nConnection: close\r\n"));
+        assert!(!s.contains("Transfer-Encoding"));
+        assert!(!s.contains("Content-Length"));
+        assert!(s.ends_with("\r\n\r\nfoo bar"));
     }
 
-    // x86 windows msvc does not support unwinding
-    // See https://github.com/rust-lang/rust/issues/25869
-    #[cfg(not(all(windows, target_arch="x86", target_env="msvc")))]
     #[test]
-    fn test_fresh_drop_panicing() {
-        use std::thread;
-        use std::sync::{Arc, Mutex};
+    fn test_http10_request_never_chunked() {
+        use std::io::Write;
+        use version::HttpVersion;
 
-        use status::StatusCode;
+        let mut headers = Headers::new();
+        let mut stream = MockStream::new();
+        {
+            let mut res = Response::new(&mut stream, &mut headers);
+            res.set_request_version(HttpVersion::Http10);
+            res.version = HttpVersion::Http11;
+            let mut res = res.start().unwrap();
+            res.write_all(b"foo bar").unwrap();
+            res.end().unwrap();
+        }
+
+        let s = String::from_utf8(stream.write).unwrap();
+        assert!(s.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(s.contains("\r\nConnection: close\r\n"));
+        assert!(!s.contains("Transfer-Encoding"));
+        assert!(s.ends_with("\r\n\r\nfoo bar"));
+    }
+
+    #[cfg(feature = "serde-serialization")]
+    #[test]
+    fn test_send_ndjson() {
+        use std::collections::BTreeMap;
+
+        let items = (1..4).map(|id| {
+            let mut item = BTreeMap::new();
+            item.insert("id".to_owned(), id);
+            Ok::<_, String>(item)
+        });
+        let mut headers = Headers::new();
+        let mut stream = MockStream::new();
+        Response::new(&mut stream, &mut headers).send_ndjson(items).unwrap();
+
+        let s = String::from_utf8(stream.write).unwrap();
+        assert!(s.contains("\r\nContent-Type: application/x-ndjson\r\n"));
+        assert!(s.contains("\r\nTransfer-Encoding: chunked\r\n"));
+        assert!(s.ends_with("\r\n\r\n9\r\n{\"id\":1}\n\r\n9\r\n{\"id\":2}\n\r\n\
+                             9\r\n{\"id\":3}\n\r\n0\r\n\r\n"));
+    }
+
+    #[cfg(feature = "serde-serialization")]
+    #[test]
+    fn test_send_ndjson_aborts() {
+        use header::Connection;
+
+        let items = vec![Ok(1), Err("database went away"), Ok(3)];
+        let mut headers = Headers::new();
+        let mut stream = MockStream::new();
+        let err = Response::new(&mut stream, &mut headers).send_ndjson(items).unwrap_err();
+        assert_eq!(err.to_string(), "database went away");
+
+        let s = String::from_utf8(stream.write).unwrap();
+        assert!(s.ends_with("\r\n\r\n2\r\n1\n\r\n"));
+        assert_eq!(headers.get(), Some(&Connection::close()));
+    }
+
+    #[test]
+    fn test_delimit_by_close() {
+        use std::io::Write;
+
+        let mut headers = Headers::new();
+        let mut stream = MockStream::new();
+        {
+            let mut res = Response::new(&mut stream, &mut headers);
+            res.delimit_by_close();
+            let mut res = res.start().unwrap();
+            res.write_all(b"foo bar").unwrap();
+            res.end().unwrap();
+        }
+
+        lines! { stream =
+            "HTTP/1.1 200 OK",
+            _date,
+            _connection,
+            "",
+            "foo bar"
+        }
+    }
+
+    #[test]
+    fn test_raw_body_replay() {
+        use std::io::{Read, Write};
+        use buffer::BufReader;
+        use header::{Encoding, TransferEncoding};
+        use http::h1::{parse_response, HttpReader};
+
+        let captured = b"5;ext=1\r\nhello\r\n6\r\n world\r\n0\r\n\r\n";
+        let mut headers = Headers::new();
+        let mut stream = MockStream::new();
+        {
+            let mut res = Response::new(&mut stream, &mut headers);
+            res.headers_mut().set(TransferEncoding(vec![Encoding::Chunked]));
+            res.raw_body_mode();
+            let mut res = res.start().unwrap();
+            res.write_all(&captured[..4]).unwrap();
+            res.write_all(&captured[4..]).unwrap();
+            res.end().unwrap();
+        }
+
+        assert!(stream.write.ends_with(b"\r\n\r\n5;ext=1\r\nhello\r\n6\r\n world\r\n0\r\n\r\n"));
+        let s = String::from_utf8(stream.write.clone()).unwrap();
+        assert_eq!(s.matches("Transfer-Encoding").count(), 1);
+
+        let mut written = &stream.write[..];
+        let mut rdr = BufReader::new(&mut written);
+        let head = parse_response(&mut rdr).unwrap();
+        assert_eq!(head.headers.get(), Some(&TransferEncoding(vec![Encoding::Chunked])));
+        let mut body = String::new();
+        HttpReader::ChunkedReader(&mut rdr, None).read_to_string(&mut body).unwrap();
+        assert_eq!(body, "hello world");
+    }
+
+    #[cfg(debug_assertions)]
+    #[test]
+    #[should_panic]
+    fn test_raw_body_not_chunked() {
+        use std::io::Write;
+        use header::{Encoding, TransferEncoding};
+
+        let mut headers = Headers::new();
+        let mut stream = MockStream::new();
+        let mut res = Response::new(&mut stream, &mut headers);
+        res.headers_mut().set(TransferEncoding(vec![Encoding::Chunked]));
+        res.raw_body_mode();
+        let mut res = res.start().unwrap();
+        res.write_all(b"hello").unwrap();
+    }
+
+    #[test]
+    fn test_copy_from() {
+        use std::io;
+        use client;
+        use header::{Connection, ContentLength, Server, TransferEncoding};
+        use status::StatusCode;
+        use url::Url;
+
+        let url = Url::parse("http://backend").unwrap();
+        let sized = client::Response::new(url.clone(), Box::new(MockStream::with_input(b"\
+            HTTP/1.1 404 Not Found\r\n\
+            Server: backend\r\n\
+            Content-Length: 4\r\n\
+            Keep-Alive: timeout=5\r\n\
+            \r\n\
+            gone\
+        "))).unwrap();
+        let mut headers = Headers::new();
+        headers.set(Connection::close());
+        let mut stream = MockStream::new();
+        {
+            let mut res = Response::new(&mut stream, &mut headers);
+            res.copy_from(sized.status, &sized.headers);
+            assert_eq!(res.status(), StatusCode::NotFound);
+        }
+        assert_eq!(headers.get(), Some(&ContentLength(4)));
+        assert_eq!(headers.get(), Some(&Server("backend".to_owned())));
+        assert_eq!(headers.get(), Some(&Connection::close()));
+        assert!(headers.get_raw("Keep-Alive").is_none());
+
+        let mut chunked = client::Response::new(url, Box::new(MockStream::with_input(b"\
+            HTTP/1.1 200 OK\r\n\
+            Transfer-Encoding: chunked\r\n\
+            \r\n\
+            4\r\n\
+            body\r\n\
+            0\r\n\
+            \r\n\
+        "))).unwrap();
+        let mut headers = Headers::new();
+        let mut stream = MockStream::new();
+        {
+            let mut res = Response::new(&mut stream, &mut headers);
+            res.copy_from(chunked.status, &chunked.headers);
+            let mut res = res.start().unwrap();
+            io::copy(&mut chunked, &mut res).unwrap();
+            res.end().unwrap();
+        }
+        assert_eq!(headers.get::<TransferEncoding>().unwrap().len(), 1);
+        let s = String::from_utf8(stream.write).unwrap();
+        assert!(s.ends_with("\r\n\r\n4\r\nbody\r\n0\r\n\r\n"));
+    }
+
+    #[test]
+    fn test_start_flushed() {
+        use std::io::{BufWriter, Write};
+        use std::sync::{Arc, Mutex};
+        use mock::CloneableMockStream;
+
+        let inner = Arc::new(Mutex::new(MockStream::new()));
+        let mut wrt = BufWriter::new(CloneableMockStream { inner: inner.clone() });
+        let mut headers = Headers::new();
+        {
+            let res = Response::new(&mut wrt, &mut headers);
+            let mut res = res.start_flushed().unwrap();
+            {
+                let written = inner.lock().unwrap().write.clone();
+                assert!(written.starts_with(b"HTTP/1.1 200 OK\r\n"));
+                assert!(written.ends_with(b"\r\n\r\n"));
+            }
+            res.write_all(b"foo").unwrap();
+            assert!(inner.lock().unwrap().write.ends_with(b"\r\n\r\n"));
+            res.end().unwrap();
+        }
+        assert!(inner.lock().unwrap().write.ends_with(b"\r\n\r\n3\r\nfoo\r\n0\r\n\r\n"));
+    }
+
+    #[test]
+    fn test_partial_head_writes() {
+        use std::io::{self, Write};
+
+        // Accepts at most 3 bytes per write, like a socket with a full buffer.
+        struct Trickle(Vec<u8>);
+
+        impl Write for Trickle {
+            fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+                let n = ::std::cmp::min(buf.len(), 3);
+                self.0.extend_from_slice(&buf[..n]);
+                Ok(n)
+            }
+
+            fn flush(&mut self) -> io::Result<()> {
+                Ok(())
+            }
+        }
+
+        let mut trickle = Trickle(Vec::new());
+        let mut headers = Headers::new();
+        headers.set_raw("X-Long", vec![vec![b'x'; 100]]);
+        {
+            let res = Response::new(&mut trickle, &mut headers);
+            res.send(b"hello").unwrap();
+        }
+
+        let s = String::from_utf8(trickle.0).unwrap();
+        assert_eq!(s.matches("HTTP/1.1 200 OK\r\n").count(), 1);
+        assert!(s.starts_with("HTTP/1.1 200 OK\r\n"));
+        let long = String::from_utf8(vec![b'x'; 100]).unwrap();
+        assert!(s.contains(&format!("\r\nX-Long: {}\r\n", long)[..]));
+        assert!(s.contains("\r\nContent-Length: 5\r\n"));
+        assert!(s.ends_with("\r\n\r\nhello"));
+    }
+
+    #[test]
+    fn test_deconstruct_skips_drop() {
+        let mut headers = Headers::new();
+        let mut stream = MockStream::new();
+        {
+            let res = Response::new(&mut stream, &mut headers);
+            let (_, _, _, headers) = res.deconstruct();
+            headers.set_raw("X-Kept", vec![b"1".to_vec()]);
+        }
+        assert!(stream.write.is_empty());
+        assert!(headers.get_raw("X-Kept").is_some());
+    }
+
+    #[test]
+    fn test_deferred_length_under_cap() {
+        use std::io::Write;
+
+        let mut headers = Headers::new();
+        let mut stream = MockStream::new();
+        {
+            let res = Response::new(&mut stream, &mut headers);
+            let mut body = res.start_deferred_length().unwrap();
+            body.write_all(b"hello ").unwrap();
+            body.write_all(b"world").unwrap();
+            body.end().unwrap();
+        }
+
+        let s = String::from_utf8(stream.write).unwrap();
+        assert!(s.contains("\r\nContent-Length: 11\r\n"));
+        assert!(!s.contains("Transfer-Encoding"));
+        assert!(s.ends_with("\r\n\r\nhello world"));
+    }
+
+    #[test]
+    fn test_deferred_length_over_cap() {
+        use std::io::Write;
+
+        let mut headers = Headers::new();
+        let mut stream = MockStream::new();
+        {
+            let res = Response::new(&mut stream, &mut headers);
+            let mut body = res.start_deferred_length().unwrap();
+            body.write_all(&[b'a'; 40 * 1024]).unwrap();
+            body.write_all(&[b'b'; 40 * 1024]).unwrap();
+            // dropped without end
+        }
+
+        let s = String::from_utf8(stream.write).unwrap();
+        assert!(!s.contains("Content-Length"));
+        assert!(s.contains("\r\nTransfer-Encoding: chunked\r\n"));
+        // the buffered write goes out as the first chunk
+        assert!(s.contains("\r\n\r\nA000\r\naaaa"));
+        assert!(s.contains("\r\nA000\r\nbbbb"));
+        assert!(s.ends_with("\r\n0\r\n\r\n"));
+    }
+
+    #[test]
+    fn test_digest_trailer_not_chunked() {
+        use std::io::Write;
+        use version::HttpVersion;
+        use super::DigestAlgorithm;
+
+        // an HTTP/1.0 client can't be sent a trailer
+        let mut headers = Headers::new();
+        let mut stream = MockStream::new();
+        {
+            let mut res = Response::new(&mut stream, &mut headers);
+            res.set_request_version(HttpVersion::Http10);
+            res.with_digest_trailer(DigestAlgorithm::Sha256);
+            let mut res = res.start().unwrap();
+            res.write_all(b"body").unwrap();
+            res.end().unwrap();
+        }
+        let s = String::from_utf8(stream.write).unwrap();
+        assert!(!s.contains("Trailer"));
+        assert!(s.ends_with("\r\n\r\nbody"));
+
+        // an aborted body isn't ended, so has no trailer either
+        let mut headers = Headers::new();
+        let mut stream = MockStream::new();
+        {
+            let mut res = Response::new(&mut stream, &mut headers);
+            res.with_digest_trailer(DigestAlgorithm::Sha256);
+            let mut res = res.start().unwrap();
+            res.write_all(b"body").unwrap();
+            res.abort().unwrap();
+        }
+        let s = String::from_utf8(stream.write).unwrap();
+        assert!(s.contains("\r\nTrailer: Digest\r\n"));
+        assert!(s.ends_with("\r\n\r\n4\r\nbody\r\n"));
+    }
+
+    #[test]
+    fn test_digest_trailer_not_accepted() {
+        use std::io::Write;
+        use header::ContentLength;
+        use super::DigestAlgorithm;
+
+        let mut headers = Headers::new();
+        let mut stream = MockStream::new();
+        {
+            let mut res = Response::new(&mut stream, &mut headers);
+            res.set_request_accepts_trailers(false);
+            res.headers_mut().set(ContentLength(4));
+            res.with_digest_trailer(DigestAlgorithm::Sha256);
+            let mut res = res.start().unwrap();
+            res.write_all(b"body").unwrap();
+            res.end().unwrap();
+        }
+        let s = String::from_utf8(stream.write).unwrap();
+        assert!(!s.contains("Trailer"));
+        assert!(!s.contains("Digest"));
+        assert!(s.contains("\r\nContent-Length: 4\r\n"));
+        assert!(s.ends_with("\r\n\r\nbody"));
+    }
+
+    #[test]
+    fn test_defer_length_single_write() {
+        use std::io::Write;
+
+        let mut headers = Headers::new();
+        let mut stream = MockStream::new();
+        {
+            let mut res = Response::new(&mut stream, &mut headers);
+            res.set_defer_length(true);
+            let mut res = res.start().unwrap();
+            res.write_all(b"hello world").unwrap();
+            res.end().unwrap();
+        }
+
+        let s = String::from_utf8(stream.write).unwrap();
+        assert!(s.contains("\r\nContent-Length: 11\r\n"));
+        assert!(!s.contains("Transfer-Encoding"));
+        assert!(s.ends_with("\r\n\r\nhello world"));
+    }
+
+    #[test]
+    fn test_defer_length_flushed() {
+        use std::io::Write;
+
+        let mut headers = Headers::new();
+        let mut stream = MockStream::new();
+        {
+            let mut res = Response::new(&mut stream, &mut headers);
+            res.set_defer_length(true);
+            let mut res = res.start().unwrap();
+            res.write_all(b"hello ").unwrap();
+            res.flush().unwrap();
+            res.write_all(b"world").unwrap();
+            // dropped without end
+        }
+
+        let s = String::from_utf8(stream.write).unwrap();
+        assert!(!s.contains("Content-Length"));
+        assert!(s.contains("\r\nTransfer-Encoding: chunked\r\n"));
+        assert!(s.ends_with("\r\n\r\n6\r\nhello \r\n5\r\nworld\r\n0\r\n\r\n"));
+    }
+
+    #[cfg(feature = "mmap")]
+    #[test]
+    fn test_write_mmap() {
+        use std::env;
+        use std::fs::{self, File};
+        use std::io::Write;
+        use std::process;
+        use memmap::{Mmap, Protection};
+        use header::ContentLength;
+
+        let path = env::temp_dir().join(format!("hyper-write-mmap-{}", process::id()));
+        let contents: Vec<u8> = (0..20_000u32).map(|i| (i % 251) as u8).collect();
+        File::create(&path).unwrap().write_all(&contents).unwrap();
+        let region = Mmap::open_path(&path, Protection::Read).unwrap();
+
+        let mut headers = Headers::new();
+        headers.set(ContentLength(10_000));
+        let mut stream = MockStream::new();
+        {
+            let res = Response::new(&mut stream, &mut headers);
+            let mut res = res.start().unwrap();
+            assert!(res.write_mmap(&region, 15_000..20_001).is_err());
+            res.write_mmap(&region, 5_000..15_000).unwrap();
+            res.end().unwrap();
+        }
+        drop(region);
+        fs::remove_file(&path).unwrap();
+
+        let body_start = stream.write.windows(4).position(|w| w == b"\r\n\r\n").unwrap() + 4;
+        assert_eq!(&stream.write[body_start..], &contents[5_000..15_000]);
+    }
+
+    #[test]
+    fn test_rate_limit() {
+        use std::io::Write;
+        use std::time::{Duration, Instant};
+
+        let mut headers = Headers::new();
+        let mut stream = MockStream::new();
+        let start = Instant::now();
+        {
+            let mut res = Response::new(&mut stream, &mut headers);
+            res.set_rate_limit(Some(100 * 1024));
+            let mut res = res.start().unwrap();
+            res.write_all(&[b'a'; 50 * 1024]).unwrap();
+            res.end().unwrap();
+        }
+        // 10KB pass right away, the other 40KB take 400ms
+        assert!(start.elapsed() >= Duration::from_millis(350));
+        let s = String::from_utf8(stream.write).unwrap();
+        let body = &s[s.find("\r\n\r\n").unwrap()..];
+        assert_eq!(body.matches('a').count(), 50 * 1024);
+    }
+
+    #[test]
+    fn test_fresh_drop() {
+        use status::StatusCode;
+        let mut headers = Headers::new();
+        let mut stream = MockStream::new();
+        {
+            let mut res = Response::new(&mut stream, &mut headers);
+            *res.status_mut() = StatusCode::NotFound;
+        }
+
+        lines! { stream =
+            "HTTP/1.1 404 Not Found",
+            _date,
+            _transfer_encoding,
+            "",
+            "0",
+            "" // empty zero body
+        }
+    }
+
+    // x86 windows msvc does not support unwinding
+    // See https://github.com/rust-lang/rust/issues/25869
+    #[cfg(not(all(windows, target_arch="x86", target_env="msvc")))]
+    #[test]
+    fn test_fresh_drop_panicing() {
+        use std::thread;
+        use std::sync::{Arc, Mutex};
+
+        use status::StatusCode;
 
         let stream = MockStream::new();
         let stream = Arc::new(Mutex::new(stream));
@@ -388,6 +1637,84 @@ mod tests {
     }
 
 
+    #[cfg(not(all(windows, target_arch="x86", target_env="msvc")))]
+    #[test]
+    fn test_streaming_drop_panicking() {
+        use std::io::Write;
+        use std::thread;
+        use std::sync::{Arc, Mutex};
+
+        use header::Connection;
+
+        let stream = Arc::new(Mutex::new(MockStream::new()));
+        let headers = Arc::new(Mutex::new(Headers::new()));
+        let (inner_stream, inner_headers) = (stream.clone(), headers.clone());
+        let join_handle = thread::spawn(move || {
+            let mut stream = inner_stream.lock().unwrap();
+            let mut headers = inner_headers.lock().unwrap();
+            let res = Response::new(&mut *stream, &mut *headers);
+            let mut res = res.start().unwrap();
+            res.write_all(b"some of the body").unwrap();
+
+            panic!("inside")
+        });
+
+        assert!(join_handle.join().is_err());
+
+        let headers = match headers.lock() {
+            Err(poisoned) => poisoned.into_inner().clone(),
+            Ok(_) => unreachable!()
+        };
+        assert_eq!(headers.get::<Connection>(), Some(&Connection::close()));
+        let stream = match stream.lock() {
+            Err(poisoned) => poisoned.into_inner().clone(),
+            Ok(_) => unreachable!()
+        };
+
+        lines! { stream =
+            "HTTP/1.1 200 OK",
+            _date,
+            _transfer_encoding,
+            "",
+            "10",
+            "some of the body"
+        }
+    }
+
+    #[cfg(not(all(windows, target_arch="x86", target_env="msvc")))]
+    #[test]
+    fn test_deferred_drop_panicking() {
+        use std::io::Write;
+        use std::thread;
+        use std::sync::{Arc, Mutex};
+
+        let stream = Arc::new(Mutex::new(MockStream::new()));
+        let inner_stream = stream.clone();
+        let join_handle = thread::spawn(move || {
+            let mut headers = Headers::new();
+            let mut stream = inner_stream.lock().unwrap();
+            let res = Response::new(&mut *stream, &mut headers);
+            let mut res = res.start_deferred_length().unwrap();
+            res.write_all(b"some of the body").unwrap();
+
+            panic!("inside")
+        });
+
+        assert!(join_handle.join().is_err());
+
+        let stream = match stream.lock() {
+            Err(poisoned) => poisoned.into_inner().clone(),
+            Ok(_) => unreachable!()
+        };
+
+        // the held back head is replaced by an error
+        let s = String::from_utf8(stream.write).unwrap();
+        assert!(s.starts_with("HTTP/1.1 500 Internal Server Error\r\n"));
+        assert!(s.contains("\r\nContent-Length: 0\r\n"));
+        assert!(s.contains("\r\nConnection: close\r\n"));
+        assert!(s.ends_with("\r\n\r\n"));
+    }
+
     #[test]
     fn test_streaming_drop() {
         use std::io::Write;
@@ -413,6 +1740,78 @@ mod tests {
         }
     }
 
+    fn send_file(name: &str, variants: &[&str], accept: Option<&str>)
+            -> (Headers, Vec<u8>) {
+        use std::env;
+        use std::fs::{self, File};
+        use std::io::Write;
+        use header::{AcceptEncoding, Header};
+
+        let dir = env::temp_dir().join(format!("hyper-{}", name));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("app.js");
+        File::create(&path).unwrap().write_all(b"plain").unwrap();
+        for ext in variants {
+            let mut f = File::create(dir.join(format!("app.js.{}", ext))).unwrap();
+            f.write_all(ext.as_bytes()).unwrap();
+        }
+
+        let accept = accept.map(|a| {
+            AcceptEncoding::parse_header(&[a.as_bytes().to_vec()]).unwrap()
+        });
+        let mut headers = Headers::new();
+        let mut stream = MockStream::new();
+        {
+            let res = Response::new(&mut stream, &mut headers);
+            res.send_file_with_variants(&path, accept.as_ref()).unwrap();
+        }
+        fs::remove_dir_all(&dir).unwrap();
+
+        let body_start = stream.write.windows(4).position(|w| w == b"\r\n\r\n").unwrap() + 4;
+        (headers, stream.write[body_start..].to_vec())
+    }
+
+    #[test]
+    fn test_send_file_prefers_br() {
+        use header::{ContentEncoding, Encoding, Vary};
+        let (headers, body) = send_file("variants-br", &["gz", "br"], Some("gzip, br"));
+        assert_eq!(body, b"br");
+        assert_eq!(headers.get(), Some(&ContentEncoding(vec![Encoding::EncodingExt("br".to_owned())])));
+        assert_eq!(headers.get(), Some(&Vary::Items(vec!["accept-encoding".parse().unwrap()])));
+    }
+
+    #[test]
+    fn test_send_file_gzip_sibling() {
+        use header::{ContentEncoding, ContentLength, Encoding};
+        let (headers, body) = send_file("variants-gz", &["gz"], Some("gzip;q=0.8, br"));
+        assert_eq!(body, b"gz");
+        assert_eq!(headers.get(), Some(&ContentEncoding(vec![Encoding::Gzip])));
+        assert_eq!(headers.get(), Some(&ContentLength(2)));
+    }
+
+    #[test]
+    fn test_send_file_not_accepted() {
+        use header::{ContentEncoding, Vary};
+        let (headers, body) = send_file("variants-refused", &["gz"], Some("gzip;q=0"));
+        assert_eq!(body, b"plain");
+        assert!(!headers.has::<ContentEncoding>());
+        assert!(headers.has::<Vary>());
+
+        let (headers, body) = send_file("variants-no-accept", &["gz"], None);
+        assert_eq!(body, b"plain");
+        assert!(!headers.has::<ContentEncoding>());
+    }
+
+    #[test]
+    fn test_send_file_without_sibling() {
+        use header::{ContentEncoding, ContentLength, Vary};
+        let (headers, body) = send_file("variants-none", &[], Some("gzip, br"));
+        assert_eq!(body, b"plain");
+        assert!(!headers.has::<ContentEncoding>());
+        assert!(!headers.has::<Vary>());
+        assert_eq!(headers.get(), Some(&ContentLength(5)));
+    }
+
     #[test]
     fn test_no_content() {
         use std::io::Write;
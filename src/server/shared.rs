@@ -0,0 +1,295 @@
+//! State shared by every request a server handles, such as counters,
+//! caches or pools.
+//!
+//! A `Handler` is shared by all the threads of a server, so state it
+//! changes must be locked. `Shared` wraps the value in a lock, and offers
+//! `try_with`, which fails instead of waiting on a busy lock, for handlers
+//! that would rather answer `503 Service Unavailable` than hold up their
+//! thread. `SharedHandler` hands a `Shared` to a handler function.
+//!
+//! ```no_run
+//! use std::collections::HashMap;
+//! use hyper::Server;
+//! use hyper::server::{Request, Response};
+//! use hyper::server::shared::{Shared, SharedHandler};
+//!
+//! fn count(hits: &Shared<HashMap<String, u64>>, req: Request, res: Response) {
+//!     let path = req.uri.to_string();
+//!     hits.with(|hits| *hits.entry(path).or_insert(0) += 1);
+//!     res.send(b"counted").unwrap();
+//! }
+//!
+//! let hits = Shared::new(HashMap::new());
+//! Server::http("127.0.0.1:0").unwrap()
+//!     .handle(SharedHandler::new(hits, count)).unwrap();
+//! ```
+use std::cell::RefCell;
+use std::error::Error as StdError;
+use std::fmt;
+use std::sync::{Arc, Mutex, MutexGuard, TryLockError};
+
+use net::Fresh;
+use server::{Handler, Request, Response};
+
+/// A value shared by the handlers of a server, behind a lock.
+///
+/// Clones share the same value.
+pub struct Shared<T> {
+    inner: Arc<Mutex<T>>,
+}
+
+/// Why `Shared::try_with` couldn't reach the value.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Unavailable {
+    /// Another thread has the value.
+    Busy,
+    /// A thread panicked while it had the value, so it may be left half
+    /// changed.
+    Poisoned,
+}
+
+impl<T> Shared<T> {
+    /// Wraps a value to be shared.
+    pub fn new(value: T) -> Shared<T> {
+        Shared { inner: Arc::new(Mutex::new(value)) }
+    }
+
+    /// Calls `f` with the value, waiting for any other thread using it.
+    ///
+    /// `f` should be quick: every thread needing the value waits on it,
+    /// so it shouldn't do I/O, such as writing a response. Calling `with`
+    /// on the same `Shared` from inside `f` would deadlock, and panics in
+    /// debug builds instead.
+    ///
+    /// # Panics
+    ///
+    /// If a thread panicked while it had the value.
+    pub fn with<R, F: FnOnce(&mut T) -> R>(&self, f: F) -> R {
+        let _entered = Entered::new(self.addr());
+        let mut value = self.inner.lock().unwrap();
+        f(&mut value)
+    }
+
+    /// Calls `f` with the value, unless another thread is using it.
+    pub fn try_with<R, F: FnOnce(&mut T) -> R>(&self, f: F) -> Result<R, Unavailable> {
+        let mut value = try!(self.try_lock());
+        Ok(f(&mut value))
+    }
+
+    /// Copies the value, unless another thread is using it.
+    pub fn try_get(&self) -> Result<T, Unavailable> where T: Clone {
+        self.try_lock().map(|value| value.clone())
+    }
+
+    fn try_lock(&self) -> Result<MutexGuard<T>, Unavailable> {
+        match self.inner.try_lock() {
+            Ok(value) => Ok(value),
+            Err(TryLockError::WouldBlock) => Err(Unavailable::Busy),
+            Err(TryLockError::Poisoned(_)) => Err(Unavailable::Poisoned),
+        }
+    }
+
+    fn addr(&self) -> usize {
+        &*self.inner as *const Mutex<T> as usize
+    }
+}
+
+impl<T> Clone for Shared<T> {
+    fn clone(&self) -> Shared<T> {
+        Shared { inner: self.inner.clone() }
+    }
+}
+
+impl<T: fmt::Debug> fmt::Debug for Shared<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.try_lock() {
+            Ok(value) => f.debug_tuple("Shared").field(&*value).finish(),
+            Err(e) => f.debug_tuple("Shared").field(&e).finish(),
+        }
+    }
+}
+
+impl fmt::Display for Unavailable {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.description())
+    }
+}
+
+impl StdError for Unavailable {
+    fn description(&self) -> &str {
+        match *self {
+            Unavailable::Busy => "shared value in use by another thread",
+            Unavailable::Poisoned => "shared value poisoned by a panic",
+        }
+    }
+}
+
+thread_local! {
+    // The `Shared` values this thread is inside `with` of.
+    static ENTERED: RefCell<Vec<usize>> = RefCell::new(Vec::new())
+}
+
+// Marks a `Shared` as entered by this thread, until dropped.
+struct Entered(usize);
+
+impl Entered {
+    #[cfg(debug_assertions)]
+    fn new(addr: usize) -> Entered {
+        ENTERED.with(|entered| {
+            let mut entered = entered.borrow_mut();
+            assert!(!entered.contains(&addr), "Shared::with called again from inside it");
+            entered.push(addr);
+        });
+        Entered(addr)
+    }
+
+    #[cfg(not(debug_assertions))]
+    fn new(addr: usize) -> Entered {
+        Entered(addr)
+    }
+}
+
+#[cfg(debug_assertions)]
+impl Drop for Entered {
+    fn drop(&mut self) {
+        let addr = self.0;
+        ENTERED.with(|entered| entered.borrow_mut().retain(|&a| a != addr));
+    }
+}
+
+/// A `Handler` calling a function with a `Shared` value for each request.
+pub struct SharedHandler<T, F> {
+    shared: Shared<T>,
+    handle: F,
+}
+
+impl<T, F> SharedHandler<T, F>
+where F: Fn(&Shared<T>, Request, Response<Fresh>) {
+    /// Creates a handler passing `shared` to `handle`.
+    pub fn new(shared: Shared<T>, handle: F) -> SharedHandler<T, F> {
+        SharedHandler { shared: shared, handle: handle }
+    }
+
+    /// Gets the shared value, such as to read it after the server closes.
+    pub fn shared(&self) -> &Shared<T> {
+        &self.shared
+    }
+}
+
+impl<T, F> Handler for SharedHandler<T, F>
+where T: Send, F: Fn(&Shared<T>, Request, Response<Fresh>) + Sync + Send {
+    fn handle<'a, 'k>(&'a self, req: Request<'a, 'k>, res: Response<'a, Fresh>) {
+        (self.handle)(&self.shared, req, res)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Barrier};
+    use std::thread;
+
+    use super::{Shared, Unavailable};
+
+    #[test]
+    fn test_try_with_busy() {
+        let shared = Shared::new(0);
+        let barrier = Arc::new(Barrier::new(2));
+        let (held, release) = (shared.clone(), barrier.clone());
+        let holder = thread::spawn(move || held.with(|n| {
+            release.wait();
+            release.wait();
+            *n += 1;
+        }));
+
+        barrier.wait();
+        assert_eq!(shared.try_with(|n| *n), Err(Unavailable::Busy));
+        assert_eq!(shared.try_get(), Err(Unavailable::Busy));
+        barrier.wait();
+        holder.join().unwrap();
+        assert_eq!(shared.try_get(), Ok(1));
+    }
+
+    #[test]
+    fn test_try_with_poisoned() {
+        let shared = Shared::new(0);
+        let poisoner = shared.clone();
+        assert!(thread::spawn(move || poisoner.with(|_| panic!("inside"))).join().is_err());
+        assert_eq!(shared.try_with(|n| *n), Err(Unavailable::Poisoned));
+    }
+
+    #[cfg(debug_assertions)]
+    #[test]
+    #[should_panic(expected = "called again from inside it")]
+    fn test_with_reentered() {
+        let shared = Shared::new(0);
+        shared.with(|_| shared.with(|n| *n += 1));
+    }
+
+    #[test]
+    fn test_with_nested_different() {
+        let a = Shared::new(1);
+        let b = Shared::new(2);
+        assert_eq!(a.with(|a| b.with(|b| *a + *b)), 3);
+        // leaving `with` forgets it was entered
+        assert_eq!(a.with(|a| *a), 1);
+    }
+
+    #[test]
+    fn test_server_busy() {
+        use std::io::{Read, Write};
+        use std::net::{SocketAddr, TcpStream};
+        use std::time::Duration;
+
+        use net::Fresh;
+        use server::{Request, Response, Server};
+        use status::StatusCode;
+        use super::SharedHandler;
+
+        fn get(addr: SocketAddr, path: &str) -> String {
+            let mut stream = TcpStream::connect(addr).unwrap();
+            stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+            write!(stream, "GET {} HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n",
+                   path).unwrap();
+            let mut res = String::new();
+            stream.read_to_string(&mut res).unwrap();
+            res
+        }
+
+        let barrier = Arc::new(Barrier::new(2));
+        let release = barrier.clone();
+        let handle = move |hits: &Shared<u32>, req: Request, mut res: Response<Fresh>| {
+            if req.uri.to_string() == "/hold" {
+                hits.with(|hits| {
+                    release.wait();
+                    release.wait();
+                    *hits += 1;
+                });
+                res.send(b"held").unwrap();
+            } else {
+                match hits.try_with(|hits| *hits) {
+                    Ok(hits) => res.send(hits.to_string().as_bytes()).unwrap(),
+                    Err(_) => {
+                        *res.status_mut() = StatusCode::ServiceUnavailable;
+                        res.send(b"busy").unwrap();
+                    }
+                }
+            }
+        };
+        let handler = SharedHandler::new(Shared::new(0), handle);
+        let mut listening = Server::http("127.0.0.1:0").unwrap().handle_threads(handler, 2).unwrap();
+        let addr = listening.socket;
+
+        let holder = thread::spawn(move || get(addr, "/hold"));
+        barrier.wait();
+        let busy = get(addr, "/count");
+        barrier.wait();
+        let held = holder.join().unwrap();
+        let count = get(addr, "/count");
+        listening.close().unwrap();
+
+        assert!(busy.starts_with("HTTP/1.1 503 Service Unavailable\r\n"), "{}", busy);
+        assert!(held.ends_with("held"));
+        assert!(count.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(count.ends_with("\r\n\r\n1"), "{}", count);
+    }
+}
@@ -0,0 +1,539 @@
+//! A cache of static files, for serving small assets without opening and
+//! reading them again for every request.
+//!
+//! Each cached file keeps its contents along with the validators made from
+//! its modification time, so conditional requests are answered with a
+//! `304 Not Modified` before any body is written, and `Range` requests are
+//! served from the cached bytes.
+//!
+//! ```
+//! use hyper::server::{Request, Response};
+//! use hyper::server::staticfile::Cache;
+//!
+//! fn handler(cache: &Cache, req: Request, res: Response) {
+//!     if let Err(e) = cache.serve(&req.headers, res, "public/app.js") {
+//!         println!("error serving app.js: {}", e);
+//!     }
+//! }
+//! ```
+use std::collections::HashMap;
+use std::fs::{self, File, Metadata};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+#[cfg(feature = "mmap")]
+use memmap::{Mmap, Protection};
+use time::{self, Timespec};
+
+use header::{Headers, AcceptRanges, ContentLength, ContentRange, ContentRangeSpec,
+             ETag, EntityTag, HttpDate, IfModifiedSince, IfNoneMatch, IfRange, LastModified,
+             Range, RangeUnit};
+use net::Fresh;
+use server::Response;
+use status::StatusCode;
+
+/// Files at least this large are mapped rather than read into memory.
+#[cfg(feature = "mmap")]
+const MMAP_THRESHOLD: u64 = 64 * 1024;
+
+/// A cache of files, shared by the handlers of a server.
+///
+/// Files are kept until the total size of the cache would go over its
+/// limit, when the least recently served ones are dropped. A file larger
+/// than the whole limit is served but never kept.
+pub struct Cache {
+    max_bytes: u64,
+    check_interval: Duration,
+    state: Mutex<State>,
+}
+
+struct State {
+    entries: HashMap<PathBuf, Cached>,
+    bytes: u64,
+    // Counts lookups, so entries can tell which was served longest ago.
+    clock: u64,
+}
+
+struct Cached {
+    entry: Arc<Entry>,
+    checked: Instant,
+    used: u64,
+}
+
+struct Entry {
+    body: Body,
+    modified: SystemTime,
+    last_modified: HttpDate,
+    etag: EntityTag,
+}
+
+enum Body {
+    Bytes(Vec<u8>),
+    #[cfg(feature = "mmap")]
+    Mapped(Mmap),
+}
+
+impl Cache {
+    /// Creates an empty cache, holding at most `max_bytes` of files.
+    pub fn new(max_bytes: u64) -> Cache {
+        Cache {
+            max_bytes: max_bytes,
+            check_interval: Duration::from_secs(1),
+            state: Mutex::new(State {
+                entries: HashMap::new(),
+                bytes: 0,
+                clock: 0,
+            }),
+        }
+    }
+
+    /// Sets how long a cached file is served before checking whether it
+    /// changed on disk.
+    ///
+    /// A file is reloaded when its modification time or length differ from
+    /// the cached copy. Files mapped into memory, with the `mmap` feature,
+    /// are checked on every request regardless. A mapped file must still
+    /// not be truncated in place while it is being served; replace it by
+    /// renaming a new file over it instead.
+    ///
+    /// Default is 1 second.
+    pub fn set_check_interval(&mut self, interval: Duration) {
+        self.check_interval = interval;
+    }
+
+    /// Writes the file at `path` as the body and ends the response.
+    ///
+    /// The response has the file's `ETag` and `Last-Modified`. If the
+    /// request's `If-None-Match` or `If-Modified-Since` shows the client
+    /// already has the file, the response is a `304 Not Modified`. A
+    /// `Range` of a single byte range is answered with a
+    /// `206 Partial Content`, or a `416 Range Not Satisfiable` if it is
+    /// past the end of the file. Other ranges, or one whose `If-Range`
+    /// doesn't match, get the whole file.
+    ///
+    /// The `Content-Type` is left to the caller.
+    pub fn serve<P: AsRef<Path>>(&self, req: &Headers, mut res: Response<Fresh>, path: P)
+            -> io::Result<()> {
+        let entry = try!(self.get(path.as_ref()));
+        res.headers_mut().set(ETag(entry.etag.clone()));
+        res.headers_mut().set(LastModified(entry.last_modified));
+        res.headers_mut().set(AcceptRanges(vec![RangeUnit::Bytes]));
+
+        if not_modified(req, &entry) {
+            *res.status_mut() = StatusCode::NotModified;
+            return res.start().and_then(|res| res.end());
+        }
+
+        let len = entry.len();
+        let (start, end) = match range(req, &entry) {
+            Ok(Some((start, end))) => {
+                *res.status_mut() = StatusCode::PartialContent;
+                res.headers_mut().set(ContentRange(ContentRangeSpec::Bytes {
+                    range: Some((start, end)),
+                    instance_length: Some(len),
+                }));
+                (start, end + 1)
+            },
+            Ok(None) => (0, len),
+            Err(()) => {
+                *res.status_mut() = StatusCode::RangeNotSatisfiable;
+                res.headers_mut().set(ContentRange(ContentRangeSpec::Bytes {
+                    range: None,
+                    instance_length: Some(len),
+                }));
+                (0, 0)
+            },
+        };
+        res.headers_mut().set(ContentLength(end - start));
+        let mut res = try!(res.start());
+        let range = start as usize..end as usize;
+        match entry.body {
+            Body::Bytes(ref bytes) => try!(res.write_all(&bytes[range])),
+            #[cfg(feature = "mmap")]
+            Body::Mapped(ref region) => try!(res.write_mmap(region, range)),
+        }
+        res.end()
+    }
+
+    // Looks up the entry for `path`, loading it if it isn't cached or has
+    // changed since it was.
+    fn get(&self, path: &Path) -> io::Result<Arc<Entry>> {
+        let cached = {
+            let mut state = self.state.lock().unwrap();
+            state.clock += 1;
+            let clock = state.clock;
+            match state.entries.get_mut(path) {
+                Some(cached) => {
+                    cached.used = clock;
+                    // a mapped file cut short under us would fault on
+                    // reading past its new end, so it is checked every time
+                    if !cached.entry.body.is_mapped()
+                            && cached.checked.elapsed() < self.check_interval {
+                        return Ok(cached.entry.clone());
+                    }
+                    Some(cached.entry.clone())
+                },
+                None => None,
+            }
+        };
+
+        let meta = match fs::metadata(path) {
+            Ok(meta) => meta,
+            Err(e) => {
+                self.remove(path);
+                return Err(e);
+            }
+        };
+        let modified = try!(meta.modified());
+        if let Some(entry) = cached {
+            if entry.modified == modified && entry.len() == meta.len() {
+                let mut state = self.state.lock().unwrap();
+                if let Some(cached) = state.entries.get_mut(path) {
+                    cached.checked = Instant::now();
+                }
+                return Ok(entry);
+            }
+            debug!("{:?} changed, reloading it", path);
+        }
+
+        let entry = Arc::new(try!(Entry::load(path, &meta)));
+        self.insert(path, entry.clone());
+        Ok(entry)
+    }
+
+    fn insert(&self, path: &Path, entry: Arc<Entry>) {
+        self.remove(path);
+        let len = entry.len();
+        if len > self.max_bytes {
+            debug!("{:?} is larger than the cache, not keeping it", path);
+            return;
+        }
+        let mut state = self.state.lock().unwrap();
+        while state.bytes + len > self.max_bytes {
+            let oldest = state.entries.iter()
+                .min_by_key(|&(_, cached)| cached.used)
+                .map(|(path, _)| path.clone());
+            match oldest {
+                Some(oldest) => {
+                    trace!("evicting {:?}", oldest);
+                    let evicted = state.entries.remove(&oldest).unwrap();
+                    state.bytes -= evicted.entry.len();
+                },
+                None => break,
+            }
+        }
+        state.bytes += len;
+        let clock = state.clock;
+        state.entries.insert(path.to_owned(), Cached {
+            entry: entry,
+            checked: Instant::now(),
+            used: clock,
+        });
+    }
+
+    fn remove(&self, path: &Path) {
+        let mut state = self.state.lock().unwrap();
+        if let Some(cached) = state.entries.remove(path) {
+            state.bytes -= cached.entry.len();
+        }
+    }
+}
+
+impl Entry {
+    fn load(path: &Path, meta: &Metadata) -> io::Result<Entry> {
+        let modified = try!(meta.modified());
+        let body = try!(Body::load(path, meta.len()));
+        // a modification time before the epoch is as good as the epoch
+        let since_epoch = modified.duration_since(UNIX_EPOCH).unwrap_or(Duration::from_secs(0));
+        let tag = format!("{:x}-{:x}.{:x}", body.len(), since_epoch.as_secs(),
+                          since_epoch.subsec_nanos());
+        Ok(Entry {
+            body: body,
+            modified: modified,
+            last_modified: HttpDate(time::at_utc(Timespec::new(since_epoch.as_secs() as i64, 0))),
+            etag: EntityTag::new(false, tag),
+        })
+    }
+
+    fn len(&self) -> u64 {
+        self.body.len()
+    }
+}
+
+impl Body {
+    fn load(path: &Path, len: u64) -> io::Result<Body> {
+        #[cfg(feature = "mmap")]
+        {
+            if len >= MMAP_THRESHOLD {
+                return Mmap::open_path(path, Protection::Read).map(Body::Mapped);
+            }
+        }
+        let mut bytes = Vec::with_capacity(len as usize);
+        try!(try!(File::open(path)).read_to_end(&mut bytes));
+        Ok(Body::Bytes(bytes))
+    }
+
+    fn is_mapped(&self) -> bool {
+        match *self {
+            Body::Bytes(_) => false,
+            #[cfg(feature = "mmap")]
+            Body::Mapped(_) => true,
+        }
+    }
+
+    fn len(&self) -> u64 {
+        match *self {
+            Body::Bytes(ref bytes) => bytes.len() as u64,
+            #[cfg(feature = "mmap")]
+            Body::Mapped(ref region) => region.len() as u64,
+        }
+    }
+}
+
+fn not_modified(req: &Headers, entry: &Entry) -> bool {
+    // If-Modified-Since is ignored when If-None-Match is sent
+    if let Some(cond) = req.get::<IfNoneMatch>() {
+        return cond.matches(&entry.etag);
+    }
+    match req.get::<IfModifiedSince>() {
+        Some(&IfModifiedSince(ref since)) => {
+            entry.last_modified.0.to_timespec() <= since.0.to_timespec()
+        },
+        None => false,
+    }
+}
+
+// The inclusive byte range requested, if the request is for one that
+// applies to this entry, or an error if it is past the end.
+fn range(req: &Headers, entry: &Entry) -> Result<Option<(u64, u64)>, ()> {
+    let spec = match req.get::<Range>() {
+        Some(&Range::Bytes(ref specs)) if specs.len() == 1 => &specs[0],
+        _ => return Ok(None),
+    };
+    let current = match req.get::<IfRange>() {
+        Some(&IfRange::EntityTag(ref tag)) => tag.strong_eq(&entry.etag),
+        Some(&IfRange::Date(ref date)) => {
+            date.0.to_timespec() == entry.last_modified.0.to_timespec()
+        },
+        None => true,
+    };
+    if !current {
+        return Ok(None);
+    }
+
+    spec.to_satisfiable_range(entry.len()).map(Some).ok_or(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs::{self, File};
+    use std::io::Write;
+    use std::path::PathBuf;
+    use std::process;
+    use std::time::Duration;
+
+    use header::{Headers, ByteRangeSpec, ContentRange, ContentRangeSpec, ETag, EntityTag,
+                 IfModifiedSince, IfNoneMatch, IfRange, LastModified, Range};
+    use mock::MockStream;
+    use server::Response;
+    use status::StatusCode;
+
+    use super::Cache;
+
+    fn dir(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("hyper-staticfile-{}-{}", name, process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn write(path: &PathBuf, contents: &[u8]) {
+        File::create(path).unwrap().write_all(contents).unwrap();
+    }
+
+    fn serve(cache: &Cache, req: &Headers, path: &PathBuf) -> (StatusCode, Headers, Vec<u8>) {
+        let mut headers = Headers::new();
+        let mut stream = MockStream::new();
+        let status = {
+            let res = Response::new(&mut stream, &mut headers);
+            cache.serve(req, res, path).unwrap();
+            let head = String::from_utf8_lossy(&stream.write[..12]).into_owned();
+            head[9..12].parse::<u16>().unwrap()
+        };
+        let body_start = stream.write.windows(4).position(|w| w == b"\r\n\r\n").unwrap() + 4;
+        (StatusCode::from_u16(status), headers, stream.write[body_start..].to_vec())
+    }
+
+    fn cached(cache: &Cache) -> Vec<String> {
+        let state = cache.state.lock().unwrap();
+        let mut names = state.entries.keys()
+            .map(|path| path.file_name().unwrap().to_string_lossy().into_owned())
+            .collect::<Vec<_>>();
+        names.sort();
+        names
+    }
+
+    #[test]
+    fn test_hit_and_miss() {
+        let dir = dir("hit");
+        let path = dir.join("app.js");
+        write(&path, b"alert(1)");
+
+        let cache = Cache::new(1024);
+        assert!(cached(&cache).is_empty());
+        let (status, headers, body) = serve(&cache, &Headers::new(), &path);
+        assert_eq!(status, StatusCode::Ok);
+        assert_eq!(body, b"alert(1)");
+        assert!(headers.has::<ETag>());
+        assert!(headers.has::<LastModified>());
+        assert_eq!(cached(&cache), vec!["app.js"]);
+
+        // served from memory, even with the file gone
+        fs::remove_file(&path).unwrap();
+        let (_, _, body) = serve(&cache, &Headers::new(), &path);
+        assert_eq!(body, b"alert(1)");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_reloads_changed_file() {
+        let dir = dir("changed");
+        let path = dir.join("app.js");
+        write(&path, b"alert(1)");
+
+        let mut cache = Cache::new(1024);
+        cache.set_check_interval(Duration::from_secs(0));
+        let (_, first, _) = serve(&cache, &Headers::new(), &path);
+
+        write(&path, b"alert(22)");
+        let (_, second, body) = serve(&cache, &Headers::new(), &path);
+        assert_eq!(body, b"alert(22)");
+        assert!(first.get::<ETag>() != second.get::<ETag>());
+
+        fs::remove_file(&path).unwrap();
+        let mut req = Headers::new();
+        let mut res_headers = Headers::new();
+        let mut stream = MockStream::new();
+        req.set(IfNoneMatch::Any);
+        assert!(cache.serve(&req, Response::new(&mut stream, &mut res_headers), &path).is_err());
+        assert!(cached(&cache).is_empty());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[cfg(feature = "mmap")]
+    #[test]
+    fn test_rechecks_mapped_file_on_every_hit() {
+        let dir = dir("mapped");
+        let path = dir.join("big.bin");
+        write(&path, &[b'x'; 100 * 1024]);
+
+        let mut cache = Cache::new(1024 * 1024);
+        cache.set_check_interval(Duration::from_secs(3600));
+        let (_, _, body) = serve(&cache, &Headers::new(), &path);
+        assert_eq!(body.len(), 100 * 1024);
+
+        // cut short well within the check interval
+        write(&path, b"short");
+        let (_, _, body) = serve(&cache, &Headers::new(), &path);
+        assert_eq!(body, b"short");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_not_modified() {
+        let dir = dir("not-modified");
+        let path = dir.join("app.js");
+        write(&path, b"alert(1)");
+        let cache = Cache::new(1024);
+        let (_, headers, _) = serve(&cache, &Headers::new(), &path);
+        let ETag(tag) = headers.get::<ETag>().unwrap().clone();
+        let LastModified(modified) = *headers.get::<LastModified>().unwrap();
+
+        let mut req = Headers::new();
+        req.set(IfNoneMatch::Items(vec![EntityTag::new(true, tag.tag().to_owned())]));
+        let (status, headers, body) = serve(&cache, &req, &path);
+        assert_eq!(status, StatusCode::NotModified);
+        assert_eq!(headers.get::<ETag>(), Some(&ETag(tag.clone())));
+        assert!(body.is_empty());
+
+        // a mismatched tag wins over a matching date
+        req.set(IfNoneMatch::Items(vec![EntityTag::new(false, "other".to_owned())]));
+        req.set(IfModifiedSince(modified));
+        let (status, _, body) = serve(&cache, &req, &path);
+        assert_eq!(status, StatusCode::Ok);
+        assert_eq!(body, b"alert(1)");
+
+        req.remove::<IfNoneMatch>();
+        let (status, _, _) = serve(&cache, &req, &path);
+        assert_eq!(status, StatusCode::NotModified);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_range() {
+        let dir = dir("range");
+        let path = dir.join("app.js");
+        write(&path, b"0123456789");
+        let cache = Cache::new(1024);
+
+        let mut req = Headers::new();
+        req.set(Range::bytes(2, 4));
+        let (status, headers, body) = serve(&cache, &req, &path);
+        assert_eq!(status, StatusCode::PartialContent);
+        assert_eq!(body, b"234");
+        assert_eq!(headers.get(), Some(&ContentRange(ContentRangeSpec::Bytes {
+            range: Some((2, 4)),
+            instance_length: Some(10),
+        })));
+
+        req.set(Range::Bytes(vec![ByteRangeSpec::Last(3)]));
+        let (_, _, body) = serve(&cache, &req, &path);
+        assert_eq!(body, b"789");
+
+        // a stale If-Range gets the whole file
+        req.set(IfRange::EntityTag(EntityTag::new(false, "other".to_owned())));
+        let (status, _, body) = serve(&cache, &req, &path);
+        assert_eq!(status, StatusCode::Ok);
+        assert_eq!(body, b"0123456789");
+
+        req.remove::<IfRange>();
+        req.set(Range::Bytes(vec![ByteRangeSpec::AllFrom(10)]));
+        let (status, headers, body) = serve(&cache, &req, &path);
+        assert_eq!(status, StatusCode::RangeNotSatisfiable);
+        assert!(body.is_empty());
+        assert_eq!(headers.get(), Some(&ContentRange(ContentRangeSpec::Bytes {
+            range: None,
+            instance_length: Some(10),
+        })));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_evicts_least_recently_served() {
+        let dir = dir("evict");
+        let paths = ["a", "b", "c", "d"].iter().map(|name| {
+            let path = dir.join(name);
+            write(&path, b"1234");
+            path
+        }).collect::<Vec<_>>();
+        let huge = dir.join("huge");
+        write(&huge, &[b'x'; 13]);
+
+        let cache = Cache::new(12);
+        for path in &paths[..3] {
+            serve(&cache, &Headers::new(), path);
+        }
+        serve(&cache, &Headers::new(), &paths[0]);
+        serve(&cache, &Headers::new(), &paths[3]);
+        assert_eq!(cached(&cache), vec!["a", "c", "d"]);
+
+        // too large to keep, so nothing is evicted for it
+        let (_, _, body) = serve(&cache, &Headers::new(), &huge);
+        assert_eq!(body.len(), 13);
+        assert_eq!(cached(&cache), vec!["a", "c", "d"]);
+        assert_eq!(cache.state.lock().unwrap().bytes, 12);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,125 @@
+//! Pacing of request and response bodies to a rate.
+use std::cell::RefCell;
+use std::cmp;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// A token bucket, letting through `rate` bytes a second on average.
+///
+/// At most a tenth of a second's worth passes at once, so a body is paced
+/// in small steps instead of bursts.
+#[derive(Debug)]
+pub struct TokenBucket {
+    rate: u64,
+    tokens: f64,
+    last: Instant,
+}
+
+impl TokenBucket {
+    pub fn new(rate: u64) -> TokenBucket {
+        TokenBucket {
+            rate: rate,
+            tokens: burst(rate) as f64,
+            last: Instant::now(),
+        }
+    }
+
+    /// Waits until some of `want` bytes may pass, and returns how many.
+    ///
+    /// This sleeps rather than spins, and only returns 0 if `want` is 0.
+    pub fn take(&mut self, want: usize) -> usize {
+        let n = cmp::min(want as u64, burst(self.rate));
+        if n == 0 {
+            return 0;
+        }
+        self.refill();
+        let missing = n as f64 - self.tokens;
+        if missing > 0.0 {
+            let nanos = (missing * 1_000_000_000.0 / self.rate as f64).ceil() as u64;
+            thread::sleep(Duration::new(nanos / 1_000_000_000, (nanos % 1_000_000_000) as u32));
+            self.refill();
+        }
+        self.tokens = (self.tokens - n as f64).max(0.0);
+        n as usize
+    }
+
+    /// Returns tokens taken for bytes that didn't pass after all.
+    pub fn give_back(&mut self, unused: usize) {
+        self.tokens = (self.tokens + unused as f64).min(burst(self.rate) as f64);
+    }
+
+    fn refill(&mut self) {
+        let now = Instant::now();
+        let elapsed = now - self.last;
+        self.last = now;
+        let secs = elapsed.as_secs() as f64 + elapsed.subsec_nanos() as f64 / 1_000_000_000.0;
+        self.tokens = (self.tokens + secs * self.rate as f64).min(burst(self.rate) as f64);
+    }
+}
+
+/// The bucket pacing a body: one of its own, or that of its connection,
+/// shared by every request or response on it.
+#[derive(Debug)]
+pub enum Throttle<'a> {
+    Own(TokenBucket),
+    Connection(&'a RefCell<TokenBucket>),
+}
+
+impl<'a> Throttle<'a> {
+    /// Like `TokenBucket::take`.
+    pub fn take(&mut self, want: usize) -> usize {
+        match *self {
+            Throttle::Own(ref mut bucket) => bucket.take(want),
+            Throttle::Connection(bucket) => bucket.borrow_mut().take(want),
+        }
+    }
+
+    /// Like `TokenBucket::give_back`.
+    pub fn give_back(&mut self, unused: usize) {
+        match *self {
+            Throttle::Own(ref mut bucket) => bucket.give_back(unused),
+            Throttle::Connection(bucket) => bucket.borrow_mut().give_back(unused),
+        }
+    }
+}
+
+fn burst(rate: u64) -> u64 {
+    cmp::max(rate / 10, 1)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    use std::time::{Duration, Instant};
+
+    use super::{Throttle, TokenBucket};
+
+    #[test]
+    fn test_take_paces() {
+        let mut bucket = TokenBucket::new(10_000);
+        assert_eq!(bucket.take(0), 0);
+        assert_eq!(bucket.take(5_000), 1_000);
+
+        let start = Instant::now();
+        let mut passed = 0;
+        while passed < 3_000 {
+            passed += bucket.take(3_000 - passed);
+        }
+        // the first 1000 bytes used up the burst
+        assert!(start.elapsed() >= Duration::from_millis(250));
+    }
+
+    #[test]
+    fn test_connection_bucket_is_shared() {
+        let bucket = RefCell::new(TokenBucket::new(10_000));
+        let mut first = Throttle::Connection(&bucket);
+        assert_eq!(first.take(600), 600);
+        first.give_back(100);
+
+        // a later body on the connection only gets what is left
+        let mut second = Throttle::Connection(&bucket);
+        let start = Instant::now();
+        assert_eq!(second.take(1_000), 1_000);
+        assert!(start.elapsed() >= Duration::from_millis(40));
+    }
+}
@@ -0,0 +1,342 @@
+//! Coarse timeouts for many connections, fired from one thread.
+//!
+//! Timers are hashed into the slots of a wheel by the tick they are due
+//! in, so arming and cancelling one is a map insert or remove, however many
+//! are outstanding. A single thread advances the wheel, sleeping while it
+//! is empty.
+//!
+//! A timer never fires early, but may fire up to one tick late. Time is
+//! read from a `Clock`, so a jump forward, such as after a suspend, at
+//! worst fires timers early.
+use std::cmp;
+use std::collections::HashMap;
+use std::sync::{Arc, Condvar, Mutex, Weak};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use super::clock::{Clock, SystemClock};
+
+/// How long a tick of the wheel is, in milliseconds.
+const TICK_MS: u64 = 100;
+/// How many slots the wheel has. Timers further out than a turn wait in
+/// their slot for the extra turns.
+const SLOTS: usize = 512;
+
+/// A callback run when a timer fires.
+pub type Fire = Box<FnMut() + Send>;
+
+/// A wheel of timers, shared by the connections of a `Server`.
+#[derive(Clone)]
+pub struct TimerWheel {
+    shared: Arc<Shared>,
+}
+
+struct Shared {
+    wheel: Mutex<Wheel>,
+    cvar: Condvar,
+    clock: Arc<Clock>,
+}
+
+struct Wheel {
+    started: Instant,
+    // The last tick that has been expired.
+    tick: u64,
+    next_id: u64,
+    len: usize,
+    slots: Vec<HashMap<u64, Entry>>,
+    running: bool,
+}
+
+struct Entry {
+    due: u64,
+    fire: Fire,
+}
+
+/// An armed timer, which can be cancelled until it fires.
+pub struct Timer {
+    shared: Weak<Shared>,
+    slot: usize,
+    id: u64,
+}
+
+impl TimerWheel {
+    /// Creates an empty wheel. Its thread starts with the first timer.
+    pub fn new() -> TimerWheel {
+        TimerWheel::with_clock(Arc::new(SystemClock))
+    }
+
+    /// Creates an empty wheel whose timers are due by the time of `clock`.
+    pub fn with_clock(clock: Arc<Clock>) -> TimerWheel {
+        let started = clock.now();
+        TimerWheel {
+            shared: Arc::new(Shared {
+                wheel: Mutex::new(Wheel {
+                    started: started,
+                    tick: 0,
+                    next_id: 0,
+                    len: 0,
+                    slots: (0..SLOTS).map(|_| HashMap::new()).collect(),
+                    running: false,
+                }),
+                cvar: Condvar::new(),
+                clock: clock,
+            })
+        }
+    }
+
+    /// Runs `fire` on the wheel's thread once `timeout` has passed.
+    pub fn arm(&self, timeout: Duration, fire: Fire) -> Timer {
+        let timer = self.arm_at(self.shared.clock.now() + timeout, fire);
+        let mut wheel = self.shared.wheel.lock().unwrap();
+        if !wheel.running {
+            wheel.running = true;
+            let shared = Arc::downgrade(&self.shared);
+            thread::spawn(move || run(shared));
+        }
+        self.shared.cvar.notify_one();
+        timer
+    }
+
+    fn arm_at(&self, deadline: Instant, fire: Fire) -> Timer {
+        let mut wheel = self.shared.wheel.lock().unwrap();
+        // round up, so the timer can't fire before its deadline
+        let due = cmp::max(ticks_ceil(deadline - wheel.started), wheel.tick + 1);
+        let id = wheel.next_id;
+        wheel.next_id += 1;
+        let slot = (due % SLOTS as u64) as usize;
+        wheel.slots[slot].insert(id, Entry { due: due, fire: fire });
+        wheel.len += 1;
+        Timer { shared: Arc::downgrade(&self.shared), slot: slot, id: id }
+    }
+
+    /// Takes the timers due by `now`, to be fired outside the lock.
+    fn expire(&self, now: Instant) -> Vec<Fire> {
+        let mut wheel = self.shared.wheel.lock().unwrap();
+        let now = ticks_floor(now - wheel.started);
+        let mut due = Vec::new();
+        if now <= wheel.tick {
+            return due;
+        }
+        // after a full turn, every slot has been passed
+        let first = cmp::max(wheel.tick + 1, now.saturating_sub(SLOTS as u64 - 1));
+        for tick in first..now + 1 {
+            let slot = &mut wheel.slots[(tick % SLOTS as u64) as usize];
+            let ids = slot.iter()
+                .filter(|&(_, entry)| entry.due <= now)
+                .map(|(&id, _)| id)
+                .collect::<Vec<_>>();
+            for id in ids {
+                due.push(slot.remove(&id).unwrap().fire);
+            }
+        }
+        wheel.tick = now;
+        wheel.len -= due.len();
+        due
+    }
+}
+
+impl Timer {
+    /// Stops the timer, returning false if it already fired.
+    pub fn cancel(&self) -> bool {
+        match self.shared.upgrade() {
+            Some(shared) => {
+                let mut wheel = shared.wheel.lock().unwrap();
+                if wheel.slots[self.slot].remove(&self.id).is_some() {
+                    wheel.len -= 1;
+                    true
+                } else {
+                    false
+                }
+            },
+            None => false
+        }
+    }
+}
+
+// Advances the wheel until every handle to it is gone.
+fn run(shared: Weak<Shared>) {
+    loop {
+        let shared = match shared.upgrade() {
+            Some(shared) => shared,
+            None => return,
+        };
+        {
+            let wheel = shared.wheel.lock().unwrap();
+            if wheel.len == 0 {
+                // woken by arm, or now and then to notice the wheel is gone
+                let _ = shared.cvar.wait_timeout(wheel, Duration::from_secs(1)).unwrap();
+                continue;
+            }
+        }
+        thread::sleep(Duration::from_millis(TICK_MS));
+        let now = shared.clock.now();
+        let wheel = TimerWheel { shared: shared };
+        for mut fire in wheel.expire(now) {
+            fire();
+        }
+    }
+}
+
+fn ticks_floor(elapsed: Duration) -> u64 {
+    millis(elapsed) / TICK_MS
+}
+
+fn ticks_ceil(elapsed: Duration) -> u64 {
+    (millis(elapsed) + TICK_MS - 1) / TICK_MS
+}
+
+fn millis(d: Duration) -> u64 {
+    d.as_secs() * 1000 + (d.subsec_nanos() / 1_000_000) as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+    use std::time::{Duration, Instant};
+
+    use server::clock::{Clock, MockClock};
+    use super::{TimerWheel, Fire, SLOTS, TICK_MS};
+
+    fn mock_wheel() -> (TimerWheel, Instant) {
+        let clock = Arc::new(MockClock::new());
+        let start = clock.now();
+        (TimerWheel::with_clock(clock), start)
+    }
+
+    fn record(log: &Arc<Mutex<Vec<u32>>>, n: u32) -> Fire {
+        let log = log.clone();
+        Box::new(move || log.lock().unwrap().push(n))
+    }
+
+    fn ms(n: u64) -> Duration {
+        Duration::from_millis(n)
+    }
+
+    #[test]
+    fn test_fires_within_a_tick() {
+        let (wheel, start) = mock_wheel();
+        let log = Arc::new(Mutex::new(Vec::new()));
+        wheel.arm_at(start + ms(250), record(&log, 1));
+        wheel.arm_at(start + ms(420), record(&log, 2));
+
+        let mut fired_at = Vec::new();
+        for step in 1..12 {
+            let now = start + ms(step * 50);
+            for mut fire in wheel.expire(now) {
+                fire();
+                fired_at.push(step * 50);
+            }
+        }
+        assert_eq!(*log.lock().unwrap(), vec![1, 2]);
+        for (&at, &deadline) in fired_at.iter().zip([250, 420].iter()) {
+            assert!(at >= deadline && at <= deadline + TICK_MS, "fired at {}", at);
+        }
+    }
+
+    #[test]
+    fn test_cancel_never_fires() {
+        let (wheel, start) = mock_wheel();
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let cancelled = wheel.arm_at(start + ms(100), record(&log, 1));
+        let kept = wheel.arm_at(start + ms(100), record(&log, 2));
+        assert!(cancelled.cancel());
+        assert!(!cancelled.cancel());
+
+        for mut fire in wheel.expire(start + ms(1000)) {
+            fire();
+        }
+        assert_eq!(*log.lock().unwrap(), vec![2]);
+        assert!(!kept.cancel());
+    }
+
+    #[test]
+    fn test_timers_past_a_turn() {
+        let (wheel, start) = mock_wheel();
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let turn = SLOTS as u64 * TICK_MS;
+        // both hash to the same slot, a turn apart
+        wheel.arm_at(start + ms(turn + TICK_MS), record(&log, 2));
+        wheel.arm_at(start + ms(TICK_MS), record(&log, 1));
+
+        for mut fire in wheel.expire(start + ms(TICK_MS)) {
+            fire();
+        }
+        assert_eq!(*log.lock().unwrap(), vec![1]);
+
+        // a jump of several turns still finds it
+        for mut fire in wheel.expire(start + ms(turn * 3)) {
+            fire();
+        }
+        assert_eq!(*log.lock().unwrap(), vec![1, 2]);
+    }
+
+    #[test]
+    fn test_thread_fires_by_clock() {
+        let clock = Arc::new(MockClock::new());
+        let wheel = TimerWheel::with_clock(clock.clone());
+        let log = Arc::new(Mutex::new(Vec::new()));
+        wheel.arm(ms(10), record(&log, 1));
+
+        // a few ticks go by, but the clock doesn't
+        ::std::thread::sleep(ms(TICK_MS * 3));
+        assert!(log.lock().unwrap().is_empty());
+
+        clock.advance(ms(TICK_MS * 2));
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while log.lock().unwrap().is_empty() && Instant::now() < deadline {
+            ::std::thread::sleep(ms(5));
+        }
+        assert_eq!(*log.lock().unwrap(), vec![1]);
+    }
+
+    #[test]
+    fn test_thread_fires() {
+        let wheel = TimerWheel::new();
+        let log = Arc::new(Mutex::new(Vec::new()));
+        wheel.arm(ms(10), record(&log, 1));
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while log.lock().unwrap().is_empty() && Instant::now() < deadline {
+            ::std::thread::sleep(ms(5));
+        }
+        assert_eq!(*log.lock().unwrap(), vec![1]);
+    }
+
+    #[cfg(feature = "nightly")]
+    use test::Bencher;
+
+    #[cfg(feature = "nightly")]
+    #[bench]
+    fn bench_arm_cancel(b: &mut Bencher) {
+        let wheel = TimerWheel::new();
+        // a wheel already busy with other connections
+        let _idle = (0..10_000).map(|_| wheel.arm_at(Instant::now() + ms(60_000), Box::new(|| ())))
+            .collect::<Vec<_>>();
+        b.iter(|| {
+            wheel.arm_at(Instant::now() + ms(5_000), Box::new(|| ())).cancel();
+        });
+    }
+
+    // What each deadline cost before the wheel: a thread waiting on a
+    // condvar until it was told the response started.
+    #[cfg(feature = "nightly")]
+    #[bench]
+    fn bench_arm_cancel_thread(b: &mut Bencher) {
+        use std::sync::Condvar;
+        use std::thread;
+
+        b.iter(|| {
+            let shared = Arc::new((Mutex::new(false), Condvar::new()));
+            let watched = shared.clone();
+            let watcher = thread::spawn(move || {
+                let &(ref lock, ref cvar) = &*watched;
+                let mut done = lock.lock().unwrap();
+                while !*done {
+                    done = cvar.wait_timeout(done, ms(5_000)).unwrap().0;
+                }
+            });
+            *shared.0.lock().unwrap() = true;
+            shared.1.notify_one();
+            watcher.join().unwrap();
+        });
+    }
+}
@@ -0,0 +1,87 @@
+//! Runs the client example against a server, checking its output and exit
+//! codes.
+extern crate hyper;
+
+use std::env;
+use std::net::{SocketAddr, TcpListener};
+use std::path::PathBuf;
+use std::process::{Command, Output};
+
+use hyper::server::{Listening, Request, Response, Server};
+use hyper::status::StatusCode;
+use hyper::uri::RequestUri;
+
+fn handle(req: Request, mut res: Response) {
+    if req.uri == RequestUri::AbsolutePath("/".to_owned()) {
+        res.send(b"hello").unwrap();
+    } else {
+        *res.status_mut() = StatusCode::NotFound;
+        res.send(b"not found").unwrap();
+    }
+}
+
+fn serve() -> Listening {
+    Server::http("127.0.0.1:0").unwrap().handle_threads(handle, 1).unwrap()
+}
+
+// `cargo test` builds the examples into `examples`, next to the `deps`
+// directory this test runs from.
+fn client() -> PathBuf {
+    let mut path = env::current_exe().unwrap();
+    path.pop();
+    if path.ends_with("deps") {
+        path.pop();
+    }
+    let path = path.join("examples").join(format!("client{}", env::consts::EXE_SUFFIX));
+    assert!(path.exists(), "{:?} is missing, build it with `cargo build --example client`", path);
+    path
+}
+
+fn run(args: &[&str]) -> Output {
+    Command::new(client()).args(args).output().unwrap()
+}
+
+// An address nothing listens on.
+fn refused() -> SocketAddr {
+    TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap()
+}
+
+#[test]
+fn test_success() {
+    let mut listening = serve();
+    let url = format!("http://{}/", listening.socket);
+    for args in &[vec![&url[..]], vec!["--connect-timeout", "5", &url[..]]] {
+        let out = run(args);
+        assert_eq!(out.status.code(), Some(0), "{:?}", out);
+        assert_eq!(out.stdout, b"hello");
+    }
+    listening.close().unwrap();
+}
+
+#[test]
+fn test_not_found() {
+    let mut listening = serve();
+    let out = run(&[&format!("http://{}/missing", listening.socket)]);
+    assert_eq!(out.status.code(), Some(22), "{:?}", out);
+    assert_eq!(out.stdout, b"not found");
+    assert!(String::from_utf8_lossy(&out.stderr).contains("404"));
+    listening.close().unwrap();
+}
+
+#[test]
+fn test_connection_refused() {
+    let url = format!("http://{}/", refused());
+    for args in &[vec![&url[..]], vec!["--connect-timeout", "5", &url[..]]] {
+        let out = run(args);
+        assert_eq!(out.status.code(), Some(7), "{:?}", out);
+        assert!(out.stdout.is_empty());
+    }
+}
+
+#[test]
+fn test_usage() {
+    let out = run(&["--connect-timeout", "soon", "http://localhost/"]);
+    assert_eq!(out.status.code(), Some(2), "{:?}", out);
+    let out = run(&["--connect-timeout", "5", "https://localhost/"]);
+    assert_eq!(out.status.code(), Some(2), "{:?}", out);
+}